@@ -0,0 +1,40 @@
+use std::env;
+use image::ExtendedColorType;
+use text_colorizer::Colorize;
+use programming_rust::chapter_two::{colorize, parse_mandelbrot_args, print_mandelbrot_usage, render_bands,
+                                    render_concurrently, render_escapes, write_image};
+
+fn main() {
+    let args = match parse_mandelbrot_args(env::args().skip(1)) {
+        Ok(v) => v,
+        Err(e) => {
+            print_mandelbrot_usage();
+            eprintln!("{} {}", "Error:".red().bold(), e);
+            std::process::exit(1);
+        }
+    };
+
+    let (pixels, color) = match &args.palette {
+        None => {
+            let mut pixels = vec![0; args.bounds.0 * args.bounds.1];
+            render_concurrently(&mut pixels, args.bounds, args.upper_left, args.lower_right,
+                                args.limit, args.radius, args.threads);
+            (pixels, ExtendedColorType::L8)
+        }
+        Some(palette) => {
+            let mut escapes = vec![None; args.bounds.0 * args.bounds.1];
+            render_bands(&mut escapes, args.bounds, args.upper_left, args.lower_right, args.threads,
+                         |band, bounds, upper_left, lower_right| {
+                             render_escapes(band, bounds, upper_left, lower_right, args.limit, args.radius)
+                         });
+            let pixels = colorize(&escapes, args.limit, args.radius, palette, args.coloring, args.alpha);
+            (pixels, if args.alpha { ExtendedColorType::Rgba8 } else { ExtendedColorType::Rgb8 })
+        }
+    };
+
+    if let Err(e) = write_image(&args.filename, &pixels, args.bounds, color, args.format) {
+        eprintln!("{} failed to write image '{}': {}",
+                  "Error:".red().bold(), args.filename, e);
+        std::process::exit(1);
+    }
+}
@@ -1,7 +1,7 @@
-/// # Chapter 5. References
-///
-/// Programming Rust
-///
-/// Fast, Safe System Development
-///
-/// Jim Blandy, Jason Orendorff & Leonora F.S. Tindall
\ No newline at end of file
+//! # Chapter 5. References
+//!
+//! Programming Rust
+//!
+//! Fast, Safe System Development
+//!
+//! Jim Blandy, Jason Orendorff & Leonora F.S. Tindall
\ No newline at end of file
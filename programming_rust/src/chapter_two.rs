@@ -1,10 +1,8 @@
 use actix_web::{web, HttpResponse, Responder};
-use image::codecs::png::PngEncoder;
-use image::{ExtendedColorType, ImageEncoder};
+use image::{ExtendedColorType, ImageFormat, ImageResult};
 use num::Complex;
 use serde::Deserialize;
 use std::env;
-use std::fs::File;
 use std::str::FromStr;
 use regex::Regex;
 use text_colorizer::*;
@@ -111,21 +109,72 @@ pub async fn post_gcd(form: web::Form<GcdParameters>) -> impl Responder {
 /// iterations to decide.
 ///
 /// If `c` is not a member, return `Some(i)`, where `i` is the number of
-/// iterations it took for `c` to leave the circle of radius 2 centered on the
-/// origin. If `c` seems to be a member (more precisely, if we reached the
-/// iteration limit without being able to prove that `c` is not a member),
+/// iterations it took for `c` to leave the circle of radius `radius` centered
+/// on the origin. If `c` seems to be a member (more precisely, if we reached
+/// the iteration limit without being able to prove that `c` is not a member),
 /// return `None`.
-fn escape_time(c: Complex<f64>, limit: usize) -> Option<usize> {
+///
+/// No point that leaves the circle of radius 2 ever comes back, so `radius`
+/// should be at least 2; a larger one makes for smoother coloring.
+fn escape_time(c: Complex<f64>, limit: u32, radius: f64) -> Option<u32> {
+    escape(c, limit, radius).map(|escape| escape.count)
+}
+
+/// How a point that is not in the Mandelbrot set got away.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Escape {
+    /// The number of iterations it took to leave the escape circle.
+    pub count: u32,
+    /// The squared distance from the origin it had reached by then.
+    pub norm_sqr: f64,
+}
+
+impl Escape {
+    /// The iteration count as a continuous value, using how far past the
+    /// escape circle of radius `radius` the point had got, so that coloring
+    /// by it shows no bands.
+    pub fn smooth_count(&self, radius: f64) -> f64 {
+        let log_z = self.norm_sqr.ln() / 2.0;
+        self.count as f64 + 1.0 - (log_z / radius.ln()).log2()
+    }
+}
+
+/// Like `escape_time`, but say how far the point had got when it escaped,
+/// as well as when.
+pub fn escape(c: Complex<f64>, limit: u32, radius: f64) -> Option<Escape> {
+    let radius_sqr = radius * radius;
     let mut z = Complex { re: 0.0, im: 0.0 };
     for i in 0..limit {
-        if z.norm_sqr() > 4.0 {
-            return Some(i);
+        let norm_sqr = z.norm_sqr();
+        if norm_sqr > radius_sqr {
+            return Some(Escape { count: i, norm_sqr });
         }
         z = z * z + c;
     }
     None
 }
 
+#[test]
+fn test_escape() {
+    assert_eq!(escape(Complex { re: 0.0, im: 0.0 }, 100, 2.0), None);
+    assert_eq!(escape(Complex { re: 3.0, im: 0.0 }, 100, 2.0), Some(Escape { count: 1, norm_sqr: 9.0 }));
+    assert_eq!(escape_time(Complex { re: 1.0, im: 0.0 }, 100, 2.0), Some(3));
+    assert_eq!(escape_time(Complex { re: 1.0, im: 0.0 }, 100, 10.0), Some(4));
+    // Close to the set, points take more than 255 iterations to escape.
+    assert_eq!(escape_time(Complex { re: 0.2501, im: 0.0 }, 255, 2.0), None);
+    assert!(escape_time(Complex { re: 0.2501, im: 0.0 }, 1000, 2.0).is_some_and(|count| count > 255));
+
+    // Points escaping one iteration apart differ by about one in their
+    // smooth counts too, whatever the radius.
+    for radius in [2.0, 1000.0] {
+        let near = escape(Complex { re: 0.3, im: 0.0 }, 100, radius).unwrap();
+        let far = escape(Complex { re: 0.4, im: 0.0 }, 100, radius).unwrap();
+        assert!(near.count > far.count);
+        assert!(near.smooth_count(radius) > far.smooth_count(radius));
+        assert!((near.smooth_count(radius) - near.count as f64).abs() < 2.0);
+    }
+}
+
 /// ## Parsing Pair Command-Line Arguments
 ///
 /// Parse the string `s` as a coordinate pair, like `"400x600"` or `"1.0,0.5"`.
@@ -211,9 +260,23 @@ fn test_pixel_to_point() {
 /// which holds one grayscale pixel per byte. The `upper_left` and `lower_right`
 /// arguments specify points on the complex plane corresponding
 /// to the `upper-left` and `lower-right` corners of the pixel buffer.
+///
+/// `limit` and `radius` are passed on to `escape_time`. The iteration counts
+/// are worked out first, then mapped to pixels by `counts_to_pixels`.
 pub fn render(pixels: &mut [u8], bounds: (usize, usize),
-              upper_left: Complex<f64>, lower_right: Complex<f64>) {
-    assert_eq!(pixels.len(), bounds.0 * bounds.1);
+              upper_left: Complex<f64>, lower_right: Complex<f64>, limit: u32, radius: f64) {
+    let mut counts = vec![0; pixels.len()];
+    render_counts(&mut counts, bounds, upper_left, lower_right, limit, radius);
+    counts_to_pixels(&counts, pixels, limit);
+}
+
+/// Render a rectangle of the Mandelbrot set into a buffer of iteration
+/// counts, laid out like `render`'s pixels. Each count is what `escape_time`
+/// returns for the pixel's point, or `limit` for points that seem to be
+/// members.
+pub fn render_counts(counts: &mut [u32], bounds: (usize, usize),
+                     upper_left: Complex<f64>, lower_right: Complex<f64>, limit: u32, radius: f64) {
+    assert_eq!(counts.len(), bounds.0 * bounds.1);
 
     for row in 0..bounds.1 {
         for column in 0..bounds.0 {
@@ -223,14 +286,39 @@ pub fn render(pixels: &mut [u8], bounds: (usize, usize),
                 upper_left,
                 lower_right);
 
-            pixels[row * bounds.0 + column] = match escape_time(point, 255) {
-                None => 0,
-                Some(count) => 255 - count as u8
-            };
+            counts[row * bounds.0 + column] = escape_time(point, limit, radius).unwrap_or(limit);
         }
     }
 }
 
+/// Map iteration counts rendered with iteration limit `limit` to grayscale
+/// pixels: members of the set are black, and other points go from white
+/// towards black the longer they took to escape.
+pub fn counts_to_pixels(counts: &[u32], pixels: &mut [u8], limit: u32) {
+    assert_eq!(counts.len(), pixels.len());
+
+    let limit = limit as u64;
+    for (pixel, &count) in pixels.iter_mut().zip(counts) {
+        let count = count as u64;
+        *pixel = if count >= limit {
+            0
+        } else {
+            // Rounded, so that with a limit of 255 each count gets a shade of
+            // its own.
+            255 - ((count * 255 + limit / 2) / limit) as u8
+        };
+    }
+}
+
+#[test]
+fn test_counts_to_pixels() {
+    let mut pixels = [0; 5];
+    counts_to_pixels(&[0, 1, 100, 254, 255], &mut pixels, 255);
+    assert_eq!(pixels, [255, 254, 155, 1, 0]);
+    counts_to_pixels(&[0, 1, 500, 999, 1000], &mut pixels, 1000);
+    assert_eq!(pixels, [255, 255, 127, 0, 0]);
+}
+
 #[test]
 fn test_render() {
     let bounds = parse_pair("100x100", 'x')
@@ -244,23 +332,96 @@ fn test_render() {
 
     let mut pixels = vec![0; bounds.0 * bounds.1];
 
-    render(&mut pixels, bounds, upper_left, lower_right);
+    render(&mut pixels, bounds, upper_left, lower_right, 255, 2.0);
+
+    // A higher limit finds points that looked like members to escape.
+    let mut deeper = vec![0; bounds.0 * bounds.1];
+    render(&mut deeper, bounds, upper_left, lower_right, 5000, 2.0);
+    let members = |pixels: &[u8]| pixels.iter().filter(|&&pixel| pixel == 0).count();
+    assert!(members(&deeper) < members(&pixels));
 }
 
-/// Write the buffer `pixels`, whose dimensions are given by `bounds`, to the
-/// file named `filename`.
-pub fn write_image(filename: &str, pixels: &[u8],
-                   bounds: (usize, usize)) -> Result<(), std::io::Error> {
-    let output = File::create(filename)?;
-    let encoder = PngEncoder::new(output);
+/// ## Running Mandelbrot Concurrently
+///
+/// Render like `render`, but split the image into horizontal bands and
+/// render each band on its own thread, using `threads` threads at most.
+pub fn render_concurrently(pixels: &mut [u8], bounds: (usize, usize),
+                           upper_left: Complex<f64>, lower_right: Complex<f64>,
+                           limit: u32, radius: f64, threads: usize) {
+    render_bands(pixels, bounds, upper_left, lower_right, threads,
+                 |band, band_bounds, band_upper_left, band_lower_right| {
+                     render(band, band_bounds, band_upper_left, band_lower_right, limit, radius)
+                 });
+}
 
-    encoder.write_image(&pixels, bounds.0 as u32, bounds.1 as u32, ExtendedColorType::L8)
-        .expect("failed to write to file");
-    Ok(())
+/// Split `buffer`, which holds one value per pixel of an image with
+/// dimensions `bounds`, into horizontal bands and call `render_band` on each
+/// band on its own thread, using `threads` threads at most. `render_band` is
+/// passed the band, its bounds, and the points at its corners.
+pub fn render_bands<T, F>(buffer: &mut [T], bounds: (usize, usize),
+                          upper_left: Complex<f64>, lower_right: Complex<f64>,
+                          threads: usize, render_band: F)
+    where T: Send,
+          F: Fn(&mut [T], (usize, usize), Complex<f64>, Complex<f64>) + Sync
+{
+    assert_eq!(buffer.len(), bounds.0 * bounds.1);
+    assert!(threads > 0, "at least one thread is needed");
+    if buffer.is_empty() {
+        return;
+    }
+
+    let rows_per_band = bounds.1 / threads + 1;
+    let bands: Vec<&mut [T]> = buffer.chunks_mut(rows_per_band * bounds.0)
+        .collect();
+
+    crossbeam::scope(|spawner| {
+        for (i, band) in bands.into_iter().enumerate() {
+            let top = rows_per_band * i;
+            let height = band.len() / bounds.0;
+            let band_bounds = (bounds.0, height);
+            let band_upper_left = pixel_to_point(
+                bounds, (0, top), upper_left, lower_right);
+            let band_lower_right = pixel_to_point(
+                bounds, (bounds.0, top + height), upper_left, lower_right);
+            let render_band = &render_band;
+            spawner.spawn(move |_| {
+                render_band(band, band_bounds, band_upper_left, band_lower_right);
+            });
+        }
+    }).unwrap();
+}
+
+#[test]
+fn test_render_concurrently() {
+    let bounds = (60, 45);
+    let upper_left = Complex { re: -1.20, im: 0.35 };
+    let lower_right = Complex { re: -1.0, im: 0.20 };
+
+    let mut expected = vec![0; bounds.0 * bounds.1];
+    render(&mut expected, bounds, upper_left, lower_right, 100, 2.0);
+
+    for threads in [1, 4, 7, 100] {
+        let mut pixels = vec![0; bounds.0 * bounds.1];
+        render_concurrently(&mut pixels, bounds, upper_left, lower_right, 100, 2.0, threads);
+        assert_eq!(pixels, expected, "{} threads", threads);
+    }
+}
+
+/// Write the buffer `pixels`, whose dimensions are given by `bounds`, to the
+/// file named `filename`, encoded in `format`. `color` says how the pixels
+/// are laid out, such as `ExtendedColorType::L8` for one grayscale byte each.
+pub fn write_image(filename: &str, pixels: &[u8], bounds: (usize, usize),
+                   color: ExtendedColorType, format: ImageFormat) -> ImageResult<()> {
+    image::save_buffer_with_format(filename, pixels, bounds.0 as u32, bounds.1 as u32,
+                                   color, format)
 }
 
 #[test]
 fn test_simple_image() {
+    use image::codecs::png::PngEncoder;
+    use image::ImageEncoder;
+    use std::fs::File;
+
     let gray = File::create("gray.png")
         .expect("failed to create file");
 
@@ -285,9 +446,9 @@ fn test_write_image() {
 
     let mut pixels = vec![0; bounds.0 * bounds.1];
 
-    render(&mut pixels, bounds, upper_left, lower_right);
+    render(&mut pixels, bounds, upper_left, lower_right, 255, 2.0);
 
-    write_image("test_write_image.png", &pixels, bounds)
+    write_image("test_write_image.png", &pixels, bounds, ExtendedColorType::L8, ImageFormat::Png)
         .expect("error writing PNG file");
 }
 
@@ -304,32 +465,424 @@ fn test_write_image_concurrently() {
 
     let mut pixels = vec![0; bounds.0 * bounds.1];
 
-    let threads = 8;
-    let rows_per_band = bounds.1 / threads + 1;
+    render_concurrently(&mut pixels, bounds, upper_left, lower_right, 255, 2.0, 8);
+
+    write_image("test_write_image_concurrently.png", &pixels, bounds, ExtendedColorType::L8, ImageFormat::Png)
+        .expect("error writing PNG file");
+}
+
+/// ## Coloring the Set
+///
+/// A color gradient: RGBA colors at positions from 0.0 to 1.0, blended
+/// together in between.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Palette {
+    stops: Vec<(f64, [u8; 4])>,
+}
+
+/// The names `Palette::builtin` knows.
+pub const PALETTES: [&str; 4] = ["gray", "fire", "ocean", "classic"];
+
+impl Palette {
+    /// Make a palette from `stops`, which must be ordered by position, with
+    /// every position between 0.0 and 1.0.
+    pub fn new(stops: Vec<(f64, [u8; 4])>) -> Result<Palette, String> {
+        if stops.is_empty() {
+            return Err("a palette needs at least one color".to_string());
+        }
+        if stops.iter().any(|&(position, _)| !(0.0..=1.0).contains(&position)) {
+            return Err("palette positions must be between 0 and 1".to_string());
+        }
+        if stops.windows(2).any(|pair| pair[0].0 > pair[1].0) {
+            return Err("palette positions must be in order".to_string());
+        }
+        Ok(Palette { stops })
+    }
 
-    {
-        let bands: Vec<&mut [u8]> = pixels.chunks_mut(rows_per_band * bounds.0)
+    /// One of the palettes named in `PALETTES`. `gray` runs from white to
+    /// black, so it colors like `render` does.
+    pub fn builtin(name: &str) -> Option<Palette> {
+        let stops: &[(f64, u32)] = match name {
+            "gray" => &[(0.0, 0xffffff), (1.0, 0x000000)],
+            "fire" => &[(0.0, 0x000000), (0.3, 0x8b0000), (0.6, 0xff8c00), (0.85, 0xffff00), (1.0, 0xffffff)],
+            "ocean" => &[(0.0, 0x000020), (0.4, 0x004080), (0.75, 0x00c0ff), (1.0, 0xffffff)],
+            "classic" => &[(0.0, 0x000764), (0.16, 0x206bcb), (0.42, 0xedffff), (0.6425, 0xffaa00),
+                           (0.8575, 0x000200), (1.0, 0x000764)],
+            _ => return None,
+        };
+        let stops = stops.iter()
+            .map(|&(position, rgb)| {
+                let [_, r, g, b] = rgb.to_be_bytes();
+                (position, [r, g, b, 255])
+            })
             .collect();
+        Some(Palette { stops })
+    }
+
+    /// Parse a gradient file: one color per line as `#rrggbb` or
+    /// `#rrggbbaa`, each optionally preceded by its position. Colors without
+    /// positions are spread evenly. Blank lines and lines starting with `//`
+    /// are skipped.
+    pub fn parse(text: &str) -> Result<Palette, String> {
+        let mut positions = Vec::new();
+        let mut colors = Vec::new();
+        let lines = text.lines()
+            .map(str::trim)
+            .filter(|line| !line.is_empty() && !line.starts_with("//"));
+        for line in lines {
+            let words: Vec<&str> = line.split_whitespace().collect();
+            let (position, color) = match words[..] {
+                [color] => (None, color),
+                [position, color] => match f64::from_str(position) {
+                    Ok(position) => (Some(position), color),
+                    Err(_) => return Err(format!("invalid position '{}'", position)),
+                },
+                _ => return Err(format!("expected '[POSITION] COLOR', got '{}'", line)),
+            };
+            positions.push(position);
+            colors.push(parse_color(color).ok_or(format!("invalid color '{}'", color))?);
+        }
+
+        let last = colors.len().saturating_sub(1).max(1) as f64;
+        let stops = match (positions.iter().all(Option::is_some), positions.iter().all(Option::is_none)) {
+            (true, _) => positions.into_iter().flatten().zip(colors).collect(),
+            (_, true) => colors.into_iter().enumerate().map(|(i, color)| (i as f64 / last, color)).collect(),
+            _ => return Err("either every color or none must have a position".to_string()),
+        };
+        Palette::new(stops)
+    }
 
-        crossbeam::scope(|spawner| {
-            for (i, band) in bands.into_iter().enumerate() {
-                let top = rows_per_band * i;
-                let height = band.len() / bounds.0;
-                let band_bounds = (bounds.0, height);
-                let band_upper_left = pixel_to_point(
-                    bounds, (0, top), upper_left, lower_right);
-                let band_lower_right = pixel_to_point(
-                    bounds, (bounds.0, top + height), upper_left, lower_right);
-                spawner.spawn(move |_| {
-                    render(band, band_bounds, band_upper_left,
-                           band_lower_right);
-                });
+    /// Read a gradient file in the format `Palette::parse` accepts.
+    pub fn from_file(filename: &str) -> Result<Palette, String> {
+        let text = std::fs::read_to_string(filename)
+            .map_err(|e| format!("failed to read palette '{}': {}", filename, e))?;
+        Palette::parse(&text).map_err(|e| format!("bad palette '{}': {}", filename, e))
+    }
+
+    /// The color at position `t`, blending the two nearest colors.
+    pub fn color(&self, t: f64) -> [u8; 4] {
+        let t = if t.is_nan() { 0.0 } else { t.clamp(0.0, 1.0) };
+        let next = self.stops.partition_point(|&(position, _)| position < t);
+        if next == 0 {
+            return self.stops[0].1;
+        }
+        if next == self.stops.len() {
+            return self.stops[next - 1].1;
+        }
+        let ((from, low), (to, high)) = (self.stops[next - 1], self.stops[next]);
+        let mix = (t - from) / (to - from);
+        let mut color = [0; 4];
+        for i in 0..4 {
+            color[i] = (low[i] as f64 + (high[i] as f64 - low[i] as f64) * mix).round() as u8;
+        }
+        color
+    }
+}
+
+/// Parse a color written as `#rrggbb` or `#rrggbbaa`.
+fn parse_color(s: &str) -> Option<[u8; 4]> {
+    let hex = s.strip_prefix('#')?;
+    if !hex.bytes().all(|b| b.is_ascii_hexdigit()) {
+        return None;
+    }
+    let value = u32::from_str_radix(hex, 16).ok()?;
+    match hex.len() {
+        6 => {
+            let [_, r, g, b] = value.to_be_bytes();
+            Some([r, g, b, 255])
+        }
+        8 => Some(value.to_be_bytes()),
+        _ => None,
+    }
+}
+
+#[test]
+fn test_palette() {
+    let gray = Palette::builtin("gray").unwrap();
+    assert_eq!(gray.color(0.0), [255, 255, 255, 255]);
+    assert_eq!(gray.color(0.5), [128, 128, 128, 255]);
+    assert_eq!(gray.color(2.0), [0, 0, 0, 255]);
+    assert!(PALETTES.iter().all(|name| Palette::builtin(name).is_some()));
+    assert_eq!(Palette::builtin("plaid"), None);
+
+    let palette = Palette::parse("// sunset\n0.0 #000000\n\n0.25 #ff000080\n1 #ffffff\n").unwrap();
+    assert_eq!(palette.color(0.125), [128, 0, 0, 192]);
+    assert_eq!(palette.color(1.0), [255, 255, 255, 255]);
+    let even = Palette::parse("#000000\n#ff0000\n#ffffff").unwrap();
+    assert_eq!(even.color(0.25), [128, 0, 0, 255]);
+    assert_eq!(Palette::parse("#123456").unwrap().color(0.7), [0x12, 0x34, 0x56, 255]);
+
+    assert!(Palette::parse("").is_err());
+    assert!(Palette::parse("0.5 #000000\n#ffffff").is_err());
+    assert!(Palette::parse("1 #000000\n0 #ffffff").is_err());
+    assert!(Palette::parse("2 #000000").is_err());
+    assert!(Palette::parse("#00000g").is_err());
+    assert!(Palette::parse("#+00000").is_err());
+    assert!(Palette::parse("half #000000").is_err());
+}
+
+/// How escaping points are spread over a palette.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct Coloring {
+    /// Color by `Escape::smooth_count` rather than the whole count.
+    pub smooth: bool,
+    /// Spread the counts so that every color covers about as many pixels,
+    /// rather than in proportion to the iteration limit.
+    pub equalize: bool,
+}
+
+/// Render a rectangle of the Mandelbrot set into a buffer with one `Escape`
+/// per pixel, `None` for members, like `render` does with grayscale bytes.
+pub fn render_escapes(escapes: &mut [Option<Escape>], bounds: (usize, usize),
+                      upper_left: Complex<f64>, lower_right: Complex<f64>, limit: u32, radius: f64) {
+    assert_eq!(escapes.len(), bounds.0 * bounds.1);
+
+    for row in 0..bounds.1 {
+        for column in 0..bounds.0 {
+            let point = pixel_to_point(bounds, (column, row), upper_left, lower_right);
+            escapes[row * bounds.0 + column] = escape(point, limit, radius);
+        }
+    }
+}
+
+/// Color the buffer `escapes`, rendered with iteration limit `limit` and
+/// escape radius `radius`, with `palette`: members of the set are black, and
+/// other points take the palette's color for how quickly they escaped.
+/// Return RGBA pixels if `alpha` is set and RGB pixels otherwise.
+pub fn colorize(escapes: &[Option<Escape>], limit: u32, radius: f64, palette: &Palette,
+                coloring: Coloring, alpha: bool) -> Vec<u8> {
+    let count = |escape: &Escape| if coloring.smooth {
+        escape.smooth_count(radius).max(0.0)
+    } else {
+        escape.count as f64
+    };
+    // With equalization, a point's position is the share of escaping points
+    // that got away sooner.
+    let mut sorted = Vec::new();
+    if coloring.equalize {
+        sorted = escapes.iter().flatten().map(count).collect();
+        sorted.sort_by(f64::total_cmp);
+    }
+
+    let channels = if alpha { 4 } else { 3 };
+    let mut pixels = Vec::with_capacity(escapes.len() * channels);
+    for escape in escapes {
+        let color = match escape {
+            None => [0, 0, 0, 255],
+            Some(escape) if coloring.equalize => {
+                let sooner = sorted.partition_point(|&other| other < count(escape));
+                palette.color(sooner as f64 / sorted.len() as f64)
             }
-        }).unwrap();
+            Some(escape) => palette.color(count(escape) / limit as f64),
+        };
+        pixels.extend_from_slice(&color[..channels]);
     }
+    pixels
+}
 
-    write_image("test_write_image_concurrently.png", &pixels, bounds)
-        .expect("error writing PNG file");
+#[test]
+fn test_colorize() {
+    let bounds = (40, 30);
+    let upper_left = Complex { re: -1.20, im: 0.35 };
+    let lower_right = Complex { re: -1.0, im: 0.20 };
+    let gray = Palette::builtin("gray").unwrap();
+
+    // Whole counts on the gray palette give what `render` does.
+    let mut pixels = vec![0; bounds.0 * bounds.1];
+    render(&mut pixels, bounds, upper_left, lower_right, 255, 2.0);
+    let mut escapes = vec![None; bounds.0 * bounds.1];
+    render_escapes(&mut escapes, bounds, upper_left, lower_right, 255, 2.0);
+    let colored = colorize(&escapes, 255, 2.0, &gray, Coloring::default(), false);
+    let expected: Vec<u8> = pixels.iter().flat_map(|&gray| [gray; 3]).collect();
+    assert_eq!(colored, expected);
+
+    let mut escapes = vec![None; bounds.0 * bounds.1];
+    render_escapes(&mut escapes, bounds, upper_left, lower_right, 255, 100.0);
+    let rgba = colorize(&escapes, 255, 100.0, &gray, Coloring { smooth: true, equalize: false }, true);
+    assert_eq!(rgba.len(), escapes.len() * 4);
+    assert!(rgba.chunks(4).all(|pixel| pixel[3] == 255));
+
+    // Equalized, the brightest and darkest escaping points reach both ends of
+    // the palette.
+    let equalized = colorize(&escapes, 255, 100.0, &gray, Coloring { smooth: true, equalize: true }, false);
+    let escaped: Vec<u8> = escapes.iter().zip(equalized.chunks(3))
+        .filter(|(escape, _)| escape.is_some())
+        .map(|(_, pixel)| pixel[0])
+        .collect();
+    assert_eq!(escaped.iter().max(), Some(&255));
+    assert!(escaped.iter().min() < Some(&5));
+}
+
+/// ## A Mandelbrot Command-Line Tool
+///
+/// `mandelbrot`: render a region of the Mandelbrot set to an image file
+#[derive(Debug, PartialEq)]
+pub struct MandelbrotArguments {
+    pub filename: String,
+    pub bounds: (usize, usize),
+    pub upper_left: Complex<f64>,
+    pub lower_right: Complex<f64>,
+    pub limit: u32,
+    pub radius: f64,
+    pub threads: usize,
+    pub format: ImageFormat,
+    /// The palette to color with, or `None` for a plain grayscale image.
+    pub palette: Option<Palette>,
+    pub coloring: Coloring,
+    /// Whether to write RGBA rather than RGB pixels.
+    pub alpha: bool,
+}
+
+pub fn print_mandelbrot_usage() {
+    eprintln!("{} - render a region of the Mandelbrot set", "mandelbrot".green());
+    eprintln!("Usage: mandelbrot [OPTIONS] FILE PIXELS UPPERLEFT LOWERRIGHT");
+    eprintln!("Example: mandelbrot mandel.png 1000x750 -1.20,0.35 -1,0.20");
+    eprintln!();
+    eprintln!("Options:");
+    eprintln!("  -l, --limit N      iterations before a point counts as a member (default 255)");
+    eprintln!("  -r, --radius R     distance from the origin at which points escape, at least 2 (default 2)");
+    eprintln!("  -t, --threads N    threads to render with (default: one per CPU)");
+    eprintln!("  -f, --format EXT   image format, such as png or jpeg (default: from FILE)");
+    eprintln!("  -p, --palette P    color with palette P: {}, or a gradient file", PALETTES.join(", "));
+    eprintln!("  -s, --smooth       color by continuous iteration counts, without bands");
+    eprintln!("  -e, --equalize     spread colors evenly over the image");
+    eprintln!("  -a, --alpha        write RGBA rather than RGB pixels");
+}
+
+/// Parse the `mandelbrot` command line, without the program name.
+///
+/// Return a message saying what is wrong if the arguments don't make sense.
+pub fn parse_mandelbrot_args<I: IntoIterator<Item = String>>(args: I)
+                                                            -> Result<MandelbrotArguments, String> {
+    let mut args = args.into_iter();
+    let mut positional = Vec::new();
+    let mut limit = 255;
+    let mut radius = 2.0;
+    let mut threads = std::thread::available_parallelism().map_or(1, |n| n.get());
+    let mut format = None;
+    let mut palette = None;
+    let mut coloring = Coloring::default();
+    let mut alpha = false;
+
+    while let Some(arg) = args.next() {
+        let mut value = || args.next().ok_or(format!("{} requires a value", arg));
+        match arg.as_str() {
+            "-l" | "--limit" => {
+                let value = value()?;
+                limit = match u32::from_str(&value) {
+                    Ok(n) if n > 0 => n,
+                    _ => return Err(format!("iteration limit '{}' must be a positive number", value)),
+                };
+            }
+            "-r" | "--radius" => {
+                let value = value()?;
+                radius = match f64::from_str(&value) {
+                    Ok(r) if r.is_finite() && r >= 2.0 => r,
+                    _ => return Err(format!("escape radius '{}' must be a number of at least 2", value)),
+                };
+            }
+            "-t" | "--threads" => {
+                let value = value()?;
+                threads = match usize::from_str(&value) {
+                    Ok(n) if n > 0 => n,
+                    _ => return Err(format!("thread count '{}' must be a positive number", value)),
+                };
+            }
+            "-f" | "--format" => {
+                let value = value()?;
+                format = match ImageFormat::from_extension(&value) {
+                    Some(format) => Some(format),
+                    None => return Err(format!("unknown image format '{}'", value)),
+                };
+            }
+            "-p" | "--palette" => {
+                let value = value()?;
+                palette = match Palette::builtin(&value) {
+                    Some(palette) => Some(palette),
+                    None => Some(Palette::from_file(&value)?),
+                };
+            }
+            "-s" | "--smooth" => coloring.smooth = true,
+            "-e" | "--equalize" => coloring.equalize = true,
+            "-a" | "--alpha" => alpha = true,
+            // Anything else starting with a dash is an option, unless it's a
+            // negative number.
+            _ if arg.starts_with('-') && !arg[1..].starts_with(|c: char| c.is_ascii_digit() || c == '.') => {
+                return Err(format!("unknown option '{}'", arg));
+            }
+            _ => positional.push(arg),
+        }
+    }
+
+    if positional.len() != 4 {
+        return Err(format!("wrong number of arguments: expected 4, got {}.", positional.len()));
+    }
+    let filename = positional[0].clone();
+    let bounds = match parse_pair(&positional[1], 'x') {
+        Some((0, _)) | Some((_, 0)) | None => {
+            return Err(format!("image size '{}' must look like 1000x750", positional[1]));
+        }
+        Some(bounds) => bounds,
+    };
+    let upper_left = parse_complex(&positional[2])
+        .ok_or(format!("upper left corner '{}' must look like -1.20,0.35", positional[2]))?;
+    let lower_right = parse_complex(&positional[3])
+        .ok_or(format!("lower right corner '{}' must look like -1,0.20", positional[3]))?;
+    let format = match format {
+        Some(format) => format,
+        None => ImageFormat::from_path(&filename)
+            .map_err(|_| format!("can't tell the image format of '{}'; use --format", filename))?,
+    };
+
+    // Coloring options without a palette color with the gray one.
+    if palette.is_none() && (coloring != Coloring::default() || alpha) {
+        palette = Palette::builtin("gray");
+    }
+
+    Ok(MandelbrotArguments {
+        filename, bounds, upper_left, lower_right, limit, radius, threads, format, palette, coloring, alpha,
+    })
+}
+
+#[test]
+fn test_parse_mandelbrot_args() {
+    let parse = |line: &str| parse_mandelbrot_args(line.split_whitespace().map(String::from));
+
+    let args = parse("mandel.png 1000x750 -1.20,0.35 -1,0.20").unwrap();
+    assert_eq!(args.filename, "mandel.png");
+    assert_eq!(args.bounds, (1000, 750));
+    assert_eq!(args.upper_left, Complex { re: -1.20, im: 0.35 });
+    assert_eq!(args.lower_right, Complex { re: -1.0, im: 0.20 });
+    assert_eq!((args.limit, args.radius), (255, 2.0));
+    assert!(args.threads > 0);
+    assert_eq!(args.format, ImageFormat::Png);
+    assert_eq!(args.palette, None);
+
+    let args = parse("-l 64 mandel 10x10 -1,1 1,-1 --threads 3 --format jpg").unwrap();
+    assert_eq!((args.limit, args.threads, args.format), (64, 3, ImageFormat::Jpeg));
+
+    let args = parse("--limit 5000 -r 100 mandel.png 10x10 -1,1 1,-1").unwrap();
+    assert_eq!((args.limit, args.radius), (5000, 100.0));
+
+    let args = parse("-p fire -s m.png 10x10 -1,1 1,-1").unwrap();
+    assert_eq!(args.palette, Palette::builtin("fire"));
+    assert_eq!(args.coloring, Coloring { smooth: true, equalize: false });
+    let args = parse("--equalize --alpha m.png 10x10 -1,1 1,-1").unwrap();
+    assert_eq!(args.palette, Palette::builtin("gray"));
+    assert!(args.coloring.equalize && args.alpha);
+
+    assert!(parse("mandel.png 1000x750 -1.20,0.35").is_err());
+    assert!(parse("mandel.png 1000x0 -1.20,0.35 -1,0.20").is_err());
+    assert!(parse("mandel.png 1000x750 -1.20 -1,0.20").is_err());
+    assert!(parse("mandel 1000x750 -1.20,0.35 -1,0.20").is_err());
+    assert!(parse("--limit 0 mandel.png 10x10 -1,1 1,-1").is_err());
+    assert!(parse("--radius 1 mandel.png 10x10 -1,1 1,-1").is_err());
+    assert!(parse("--radius inf mandel.png 10x10 -1,1 1,-1").is_err());
+    assert!(parse("--threads 0 mandel.png 10x10 -1,1 1,-1").is_err());
+    assert!(parse("--format xyz mandel.png 10x10 -1,1 1,-1").is_err());
+    assert!(parse("--colour mandel.png 10x10 -1,1 1,-1").is_err());
+    assert!(parse("mandel.png 10x10 -1,1 1,-1 --limit").is_err());
+    assert!(parse("--palette no-such-palette.txt mandel.png 10x10 -1,1 1,-1").is_err());
 }
 
 /// # Filesystems and Command-Line Tools
@@ -373,7 +926,7 @@ pub fn replace(target: &str, replacement: &str, text: &str) -> Result<String, re
 
 #[test]
 fn test_replace() {
-    match replace("I am Oyen", "Groot", "Oyen") {
+    match replace("Oyen", "Groot", "I am Oyen") {
         Ok(v) => assert_eq!(v, "I am Groot"),
         Err(e) => panic!("{}", e),
     }
@@ -0,0 +1,5 @@
+pub mod chapter_one;
+pub mod chapter_two;
+pub mod chapter_three;
+pub mod chapter_four;
+pub mod chapter_five;
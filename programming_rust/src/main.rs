@@ -1,12 +1,6 @@
 use std::fs;
 use text_colorizer::Colorize;
-use crate::chapter_two::{parse_args, replace};
-
-mod chapter_one;
-mod chapter_two;
-mod chapter_three;
-mod chapter_four;
-mod chapter_five;
+use programming_rust::chapter_two::{parse_args, replace};
 
 fn main() {
     let args = parse_args();
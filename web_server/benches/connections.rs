@@ -0,0 +1,165 @@
+//! Compare the event loop with the blocking connection loop.
+//!
+//! For each mode and each number of concurrent clients, every client opens
+//! one keep-alive connection and sends requests for `/` back to back for a
+//! fixed time. The table shows throughput, latency percentiles and how many
+//! clients got no response before the run ended.
+//!
+//! Run with `cargo bench --bench connections`. `BENCH_SECONDS` (default 3)
+//! sets the length of each run and `BENCH_WORKERS` (default 8) the size of
+//! the worker pool.
+
+use std::env;
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::net::{SocketAddr, TcpStream};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use web_server::config::Config;
+use web_server::logger::LogTarget;
+use web_server::server::Server;
+
+const CLIENTS: [usize; 3] = [10, 100, 1000];
+
+struct Run {
+    requests: usize,
+    latencies: Vec<Duration>,
+    starved: usize,
+    failed: usize,
+}
+
+fn main() {
+    let seconds = env_number("BENCH_SECONDS", 3);
+    let workers = env_number("BENCH_WORKERS", 8) as usize;
+    let duration = Duration::from_secs(seconds);
+
+    println!("{seconds}s per run, {workers} workers");
+    println!("{:<10} {:>7} {:>10} {:>10} {:>10} {:>10} {:>8} {:>7}",
+             "mode", "clients", "requests", "req/s", "p50", "p99", "starved", "failed");
+
+    for (mode, event_loop) in [("events", true), ("blocking", false)] {
+        let addr = spawn_server(event_loop, workers);
+        for clients in CLIENTS {
+            let run = run_clients(addr, clients, duration);
+            let mut latencies = run.latencies;
+            latencies.sort();
+            println!("{:<10} {:>7} {:>10} {:>10.0} {:>10} {:>10} {:>8} {:>7}",
+                     mode,
+                     clients,
+                     run.requests,
+                     run.requests as f64 / duration.as_secs_f64(),
+                     percentile(&latencies, 0.50),
+                     percentile(&latencies, 0.99),
+                     run.starved,
+                     run.failed);
+            // Let the server notice the closed connections before the next run.
+            thread::sleep(Duration::from_millis(500));
+        }
+    }
+}
+
+fn env_number(name: &str, default: u64) -> u64 {
+    env::var(name).ok().and_then(|v| v.parse().ok()).unwrap_or(default)
+}
+
+fn spawn_server(event_loop: bool, workers: usize) -> SocketAddr {
+    let config = Config {
+        listen: vec!["127.0.0.1:0".to_string()],
+        workers,
+        event_loop,
+        keep_alive_timeout: Duration::from_secs(60),
+        // Every client connects from the same address.
+        max_connections_per_ip: 0,
+        access_log: LogTarget::Off,
+        ..Config::default()
+    };
+    let server = Server::bind(config).expect("failed to start server");
+    let addr = server.local_addrs()[0];
+    thread::spawn(move || server.run());
+    addr
+}
+
+fn run_clients(addr: SocketAddr, clients: usize, duration: Duration) -> Run {
+    let deadline = Instant::now() + duration;
+    let handles: Vec<_> = (0..clients)
+        .map(|_| thread::spawn(move || client(addr, deadline)))
+        .collect();
+
+    let mut run = Run { requests: 0, latencies: Vec::new(), starved: 0, failed: 0 };
+    for handle in handles {
+        match handle.join().expect("client panicked") {
+            Ok(latencies) => {
+                if latencies.is_empty() {
+                    run.starved += 1;
+                }
+                run.requests += latencies.len();
+                run.latencies.extend(latencies);
+            }
+            Err(_) => run.failed += 1,
+        }
+    }
+    run
+}
+
+/// Send requests on one connection until `deadline`, returning the latency
+/// of each one answered in time.
+fn client(addr: SocketAddr, deadline: Instant) -> io::Result<Vec<Duration>> {
+    let stream = TcpStream::connect(addr)?;
+    stream.set_nodelay(true)?;
+    let mut reader = BufReader::new(stream.try_clone()?);
+    let mut writer = stream;
+    let mut latencies = Vec::new();
+
+    loop {
+        let now = Instant::now();
+        if now >= deadline {
+            return Ok(latencies);
+        }
+        reader.get_ref().set_read_timeout(Some(deadline - now))?;
+
+        writer.write_all(b"GET / HTTP/1.1\r\nHost: bench\r\n\r\n")?;
+        match read_response(&mut reader) {
+            // Late answers are not counted: a client starved all run is
+            // answered as soon as the others hang up at the deadline.
+            Ok(()) if Instant::now() > deadline => return Ok(latencies),
+            Ok(()) => latencies.push(now.elapsed()),
+            // Still waiting for a worker when time ran out.
+            Err(e) if matches!(e.kind(), io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut) => {
+                return Ok(latencies)
+            }
+            Err(e) => return Err(e),
+        }
+    }
+}
+
+fn read_response<R: BufRead>(reader: &mut R) -> io::Result<()> {
+    let mut content_length = 0;
+    let mut line = String::new();
+    loop {
+        line.clear();
+        if reader.read_line(&mut line)? == 0 {
+            return Err(io::ErrorKind::UnexpectedEof.into());
+        }
+        let line = line.trim_end();
+        if line.is_empty() {
+            break;
+        }
+        if let Some((name, value)) = line.split_once(':') {
+            if name.eq_ignore_ascii_case("content-length") {
+                content_length = value.trim().parse().map_err(|_| io::ErrorKind::InvalidData)?;
+            }
+        }
+    }
+    io::copy(&mut reader.take(content_length), &mut io::sink())?;
+    Ok(())
+}
+
+fn percentile(sorted: &[Duration], p: f64) -> String {
+    match sorted.len() {
+        0 => "-".to_string(),
+        len => {
+            let latency = sorted[((len - 1) as f64 * p) as usize];
+            format!("{:.2}ms", latency.as_secs_f64() * 1000.0)
+        }
+    }
+}
@@ -0,0 +1,229 @@
+use std::fmt::Write as _;
+use std::io;
+use std::net::IpAddr;
+use std::str::FromStr;
+use std::time::{Duration, SystemTime};
+
+use serde_json::json;
+
+use crate::logger::{LogOutput, LogTarget, Rotation, UtcTime};
+use crate::request::Request;
+
+/// The line format of the access log.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum AccessLogFormat {
+    /// `host ident user [time] "request" status bytes`
+    Common,
+    /// Common, followed by the quoted referer and user agent.
+    Combined,
+    /// One JSON object per line.
+    Json,
+}
+
+impl FromStr for AccessLogFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "common" => Ok(AccessLogFormat::Common),
+            "combined" => Ok(AccessLogFormat::Combined),
+            "json" => Ok(AccessLogFormat::Json),
+            other => Err(format!("unknown access log format {other:?}")),
+        }
+    }
+}
+
+/// What is known about one request once its response has been sent.
+#[derive(Debug, Clone)]
+pub struct AccessEntry {
+    pub time: SystemTime,
+    pub client: Option<IpAddr>,
+    /// The authenticated user, if the request carried valid credentials.
+    pub user: Option<String>,
+    /// `None` when the request could not be parsed.
+    pub method: Option<String>,
+    pub target: Option<String>,
+    pub version: Option<String>,
+    pub status: u16,
+    /// Body bytes sent, not counting the response head.
+    pub bytes: u64,
+    pub duration: Duration,
+    pub referer: Option<String>,
+    pub user_agent: Option<String>,
+}
+
+impl AccessEntry {
+    /// An entry for a request from `client` that starts now, before
+    /// anything else is known about it.
+    pub fn new(client: Option<IpAddr>) -> AccessEntry {
+        AccessEntry {
+            time: SystemTime::now(),
+            client,
+            user: None,
+            method: None,
+            target: None,
+            version: None,
+            status: 0,
+            bytes: 0,
+            duration: Duration::ZERO,
+            referer: None,
+            user_agent: None,
+        }
+    }
+
+    /// Fill in the request line and the headers the log formats show.
+    pub fn describe(&mut self, request: &Request) {
+        self.method = Some(request.method.to_string());
+        self.target = Some(request.target.clone());
+        self.version = Some(request.version.clone());
+        self.referer = request.headers.get("Referer").map(String::from);
+        self.user_agent = request.headers.get("User-Agent").map(String::from);
+    }
+
+    pub fn format(&self, format: AccessLogFormat) -> String {
+        match format {
+            AccessLogFormat::Common => self.common(),
+            AccessLogFormat::Combined => {
+                let mut line = self.common();
+                let _ = write!(line, " \"{}\" \"{}\"",
+                               quoted(self.referer.as_deref()), quoted(self.user_agent.as_deref()));
+                line
+            }
+            AccessLogFormat::Json => json!({
+                "time": UtcTime::from(self.time).rfc3339(),
+                "client": self.client.map(|ip| ip.to_string()),
+                "user": self.user,
+                "method": self.method,
+                "path": self.target,
+                "protocol": self.version,
+                "status": self.status,
+                "bytes": self.bytes,
+                "duration_ms": self.duration.as_secs_f64() * 1000.0,
+                "referer": self.referer,
+                "user_agent": self.user_agent,
+            })
+            .to_string(),
+        }
+    }
+
+    fn common(&self) -> String {
+        let client = self.client.map_or_else(|| "-".to_string(), |ip| ip.to_string());
+        let request = match (&self.method, &self.target, &self.version) {
+            (Some(method), Some(target), Some(version)) => format!("{method} {target} {version}"),
+            _ => "-".to_string(),
+        };
+        let bytes = match self.bytes {
+            0 => "-".to_string(),
+            n => n.to_string(),
+        };
+        format!("{client} - {} [{}] \"{}\" {} {bytes}",
+                self.user.as_deref().unwrap_or("-"), UtcTime::from(self.time).clf(), quoted(Some(&request)), self.status)
+    }
+}
+
+/// Escape a value for use inside double quotes, writing `-` when missing.
+///
+/// Quotes, backslashes and control characters are escaped so a hostile
+/// client cannot forge log lines.
+fn quoted(value: Option<&str>) -> String {
+    let Some(value) = value else {
+        return "-".to_string();
+    };
+
+    let mut escaped = String::with_capacity(value.len());
+    for c in value.chars() {
+        match c {
+            '"' => escaped.push_str("\\\""),
+            '\\' => escaped.push_str("\\\\"),
+            c if c.is_control() => {
+                let _ = write!(escaped, "\\x{:02x}", c as u32);
+            }
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+/// The access log: one line per request, in the configured format.
+pub struct AccessLog {
+    format: AccessLogFormat,
+    output: LogOutput,
+}
+
+impl AccessLog {
+    pub fn open(target: &LogTarget, format: AccessLogFormat, rotation: Rotation) -> io::Result<AccessLog> {
+        Ok(AccessLog {
+            format,
+            output: LogOutput::open(target, LogOutput::Stdout, rotation)?,
+        })
+    }
+
+    /// An access log that discards every entry.
+    pub fn off() -> AccessLog {
+        AccessLog {
+            format: AccessLogFormat::Common,
+            output: LogOutput::Off,
+        }
+    }
+
+    pub fn record(&self, entry: &AccessEntry) {
+        if !matches!(self.output, LogOutput::Off) {
+            self.output.write_line(&entry.format(self.format));
+        }
+    }
+}
+
+#[cfg(test)]
+fn sample_entry() -> AccessEntry {
+    AccessEntry {
+        time: SystemTime::UNIX_EPOCH + Duration::from_secs(971_186_136),
+        client: Some(IpAddr::from([127, 0, 0, 1])),
+        user: Some("frank".to_string()),
+        method: Some("GET".to_string()),
+        target: Some("/apache_pb.gif".to_string()),
+        version: Some("HTTP/1.0".to_string()),
+        status: 200,
+        bytes: 2326,
+        duration: Duration::from_micros(1500),
+        referer: Some("http://www.example.com/start.html".to_string()),
+        user_agent: Some("Mozilla/4.08 [en] (Win98; I ;Nav)".to_string()),
+    }
+}
+
+#[test]
+fn test_common_format() {
+    assert_eq!(sample_entry().format(AccessLogFormat::Common),
+               r#"127.0.0.1 - frank [10/Oct/2000:13:55:36 +0000] "GET /apache_pb.gif HTTP/1.0" 200 2326"#);
+}
+
+#[test]
+fn test_combined_format() {
+    assert_eq!(sample_entry().format(AccessLogFormat::Combined),
+               r#"127.0.0.1 - frank [10/Oct/2000:13:55:36 +0000] "GET /apache_pb.gif HTTP/1.0" 200 2326 "http://www.example.com/start.html" "Mozilla/4.08 [en] (Win98; I ;Nav)""#);
+
+    let entry = AccessEntry {
+        user: None,
+        method: None,
+        bytes: 0,
+        referer: None,
+        user_agent: Some("evil\"\n127.0.0.1".to_string()),
+        ..sample_entry()
+    };
+    assert_eq!(entry.format(AccessLogFormat::Combined),
+               r#"127.0.0.1 - - [10/Oct/2000:13:55:36 +0000] "-" 200 - "-" "evil\"\x0a127.0.0.1""#);
+}
+
+#[test]
+fn test_json_format() {
+    let line = sample_entry().format(AccessLogFormat::Json);
+    let value: serde_json::Value = serde_json::from_str(&line).unwrap();
+    assert_eq!(value["time"], "2000-10-10T13:55:36Z");
+    assert_eq!(value["client"], "127.0.0.1");
+    assert_eq!(value["user"], "frank");
+    assert_eq!(value["method"], "GET");
+    assert_eq!(value["path"], "/apache_pb.gif");
+    assert_eq!(value["status"], 200);
+    assert_eq!(value["bytes"], 2326);
+    assert_eq!(value["duration_ms"], 1.5);
+    assert_eq!(value["user_agent"], "Mozilla/4.08 [en] (Win98; I ;Nav)");
+}
@@ -0,0 +1,336 @@
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use argon2::{Argon2, PasswordHash, PasswordVerifier};
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine as _;
+use log::warn;
+use serde::Deserialize;
+use subtle::ConstantTimeEq;
+
+use crate::files;
+use crate::middleware::{Middleware, Next};
+use crate::request::{self, Request};
+use crate::response::{Body, Response, Status};
+
+/// A path prefix that requires credentials, as configured.
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct AuthRule {
+    /// A path prefix such as `/admin/`; it covers the path itself and
+    /// everything below it.
+    pub path: String,
+    /// Shown by browsers when they ask for a user name and password.
+    #[serde(default = "default_realm")]
+    pub realm: String,
+    /// An htpasswd-style file of `user:hash` lines, with bcrypt or argon2
+    /// hashes, accepted as HTTP Basic credentials.
+    pub htpasswd: Option<PathBuf>,
+    /// A file of bearer tokens, one per line.
+    pub tokens: Option<PathBuf>,
+}
+
+pub(crate) fn default_realm() -> String {
+    "Restricted".to_string()
+}
+
+impl AuthRule {
+    /// Whether the rule covers `path`, which must already be normalized.
+    pub fn matches(&self, path: &str) -> bool {
+        request::is_under(path, &self.path)
+    }
+}
+
+/// A password hash from an htpasswd file.
+#[derive(Debug)]
+enum Hash {
+    Bcrypt(String),
+    Argon2(String),
+}
+
+impl Hash {
+    fn parse(hash: &str) -> Option<Hash> {
+        if ["$2a$", "$2b$", "$2x$", "$2y$"].iter().any(|prefix| hash.starts_with(prefix)) {
+            Some(Hash::Bcrypt(hash.to_string()))
+        } else if hash.starts_with("$argon2") && PasswordHash::new(hash).is_ok() {
+            Some(Hash::Argon2(hash.to_string()))
+        } else {
+            None
+        }
+    }
+
+    fn verify(&self, password: &str) -> bool {
+        match self {
+            Hash::Bcrypt(hash) => bcrypt::verify(password, hash).unwrap_or(false),
+            Hash::Argon2(hash) => PasswordHash::new(hash)
+                .is_ok_and(|hash| Argon2::default().verify_password(password.as_bytes(), &hash).is_ok()),
+        }
+    }
+}
+
+/// The credentials accepted for one protected prefix.
+#[derive(Debug)]
+struct Protected {
+    rule: AuthRule,
+    users: Vec<(String, Hash)>,
+    tokens: Vec<String>,
+}
+
+impl Protected {
+    fn load(rule: &AuthRule) -> io::Result<Protected> {
+        let users = match &rule.htpasswd {
+            Some(path) => read_htpasswd(path)?,
+            None => Vec::new(),
+        };
+        let tokens = match &rule.tokens {
+            Some(path) => lines(path)?.into_iter().map(|(_, token)| token).collect(),
+            None => Vec::new(),
+        };
+        Ok(Protected { rule: rule.clone(), users, tokens })
+    }
+
+    /// The user the request's credentials belong to, or `None` if it has
+    /// none that are valid here. Bearer tokens authenticate as `token`.
+    fn authenticate(&self, request: &Request) -> Option<String> {
+        let (scheme, credentials) = request.headers.get("Authorization")?.trim().split_once(' ')?;
+        let credentials = credentials.trim();
+        if scheme.eq_ignore_ascii_case("Basic") && !self.users.is_empty() {
+            let decoded = STANDARD.decode(credentials).ok()?;
+            let (user, password) = std::str::from_utf8(&decoded).ok()?.split_once(':')?;
+            self.check_password(user, password).then(|| user.to_string())
+        } else if scheme.eq_ignore_ascii_case("Bearer") && !self.tokens.is_empty() {
+            self.check_token(credentials).then(|| "token".to_string())
+        } else {
+            None
+        }
+    }
+
+    fn check_password(&self, user: &str, password: &str) -> bool {
+        match self.users.iter().find(|(name, _)| name == user) {
+            Some((_, hash)) => hash.verify(password),
+            None => {
+                // Spend as long as for a known user, so response times do
+                // not reveal which user names exist.
+                if let Some((_, hash)) = self.users.first() {
+                    hash.verify(password);
+                }
+                false
+            }
+        }
+    }
+
+    /// Compare `token` with every configured token in constant time, so
+    /// response times do not reveal how much of a guess was right.
+    fn check_token(&self, token: &str) -> bool {
+        self.tokens
+            .iter()
+            .fold(false, |found, known| found | bool::from(known.as_bytes().ct_eq(token.as_bytes())))
+    }
+
+    fn challenge(&self) -> Response {
+        let mut response = Response::new(Status::Unauthorized)
+            .with_header("Content-Type", "text/plain; charset=utf-8")
+            .with_body(Body::Text("401 Unauthorized\n".to_string()));
+        let realm = self.rule.realm.replace(['\\', '"'], "");
+        if !self.users.is_empty() {
+            response.headers.append("WWW-Authenticate", format!("Basic realm=\"{realm}\", charset=\"UTF-8\""));
+        }
+        if !self.tokens.is_empty() {
+            response.headers.append("WWW-Authenticate", format!("Bearer realm=\"{realm}\""));
+        }
+        response
+    }
+}
+
+/// Requires HTTP Basic credentials or a bearer token for the configured
+/// path prefixes. The first rule covering a request applies; requests no
+/// rule covers pass through untouched.
+///
+/// On success the user name is recorded in `request.user` for the access
+/// log; otherwise the request is answered with a 401 and a
+/// `WWW-Authenticate` challenge for each accepted scheme.
+#[derive(Debug)]
+pub struct Auth {
+    protected: Vec<Protected>,
+}
+
+impl Auth {
+    /// Read the credential files named by `rules`.
+    pub fn load(rules: &[AuthRule]) -> io::Result<Auth> {
+        let protected = rules.iter().map(Protected::load).collect::<io::Result<_>>()?;
+        Ok(Auth { protected })
+    }
+}
+
+impl Middleware for Auth {
+    fn handle(&self, request: &mut Request, next: Next<'_>) -> Response {
+        // Match against the path the file handler will see, so encoded or
+        // doubled slashes cannot step around a rule.
+        let Some(path) = normalize(request.path()) else {
+            return Response::new(Status::BadRequest);
+        };
+        let Some(protected) = self.protected.iter().find(|p| p.rule.matches(&path)) else {
+            return next.run(request);
+        };
+
+        match protected.authenticate(request) {
+            Some(user) => {
+                request.user = Some(user);
+                next.run(request)
+            }
+            None => {
+                if request.headers.contains("Authorization") {
+                    let client = request.peer.map_or_else(|| "unknown peer".to_string(), |peer| peer.ip().to_string());
+                    warn!("{client}: invalid credentials for {path}");
+                }
+                protected.challenge()
+            }
+        }
+    }
+}
+
+/// Percent-decode `path` and drop empty and `.` segments, as
+/// `files::resolve` does. Return `None` for paths it would reject.
+fn normalize(path: &str) -> Option<String> {
+    let decoded = files::percent_decode(path.strip_prefix('/')?)?;
+    let mut normalized = String::new();
+    for segment in decoded.split('/') {
+        match segment {
+            "" | "." => {}
+            ".." => return None,
+            s => {
+                normalized.push('/');
+                normalized.push_str(s);
+            }
+        }
+    }
+    if normalized.is_empty() {
+        normalized.push('/');
+    }
+    Some(normalized)
+}
+
+fn read_htpasswd(path: &Path) -> io::Result<Vec<(String, Hash)>> {
+    lines(path)?
+        .into_iter()
+        .map(|(number, line)| {
+            let invalid = |message: &str| {
+                io::Error::new(io::ErrorKind::InvalidData, format!("{}:{number}: {message}", path.display()))
+            };
+            let (user, hash) = line.split_once(':').ok_or_else(|| invalid("expected user:hash"))?;
+            // User names end up unquoted in access log lines.
+            if user.is_empty() || user.chars().any(|c| c.is_whitespace() || c.is_control()) {
+                return Err(invalid("user names must not be empty or contain spaces"));
+            }
+            let hash = Hash::parse(hash).ok_or_else(|| invalid("unsupported password hash; use bcrypt or argon2"))?;
+            Ok((user.to_string(), hash))
+        })
+        .collect()
+}
+
+/// The non-empty lines of the file at `path` that are not `#` comments,
+/// with their line numbers.
+fn lines(path: &Path) -> io::Result<Vec<(usize, String)>> {
+    let text = fs::read_to_string(path)
+        .map_err(|e| io::Error::new(e.kind(), format!("failed to read {}: {e}", path.display())))?;
+    Ok(text
+        .lines()
+        .enumerate()
+        .map(|(i, line)| (i + 1, line.trim().to_string()))
+        .filter(|(_, line)| !line.is_empty() && !line.starts_with('#'))
+        .collect())
+}
+
+#[cfg(test)]
+use crate::middleware::Pipeline;
+
+#[cfg(test)]
+fn request(raw: &str) -> Request {
+    Request::read_from(&mut raw.as_bytes()).unwrap()
+}
+
+/// Write credential files for `alice` (bcrypt), `bob` (argon2) and the
+/// token `s3cret-token` to a fresh directory.
+#[cfg(test)]
+fn write_credentials(name: &str) -> (PathBuf, PathBuf) {
+    use argon2::password_hash::{PasswordHasher, SaltString};
+
+    let dir = std::env::temp_dir().join(format!("web_server_auth_{name}_{}", std::process::id()));
+    fs::create_dir_all(&dir).unwrap();
+    // Low costs keep the tests fast; the hashes record their own costs.
+    let bcrypt = bcrypt::hash("wonderland", 4).unwrap();
+    let params = argon2::Params::new(256, 1, 1, None).unwrap();
+    let argon2 = Argon2::new(argon2::Algorithm::Argon2id, argon2::Version::V0x13, params)
+        .hash_password(b"builder", &SaltString::encode_b64(b"web_server_salt").unwrap())
+        .unwrap()
+        .to_string();
+
+    let htpasswd = dir.join("users.htpasswd");
+    fs::write(&htpasswd, format!("# users\nalice:{bcrypt}\n\nbob:{argon2}\n")).unwrap();
+    let tokens = dir.join("tokens");
+    fs::write(&tokens, "s3cret-token\n").unwrap();
+    (htpasswd, tokens)
+}
+
+#[test]
+fn test_normalize() {
+    assert_eq!(normalize("//admin/./x").as_deref(), Some("/admin/x"));
+    assert_eq!(normalize("/%61dmin/").as_deref(), Some("/admin"));
+    assert_eq!(normalize("/"), Some("/".to_string()));
+    assert_eq!(normalize("/admin/../x"), None);
+}
+
+#[test]
+fn test_auth() {
+    let (htpasswd, tokens) = write_credentials("basic");
+    let rules = vec![
+        AuthRule { path: "/admin/".to_string(), realm: "Admin".to_string(), htpasswd: Some(htpasswd), tokens: None },
+        AuthRule { path: "/api".to_string(), realm: default_realm(), htpasswd: None, tokens: Some(tokens) },
+    ];
+    let mut pipeline = Pipeline::new();
+    pipeline.push(Auth::load(&rules).unwrap());
+    let handler = |request: &Request| {
+        Response::new(Status::Ok).with_header("X-User", request.user.clone().unwrap_or_default())
+    };
+    let basic = |credentials: &str| format!("Basic {}", STANDARD.encode(credentials));
+    let run = |raw: String| pipeline.run(&mut request(&raw), &handler);
+
+    let response = run("GET /public.html HTTP/1.1\r\n\r\n".to_string());
+    assert_eq!(response.status, Status::Ok);
+
+    let response = run("GET /admin/ HTTP/1.1\r\n\r\n".to_string());
+    assert_eq!(response.status, Status::Unauthorized);
+    assert_eq!(response.headers.get("WWW-Authenticate"), Some("Basic realm=\"Admin\", charset=\"UTF-8\""));
+
+    for credentials in ["alice:wonderland", "bob:builder"] {
+        let response = run(format!("GET /%61dmin//x HTTP/1.1\r\nAuthorization: {}\r\n\r\n", basic(credentials)));
+        assert_eq!(response.status, Status::Ok, "{credentials}");
+        assert_eq!(response.headers.get("X-User"), credentials.split(':').next());
+    }
+    for credentials in ["alice:builder", "carol:wonderland", "alice"] {
+        let response = run(format!("GET /admin HTTP/1.1\r\nAuthorization: {}\r\n\r\n", basic(credentials)));
+        assert_eq!(response.status, Status::Unauthorized, "{credentials}");
+    }
+
+    let response = run("GET /api/items HTTP/1.1\r\nAuthorization: Bearer s3cret-token\r\n\r\n".to_string());
+    assert_eq!(response.status, Status::Ok);
+    assert_eq!(response.headers.get("X-User"), Some("token"));
+    let response = run("GET /api/items HTTP/1.1\r\nAuthorization: Bearer s3cret\r\n\r\n".to_string());
+    assert_eq!(response.status, Status::Unauthorized);
+    assert_eq!(response.headers.get("WWW-Authenticate"), Some("Bearer realm=\"Restricted\""));
+    // Basic credentials are not accepted where only tokens are.
+    let response = run(format!("GET /api HTTP/1.1\r\nAuthorization: {}\r\n\r\n", basic("alice:wonderland")));
+    assert_eq!(response.status, Status::Unauthorized);
+}
+
+#[test]
+fn test_load_rejects_unsupported_hashes() {
+    let path = std::env::temp_dir().join(format!("web_server_auth_md5_{}.htpasswd", std::process::id()));
+    fs::write(&path, "alice:$apr1$abc$def\n").unwrap();
+    let rule = AuthRule { path: "/".to_string(), realm: default_realm(), htpasswd: Some(path.clone()), tokens: None };
+    let error = Auth::load(&[rule]).unwrap_err();
+    fs::remove_file(&path).unwrap();
+    assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+    assert!(error.to_string().contains(":1: unsupported password hash"), "{error}");
+}
@@ -0,0 +1,217 @@
+use std::fmt;
+use std::io::{self, BufRead, Read, Take};
+
+use crate::request::{self, Framing};
+use crate::response::Status;
+
+/// The longest chunk size accepted, in hex digits; more would overflow.
+const MAX_CHUNK_SIZE_DIGITS: usize = 15;
+
+/// Reads a message body from `reader`, stopping where `framing` says it
+/// ends, so whatever follows on the connection is left unread.
+pub struct BodyReader<R> {
+    inner: Inner<R>,
+}
+
+enum Inner<R> {
+    Empty,
+    Length(Take<R>),
+    Chunked(ChunkedReader<R>),
+}
+
+impl<R: BufRead> BodyReader<R> {
+    pub fn new(reader: R, framing: Framing) -> BodyReader<R> {
+        let inner = match framing {
+            Framing::None => Inner::Empty,
+            Framing::Length(len) => Inner::Length(reader.take(len)),
+            Framing::Chunked => Inner::Chunked(ChunkedReader::new(reader)),
+        };
+        BodyReader { inner }
+    }
+
+    /// Whether the whole body has been read.
+    pub fn is_finished(&self) -> bool {
+        match &self.inner {
+            Inner::Empty => true,
+            Inner::Length(reader) => reader.limit() == 0,
+            Inner::Chunked(reader) => reader.done,
+        }
+    }
+}
+
+impl<R: BufRead> Read for BodyReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let read = match &mut self.inner {
+            Inner::Empty => return Ok(0),
+            Inner::Length(reader) => reader.read(buf)?,
+            Inner::Chunked(reader) => return reader.read(buf),
+        };
+        if read == 0 && !buf.is_empty() && !self.is_finished() {
+            return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "body shorter than Content-Length"));
+        }
+        Ok(read)
+    }
+}
+
+/// Decodes a body sent with `Transfer-Encoding: chunked`, discarding chunk
+/// extensions and trailer fields.
+pub struct ChunkedReader<R> {
+    reader: R,
+    /// Bytes left in the current chunk.
+    remaining: u64,
+    done: bool,
+}
+
+impl<R: BufRead> ChunkedReader<R> {
+    pub fn new(reader: R) -> ChunkedReader<R> {
+        ChunkedReader { reader, remaining: 0, done: false }
+    }
+
+    /// Read the next chunk-size line, and the trailer section after the
+    /// last chunk.
+    fn next_chunk(&mut self) -> io::Result<()> {
+        let line = request::read_line(&mut self.reader)?;
+        let size = line.split(';').next().unwrap_or("").trim();
+        if size.is_empty() || size.len() > MAX_CHUNK_SIZE_DIGITS {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "invalid chunk size"));
+        }
+        self.remaining = u64::from_str_radix(size, 16)
+            .map_err(|_| io::Error::new(io::ErrorKind::InvalidData, "invalid chunk size"))?;
+        if self.remaining == 0 {
+            request::read_headers(&mut self.reader)?;
+            self.done = true;
+        }
+        Ok(())
+    }
+}
+
+impl<R: BufRead> Read for ChunkedReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.done || buf.is_empty() {
+            return Ok(0);
+        }
+        if self.remaining == 0 {
+            self.next_chunk()?;
+            if self.done {
+                return Ok(0);
+            }
+        }
+
+        let max = buf.len().min(usize::try_from(self.remaining).unwrap_or(usize::MAX));
+        let read = self.reader.read(&mut buf[..max])?;
+        if read == 0 {
+            return Err(io::ErrorKind::UnexpectedEof.into());
+        }
+        self.remaining -= read as u64;
+        if self.remaining == 0 {
+            let line = request::read_line(&mut self.reader)?;
+            if !line.is_empty() {
+                return Err(io::Error::new(io::ErrorKind::InvalidData, "chunk longer than its size"));
+            }
+        }
+        Ok(read)
+    }
+}
+
+/// Reads a body of at most `limit` bytes, failing with a [`TooLarge`]
+/// error once the body turns out to be longer.
+pub struct Limited<R> {
+    reader: R,
+    remaining: u64,
+}
+
+impl<R: Read> Limited<R> {
+    pub fn new(reader: R, limit: u64) -> Limited<R> {
+        Limited { reader, remaining: limit }
+    }
+}
+
+impl<R: Read> Read for Limited<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if buf.is_empty() {
+            return Ok(0);
+        }
+        if self.remaining == 0 {
+            // Only a byte past the limit tells a body that is exactly
+            // `limit` long from a longer one.
+            return match self.reader.read(&mut [0])? {
+                0 => Ok(0),
+                _ => Err(io::Error::new(io::ErrorKind::InvalidData, TooLarge)),
+            };
+        }
+        let max = buf.len().min(usize::try_from(self.remaining).unwrap_or(usize::MAX));
+        let read = self.reader.read(&mut buf[..max])?;
+        self.remaining -= read as u64;
+        Ok(read)
+    }
+}
+
+/// The error a [`Limited`] reader fails with.
+#[derive(Debug)]
+pub struct TooLarge;
+
+impl fmt::Display for TooLarge {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "body exceeds the size limit")
+    }
+}
+
+impl std::error::Error for TooLarge {}
+
+/// The status to answer with when reading a request body failed with `e`.
+pub fn error_status(e: &io::Error) -> Status {
+    match e.kind() {
+        _ if e.get_ref().is_some_and(|inner| inner.is::<TooLarge>()) => Status::PayloadTooLarge,
+        io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut => Status::RequestTimeout,
+        _ => Status::BadRequest,
+    }
+}
+
+#[test]
+fn test_length_body() {
+    let mut raw = &b"hello worldGET / HTTP/1.1"[..];
+    let mut body = BodyReader::new(&mut raw, Framing::Length(11));
+    let mut text = String::new();
+    body.read_to_string(&mut text).unwrap();
+    assert_eq!(text, "hello world");
+    assert!(body.is_finished());
+    assert_eq!(raw, b"GET / HTTP/1.1");
+
+    let mut short = BodyReader::new(&b"hello"[..], Framing::Length(11));
+    assert_eq!(short.read_to_end(&mut Vec::new()).unwrap_err().kind(), io::ErrorKind::UnexpectedEof);
+}
+
+#[test]
+fn test_chunked_body() {
+    let mut raw = &b"5;name=value\r\nhello\r\n6\r\n world\r\n0\r\nExpires: never\r\n\r\nnext"[..];
+    let mut body = BodyReader::new(&mut raw, Framing::Chunked);
+    assert!(!body.is_finished());
+    let mut text = String::new();
+    body.read_to_string(&mut text).unwrap();
+    assert_eq!(text, "hello world");
+    assert!(body.is_finished());
+    assert_eq!(raw, b"next");
+}
+
+#[test]
+fn test_malformed_chunked_body() {
+    let read = |raw: &[u8]| BodyReader::new(raw, Framing::Chunked).read_to_end(&mut Vec::new()).unwrap_err().kind();
+    assert_eq!(read(b"zz\r\nhello\r\n0\r\n\r\n"), io::ErrorKind::InvalidData);
+    assert_eq!(read(b"3\r\nhello\r\n0\r\n\r\n"), io::ErrorKind::InvalidData);
+    assert_eq!(read(b"ffffffffffffffffff\r\n"), io::ErrorKind::InvalidData);
+    assert_eq!(read(b"5\r\nhel"), io::ErrorKind::UnexpectedEof);
+}
+
+#[test]
+fn test_limited_body() {
+    let read = |raw: &[u8], limit| {
+        let mut body = Vec::new();
+        Limited::new(raw, limit).read_to_end(&mut body).map(|_| body)
+    };
+    assert_eq!(read(b"hello", 5).unwrap(), b"hello");
+    assert_eq!(read(b"hello", 10).unwrap(), b"hello");
+    let e = read(b"hello world", 5).unwrap_err();
+    assert_eq!(error_status(&e), Status::PayloadTooLarge);
+    assert_eq!(error_status(&io::ErrorKind::TimedOut.into()), Status::RequestTimeout);
+    assert_eq!(error_status(&io::ErrorKind::UnexpectedEof.into()), Status::BadRequest);
+}
@@ -0,0 +1,342 @@
+use std::collections::HashMap;
+use std::fs::{self, Metadata};
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use serde::Deserialize;
+
+use crate::headers::Headers;
+use crate::response::{Body, Response, Status};
+
+/// The validators of a file, used to answer conditional requests.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Validators {
+    /// A strong entity tag, quotes included.
+    pub etag: String,
+    pub last_modified: Option<SystemTime>,
+}
+
+impl Validators {
+    /// Derive validators from file metadata. The entity tag combines the
+    /// size and the modification time, so it changes whenever either does.
+    pub fn from_metadata(metadata: &Metadata) -> Validators {
+        let modified = metadata.modified().ok();
+        let nanos = modified
+            .and_then(|m| m.duration_since(UNIX_EPOCH).ok())
+            .map_or(0, |d| d.as_nanos());
+        Validators {
+            etag: format!("\"{:x}-{:x}\"", metadata.len(), nanos),
+            last_modified: modified,
+        }
+    }
+
+    /// Add `ETag` and `Last-Modified` to `headers`.
+    pub fn apply(&self, headers: &mut Headers) {
+        headers.set("ETag", self.etag.as_str());
+        if let Some(modified) = self.last_modified {
+            headers.set("Last-Modified", httpdate::fmt_http_date(modified));
+        }
+    }
+
+    /// Whether the request's preconditions show the client already holds
+    /// the current representation, following RFC 9110 section 13.2.2:
+    /// `If-None-Match` is evaluated when present, and `If-Modified-Since`
+    /// only otherwise.
+    pub fn is_not_modified(&self, request_headers: &Headers) -> bool {
+        if let Some(if_none_match) = request_headers.get("If-None-Match") {
+            return if_none_match.trim() == "*"
+                || if_none_match.split(',').any(|tag| weak_eq(tag.trim(), &self.etag));
+        }
+
+        match (request_headers.get("If-Modified-Since"), self.last_modified) {
+            (Some(since), Some(modified)) => match httpdate::parse_http_date(since) {
+                // HTTP dates have whole-second precision.
+                Ok(since) => truncate_to_seconds(modified) <= since,
+                Err(_) => false,
+            },
+            _ => false,
+        }
+    }
+}
+
+/// Compare two entity tags the weak way, ignoring any `W/` prefix.
+pub fn weak_eq(a: &str, b: &str) -> bool {
+    let opaque = |tag: &str| tag.strip_prefix("W/").unwrap_or(tag).to_string();
+    opaque(a) == opaque(b)
+}
+
+/// Mark a strong entity tag weak, as needed when the body it describes is
+/// transformed, for example by compression.
+pub fn weaken(etag: &str) -> String {
+    if etag.starts_with("W/") {
+        etag.to_string()
+    } else {
+        format!("W/{etag}")
+    }
+}
+
+fn truncate_to_seconds(time: SystemTime) -> SystemTime {
+    match time.duration_since(UNIX_EPOCH) {
+        Ok(d) => UNIX_EPOCH + std::time::Duration::from_secs(d.as_secs()),
+        Err(_) => time,
+    }
+}
+
+/// Turn a `200 OK` response into the `304 Not Modified` that replaces it,
+/// keeping only the headers a cache needs to refresh its stored copy.
+pub fn not_modified(response: Response) -> Response {
+    let mut headers = Headers::new();
+    for (name, value) in response.headers.iter() {
+        let keep = ["Cache-Control", "Content-Location", "Date", "ETag", "Expires", "Last-Modified", "Vary", "Connection"]
+            .iter()
+            .any(|kept| kept.eq_ignore_ascii_case(name));
+        if keep {
+            headers.append(name, value);
+        }
+    }
+    Response {
+        status: Status::NotModified,
+        headers,
+        body: Body::Empty,
+        upgrade: None,
+    }
+}
+
+/// A `Cache-Control` value to send for the paths matching `path`.
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct CacheRule {
+    /// A path prefix such as `/static/`, or an extension pattern such as
+    /// `*.css`.
+    pub path: String,
+    pub value: String,
+}
+
+impl CacheRule {
+    pub fn matches(&self, request_path: &str) -> bool {
+        match self.path.strip_prefix('*') {
+            Some(suffix) => request_path.ends_with(suffix),
+            None => request_path.starts_with(&self.path),
+        }
+    }
+}
+
+/// The `Cache-Control` value of the first rule matching `request_path`.
+pub fn cache_control<'a>(rules: &'a [CacheRule], request_path: &str) -> Option<&'a str> {
+    rules
+        .iter()
+        .find(|rule| rule.matches(request_path))
+        .map(|rule| rule.value.as_str())
+}
+
+/// An in-memory cache of small files, keyed by path.
+///
+/// Each lookup is given the file's current metadata, and an entry whose
+/// size or modification time no longer match is read again, so edits to
+/// the document root show up without a restart. When the cache grows past
+/// its total size, the least recently used entries are dropped.
+pub struct FileCache {
+    max_entry: u64,
+    max_total: u64,
+    inner: Mutex<CacheInner>,
+}
+
+#[derive(Default)]
+struct CacheInner {
+    entries: HashMap<PathBuf, CacheEntry>,
+    total: u64,
+    clock: u64,
+}
+
+struct CacheEntry {
+    data: Arc<[u8]>,
+    len: u64,
+    modified: Option<SystemTime>,
+    last_used: u64,
+}
+
+impl FileCache {
+    pub fn new(max_entry: u64, max_total: u64) -> FileCache {
+        FileCache {
+            max_entry,
+            max_total,
+            inner: Mutex::new(CacheInner::default()),
+        }
+    }
+
+    /// Whether a file of this size would be kept in the cache.
+    pub fn accepts(&self, metadata: &Metadata) -> bool {
+        metadata.len() <= self.max_entry && metadata.len() <= self.max_total
+    }
+
+    /// Return the contents of `path`, from memory if the cached copy still
+    /// matches `metadata`, and from disk otherwise.
+    pub fn load(&self, path: &Path, metadata: &Metadata) -> io::Result<Arc<[u8]>> {
+        let modified = metadata.modified().ok();
+        if let Some(data) = self.lookup(path, metadata.len(), modified) {
+            return Ok(data);
+        }
+
+        let data: Arc<[u8]> = fs::read(path)?.into();
+        if self.accepts(metadata) && data.len() as u64 == metadata.len() {
+            self.insert(path, Arc::clone(&data), modified);
+        }
+        Ok(data)
+    }
+
+    fn lookup(&self, path: &Path, len: u64, modified: Option<SystemTime>) -> Option<Arc<[u8]>> {
+        let mut inner = self.inner.lock().ok()?;
+        inner.clock += 1;
+        let clock = inner.clock;
+        let entry = inner.entries.get_mut(path)?;
+        if entry.len != len || entry.modified != modified {
+            return None;
+        }
+        entry.last_used = clock;
+        Some(Arc::clone(&entry.data))
+    }
+
+    fn insert(&self, path: &Path, data: Arc<[u8]>, modified: Option<SystemTime>) {
+        let Ok(mut inner) = self.inner.lock() else {
+            return;
+        };
+        let len = data.len() as u64;
+        if let Some(old) = inner.entries.remove(path) {
+            inner.total -= old.len;
+        }
+
+        while inner.total + len > self.max_total {
+            let oldest = inner
+                .entries
+                .iter()
+                .min_by_key(|(_, entry)| entry.last_used)
+                .map(|(path, _)| path.clone());
+            match oldest.and_then(|path| inner.entries.remove(&path)) {
+                Some(evicted) => inner.total -= evicted.len,
+                None => break,
+            }
+        }
+
+        let last_used = inner.clock;
+        inner.total += len;
+        inner.entries.insert(path.to_path_buf(), CacheEntry { data, len, modified, last_used });
+    }
+
+    /// Total bytes currently held.
+    pub fn size(&self) -> u64 {
+        self.inner.lock().map_or(0, |inner| inner.total)
+    }
+}
+
+#[cfg(test)]
+fn request_headers(fields: &[(&str, &str)]) -> Headers {
+    let mut headers = Headers::new();
+    for (name, value) in fields {
+        headers.append(name, *value);
+    }
+    headers
+}
+
+#[test]
+fn test_if_none_match() {
+    let validators = Validators {
+        etag: "\"a4-1\"".to_string(),
+        last_modified: Some(UNIX_EPOCH + std::time::Duration::from_secs(784_111_777)),
+    };
+
+    assert!(validators.is_not_modified(&request_headers(&[("If-None-Match", "\"a4-1\"")])));
+    assert!(validators.is_not_modified(&request_headers(&[("If-None-Match", "\"x\", W/\"a4-1\"")])));
+    assert!(validators.is_not_modified(&request_headers(&[("If-None-Match", "*")])));
+    assert!(!validators.is_not_modified(&request_headers(&[("If-None-Match", "\"a4-2\"")])));
+
+    // If-None-Match takes precedence over If-Modified-Since.
+    assert!(!validators.is_not_modified(&request_headers(&[
+        ("If-None-Match", "\"other\""),
+        ("If-Modified-Since", "Sun, 06 Nov 1994 08:49:37 GMT"),
+    ])));
+}
+
+#[test]
+fn test_if_modified_since() {
+    let validators = Validators {
+        etag: "\"a4-1\"".to_string(),
+        last_modified: Some(UNIX_EPOCH + std::time::Duration::from_millis(784_111_777_500)),
+    };
+
+    assert!(validators.is_not_modified(&request_headers(&[("If-Modified-Since", "Sun, 06 Nov 1994 08:49:37 GMT")])));
+    assert!(validators.is_not_modified(&request_headers(&[("If-Modified-Since", "Mon, 07 Nov 1994 08:49:37 GMT")])));
+    assert!(!validators.is_not_modified(&request_headers(&[("If-Modified-Since", "Sun, 06 Nov 1994 08:49:36 GMT")])));
+    assert!(!validators.is_not_modified(&request_headers(&[("If-Modified-Since", "yesterday")])));
+    assert!(!validators.is_not_modified(&Headers::new()));
+}
+
+#[test]
+fn test_cache_control_rules() {
+    let rules = vec![
+        CacheRule { path: "/static/".to_string(), value: "public, max-age=31536000, immutable".to_string() },
+        CacheRule { path: "*.html".to_string(), value: "no-cache".to_string() },
+    ];
+    assert_eq!(cache_control(&rules, "/static/app.js"), Some("public, max-age=31536000, immutable"));
+    assert_eq!(cache_control(&rules, "/static/page.html"), Some("public, max-age=31536000, immutable"));
+    assert_eq!(cache_control(&rules, "/hello.html"), Some("no-cache"));
+    assert_eq!(cache_control(&rules, "/data.json"), None);
+}
+
+#[test]
+fn test_not_modified_keeps_cache_headers() {
+    let response = Response::new(Status::Ok)
+        .with_header("Content-Type", "text/html")
+        .with_header("ETag", "\"1\"")
+        .with_header("Cache-Control", "no-cache")
+        .with_body(Body::Text("hello".to_string()));
+
+    let response = not_modified(response);
+    assert_eq!(response.status, Status::NotModified);
+    assert_eq!(response.headers.get("ETag"), Some("\"1\""));
+    assert_eq!(response.headers.get("Cache-Control"), Some("no-cache"));
+    assert_eq!(response.headers.get("Content-Type"), None);
+    assert!(response.body.is_empty());
+}
+
+#[test]
+fn test_file_cache_invalidation() {
+    let dir = std::env::temp_dir().join(format!("web_server_cache_{}", std::process::id()));
+    fs::create_dir_all(&dir).unwrap();
+    let path = dir.join("page.html");
+    let cache = FileCache::new(1024, 4096);
+
+    fs::write(&path, "first").unwrap();
+    let data = cache.load(&path, &fs::metadata(&path).unwrap()).unwrap();
+    assert_eq!(&data[..], b"first");
+    assert_eq!(cache.size(), 5);
+
+    // A change in size or modification time is noticed on the next load.
+    fs::write(&path, "second").unwrap();
+    let data = cache.load(&path, &fs::metadata(&path).unwrap()).unwrap();
+    assert_eq!(&data[..], b"second");
+    assert_eq!(cache.size(), 6);
+
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn test_file_cache_eviction() {
+    let dir = std::env::temp_dir().join(format!("web_server_evict_{}", std::process::id()));
+    fs::create_dir_all(&dir).unwrap();
+    let cache = FileCache::new(100, 250);
+
+    for name in ["a", "b", "c"] {
+        let path = dir.join(name);
+        fs::write(&path, vec![0; 100]).unwrap();
+        cache.load(&path, &fs::metadata(&path).unwrap()).unwrap();
+    }
+    assert_eq!(cache.size(), 200);
+    let inner = cache.inner.lock().unwrap();
+    assert!(!inner.entries.contains_key(&dir.join("a")));
+    assert!(inner.entries.contains_key(&dir.join("c")));
+    drop(inner);
+
+    fs::remove_dir_all(&dir).unwrap();
+}
@@ -0,0 +1,687 @@
+use std::collections::BTreeMap;
+use std::fs::Metadata;
+use std::io::{self, BufRead, BufReader, BufWriter, Read, Write};
+use std::path::{Path, PathBuf};
+use std::process::{Child, Command, Stdio};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use log::{debug, warn};
+use serde::Deserialize;
+
+use crate::body;
+use crate::config::Config;
+use crate::files::percent_decode;
+use crate::proxy;
+use crate::request::{self, Request};
+use crate::response::{Body, Response, Status};
+use crate::server::error_response;
+
+/// Reported to scripts in `SERVER_SOFTWARE`.
+const SOFTWARE: &str = concat!("web_server/", env!("CARGO_PKG_VERSION"));
+
+// FastCGI record types and the responder role (FastCGI 1.0 section 8).
+const BEGIN_REQUEST: u8 = 1;
+const END_REQUEST: u8 = 3;
+const PARAMS: u8 = 4;
+const STDIN: u8 = 5;
+const STDOUT: u8 = 6;
+const STDERR: u8 = 7;
+const RESPONDER: u16 = 1;
+/// Each connection carries one request, always with this id.
+const REQUEST_ID: u16 = 1;
+
+/// A path prefix answered by CGI scripts or by a FastCGI backend, as
+/// configured.
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct CgiRule {
+    /// A path prefix such as `/cgi-bin/`; it covers the path itself and
+    /// everything below it.
+    pub path: String,
+    /// Directory holding the scripts. The first path segment below `path`
+    /// names the script and the rest is passed on as `PATH_INFO`.
+    #[serde(default)]
+    pub dir: Option<PathBuf>,
+    /// A FastCGI backend as `host:port`, sent requests instead of scripts
+    /// being run. With `dir`, the script's file in it is sent as
+    /// `SCRIPT_FILENAME`; without, the backend answers for the whole prefix.
+    #[serde(default)]
+    pub fastcgi: Option<String>,
+    /// Seconds a script may run, or a backend may take for each read and
+    /// write.
+    #[serde(default = "default_timeout")]
+    pub timeout: u64,
+}
+
+fn default_timeout() -> u64 {
+    30
+}
+
+impl CgiRule {
+    /// Run the scripts in `dir` for requests under `path`.
+    pub fn scripts(path: &str, dir: PathBuf) -> CgiRule {
+        CgiRule {
+            path: path.to_string(),
+            dir: Some(dir),
+            fastcgi: None,
+            timeout: default_timeout(),
+        }
+    }
+
+    /// Send requests under `path` to the FastCGI backend at `address`.
+    pub fn fastcgi(path: &str, address: &str) -> CgiRule {
+        CgiRule {
+            path: path.to_string(),
+            dir: None,
+            fastcgi: Some(address.to_string()),
+            timeout: default_timeout(),
+        }
+    }
+}
+
+/// The script a request is for.
+#[derive(Debug, PartialEq)]
+struct Script {
+    /// The part of the request path naming the script, as `SCRIPT_NAME`.
+    name: String,
+    /// The script's file, as `SCRIPT_FILENAME`.
+    file: Option<PathBuf>,
+    /// The decoded rest of the path, as `PATH_INFO`.
+    path_info: String,
+}
+
+/// Find the script `request_path`, which lies under `rule.path`, is for.
+///
+/// Return `None` when the path is malformed or names no script file, for
+/// instance with a `..` segment.
+fn locate(rule: &CgiRule, request_path: &str) -> Option<Script> {
+    let prefix = rule.path.trim_end_matches('/');
+    let rest = request_path.strip_prefix(prefix)?;
+    let Some(dir) = &rule.dir else {
+        return Some(Script { name: prefix.to_string(), file: None, path_info: percent_decode(rest)? });
+    };
+
+    let rest = rest.strip_prefix('/')?;
+    let (segment, path_info) = rest.split_at(rest.find('/').unwrap_or(rest.len()));
+    let file_name = percent_decode(segment)?;
+    if matches!(file_name.as_str(), "" | "." | "..") || file_name.contains(['/', '\\', '\0']) {
+        return None;
+    }
+    Some(Script {
+        name: format!("{prefix}/{segment}"),
+        file: Some(dir.join(file_name)),
+        path_info: percent_decode(path_info)?,
+    })
+}
+
+/// The meta-variables describing `request` to `script` (RFC 3875 section
+/// 4.1), with an `HTTP_` variable for each header field.
+fn environment(request: &Request, script: &Script, content_length: usize, config: &Config) -> BTreeMap<String, String> {
+    let port = match request.headers.get("Host").and_then(|host| host.rsplit_once(':')) {
+        Some((_, port)) if !port.contains(']') => port,
+        _ if request.secure => "443",
+        _ => "80",
+    };
+    let mut env: BTreeMap<String, String> = [
+        ("GATEWAY_INTERFACE", "CGI/1.1"),
+        ("SERVER_SOFTWARE", SOFTWARE),
+        ("SERVER_NAME", request.host().unwrap_or("localhost")),
+        ("SERVER_PORT", port),
+        ("SERVER_PROTOCOL", &request.version),
+        ("REQUEST_METHOD", request.method.as_str()),
+        ("REQUEST_URI", &request.target),
+        ("SCRIPT_NAME", &script.name),
+        ("QUERY_STRING", request.query().unwrap_or("")),
+        ("DOCUMENT_ROOT", &config.root.to_string_lossy()),
+    ]
+    .into_iter()
+    .map(|(name, value)| (name.to_string(), value.to_string()))
+    .collect();
+
+    let mut set = |name: &str, value: String| {
+        env.insert(name.to_string(), value);
+    };
+    if !script.path_info.is_empty() {
+        set("PATH_INFO", script.path_info.clone());
+    }
+    if let Some(file) = &script.file {
+        set("SCRIPT_FILENAME", file.to_string_lossy().into_owned());
+    }
+    if let Some(peer) = request.peer {
+        set("REMOTE_ADDR", peer.ip().to_string());
+        set("REMOTE_HOST", peer.ip().to_string());
+        set("REMOTE_PORT", peer.port().to_string());
+    }
+    if let Some(user) = &request.user {
+        set("REMOTE_USER", user.clone());
+        if let Some(scheme) = request.headers.get("Authorization").and_then(|v| v.split_whitespace().next()) {
+            set("AUTH_TYPE", scheme.to_string());
+        }
+    }
+    if content_length > 0 {
+        set("CONTENT_LENGTH", content_length.to_string());
+    }
+    if let Some(content_type) = request.headers.get("Content-Type") {
+        set("CONTENT_TYPE", content_type.to_string());
+    }
+    if request.secure {
+        set("HTTPS", "on".to_string());
+    }
+
+    for (name, value) in request.headers.iter() {
+        // A field with an underscore could pose as one with a hyphen, and
+        // HTTP_PROXY is taken by many HTTP clients as the proxy to use.
+        // Credentials are only passed on as REMOTE_USER.
+        let skipped = ["Content-Length", "Content-Type", "Authorization", "Proxy"];
+        if name.contains('_') || skipped.iter().any(|skip| skip.eq_ignore_ascii_case(name)) {
+            continue;
+        }
+        let separator = if name.eq_ignore_ascii_case("Cookie") { "; " } else { ", " };
+        env.entry(format!("HTTP_{}", name.to_ascii_uppercase().replace('-', "_")))
+            .and_modify(|joined| {
+                joined.push_str(separator);
+                joined.push_str(value);
+            })
+            .or_insert_with(|| value.to_string());
+    }
+    env
+}
+
+/// Turn a script's output, CGI header fields followed by the body, into a
+/// response (RFC 3875 section 6). A `Location` without a `Status` is sent
+/// to the client as a redirect.
+fn read_output<R: BufRead + Send + 'static>(mut output: R) -> io::Result<Response> {
+    let invalid = |message: &str| io::Error::new(io::ErrorKind::InvalidData, message.to_string());
+    let mut headers = request::read_headers(&mut output)?;
+    let status = match headers.get("Status") {
+        Some(status) => status
+            .split(' ')
+            .next()
+            .and_then(|code| code.parse::<u16>().ok())
+            .filter(|code| (200..600).contains(code))
+            .map(Status::from_code)
+            .ok_or_else(|| invalid("invalid Status field"))?,
+        None if headers.contains("Location") => Status::Found,
+        None if headers.contains("Content-Type") => Status::Ok,
+        None => return Err(invalid("output has no Content-Type, Location or Status")),
+    };
+    headers.remove("Status");
+
+    let body = if status.allows_body() { Body::from_reader(output) } else { Body::Empty };
+    Ok(Response { status, headers: proxy::end_to_end(&headers), body, upgrade: None })
+}
+
+/// Answer `request`, whose path lies under `rule.path`, by running its
+/// script or asking the FastCGI backend, reading its body from `body`.
+///
+/// The body is read in full first, since a script is told its length up
+/// front; `body` should fail once it passes `config.max_body_size`, as the
+/// server's does.
+pub fn handle(rule: &CgiRule, request: &Request, body: &mut dyn Read, config: &Config) -> Response {
+    let Some(mut script) = locate(rule, request.path()) else {
+        return error_response(Status::NotFound, config);
+    };
+    if rule.fastcgi.is_none() {
+        match script.file.as_deref().map_or(Err(Status::NotFound), executable) {
+            Ok(file) => script.file = Some(file),
+            Err(status) => return error_response(status, config),
+        }
+    }
+
+    let mut input = Vec::new();
+    if let Err(e) = body.read_to_end(&mut input) {
+        debug!("failed to read request body: {e}");
+        return error_response(body::error_status(&e), config);
+    }
+
+    let env = environment(request, &script, input.len(), config);
+    let timeout = Duration::from_secs(rule.timeout);
+    let (handler, result) = match (&rule.fastcgi, &script.file) {
+        (Some(address), _) => (address.clone(), fastcgi(address, &env, &input, timeout)),
+        (None, Some(file)) => (file.display().to_string(), run(file, &env, input, timeout)),
+        (None, None) => unreachable!("a script without a backend has a file"),
+    };
+    match result {
+        Ok(response) => response,
+        Err(e) => {
+            warn!("{handler} failed for {}: {e}", request.target);
+            match e.kind() {
+                io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut => error_response(Status::GatewayTimeout, config),
+                _ => error_response(Status::BadGateway, config),
+            }
+        }
+    }
+}
+
+/// The full path of the script at `file`, or the status to answer with
+/// when there is no script there that may be run.
+fn executable(file: &Path) -> Result<PathBuf, Status> {
+    let file = file.canonicalize().map_err(|_| Status::NotFound)?;
+    match file.metadata() {
+        Ok(metadata) if metadata.is_file() && is_executable(&metadata) => Ok(file),
+        Ok(metadata) if metadata.is_file() => Err(Status::Forbidden),
+        _ => Err(Status::NotFound),
+    }
+}
+
+#[cfg(unix)]
+fn is_executable(metadata: &Metadata) -> bool {
+    use std::os::unix::fs::PermissionsExt;
+
+    metadata.permissions().mode() & 0o111 != 0
+}
+
+#[cfg(not(unix))]
+fn is_executable(_metadata: &Metadata) -> bool {
+    true
+}
+
+/// Run the script at `file` with `env` and `input` on its standard input,
+/// and answer with what it prints. Its standard error goes to the error
+/// log, and it is killed once it has run for `timeout`.
+fn run(file: &Path, env: &BTreeMap<String, String>, input: Vec<u8>, timeout: Duration) -> io::Result<Response> {
+    let mut command = Command::new(file);
+    command
+        .env_clear()
+        .envs(env)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped());
+    // `#!/usr/bin/env` lines search PATH for the interpreter.
+    if let Some(path) = std::env::var_os("PATH") {
+        command.env("PATH", path);
+    }
+    if let Some(dir) = file.parent() {
+        command.current_dir(dir);
+    }
+    // A group of its own, so whatever the script starts is killed with it.
+    #[cfg(unix)]
+    std::os::unix::process::CommandExt::process_group(&mut command, 0);
+    let mut child = command.spawn()?;
+    let deadline = Instant::now() + timeout;
+    let name = file.display().to_string();
+
+    let (stdin, stdout, stderr) = (child.stdin.take(), child.stdout.take(), child.stderr.take());
+    let (Some(mut stdin), Some(stdout), Some(stderr)) = (stdin, stdout, stderr) else {
+        unreachable!("every stream is piped");
+    };
+    if !input.is_empty() {
+        // In a thread of its own, since a script may answer before reading
+        // its input, or never read it at all.
+        thread::spawn(move || stdin.write_all(&input));
+    }
+    let stderr_name = name.clone();
+    thread::spawn(move || log_stderr(&stderr_name, BufReader::new(stderr)));
+    thread::spawn(move || supervise(child, deadline, &name));
+
+    match read_output(BufReader::new(stdout)) {
+        // The output ended early because the script was killed.
+        Err(_) if Instant::now() >= deadline => {
+            Err(io::Error::new(io::ErrorKind::TimedOut, format!("no response within {}s", timeout.as_secs())))
+        }
+        result => result,
+    }
+}
+
+/// Wait for a script to exit, killing it at `deadline`.
+fn supervise(mut child: Child, deadline: Instant, name: &str) {
+    let mut pause = Duration::from_millis(1);
+    loop {
+        match child.try_wait() {
+            Ok(Some(status)) if status.success() => return,
+            Ok(Some(status)) => {
+                warn!("{name} {status}");
+                return;
+            }
+            Ok(None) => {}
+            Err(e) => {
+                warn!("failed to wait for {name}: {e}");
+                return;
+            }
+        }
+        let now = Instant::now();
+        if now >= deadline {
+            warn!("{name} ran too long, killing it");
+            #[cfg(unix)]
+            if let Ok(group) = libc::pid_t::try_from(child.id()) {
+                // SAFETY: kill has no memory effects; the group is the
+                // script's own, which has not been reaped yet.
+                unsafe { libc::kill(-group, libc::SIGKILL) };
+            }
+            let _ = child.kill();
+            let _ = child.wait();
+            return;
+        }
+        thread::sleep(pause.min(deadline - now));
+        pause = (pause * 2).min(Duration::from_millis(100));
+    }
+}
+
+/// Copy each line a script writes to standard error to the error log.
+fn log_stderr<R: BufRead>(name: &str, stderr: R) {
+    for line in stderr.split(b'\n') {
+        let Ok(line) = line else { return };
+        warn!("{name}: {}", String::from_utf8_lossy(&line).trim_end());
+    }
+}
+
+/// Send a request to the FastCGI backend at `address`, with `params` and
+/// then `input` as its body, and read the head of its response. The body
+/// is streamed to the client as the backend sends it.
+fn fastcgi(address: &str, params: &BTreeMap<String, String>, input: &[u8], timeout: Duration) -> io::Result<Response> {
+    let stream = proxy::connect(address, timeout)?;
+    stream.set_read_timeout(Some(timeout))?;
+    stream.set_write_timeout(Some(timeout))?;
+    let reader = stream.try_clone()?;
+
+    let mut writer = BufWriter::new(stream);
+    let mut begin = [0; 8];
+    // No flags, so the backend closes the connection when it is done.
+    begin[..2].copy_from_slice(&RESPONDER.to_be_bytes());
+    write_record(&mut writer, BEGIN_REQUEST, &begin)?;
+    write_stream(&mut writer, PARAMS, &encode_params(params))?;
+    write_stream(&mut writer, STDIN, input)?;
+    writer.flush()?;
+
+    read_output(BufReader::new(Records::new(BufReader::new(reader), address)))
+}
+
+fn write_record<W: Write>(writer: &mut W, kind: u8, content: &[u8]) -> io::Result<()> {
+    let len = u16::try_from(content.len()).expect("record content fits in 64 KiB");
+    // Content is padded to a multiple of 8 bytes.
+    let padding = len.wrapping_neg() % 8;
+    let [id_high, id_low] = REQUEST_ID.to_be_bytes();
+    let [len_high, len_low] = len.to_be_bytes();
+    writer.write_all(&[1, kind, id_high, id_low, len_high, len_low, padding as u8, 0])?;
+    writer.write_all(content)?;
+    writer.write_all(&[0; 8][..padding as usize])
+}
+
+/// Write `content` as records of `kind`, closing the stream with an empty
+/// one.
+fn write_stream<W: Write>(writer: &mut W, kind: u8, content: &[u8]) -> io::Result<()> {
+    for chunk in content.chunks(u16::MAX as usize) {
+        write_record(writer, kind, chunk)?;
+    }
+    write_record(writer, kind, &[])
+}
+
+/// Encode name-value pairs, each length in one byte below 128 and four
+/// otherwise.
+fn encode_params(params: &BTreeMap<String, String>) -> Vec<u8> {
+    let mut encoded = Vec::new();
+    for (name, value) in params {
+        for len in [name.len(), value.len()] {
+            match u8::try_from(len) {
+                Ok(len) if len < 128 => encoded.push(len),
+                _ => encoded.extend_from_slice(&(len as u32 | 1 << 31).to_be_bytes()),
+            }
+        }
+        encoded.extend_from_slice(name.as_bytes());
+        encoded.extend_from_slice(value.as_bytes());
+    }
+    encoded
+}
+
+/// The standard output of a FastCGI request, read from the records the
+/// backend sends. Standard error records go to the error log, and the end
+/// of the request ends the stream.
+struct Records<R> {
+    reader: R,
+    /// The backend, for log messages.
+    backend: String,
+    /// Content bytes left in the current standard output record.
+    remaining: usize,
+    /// Padding after the current standard output record.
+    padding: u64,
+    done: bool,
+}
+
+impl<R: Read> Records<R> {
+    fn new(reader: R, backend: &str) -> Records<R> {
+        Records { reader, backend: backend.to_string(), remaining: 0, padding: 0, done: false }
+    }
+}
+
+impl<R: Read> Read for Records<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        loop {
+            if self.remaining > 0 {
+                let len = buf.len().min(self.remaining);
+                let read = self.reader.read(&mut buf[..len])?;
+                if read == 0 {
+                    return Err(io::ErrorKind::UnexpectedEof.into());
+                }
+                self.remaining -= read;
+                return Ok(read);
+            }
+            if self.done {
+                return Ok(0);
+            }
+            io::copy(&mut self.reader.by_ref().take(self.padding), &mut io::sink())?;
+            self.padding = 0;
+
+            let mut header = [0; 8];
+            self.reader.read_exact(&mut header)?;
+            let kind = header[1];
+            let len = u16::from_be_bytes([header[4], header[5]]) as usize;
+            let padding = header[6] as usize;
+            if kind == STDOUT {
+                self.remaining = len;
+                self.padding = padding as u64;
+                continue;
+            }
+            let mut content = vec![0; len + padding];
+            self.reader.read_exact(&mut content)?;
+            content.truncate(len);
+            match kind {
+                STDERR => {
+                    for line in String::from_utf8_lossy(&content).lines() {
+                        warn!("{}: {line}", self.backend);
+                    }
+                }
+                // The protocol status follows the application's exit code.
+                END_REQUEST if content.get(4) != Some(&0) => {
+                    return Err(io::Error::other("backend refused the request"));
+                }
+                END_REQUEST => self.done = true,
+                _ => {}
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+fn request(raw: &str) -> Request {
+    let mut request = Request::read_from(&mut raw.as_bytes()).unwrap();
+    request.peer = Some("192.0.2.7:5000".parse().unwrap());
+    request
+}
+
+#[cfg(test)]
+fn body_text(response: Response) -> String {
+    match response.body {
+        Body::Chunked(chunks) => String::from_utf8(chunks.flat_map(Result::unwrap).collect()).unwrap(),
+        Body::Empty => String::new(),
+        _ => panic!("unexpected body"),
+    }
+}
+
+/// Accept one FastCGI connection on an ephemeral port and answer it with
+/// the params and input it was sent.
+#[cfg(test)]
+fn spawn_backend() -> String {
+    let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+    let address = listener.local_addr().unwrap().to_string();
+    thread::spawn(move || {
+        let (mut stream, _) = listener.accept().unwrap();
+        let mut streams: BTreeMap<u8, Vec<u8>> = BTreeMap::new();
+        loop {
+            let mut header = [0; 8];
+            stream.read_exact(&mut header).unwrap();
+            let len = u16::from_be_bytes([header[4], header[5]]) as usize;
+            let mut content = vec![0; len + header[6] as usize];
+            stream.read_exact(&mut content).unwrap();
+            content.truncate(len);
+            if header[1] == STDIN && len == 0 {
+                break;
+            }
+            streams.entry(header[1]).or_default().extend(content);
+        }
+
+        let mut params = Vec::new();
+        let mut encoded = &streams[&PARAMS][..];
+        while !encoded.is_empty() {
+            let (name_len, value_len) = (encoded[0] as usize, encoded[1] as usize);
+            let pair = &encoded[2..2 + name_len + value_len];
+            params.push(String::from_utf8_lossy(pair).into_owned());
+            encoded = &encoded[2 + name_len + value_len..];
+        }
+        let output = format!("Content-Type: text/plain\r\n\r\n{}\n{}", params.join(" "),
+                             String::from_utf8_lossy(streams.get(&STDIN).map_or(&[][..], Vec::as_slice)));
+        write_record(&mut stream, STDERR, b"a warning\n").unwrap();
+        for part in output.as_bytes().chunks(10) {
+            write_record(&mut stream, STDOUT, part).unwrap();
+        }
+        write_record(&mut stream, STDOUT, &[]).unwrap();
+        write_record(&mut stream, END_REQUEST, &[0; 8]).unwrap();
+    });
+    address
+}
+
+#[test]
+fn test_locate() {
+    let rule = CgiRule::scripts("/cgi-bin/", PathBuf::from("scripts"));
+    assert_eq!(locate(&rule, "/cgi-bin/hello%20world.sh/a%2Fb/c"), Some(Script {
+        name: "/cgi-bin/hello%20world.sh".to_string(),
+        file: Some(PathBuf::from("scripts/hello world.sh")),
+        path_info: "/a/b/c".to_string(),
+    }));
+    assert_eq!(locate(&rule, "/cgi-bin/run").unwrap().path_info, "");
+    for path in ["/cgi-bin", "/cgi-bin/", "/cgi-bin/../secret", "/cgi-bin/a%2Fb", "/cgi-bin/bad%zz"] {
+        assert_eq!(locate(&rule, path), None, "{path}");
+    }
+
+    let rule = CgiRule::fastcgi("/app", "127.0.0.1:9000");
+    assert_eq!(locate(&rule, "/app/users/7"), Some(Script {
+        name: "/app".to_string(),
+        file: None,
+        path_info: "/users/7".to_string(),
+    }));
+}
+
+#[test]
+fn test_environment() {
+    let mut request = request("POST /cgi-bin/form.sh/extra?q=1 HTTP/1.1\r\nHost: example.com:8080\r\n\
+                               Content-Type: text/plain\r\nContent-Length: 4\r\nAuthorization: Basic YTpi\r\n\
+                               Cookie: a=1\r\nCookie: b=2\r\nX-Custom: yes\r\nX_Custom: spoofed\r\nProxy: evil\r\n\r\n");
+    request.user = Some("alice".to_string());
+    let script = locate(&CgiRule::scripts("/cgi-bin", PathBuf::from("/srv/cgi")), request.path()).unwrap();
+    let env = environment(&request, &script, 4, &Config::default());
+
+    for (name, value) in [
+        ("GATEWAY_INTERFACE", "CGI/1.1"),
+        ("SERVER_NAME", "example.com"),
+        ("SERVER_PORT", "8080"),
+        ("SERVER_PROTOCOL", "HTTP/1.1"),
+        ("REQUEST_METHOD", "POST"),
+        ("REQUEST_URI", "/cgi-bin/form.sh/extra?q=1"),
+        ("SCRIPT_NAME", "/cgi-bin/form.sh"),
+        ("SCRIPT_FILENAME", "/srv/cgi/form.sh"),
+        ("PATH_INFO", "/extra"),
+        ("QUERY_STRING", "q=1"),
+        ("REMOTE_ADDR", "192.0.2.7"),
+        ("REMOTE_USER", "alice"),
+        ("AUTH_TYPE", "Basic"),
+        ("CONTENT_LENGTH", "4"),
+        ("CONTENT_TYPE", "text/plain"),
+        ("HTTP_HOST", "example.com:8080"),
+        ("HTTP_COOKIE", "a=1; b=2"),
+        ("HTTP_X_CUSTOM", "yes"),
+    ] {
+        assert_eq!(env.get(name).map(String::as_str), Some(value), "{name}");
+    }
+    for name in ["HTTP_AUTHORIZATION", "HTTP_CONTENT_LENGTH", "HTTP_PROXY", "HTTPS"] {
+        assert!(!env.contains_key(name), "{name}");
+    }
+}
+
+#[test]
+fn test_read_output() {
+    let output = |text: &str| read_output(io::Cursor::new(text.as_bytes().to_vec()));
+
+    let response = output("Content-Type: text/html\nX-Script: 1\n\n<p>hi</p>").unwrap();
+    assert_eq!(response.status, Status::Ok);
+    assert_eq!(response.headers.get("X-Script"), Some("1"));
+    assert_eq!(body_text(response), "<p>hi</p>");
+
+    let response = output("Status: 404 Not Found\r\nContent-Type: text/plain\r\nConnection: close\r\n\r\ngone").unwrap();
+    assert_eq!(response.status, Status::NotFound);
+    assert!(!response.headers.contains("Status"));
+    assert!(!response.headers.contains("Connection"));
+
+    let response = output("Location: https://example.com/\n\n").unwrap();
+    assert_eq!(response.status, Status::Found);
+    assert_eq!(output("Status: 304\n\n").unwrap().body.len(), Some(0));
+
+    assert!(output("X-Only: this\n\n").is_err());
+    assert!(output("Status: abc\n\n").is_err());
+    assert!(output("Content-Type: text/plain\n").is_err());
+}
+
+#[cfg(unix)]
+#[test]
+fn test_run_scripts() {
+    use std::fs;
+    use std::os::unix::fs::PermissionsExt;
+
+    let dir = std::env::temp_dir().join(format!("web_server_cgi_{}", std::process::id()));
+    fs::create_dir_all(&dir).unwrap();
+    let script = |name: &str, text: &str, mode: u32| {
+        fs::write(dir.join(name), text).unwrap();
+        fs::set_permissions(dir.join(name), fs::Permissions::from_mode(mode)).unwrap();
+    };
+    script("echo.sh", "#!/bin/sh\necho 'Content-Type: text/plain'\necho\n\
+                       echo \"$REQUEST_METHOD $SCRIPT_NAME $PATH_INFO $QUERY_STRING\"\necho oops >&2\ncat\n", 0o755);
+    script("slow.sh", "#!/bin/sh\nsleep 5\n", 0o755);
+    script("broken.sh", "#!/bin/sh\necho not a header\n", 0o755);
+    script("plain.txt", "not a script", 0o644);
+
+    let mut rule = CgiRule::scripts("/cgi-bin", dir.clone());
+    rule.timeout = 1;
+    let config = Config::default();
+    let handle = |raw: &str, body: &[u8]| handle(&rule, &request(raw), &mut &body[..], &config);
+
+    let response = handle("POST /cgi-bin/echo.sh/more?x=y HTTP/1.1\r\nContent-Length: 5\r\n\r\n", b"input");
+    assert_eq!(response.status, Status::Ok);
+    assert_eq!(body_text(response), "POST /cgi-bin/echo.sh /more x=y\ninput");
+
+    assert_eq!(handle("GET /cgi-bin/missing.sh HTTP/1.1\r\n\r\n", b"").status, Status::NotFound);
+    assert_eq!(handle("GET /cgi-bin/plain.txt HTTP/1.1\r\n\r\n", b"").status, Status::Forbidden);
+    assert_eq!(handle("GET /cgi-bin/broken.sh HTTP/1.1\r\n\r\n", b"").status, Status::BadGateway);
+    let started = Instant::now();
+    assert_eq!(handle("GET /cgi-bin/slow.sh HTTP/1.1\r\n\r\n", b"").status, Status::GatewayTimeout);
+    assert!(started.elapsed() < Duration::from_secs(3));
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn test_fastcgi() {
+    let mut rule = CgiRule::fastcgi("/app/", &spawn_backend());
+    rule.dir = Some(PathBuf::from("/srv/app"));
+    let raw = "PUT /app/index.php/x HTTP/1.1\r\nHost: localhost\r\nContent-Length: 4\r\n\r\n";
+    let response = handle(&rule, &request(raw), &mut &b"data"[..], &Config::default());
+    assert_eq!(response.status, Status::Ok);
+    let text = body_text(response);
+    for param in ["REQUEST_METHODPUT", "SCRIPT_FILENAME/srv/app/index.php", "PATH_INFO/x", "CONTENT_LENGTH4"] {
+        assert!(text.contains(param), "missing {param} in {text}");
+    }
+    assert!(text.ends_with("\ndata"));
+
+    // Nothing listens on a port just released.
+    let dead = std::net::TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap().to_string();
+    let response = handle(&CgiRule::fastcgi("/", &dead), &request("GET / HTTP/1.1\r\n\r\n"), &mut io::empty(),
+                          &Config::default());
+    assert_eq!(response.status, Status::BadGateway);
+}
@@ -0,0 +1,373 @@
+use std::io::{self, Read};
+use std::path::{Path, PathBuf};
+
+use flate2::read::{GzEncoder, ZlibEncoder};
+use flate2::Compression;
+
+use crate::config::Config;
+use crate::{cache, mime};
+use crate::request::{Method, Request};
+use crate::response::{Body, Response, Status};
+use crate::server::error_response;
+
+/// Bodies up to this size are compressed in memory and sent with a
+/// `Content-Length`; larger ones are compressed as they are streamed.
+const BUFFER_LIMIT: u64 = 1024 * 1024;
+
+/// A content coding this server can produce.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Encoding {
+    Brotli,
+    Gzip,
+    /// The zlib format, which is what HTTP calls `deflate`.
+    Deflate,
+}
+
+impl Encoding {
+    /// Every supported coding, most preferred first.
+    const ALL: [Encoding; 3] = [Encoding::Brotli, Encoding::Gzip, Encoding::Deflate];
+
+    /// The token used in `Accept-Encoding` and `Content-Encoding`.
+    pub fn token(&self) -> &'static str {
+        match self {
+            Encoding::Brotli => "br",
+            Encoding::Gzip => "gzip",
+            Encoding::Deflate => "deflate",
+        }
+    }
+
+    /// The file name suffix of a precompressed sibling file, if this coding
+    /// has one.
+    pub fn file_suffix(&self) -> Option<&'static str> {
+        match self {
+            Encoding::Brotli => Some(".br"),
+            Encoding::Gzip => Some(".gz"),
+            Encoding::Deflate => None,
+        }
+    }
+
+    /// Wrap `reader` so that it yields its contents compressed.
+    pub fn encoder(&self, reader: Box<dyn Read + Send>) -> Box<dyn Read + Send> {
+        match self {
+            Encoding::Brotli => Box::new(brotli::CompressorReader::new(reader, 16 * 1024, 5, 22)),
+            Encoding::Gzip => Box::new(GzEncoder::new(reader, Compression::default())),
+            Encoding::Deflate => Box::new(ZlibEncoder::new(reader, Compression::default())),
+        }
+    }
+}
+
+/// The codings acceptable to the client according to `accept_encoding`,
+/// best first.
+///
+/// Codings are ordered by their quality value, and by this server's
+/// preference among equal ones. A coding rated below an explicitly listed
+/// `identity` is left out, since the client would rather have the body
+/// uncompressed.
+pub fn accepted(accept_encoding: Option<&str>) -> Vec<Encoding> {
+    let Some(header) = accept_encoding else {
+        return Vec::new();
+    };
+
+    let mut wildcard = None;
+    let mut identity = None;
+    let mut listed = Vec::new();
+    for item in header.split(',') {
+        let mut params = item.split(';');
+        let coding = params.next().unwrap_or("").trim().to_ascii_lowercase();
+        let quality = params
+            .filter_map(|p| p.trim().strip_prefix("q="))
+            .find_map(|q| q.trim().parse::<f32>().ok())
+            .unwrap_or(1.0);
+
+        match coding.as_str() {
+            "*" => wildcard = Some(quality),
+            "identity" => identity = Some(quality),
+            "x-gzip" => listed.push(("gzip".to_string(), quality)),
+            _ => listed.push((coding, quality)),
+        }
+    }
+
+    let quality_of = |token: &str| {
+        listed
+            .iter()
+            .find(|(coding, _)| coding == token)
+            .map(|&(_, q)| q)
+            .or(wildcard)
+            .unwrap_or(0.0)
+    };
+    let identity = identity.unwrap_or(0.0);
+
+    let mut encodings: Vec<(Encoding, f32)> = Encoding::ALL
+        .iter()
+        .map(|&e| (e, quality_of(e.token())))
+        .filter(|&(_, q)| q > 0.0 && q >= identity)
+        .collect();
+    // A stable sort keeps the server's preference order among equals.
+    encodings.sort_by(|a, b| b.1.total_cmp(&a.1));
+    encodings.into_iter().map(|(e, _)| e).collect()
+}
+
+/// Whether a body of this `Content-Type` is worth compressing. Images,
+/// video and archives are already compressed, and event streams must reach
+/// the client as soon as each event is written.
+pub fn is_compressible(content_type: &str) -> bool {
+    let mime = content_type.split(';').next().unwrap_or("").trim();
+    (mime.starts_with("text/") && mime != "text/event-stream")
+        || mime.ends_with("+xml")
+        || mime.ends_with("+json")
+        || matches!(mime, "application/json" | "application/javascript" | "application/xml" | "application/wasm")
+}
+
+/// Compress `response` for the client that sent `request`, if the client
+/// accepts a supported coding and the body is compressible and large enough.
+///
+/// `Vary: Accept-Encoding` is added to every response whose representation
+/// depends on the request's `Accept-Encoding`, compressed or not, so caches
+/// do not hand a compressed body to a client that cannot decode it.
+pub fn compress_response(request: &Request, mut response: Response, config: &Config) -> Response {
+    let compressible = response
+        .headers
+        .get("Content-Type")
+        .is_some_and(is_compressible);
+    if response.status != Status::Ok || !compressible || response.headers.contains("Content-Encoding") {
+        return response;
+    }
+
+    if config.compression || config.precompressed {
+        response.headers.append_token("Vary", "Accept-Encoding");
+    }
+    if !config.compression {
+        return response;
+    }
+    if response.body.len().is_some_and(|len| len < config.compression_min_size) {
+        return response;
+    }
+    let Some(&encoding) = accepted(request.headers.get("Accept-Encoding")).first() else {
+        return response;
+    };
+
+    // HTTP/1.0 clients cannot receive chunked bodies, so bodies too large
+    // to compress in memory are only compressed for HTTP/1.1.
+    let buffered = response.body.len().is_some_and(|len| len <= BUFFER_LIMIT);
+    if !buffered && request.version != "HTTP/1.1" {
+        return response;
+    }
+
+    // The body of a HEAD response is never sent, so it is not compressed;
+    // the headers describe the encoded body, whose length is not known
+    // without encoding it.
+    let body = std::mem::replace(&mut response.body, Body::Empty);
+    let encoded = if request.method == Method::Head {
+        Ok(Body::Chunked(Box::new(std::iter::empty())))
+    } else {
+        encode(body, encoding, buffered)
+    };
+    match encoded {
+        Ok(body) => {
+            response.body = body;
+            response.headers.set("Content-Encoding", encoding.token());
+            // The compressed bytes differ from those the strong tag was
+            // computed for.
+            if let Some(etag) = response.headers.get("ETag").map(cache::weaken) {
+                response.headers.set("ETag", etag);
+            }
+            response
+        }
+        Err(e) => {
+            log::error!("failed to compress response: {e}");
+            error_response(Status::InternalServerError, config)
+        }
+    }
+}
+
+/// Find a precompressed sibling of `path`, such as `hello.html.br`, that the
+/// client accepts. Return its path and coding.
+pub fn precompressed_sibling(path: &Path, request: &Request) -> Option<(PathBuf, Encoding)> {
+    if !is_compressible(mime::content_type(path)) {
+        return None;
+    }
+
+    accepted(request.headers.get("Accept-Encoding"))
+        .into_iter()
+        .filter_map(|encoding| {
+            let mut sibling = path.as_os_str().to_os_string();
+            sibling.push(encoding.file_suffix()?);
+            Some((PathBuf::from(sibling), encoding))
+        })
+        .find(|(sibling, _)| sibling.is_file())
+}
+
+/// Compress `body` with `encoding`, either all at once into memory or as it
+/// is streamed to the client.
+fn encode(body: Body, encoding: Encoding, buffered: bool) -> io::Result<Body> {
+    let reader: Box<dyn Read + Send> = match body {
+        Body::Empty => return Ok(Body::Empty),
+        Body::Bytes(bytes) => Box::new(io::Cursor::new(bytes)),
+        Body::Text(text) => Box::new(io::Cursor::new(text.into_bytes())),
+        Body::Stream(reader, len) => Box::new(reader.take(len)),
+        Body::Chunked(chunks) => Box::new(ChunkReader::new(chunks)),
+    };
+
+    let mut encoder = encoding.encoder(reader);
+    if buffered {
+        let mut compressed = Vec::new();
+        encoder.read_to_end(&mut compressed)?;
+        Ok(Body::Bytes(compressed))
+    } else {
+        Ok(Body::from_reader(encoder))
+    }
+}
+
+/// Adapt a chunk iterator back into a reader, so it can be compressed.
+struct ChunkReader {
+    chunks: Box<dyn Iterator<Item = io::Result<Vec<u8>>> + Send>,
+    current: io::Cursor<Vec<u8>>,
+}
+
+impl ChunkReader {
+    fn new(chunks: Box<dyn Iterator<Item = io::Result<Vec<u8>>> + Send>) -> Self {
+        ChunkReader {
+            chunks,
+            current: io::Cursor::new(Vec::new()),
+        }
+    }
+}
+
+impl Read for ChunkReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        loop {
+            let read = self.current.read(buf)?;
+            if read > 0 || buf.is_empty() {
+                return Ok(read);
+            }
+            match self.chunks.next() {
+                Some(chunk) => self.current = io::Cursor::new(chunk?),
+                None => return Ok(0),
+            }
+        }
+    }
+}
+
+#[test]
+fn test_accepted() {
+    use Encoding::*;
+
+    assert_eq!(accepted(None), vec![]);
+    assert_eq!(accepted(Some("gzip, deflate, br")), vec![Brotli, Gzip, Deflate]);
+    assert_eq!(accepted(Some("gzip;q=1.0, br;q=0.8")), vec![Gzip, Brotli]);
+    assert_eq!(accepted(Some("br;q=0, *")), vec![Gzip, Deflate]);
+    assert_eq!(accepted(Some("x-gzip")), vec![Gzip]);
+    assert_eq!(accepted(Some("identity")), vec![]);
+    assert_eq!(accepted(Some("gzip;q=0.5, identity")), vec![]);
+    assert_eq!(accepted(Some("compress, zstd")), vec![]);
+}
+
+#[test]
+fn test_is_compressible() {
+    assert!(is_compressible("text/html; charset=utf-8"));
+    assert!(is_compressible("application/json"));
+    assert!(is_compressible("image/svg+xml"));
+    assert!(!is_compressible("image/png"));
+    assert!(!is_compressible("application/zip"));
+    assert!(!is_compressible("text/event-stream"));
+}
+
+#[cfg(test)]
+fn compressed_request(accept_encoding: &str) -> Request {
+    let raw = format!("GET / HTTP/1.1\r\nAccept-Encoding: {accept_encoding}\r\n\r\n");
+    Request::read_from(&mut raw.as_bytes()).unwrap()
+}
+
+#[cfg(test)]
+fn text_response(text: &str) -> Response {
+    Response::new(Status::Ok)
+        .with_header("Content-Type", "text/plain")
+        .with_body(Body::Text(text.to_string()))
+}
+
+#[test]
+fn test_compress_response() {
+    let text = "Hi from Rust! ".repeat(200);
+    let config = Config::default();
+
+    let response = text_response(&text).with_header("ETag", "\"1\"");
+    let response = compress_response(&compressed_request("gzip"), response, &config);
+    assert_eq!(response.headers.get("Content-Encoding"), Some("gzip"));
+    assert_eq!(response.headers.get("ETag"), Some("W/\"1\""));
+    assert_eq!(response.headers.get("Vary"), Some("Accept-Encoding"));
+    let Body::Bytes(bytes) = response.body else { panic!("expected a buffered body") };
+    let mut decoded = String::new();
+    flate2::read::GzDecoder::new(&bytes[..]).read_to_string(&mut decoded).unwrap();
+    assert_eq!(decoded, text);
+
+    let response = compress_response(&compressed_request("br"), text_response(&text), &config);
+    assert_eq!(response.headers.get("Content-Encoding"), Some("br"));
+    let Body::Bytes(bytes) = response.body else { panic!("expected a buffered body") };
+    let mut decoded = String::new();
+    brotli::Decompressor::new(&bytes[..], 4096).read_to_string(&mut decoded).unwrap();
+    assert_eq!(decoded, text);
+
+    // A HEAD response gets the headers of the encoded body without one.
+    let request = Request::read_from(&mut &b"HEAD / HTTP/1.1\r\nAccept-Encoding: gzip\r\n\r\n"[..]).unwrap();
+    let response = compress_response(&request, text_response(&text), &config);
+    assert_eq!(response.headers.get("Content-Encoding"), Some("gzip"));
+    assert!(matches!(response.body, Body::Chunked(_)));
+    assert_eq!(response.body.len(), None);
+}
+
+#[test]
+fn test_compress_response_skips() {
+    let config = Config::default();
+
+    // Too small to be worth it, but the representation still varies.
+    let response = compress_response(&compressed_request("gzip"), text_response("tiny"), &config);
+    assert_eq!(response.headers.get("Content-Encoding"), None);
+    assert_eq!(response.headers.get("Vary"), Some("Accept-Encoding"));
+
+    // Not a compressible type.
+    let response = Response::new(Status::Ok)
+        .with_header("Content-Type", "image/png")
+        .with_body(Body::Bytes(vec![0; 4096]));
+    let response = compress_response(&compressed_request("gzip"), response, &config);
+    assert_eq!(response.headers.get("Content-Encoding"), None);
+    assert_eq!(response.headers.get("Vary"), None);
+
+    // Compression turned off.
+    let config = Config { compression: false, ..Config::default() };
+    let response = compress_response(&compressed_request("gzip"), text_response(&"x".repeat(4096)), &config);
+    assert_eq!(response.headers.get("Content-Encoding"), None);
+    assert_eq!(response.headers.get("Vary"), None);
+}
+
+#[test]
+fn test_precompressed_sibling() {
+    let dir = std::env::temp_dir().join(format!("web_server_precompressed_{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let page = dir.join("page.html");
+    std::fs::write(&page, "<p>hello</p>").unwrap();
+    std::fs::write(dir.join("page.html.gz"), "not really gzip").unwrap();
+
+    assert_eq!(precompressed_sibling(&page, &compressed_request("br, gzip")),
+               Some((dir.join("page.html.gz"), Encoding::Gzip)));
+    assert_eq!(precompressed_sibling(&page, &compressed_request("br")), None);
+    assert_eq!(precompressed_sibling(&page, &compressed_request("identity")), None);
+
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn test_compress_streamed_body() {
+    let chunks = vec![Ok(b"Hello, ".to_vec()), Ok(b"world!".to_vec())];
+    let response = Response::new(Status::Ok)
+        .with_header("Content-Type", "text/plain")
+        .with_body(Body::Chunked(Box::new(chunks.into_iter())));
+
+    let response = compress_response(&compressed_request("deflate"), response, &Config::default());
+    assert_eq!(response.headers.get("Content-Encoding"), Some("deflate"));
+
+    let Body::Chunked(chunks) = response.body else { panic!("expected a chunked body") };
+    let compressed: Vec<u8> = chunks.flat_map(Result::unwrap).collect();
+    let mut decoded = String::new();
+    flate2::read::ZlibDecoder::new(&compressed[..]).read_to_string(&mut decoded).unwrap();
+    assert_eq!(decoded, "Hello, world!");
+}
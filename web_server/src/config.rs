@@ -0,0 +1,1088 @@
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::fmt;
+use std::fs;
+use std::io;
+use std::net::ToSocketAddrs;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::time::Duration;
+
+use log::Level;
+use serde::Deserialize;
+
+use crate::access_log::AccessLogFormat;
+use crate::auth::{self, AuthRule};
+use crate::cache::CacheRule;
+use crate::cgi::CgiRule;
+use crate::logger::{LogTarget, Rotation};
+use crate::proxy::ProxyRule;
+use crate::upload::UploadRule;
+use crate::vhost::VirtualHost;
+
+/// The validated server configuration.
+#[derive(Debug, Clone)]
+pub struct Config {
+    /// Addresses to accept connections on, like `127.0.0.1:7878`.
+    pub listen: Vec<String>,
+    /// Directory that request paths are resolved against.
+    pub root: PathBuf,
+    /// File served when a request path names a directory.
+    pub index: String,
+    /// Number of threads handling connections.
+    pub workers: usize,
+    /// Whether idle connections are watched by an event loop rather than
+    /// each holding a worker thread.
+    pub event_loop: bool,
+    /// How long to wait for a client to send its request.
+    pub read_timeout: Duration,
+    /// How long to wait for a client to accept the response.
+    pub write_timeout: Duration,
+    /// How long a connection may stay idle between requests; zero closes
+    /// every connection after one request.
+    pub keep_alive_timeout: Duration,
+    pub log_level: Level,
+    /// Custom error pages, by status code.
+    pub error_pages: HashMap<u16, PathBuf>,
+    pub access_log: LogTarget,
+    pub access_log_format: AccessLogFormat,
+    pub error_log: LogTarget,
+    /// Rotation applied to both log files.
+    pub log_rotation: Rotation,
+    /// Whether to compress responses on the fly.
+    pub compression: bool,
+    /// Bodies smaller than this many bytes are sent uncompressed.
+    pub compression_min_size: u64,
+    /// Whether to serve `NAME.br` and `NAME.gz` in place of `NAME` when they
+    /// exist and the client accepts them.
+    pub precompressed: bool,
+    /// `Cache-Control` values by path; the first matching rule applies.
+    pub cache_control: Vec<CacheRule>,
+    /// Whether to keep small files in memory.
+    pub file_cache: bool,
+    /// Largest file kept in the file cache, in bytes.
+    pub file_cache_max_entry: u64,
+    /// Total bytes the file cache may hold.
+    pub file_cache_max_size: u64,
+    /// Addresses to accept HTTPS connections on.
+    pub tls_listen: Vec<String>,
+    /// PEM file holding the certificate chain, leaf first.
+    pub tls_cert: Option<PathBuf>,
+    /// PEM file holding the certificate's private key.
+    pub tls_key: Option<PathBuf>,
+    /// Whether requests on the plain listeners are redirected to HTTPS.
+    pub https_redirect: bool,
+    /// Whether clients may speak HTTP/2: over TLS when they ask for it
+    /// during the handshake, and in cleartext when they start with the
+    /// HTTP/2 connection preface.
+    pub http2: bool,
+    /// Origins allowed to read responses cross-origin, or `*` for any; CORS
+    /// is off when empty.
+    pub cors_origins: Vec<String>,
+    /// Request headers cross-origin scripts may send.
+    pub cors_headers: Vec<String>,
+    /// Whether every request gets an `X-Request-Id`.
+    pub request_id: bool,
+    /// Whether responses report their handling time in `Server-Timing`.
+    pub server_timing: bool,
+    /// Requests per second allowed from one client address; zero for no
+    /// limit.
+    pub rate_limit: f64,
+    /// Requests a client may make at once before `rate_limit` applies.
+    pub rate_limit_burst: u32,
+    /// Most connections open at once; zero for no limit.
+    pub max_connections: usize,
+    /// Most connections open at once from one client address; zero for no
+    /// limit.
+    pub max_connections_per_ip: usize,
+    /// Most threads for connections that outlive a request: upgraded
+    /// protocols, HTTP/2 and event streams; zero for no limit.
+    pub stream_threads: usize,
+    /// How long a client may take to send a whole request head.
+    pub header_timeout: Duration,
+    /// How long open connections get to finish once the server stops
+    /// accepting new ones.
+    pub shutdown_timeout: Duration,
+    /// Path prefixes that require credentials; the first matching rule
+    /// applies.
+    pub auth: Vec<AuthRule>,
+    /// Path prefixes forwarded to upstream servers; the first matching rule
+    /// applies.
+    pub proxy: Vec<ProxyRule>,
+    /// Path prefixes answered by CGI scripts or FastCGI backends; the first
+    /// matching rule applies.
+    pub cgi: Vec<CgiRule>,
+    /// Path prefixes that accept form posts and store uploaded files; the
+    /// first matching rule applies.
+    pub upload: Vec<UploadRule>,
+    /// Largest request body accepted, in bytes; longer ones are answered
+    /// with 413. 0 means no limit.
+    pub max_body_size: u64,
+    /// Whether request metrics are kept and served at `metrics_path`.
+    pub metrics: bool,
+    /// Path of the Prometheus metrics endpoint.
+    pub metrics_path: String,
+    /// Sites chosen by the `Host` header. Requests for any other host are
+    /// served with the settings above.
+    pub vhosts: Vec<VirtualHost>,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            listen: vec!["127.0.0.1:7878".to_string()],
+            root: PathBuf::from("public"),
+            index: "hello.html".to_string(),
+            workers: 4,
+            event_loop: true,
+            read_timeout: Duration::from_secs(30),
+            write_timeout: Duration::from_secs(30),
+            keep_alive_timeout: Duration::from_secs(5),
+            log_level: Level::Info,
+            error_pages: HashMap::from([(404, PathBuf::from("404.html"))]),
+            access_log: LogTarget::Console,
+            access_log_format: AccessLogFormat::Combined,
+            error_log: LogTarget::Console,
+            log_rotation: Rotation {
+                max_size: 10 * 1024 * 1024,
+                keep: 5,
+            },
+            compression: true,
+            compression_min_size: 1024,
+            precompressed: false,
+            cache_control: Vec::new(),
+            file_cache: false,
+            file_cache_max_entry: 1024 * 1024,
+            file_cache_max_size: 64 * 1024 * 1024,
+            tls_listen: Vec::new(),
+            tls_cert: None,
+            tls_key: None,
+            https_redirect: false,
+            http2: true,
+            cors_origins: Vec::new(),
+            cors_headers: Vec::new(),
+            request_id: false,
+            server_timing: false,
+            rate_limit: 0.0,
+            rate_limit_burst: 20,
+            max_connections: 10_000,
+            max_connections_per_ip: 256,
+            stream_threads: 512,
+            header_timeout: Duration::from_secs(10),
+            shutdown_timeout: Duration::from_secs(30),
+            auth: Vec::new(),
+            proxy: Vec::new(),
+            cgi: Vec::new(),
+            upload: Vec::new(),
+            max_body_size: 64 * 1024 * 1024,
+            metrics: false,
+            metrics_path: "/metrics".to_string(),
+            vhosts: Vec::new(),
+        }
+    }
+}
+
+/// Settings as written in the TOML file or given on the command line.
+///
+/// Every field is optional so that the file, the flags and the defaults can
+/// be layered on top of each other before validation.
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct Settings {
+    listen: Option<Vec<String>>,
+    root: Option<PathBuf>,
+    index: Option<String>,
+    workers: Option<usize>,
+    event_loop: Option<bool>,
+    read_timeout: Option<u64>,
+    write_timeout: Option<u64>,
+    keep_alive_timeout: Option<u64>,
+    log_level: Option<String>,
+    #[serde(default)]
+    error_pages: BTreeMap<String, PathBuf>,
+    access_log: Option<String>,
+    access_log_format: Option<String>,
+    error_log: Option<String>,
+    log_max_size: Option<u64>,
+    log_keep: Option<usize>,
+    compression: Option<bool>,
+    compression_min_size: Option<u64>,
+    precompressed: Option<bool>,
+    #[serde(default)]
+    cache_control: Vec<CacheRule>,
+    file_cache: Option<bool>,
+    file_cache_max_entry: Option<u64>,
+    file_cache_max_size: Option<u64>,
+    tls_listen: Option<Vec<String>>,
+    tls_cert: Option<PathBuf>,
+    tls_key: Option<PathBuf>,
+    https_redirect: Option<bool>,
+    http2: Option<bool>,
+    cors_origins: Option<Vec<String>>,
+    cors_headers: Option<Vec<String>>,
+    request_id: Option<bool>,
+    server_timing: Option<bool>,
+    rate_limit: Option<f64>,
+    rate_limit_burst: Option<u32>,
+    max_connections: Option<usize>,
+    max_connections_per_ip: Option<usize>,
+    stream_threads: Option<usize>,
+    header_timeout: Option<u64>,
+    shutdown_timeout: Option<u64>,
+    #[serde(default)]
+    auth: Vec<AuthRule>,
+    #[serde(default)]
+    proxy: Vec<ProxyRule>,
+    #[serde(default)]
+    cgi: Vec<CgiRule>,
+    #[serde(default)]
+    upload: Vec<UploadRule>,
+    max_body_size: Option<u64>,
+    metrics: Option<bool>,
+    metrics_path: Option<String>,
+    #[serde(default)]
+    vhost: Vec<HostSettings>,
+}
+
+/// The settings of one `[[vhost]]` table.
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct HostSettings {
+    names: Vec<String>,
+    root: PathBuf,
+    index: Option<String>,
+    #[serde(default)]
+    error_pages: BTreeMap<String, PathBuf>,
+    #[serde(default)]
+    cache_control: Vec<CacheRule>,
+    #[serde(default)]
+    proxy: Vec<ProxyRule>,
+}
+
+impl Settings {
+    /// Layer `other` on top of `self`; values set in `other` win.
+    fn merge(mut self, mut other: Settings) -> Settings {
+        self.error_pages.extend(other.error_pages);
+        other.cache_control.append(&mut self.cache_control);
+        other.auth.append(&mut self.auth);
+        other.proxy.append(&mut self.proxy);
+        other.cgi.append(&mut self.cgi);
+        other.upload.append(&mut self.upload);
+        other.vhost.append(&mut self.vhost);
+        Settings {
+            listen: other.listen.or(self.listen),
+            root: other.root.or(self.root),
+            index: other.index.or(self.index),
+            workers: other.workers.or(self.workers),
+            event_loop: other.event_loop.or(self.event_loop),
+            read_timeout: other.read_timeout.or(self.read_timeout),
+            write_timeout: other.write_timeout.or(self.write_timeout),
+            keep_alive_timeout: other.keep_alive_timeout.or(self.keep_alive_timeout),
+            log_level: other.log_level.or(self.log_level),
+            error_pages: self.error_pages,
+            access_log: other.access_log.or(self.access_log),
+            access_log_format: other.access_log_format.or(self.access_log_format),
+            error_log: other.error_log.or(self.error_log),
+            log_max_size: other.log_max_size.or(self.log_max_size),
+            log_keep: other.log_keep.or(self.log_keep),
+            compression: other.compression.or(self.compression),
+            compression_min_size: other.compression_min_size.or(self.compression_min_size),
+            precompressed: other.precompressed.or(self.precompressed),
+            cache_control: other.cache_control,
+            file_cache: other.file_cache.or(self.file_cache),
+            file_cache_max_entry: other.file_cache_max_entry.or(self.file_cache_max_entry),
+            file_cache_max_size: other.file_cache_max_size.or(self.file_cache_max_size),
+            tls_listen: other.tls_listen.or(self.tls_listen),
+            tls_cert: other.tls_cert.or(self.tls_cert),
+            tls_key: other.tls_key.or(self.tls_key),
+            https_redirect: other.https_redirect.or(self.https_redirect),
+            http2: other.http2.or(self.http2),
+            cors_origins: other.cors_origins.or(self.cors_origins),
+            cors_headers: other.cors_headers.or(self.cors_headers),
+            request_id: other.request_id.or(self.request_id),
+            server_timing: other.server_timing.or(self.server_timing),
+            rate_limit: other.rate_limit.or(self.rate_limit),
+            rate_limit_burst: other.rate_limit_burst.or(self.rate_limit_burst),
+            max_connections: other.max_connections.or(self.max_connections),
+            max_connections_per_ip: other.max_connections_per_ip.or(self.max_connections_per_ip),
+            stream_threads: other.stream_threads.or(self.stream_threads),
+            header_timeout: other.header_timeout.or(self.header_timeout),
+            shutdown_timeout: other.shutdown_timeout.or(self.shutdown_timeout),
+            auth: other.auth,
+            proxy: other.proxy,
+            cgi: other.cgi,
+            upload: other.upload,
+            max_body_size: other.max_body_size.or(self.max_body_size),
+            metrics: other.metrics.or(self.metrics),
+            metrics_path: other.metrics_path.or(self.metrics_path),
+            vhost: other.vhost,
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum ConfigError {
+    /// The command line could not be parsed.
+    Usage(String),
+    Read(PathBuf, io::Error),
+    Parse(PathBuf, toml::de::Error),
+    /// The configuration parsed but is not usable; one message per problem.
+    Invalid(Vec<String>),
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ConfigError::Usage(message) => write!(f, "{message}"),
+            ConfigError::Read(path, e) => write!(f, "failed to read {}: {e}", path.display()),
+            ConfigError::Parse(path, e) => write!(f, "failed to parse {}: {e}", path.display()),
+            ConfigError::Invalid(problems) => {
+                write!(f, "invalid configuration:")?;
+                for problem in problems {
+                    write!(f, "\n  - {problem}")?;
+                }
+                Ok(())
+            }
+        }
+    }
+}
+
+impl std::error::Error for ConfigError {}
+
+pub fn print_usage() {
+    eprintln!("web_server - serve files over HTTP");
+    eprintln!("Usage: web_server [OPTIONS]");
+    eprintln!();
+    eprintln!("Options:");
+    eprintln!("  -c, --config FILE         read settings from a TOML file");
+    eprintln!("  -l, --listen ADDR         address to listen on, may be repeated");
+    eprintln!("  -r, --root DIR            document root");
+    eprintln!("      --index FILE          file served for directory requests");
+    eprintln!("  -w, --workers N           number of worker threads");
+    eprintln!("      --blocking            keep each connection on its own worker thread");
+    eprintln!("      --read-timeout SECS   time allowed to receive a request");
+    eprintln!("      --write-timeout SECS  time allowed to send a response");
+    eprintln!("      --keep-alive-timeout SECS  idle time allowed between requests, 0 to disable");
+    eprintln!("      --log-level LEVEL     error, warn, info, debug or trace");
+    eprintln!("      --error-page CODE=FILE  custom page for an error status");
+    eprintln!("      --access-log TARGET   access log file, '-' for stdout or 'off'");
+    eprintln!("      --access-log-format FORMAT  common, combined or json");
+    eprintln!("      --error-log TARGET    error log file, '-' for stderr or 'off'");
+    eprintln!("      --no-compression      never compress responses");
+    eprintln!("      --compression-min-size BYTES  smallest body worth compressing");
+    eprintln!("      --precompressed       serve NAME.br and NAME.gz files when present");
+    eprintln!("      --cache-control PATH=VALUE  Cache-Control for a path prefix or *.ext");
+    eprintln!("      --file-cache          keep small files in memory");
+    eprintln!("      --tls-listen ADDR     address to accept HTTPS on, may be repeated");
+    eprintln!("      --tls-cert FILE       PEM certificate chain for HTTPS");
+    eprintln!("      --tls-key FILE        PEM private key for HTTPS");
+    eprintln!("      --https-redirect      redirect plain HTTP requests to HTTPS");
+    eprintln!("      --no-http2            speak HTTP/1.1 only");
+    eprintln!("      --cors-origin ORIGIN  allow cross-origin reads from ORIGIN or '*', may be repeated");
+    eprintln!("      --request-id          tag requests and responses with X-Request-Id");
+    eprintln!("      --server-timing       report handling time in Server-Timing");
+    eprintln!("      --rate-limit N        requests per second allowed per client, 0 for none");
+    eprintln!("      --rate-limit-burst N  requests a client may make at once");
+    eprintln!("      --max-connections N   connections open at once, 0 for no limit");
+    eprintln!("      --max-connections-per-ip N  connections open at once per client, 0 for no limit");
+    eprintln!("      --stream-threads N    threads for WebSockets, HTTP/2 and event streams, 0 for no limit");
+    eprintln!("      --header-timeout SECS time allowed to send a whole request head");
+    eprintln!("      --shutdown-timeout SECS  time open connections get to finish when stopping");
+    eprintln!("      --auth-basic PATH=FILE  require a user from an htpasswd FILE under PATH");
+    eprintln!("      --auth-tokens PATH=FILE  require a bearer token from FILE under PATH");
+    eprintln!("      --proxy PATH=ADDR[,ADDR...]  forward a path prefix to upstream servers");
+    eprintln!("      --cgi PATH=DIR        run the CGI scripts in DIR for a path prefix");
+    eprintln!("      --fastcgi PATH=ADDR   send a path prefix to a FastCGI backend");
+    eprintln!("      --upload PATH=DIR     accept form posts under a path prefix, storing files in DIR");
+    eprintln!("      --max-body-size BYTES  largest request body accepted, 0 for no limit");
+    eprintln!("      --vhost NAME[,NAME...]=DIR  serve requests for the named hosts from DIR");
+    eprintln!("      --metrics             serve Prometheus metrics");
+    eprintln!("      --metrics-path PATH   path of the metrics endpoint");
+    eprintln!("  -h, --help                print this help");
+    eprintln!();
+    eprintln!("Command-line options override the configuration file.");
+    eprintln!();
+    eprintln!("Signals:");
+    eprintln!("  HUP                       reload the configuration file");
+    eprintln!("  USR2                      restart without dropping connections");
+    eprintln!("  TERM, INT                 stop once open connections finish");
+}
+
+impl Config {
+    /// Build the configuration from command-line arguments (without the
+    /// program name), reading the file named by `--config` if given.
+    pub fn from_args<I: IntoIterator<Item = String>>(args: I) -> Result<Config, ConfigError> {
+        let (config_file, overrides) = parse_args(args)?;
+        let settings = match config_file {
+            Some(path) => read_settings(&path)?.merge(overrides),
+            None => overrides,
+        };
+        Config::from_settings(settings)
+    }
+
+    /// Parse a TOML configuration, as found in a configuration file.
+    pub fn from_toml(text: &str) -> Result<Config, ConfigError> {
+        let settings = toml::from_str(text)
+            .map_err(|e| ConfigError::Parse(PathBuf::from("<string>"), e))?;
+        Config::from_settings(settings)
+    }
+
+    fn from_settings(settings: Settings) -> Result<Config, ConfigError> {
+        let defaults = Config::default();
+        let mut problems = Vec::new();
+
+        let listen = settings.listen.unwrap_or(defaults.listen);
+        let tls_listen = settings.tls_listen.unwrap_or(defaults.tls_listen);
+        if listen.is_empty() && tls_listen.is_empty() {
+            problems.push("at least one listen address is required".to_string());
+        }
+        for address in listen.iter().chain(&tls_listen) {
+            if let Err(e) = address.to_socket_addrs() {
+                problems.push(format!("invalid listen address {address:?}: {e}"));
+            }
+        }
+
+        let root = settings.root.unwrap_or(defaults.root);
+        if !root.is_dir() {
+            problems.push(format!("document root {} is not a directory", root.display()));
+        }
+
+        let index = settings.index.unwrap_or(defaults.index);
+        if index.is_empty() || index.contains('/') {
+            problems.push(format!("index {index:?} must be a plain file name"));
+        }
+
+        let workers = settings.workers.unwrap_or(defaults.workers);
+        if workers == 0 {
+            problems.push("workers must be at least 1".to_string());
+        }
+
+        let read_timeout = timeout("read_timeout", settings.read_timeout, defaults.read_timeout, &mut problems);
+        let write_timeout = timeout("write_timeout", settings.write_timeout, defaults.write_timeout, &mut problems);
+        let keep_alive_timeout = settings.keep_alive_timeout.map_or(defaults.keep_alive_timeout, Duration::from_secs);
+        let header_timeout = timeout("header_timeout", settings.header_timeout, defaults.header_timeout, &mut problems);
+        let shutdown_timeout =
+            timeout("shutdown_timeout", settings.shutdown_timeout, defaults.shutdown_timeout, &mut problems);
+
+        let log_level = match settings.log_level {
+            Some(level) => Level::from_str(&level).unwrap_or_else(|_| {
+                problems.push(format!("unknown log level {level:?}"));
+                defaults.log_level
+            }),
+            None => defaults.log_level,
+        };
+
+        let error_pages = resolve_error_pages(defaults.error_pages, settings.error_pages, &root, &mut problems);
+
+        let access_log = settings.access_log.map_or(defaults.access_log, |t| LogTarget::parse(&t));
+        let access_log_format = match settings.access_log_format {
+            Some(format) => format.parse().unwrap_or_else(|e| {
+                problems.push(e);
+                defaults.access_log_format
+            }),
+            None => defaults.access_log_format,
+        };
+        let error_log = settings.error_log.map_or(defaults.error_log, |t| LogTarget::parse(&t));
+
+        let log_rotation = Rotation {
+            max_size: settings.log_max_size.unwrap_or(defaults.log_rotation.max_size),
+            keep: settings.log_keep.unwrap_or(defaults.log_rotation.keep),
+        };
+        if log_rotation.max_size == 0 {
+            problems.push("log_max_size must be at least 1 byte".to_string());
+        }
+
+        check_cache_control(&settings.cache_control, &mut problems);
+
+        let https_redirect = settings.https_redirect.unwrap_or(defaults.https_redirect);
+        if !tls_listen.is_empty() {
+            for (name, path) in [("tls_cert", &settings.tls_cert), ("tls_key", &settings.tls_key)] {
+                match path {
+                    Some(path) if !path.is_file() => {
+                        problems.push(format!("{name} {} does not exist", path.display()));
+                    }
+                    Some(_) => {}
+                    None => problems.push(format!("{name} is required with tls_listen")),
+                }
+            }
+        } else if https_redirect {
+            problems.push("https_redirect requires tls_listen".to_string());
+        }
+
+        let cors_origins = settings.cors_origins.unwrap_or(defaults.cors_origins);
+        for origin in &cors_origins {
+            if origin != "*" && !origin.starts_with("http://") && !origin.starts_with("https://") {
+                problems.push(format!("CORS origin {origin:?} must be * or start with http:// or https://"));
+            }
+        }
+
+        let rate_limit = settings.rate_limit.unwrap_or(defaults.rate_limit);
+        if !rate_limit.is_finite() || rate_limit < 0.0 {
+            problems.push(format!("rate_limit {rate_limit} must be zero or positive"));
+        }
+        let rate_limit_burst = settings.rate_limit_burst.unwrap_or(defaults.rate_limit_burst);
+        if rate_limit_burst == 0 {
+            problems.push("rate_limit_burst must be at least 1".to_string());
+        }
+
+        for rule in &settings.auth {
+            if !rule.path.starts_with('/') {
+                problems.push(format!("auth path {:?} must start with /", rule.path));
+            }
+            if rule.htpasswd.is_none() && rule.tokens.is_none() {
+                problems.push(format!("auth for {:?} needs htpasswd or tokens", rule.path));
+            }
+            for (name, path) in [("htpasswd", &rule.htpasswd), ("tokens", &rule.tokens)] {
+                match path {
+                    Some(path) if !path.is_file() => {
+                        problems.push(format!("auth {name} file {} does not exist", path.display()));
+                    }
+                    _ => {}
+                }
+            }
+        }
+
+        check_proxy(&settings.proxy, &mut problems);
+        check_cgi(&settings.cgi, &mut problems);
+        check_upload(&settings.upload, &mut problems);
+
+        let metrics_path = settings.metrics_path.unwrap_or(defaults.metrics_path);
+        if !metrics_path.starts_with('/') {
+            problems.push(format!("metrics_path {metrics_path:?} must start with /"));
+        }
+
+        let mut names = HashSet::new();
+        let mut vhosts = Vec::new();
+        for host in settings.vhost {
+            if host.names.is_empty() {
+                problems.push(format!("vhost for {} needs at least one name", host.root.display()));
+            }
+            for name in &host.names {
+                let domain = name.strip_prefix("*.").unwrap_or(name);
+                if domain.is_empty() || domain.contains(|c: char| matches!(c, '*' | '/' | ':') || c.is_whitespace()) {
+                    problems.push(format!("vhost name {name:?} must be a host name or *.domain"));
+                }
+                if !names.insert(name.to_ascii_lowercase()) {
+                    problems.push(format!("vhost name {name:?} is listed more than once"));
+                }
+            }
+            if !host.root.is_dir() {
+                problems.push(format!("vhost root {} is not a directory", host.root.display()));
+            }
+            let host_index = host.index.unwrap_or_else(|| index.clone());
+            if host_index.is_empty() || host_index.contains('/') {
+                problems.push(format!("index {host_index:?} must be a plain file name"));
+            }
+            check_cache_control(&host.cache_control, &mut problems);
+            check_proxy(&host.proxy, &mut problems);
+            vhosts.push(VirtualHost {
+                error_pages: resolve_error_pages(HashMap::new(), host.error_pages, &host.root, &mut problems),
+                names: host.names,
+                root: host.root,
+                index: host_index,
+                cache_control: host.cache_control,
+                proxy: host.proxy,
+            });
+        }
+
+        if !problems.is_empty() {
+            return Err(ConfigError::Invalid(problems));
+        }
+
+        Ok(Config {
+            listen,
+            root,
+            index,
+            workers,
+            event_loop: settings.event_loop.unwrap_or(defaults.event_loop),
+            read_timeout,
+            write_timeout,
+            keep_alive_timeout,
+            log_level,
+            error_pages,
+            access_log,
+            access_log_format,
+            error_log,
+            log_rotation,
+            compression: settings.compression.unwrap_or(defaults.compression),
+            compression_min_size: settings.compression_min_size.unwrap_or(defaults.compression_min_size),
+            precompressed: settings.precompressed.unwrap_or(defaults.precompressed),
+            cache_control: settings.cache_control,
+            file_cache: settings.file_cache.unwrap_or(defaults.file_cache),
+            file_cache_max_entry: settings.file_cache_max_entry.unwrap_or(defaults.file_cache_max_entry),
+            file_cache_max_size: settings.file_cache_max_size.unwrap_or(defaults.file_cache_max_size),
+            tls_listen,
+            tls_cert: settings.tls_cert,
+            tls_key: settings.tls_key,
+            https_redirect,
+            http2: settings.http2.unwrap_or(defaults.http2),
+            cors_origins,
+            cors_headers: settings.cors_headers.unwrap_or(defaults.cors_headers),
+            request_id: settings.request_id.unwrap_or(defaults.request_id),
+            server_timing: settings.server_timing.unwrap_or(defaults.server_timing),
+            rate_limit,
+            rate_limit_burst,
+            max_connections: settings.max_connections.unwrap_or(defaults.max_connections),
+            max_connections_per_ip: settings.max_connections_per_ip.unwrap_or(defaults.max_connections_per_ip),
+            stream_threads: settings.stream_threads.unwrap_or(defaults.stream_threads),
+            header_timeout,
+            shutdown_timeout,
+            auth: settings.auth,
+            proxy: settings.proxy,
+            cgi: settings.cgi,
+            upload: settings.upload,
+            max_body_size: settings.max_body_size.unwrap_or(defaults.max_body_size),
+            metrics: settings.metrics.unwrap_or(defaults.metrics),
+            metrics_path,
+            vhosts,
+        })
+    }
+}
+
+/// Resolve the `defaults` and the error pages configured in `settings`
+/// against `root`. A default page missing from the root is left out, so
+/// its status gets the plain-text body; a configured one is a problem.
+fn resolve_error_pages(defaults: HashMap<u16, PathBuf>, settings: BTreeMap<String, PathBuf>, root: &Path,
+                       problems: &mut Vec<String>) -> HashMap<u16, PathBuf> {
+    let mut pages: HashMap<u16, PathBuf> = defaults
+        .into_iter()
+        .map(|(code, page)| (code, root.join(page)))
+        .filter(|(_, page)| page.is_file())
+        .collect();
+    for (code, page) in settings {
+        match code.parse::<u16>() {
+            Ok(code) if (400..600).contains(&code) => {
+                let page = root.join(page);
+                if !page.is_file() {
+                    problems.push(format!("error page for {code} ({}) does not exist", page.display()));
+                }
+                pages.insert(code, page);
+            }
+            _ => problems.push(format!("error page status {code:?} is not a 4xx or 5xx code")),
+        }
+    }
+    pages
+}
+
+fn check_cache_control(rules: &[CacheRule], problems: &mut Vec<String>) {
+    for rule in rules {
+        if !rule.path.starts_with('/') && !rule.path.starts_with("*.") {
+            problems.push(format!("cache_control path {:?} must start with / or *.", rule.path));
+        }
+    }
+}
+
+fn check_proxy(rules: &[ProxyRule], problems: &mut Vec<String>) {
+    for rule in rules {
+        if !rule.path.starts_with('/') {
+            problems.push(format!("proxy path {:?} must start with /", rule.path));
+        }
+        if rule.upstreams.is_empty() {
+            problems.push(format!("proxy for {:?} needs at least one upstream", rule.path));
+        }
+        for upstream in &rule.upstreams {
+            if let Err(e) = upstream.to_socket_addrs() {
+                problems.push(format!("invalid upstream address {upstream:?}: {e}"));
+            }
+        }
+        if rule.connect_timeout == 0 || rule.timeout == 0 {
+            problems.push(format!("proxy timeouts for {:?} must be at least 1 second", rule.path));
+        }
+        if rule.max_fails == 0 {
+            problems.push(format!("proxy max_fails for {:?} must be at least 1", rule.path));
+        }
+    }
+}
+
+fn check_cgi(rules: &[CgiRule], problems: &mut Vec<String>) {
+    for rule in rules {
+        if !rule.path.starts_with('/') {
+            problems.push(format!("cgi path {:?} must start with /", rule.path));
+        }
+        match (&rule.dir, &rule.fastcgi) {
+            (None, None) => problems.push(format!("cgi for {:?} needs dir or fastcgi", rule.path)),
+            // A backend's files may be on another machine.
+            (Some(dir), None) if !dir.is_dir() => {
+                problems.push(format!("cgi dir {} is not a directory", dir.display()));
+            }
+            (_, Some(address)) => {
+                if let Err(e) = address.to_socket_addrs() {
+                    problems.push(format!("invalid fastcgi address {address:?}: {e}"));
+                }
+            }
+            _ => {}
+        }
+        if rule.timeout == 0 {
+            problems.push(format!("cgi timeout for {:?} must be at least 1 second", rule.path));
+        }
+    }
+}
+
+fn check_upload(rules: &[UploadRule], problems: &mut Vec<String>) {
+    for rule in rules {
+        if !rule.path.starts_with('/') {
+            problems.push(format!("upload path {:?} must start with /", rule.path));
+        }
+        if !rule.dir.is_dir() {
+            problems.push(format!("upload dir {} is not a directory", rule.dir.display()));
+        }
+    }
+}
+
+fn timeout(name: &str, seconds: Option<u64>, default: Duration, problems: &mut Vec<String>) -> Duration {
+    match seconds {
+        Some(0) => {
+            problems.push(format!("{name} must be at least 1 second"));
+            default
+        }
+        Some(seconds) => Duration::from_secs(seconds),
+        None => default,
+    }
+}
+
+fn read_settings(path: &Path) -> Result<Settings, ConfigError> {
+    let text = fs::read_to_string(path).map_err(|e| ConfigError::Read(path.to_path_buf(), e))?;
+    toml::from_str(&text).map_err(|e| ConfigError::Parse(path.to_path_buf(), e))
+}
+
+/// Split the command line into the configuration file path and the settings
+/// given as flags.
+fn parse_args<I: IntoIterator<Item = String>>(args: I) -> Result<(Option<PathBuf>, Settings), ConfigError> {
+    let mut args = args.into_iter();
+    let mut config_file = None;
+    let mut settings = Settings::default();
+
+    while let Some(flag) = args.next() {
+        let mut value = || {
+            args.next()
+                .ok_or_else(|| ConfigError::Usage(format!("{flag} requires a value")))
+        };
+        match flag.as_str() {
+            "-c" | "--config" => config_file = Some(PathBuf::from(value()?)),
+            "-l" | "--listen" => settings.listen.get_or_insert_with(Vec::new).push(value()?),
+            "-r" | "--root" => settings.root = Some(PathBuf::from(value()?)),
+            "--index" => settings.index = Some(value()?),
+            "-w" | "--workers" => settings.workers = Some(number(&flag, &value()?)?),
+            "--read-timeout" => settings.read_timeout = Some(number(&flag, &value()?)?),
+            "--write-timeout" => settings.write_timeout = Some(number(&flag, &value()?)?),
+            "--keep-alive-timeout" => settings.keep_alive_timeout = Some(number(&flag, &value()?)?),
+            "--blocking" => settings.event_loop = Some(false),
+            "--log-level" => settings.log_level = Some(value()?),
+            "--access-log" => settings.access_log = Some(value()?),
+            "--access-log-format" => settings.access_log_format = Some(value()?),
+            "--error-log" => settings.error_log = Some(value()?),
+            "--no-compression" => settings.compression = Some(false),
+            "--compression-min-size" => settings.compression_min_size = Some(number(&flag, &value()?)?),
+            "--precompressed" => settings.precompressed = Some(true),
+            "--cache-control" => {
+                let value = value()?;
+                match value.split_once('=') {
+                    Some((path, value)) => settings.cache_control.push(CacheRule {
+                        path: path.to_string(),
+                        value: value.to_string(),
+                    }),
+                    None => return Err(ConfigError::Usage(format!("--cache-control expects PATH=VALUE, got {value:?}"))),
+                }
+            }
+            "--file-cache" => settings.file_cache = Some(true),
+            "--tls-listen" => settings.tls_listen.get_or_insert_with(Vec::new).push(value()?),
+            "--tls-cert" => settings.tls_cert = Some(PathBuf::from(value()?)),
+            "--tls-key" => settings.tls_key = Some(PathBuf::from(value()?)),
+            "--https-redirect" => settings.https_redirect = Some(true),
+            "--no-http2" => settings.http2 = Some(false),
+            "--cors-origin" => settings.cors_origins.get_or_insert_with(Vec::new).push(value()?),
+            "--request-id" => settings.request_id = Some(true),
+            "--server-timing" => settings.server_timing = Some(true),
+            "--rate-limit" => settings.rate_limit = Some(number(&flag, &value()?)?),
+            "--rate-limit-burst" => settings.rate_limit_burst = Some(number(&flag, &value()?)?),
+            "--max-connections" => settings.max_connections = Some(number(&flag, &value()?)?),
+            "--max-connections-per-ip" => settings.max_connections_per_ip = Some(number(&flag, &value()?)?),
+            "--stream-threads" => settings.stream_threads = Some(number(&flag, &value()?)?),
+            "--header-timeout" => settings.header_timeout = Some(number(&flag, &value()?)?),
+            "--shutdown-timeout" => settings.shutdown_timeout = Some(number(&flag, &value()?)?),
+            "--auth-basic" | "--auth-tokens" => {
+                let value = value()?;
+                let Some((path, file)) = value.split_once('=') else {
+                    return Err(ConfigError::Usage(format!("{flag} expects PATH=FILE, got {value:?}")));
+                };
+                // Both flags for the same path make one rule accepting either.
+                let rule = match settings.auth.iter().position(|rule| rule.path == path) {
+                    Some(i) => &mut settings.auth[i],
+                    None => {
+                        settings.auth.push(AuthRule {
+                            path: path.to_string(),
+                            realm: auth::default_realm(),
+                            htpasswd: None,
+                            tokens: None,
+                        });
+                        settings.auth.last_mut().unwrap()
+                    }
+                };
+                if flag == "--auth-basic" {
+                    rule.htpasswd = Some(PathBuf::from(file));
+                } else {
+                    rule.tokens = Some(PathBuf::from(file));
+                }
+            }
+            "--proxy" => {
+                let value = value()?;
+                match value.split_once('=') {
+                    Some((path, upstreams)) => {
+                        settings.proxy.push(ProxyRule::new(path, upstreams.split(',').map(String::from).collect()));
+                    }
+                    None => return Err(ConfigError::Usage(format!("--proxy expects PATH=ADDR, got {value:?}"))),
+                }
+            }
+            "--cgi" => {
+                let value = value()?;
+                match value.split_once('=') {
+                    Some((path, dir)) => settings.cgi.push(CgiRule::scripts(path, PathBuf::from(dir))),
+                    None => return Err(ConfigError::Usage(format!("--cgi expects PATH=DIR, got {value:?}"))),
+                }
+            }
+            "--fastcgi" => {
+                let value = value()?;
+                match value.split_once('=') {
+                    Some((path, address)) => settings.cgi.push(CgiRule::fastcgi(path, address)),
+                    None => return Err(ConfigError::Usage(format!("--fastcgi expects PATH=ADDR, got {value:?}"))),
+                }
+            }
+            "--upload" => {
+                let value = value()?;
+                match value.split_once('=') {
+                    Some((path, dir)) => settings.upload.push(UploadRule::new(path, PathBuf::from(dir))),
+                    None => return Err(ConfigError::Usage(format!("--upload expects PATH=DIR, got {value:?}"))),
+                }
+            }
+            "--max-body-size" => settings.max_body_size = Some(number(&flag, &value()?)?),
+            "--metrics" => settings.metrics = Some(true),
+            "--metrics-path" => settings.metrics_path = Some(value()?),
+            "--vhost" => {
+                let value = value()?;
+                match value.split_once('=') {
+                    Some((names, root)) => settings.vhost.push(HostSettings {
+                        names: names.split(',').map(String::from).collect(),
+                        root: PathBuf::from(root),
+                        ..HostSettings::default()
+                    }),
+                    None => return Err(ConfigError::Usage(format!("--vhost expects NAME=DIR, got {value:?}"))),
+                }
+            }
+            "--error-page" => {
+                let value = value()?;
+                match value.split_once('=') {
+                    Some((code, page)) => {
+                        settings.error_pages.insert(code.to_string(), PathBuf::from(page));
+                    }
+                    None => return Err(ConfigError::Usage(format!("--error-page expects CODE=FILE, got {value:?}"))),
+                }
+            }
+            _ => return Err(ConfigError::Usage(format!("unknown option {flag:?}"))),
+        }
+    }
+
+    Ok((config_file, settings))
+}
+
+fn number<T: FromStr>(flag: &str, value: &str) -> Result<T, ConfigError> {
+    value
+        .parse()
+        .map_err(|_| ConfigError::Usage(format!("{flag} expects a number, got {value:?}")))
+}
+
+#[cfg(test)]
+fn args(line: &str) -> Vec<String> {
+    line.split_whitespace().map(String::from).collect()
+}
+
+#[test]
+fn test_default_config() {
+    let config = Config::from_args(Vec::new()).unwrap();
+    assert_eq!(config.listen, vec!["127.0.0.1:7878"]);
+    assert_eq!(config.workers, 4);
+    assert_eq!(config.root, Path::new("public"));
+    assert_eq!(config.error_pages[&404], Path::new("public/404.html"));
+
+    // The default page is only used when the root has one.
+    let config = Config::from_args(args("--root src")).unwrap();
+    assert!(config.error_pages.is_empty());
+}
+
+#[test]
+fn test_config_from_toml() {
+    let config = Config::from_toml(r#"
+        listen = ["127.0.0.1:8080", "[::1]:8080"]
+        root = "public"
+        workers = 8
+        event_loop = false
+        read_timeout = 5
+        keep_alive_timeout = 0
+        shutdown_timeout = 10
+        log_level = "debug"
+        access_log = "off"
+        access_log_format = "json"
+        error_log = "/var/log/web_server/error.log"
+        log_max_size = 1024
+        compression = false
+        precompressed = true
+        file_cache = true
+        cors_origins = ["*"]
+        request_id = true
+        http2 = false
+        metrics = true
+        max_body_size = 0
+
+        [[cache_control]]
+        path = "/static/"
+        value = "public, max-age=86400"
+
+        [[auth]]
+        path = "/admin/"
+        realm = "Admin area"
+        htpasswd = "Cargo.toml"
+
+        [[proxy]]
+        path = "/api/"
+        upstreams = ["127.0.0.1:9000", "127.0.0.1:9001"]
+        strip_prefix = true
+        timeout = 10
+
+        [[cgi]]
+        path = "/cgi-bin/"
+        dir = "src"
+        timeout = 5
+
+        [[cgi]]
+        path = "/app/"
+        fastcgi = "127.0.0.1:9003"
+
+        [[upload]]
+        path = "/upload/"
+        dir = "src"
+        max_body_size = 1024
+
+        [[vhost]]
+        names = ["example.com", "*.example.com"]
+        root = "src"
+        index = "lib.rs"
+
+        [vhost.error_pages]
+        404 = "main.rs"
+
+        [[vhost.proxy]]
+        path = "/api/"
+        upstreams = ["127.0.0.1:9002"]
+
+        [error_pages]
+        500 = "hello.html"
+    "#).unwrap();
+
+    assert_eq!(config.listen.len(), 2);
+    assert_eq!(config.workers, 8);
+    assert_eq!(config.read_timeout, Duration::from_secs(5));
+    assert_eq!(config.write_timeout, Duration::from_secs(30));
+    assert!(!config.event_loop);
+    assert_eq!(config.keep_alive_timeout, Duration::ZERO);
+    assert_eq!(config.shutdown_timeout, Duration::from_secs(10));
+    assert_eq!(config.log_level, Level::Debug);
+    assert_eq!(config.error_pages[&500], Path::new("public/hello.html"));
+    assert_eq!(config.error_pages[&404], Path::new("public/404.html"));
+    assert_eq!(config.access_log, LogTarget::Off);
+    assert_eq!(config.access_log_format, AccessLogFormat::Json);
+    assert_eq!(config.error_log, LogTarget::File(PathBuf::from("/var/log/web_server/error.log")));
+    assert_eq!(config.log_rotation, Rotation { max_size: 1024, keep: 5 });
+    assert!(!config.compression);
+    assert!(config.precompressed);
+    assert!(config.file_cache);
+    assert_eq!(config.cors_origins, vec!["*"]);
+    assert!(config.request_id);
+    assert!(!config.server_timing);
+    assert!(!config.http2);
+    assert!(config.metrics);
+    assert_eq!(config.metrics_path, "/metrics");
+    assert_eq!(config.cache_control, vec![CacheRule {
+        path: "/static/".to_string(),
+        value: "public, max-age=86400".to_string(),
+    }]);
+    assert_eq!(config.auth, vec![AuthRule {
+        path: "/admin/".to_string(),
+        realm: "Admin area".to_string(),
+        htpasswd: Some(PathBuf::from("Cargo.toml")),
+        tokens: None,
+    }]);
+    assert_eq!(config.proxy.len(), 1);
+    assert_eq!(config.proxy[0].upstreams, vec!["127.0.0.1:9000", "127.0.0.1:9001"]);
+    assert!(config.proxy[0].strip_prefix);
+    assert_eq!(config.proxy[0].timeout, 10);
+    assert_eq!(config.proxy[0].connect_timeout, 5);
+    assert_eq!(config.cgi, vec![
+        CgiRule { timeout: 5, ..CgiRule::scripts("/cgi-bin/", PathBuf::from("src")) },
+        CgiRule::fastcgi("/app/", "127.0.0.1:9003"),
+    ]);
+    assert_eq!(config.upload, vec![
+        UploadRule { max_body_size: Some(1024), ..UploadRule::new("/upload/", PathBuf::from("src")) },
+    ]);
+    assert_eq!(config.max_body_size, 0);
+    assert_eq!(config.vhosts.len(), 1);
+    assert_eq!(config.vhosts[0].names, vec!["example.com", "*.example.com"]);
+    assert_eq!(config.vhosts[0].index, "lib.rs");
+    assert_eq!(config.vhosts[0].error_pages, HashMap::from([(404, PathBuf::from("src/main.rs"))]));
+    assert_eq!(config.vhosts[0].proxy[0].upstreams, vec!["127.0.0.1:9002"]);
+}
+
+#[test]
+fn test_flags_override_file() {
+    let path = std::env::temp_dir().join(format!("web_server_config_{}.toml", std::process::id()));
+    fs::write(&path, "workers = 8\nlog_level = \"warn\"\n\
+                      [[cache_control]]\npath = \"/\"\nvalue = \"no-cache\"\n").unwrap();
+
+    let config = Config::from_args(args(&format!(
+        "--config {} -w 2 --listen 127.0.0.1:0 --error-page 403=404.html \
+         --cache-control *.css=max-age=60 --auth-basic /admin=Cargo.toml --auth-tokens /admin=Cargo.toml --vhost a.test,b.test=src",
+        path.display()))).unwrap();
+    fs::remove_file(&path).unwrap();
+
+    assert_eq!(config.workers, 2);
+    assert_eq!(config.log_level, Level::Warn);
+    assert_eq!(config.listen, vec!["127.0.0.1:0"]);
+    assert_eq!(config.error_pages[&403], Path::new("public/404.html"));
+    let paths: Vec<&str> = config.cache_control.iter().map(|rule| rule.path.as_str()).collect();
+    assert_eq!(paths, vec!["*.css", "/"]);
+    assert_eq!(config.cache_control[0].value, "max-age=60");
+    assert_eq!(config.auth.len(), 1);
+    assert_eq!(config.auth[0].htpasswd, Some(PathBuf::from("Cargo.toml")));
+    assert_eq!(config.auth[0].tokens, Some(PathBuf::from("Cargo.toml")));
+    assert_eq!(config.vhosts[0].names, vec!["a.test", "b.test"]);
+    assert_eq!(config.vhosts[0].root, Path::new("src"));
+    assert_eq!(config.vhosts[0].index, "hello.html");
+    assert!(config.vhosts[0].error_pages.is_empty());
+}
+
+#[test]
+fn test_invalid_config_reports_every_problem() {
+    let error = Config::from_args(args(
+        "--workers 0 --root missing-dir --read-timeout 0 --shutdown-timeout 0 --log-level loud --listen nonsense \
+         --access-log-format xml --tls-listen 127.0.0.1:0 --tls-key missing.key --cors-origin example.com --rate-limit -1 \
+         --auth-basic admin=missing.htpasswd --proxy /api=nowhere --cgi cgi-bin=missing-dir --upload upload=missing-dir --vhost a.test,*=missing-dir --vhost A.test=src \
+         --metrics-path metrics"))
+        .unwrap_err();
+
+    match error {
+        ConfigError::Invalid(problems) => assert_eq!(problems.len(), 22, "{problems:?}"),
+        other => panic!("unexpected error: {other}"),
+    }
+}
+
+#[test]
+fn test_usage_errors() {
+    assert!(matches!(Config::from_args(args("--workers")), Err(ConfigError::Usage(_))));
+    assert!(matches!(Config::from_args(args("--workers many")), Err(ConfigError::Usage(_))));
+    assert!(matches!(Config::from_args(args("--frobnicate")), Err(ConfigError::Usage(_))));
+    assert!(matches!(Config::from_args(args("--error-page 404")), Err(ConfigError::Usage(_))));
+    assert!(matches!(Config::from_args(args("--auth-basic /admin")), Err(ConfigError::Usage(_))));
+    assert!(matches!(Config::from_args(args("--proxy 127.0.0.1:9000")), Err(ConfigError::Usage(_))));
+    assert!(matches!(Config::from_args(args("--fastcgi 127.0.0.1:9000")), Err(ConfigError::Usage(_))));
+    assert!(matches!(Config::from_args(args("--upload uploads")), Err(ConfigError::Usage(_))));
+    assert!(matches!(Config::from_args(args("--vhost example.com")), Err(ConfigError::Usage(_))));
+    assert!(matches!(Config::from_toml("listen = 7878"), Err(ConfigError::Parse(..))));
+    assert!(matches!(Config::from_toml("colour = \"blue\""), Err(ConfigError::Parse(..))));
+}
@@ -0,0 +1,364 @@
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::net::{SocketAddr, TcpStream};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use rustls::{ServerConfig, ServerConnection, StreamOwned};
+
+use crate::body::BodyReader;
+use crate::error::Error;
+use crate::limit::ConnectionPermit;
+use crate::request::{Framing, Request};
+
+/// The byte stream of a connection, plain or wrapped in TLS.
+pub enum Stream {
+    Plain(TcpStream),
+    Tls(Box<StreamOwned<ServerConnection, TcpStream>>),
+}
+
+impl Stream {
+    /// The underlying socket.
+    pub fn socket(&self) -> &TcpStream {
+        match self {
+            Stream::Plain(stream) => stream,
+            Stream::Tls(stream) => &stream.sock,
+        }
+    }
+}
+
+impl Read for Stream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self {
+            Stream::Plain(stream) => stream.read(buf),
+            Stream::Tls(stream) => stream.read(buf),
+        }
+    }
+}
+
+impl Write for Stream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            Stream::Plain(stream) => stream.write(buf),
+            Stream::Tls(stream) => stream.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            Stream::Plain(stream) => stream.flush(),
+            Stream::Tls(stream) => stream.flush(),
+        }
+    }
+}
+
+/// A stream that counts the bytes read from and written to it.
+pub struct Counted {
+    stream: Stream,
+    received: u64,
+    sent: u64,
+}
+
+impl Read for Counted {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let read = self.stream.read(buf)?;
+        self.received += read as u64;
+        Ok(read)
+    }
+}
+
+impl Write for Counted {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let written = self.stream.write(buf)?;
+        self.sent += written as u64;
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.stream.flush()
+    }
+}
+
+/// A client connection that may carry several requests.
+///
+/// Reads go through a buffer that lives as long as the connection, so bytes
+/// of a pipelined request read along with the previous one are not lost
+/// between requests.
+pub struct Connection {
+    reader: BufReader<Counted>,
+    peer: Option<SocketAddr>,
+    /// Keeps the connection counted against the connection limits.
+    _permit: Option<ConnectionPermit>,
+}
+
+/// What a connection is made of, see `Connection::into_parts`.
+pub struct Parts {
+    pub stream: Stream,
+    /// Bytes already read from the stream that nothing has consumed yet.
+    pub buffered: Vec<u8>,
+    pub peer: Option<SocketAddr>,
+    pub permit: Option<ConnectionPermit>,
+}
+
+impl Connection {
+    /// Wrap an accepted socket, starting a TLS session on it when `tls` is
+    /// given. The handshake itself happens on the first read.
+    pub fn new(socket: TcpStream, tls: Option<Arc<ServerConfig>>, permit: Option<ConnectionPermit>) -> io::Result<Connection> {
+        let peer = socket.peer_addr().ok();
+        // Responses are written whole through a buffer; waiting to coalesce
+        // them with later writes only adds latency.
+        socket.set_nodelay(true)?;
+        let stream = match tls {
+            Some(tls) => {
+                let session = ServerConnection::new(tls).map_err(io::Error::other)?;
+                Stream::Tls(Box::new(StreamOwned::new(session, socket)))
+            }
+            None => Stream::Plain(socket),
+        };
+        Ok(Connection {
+            reader: BufReader::new(Counted { stream, received: 0, sent: 0 }),
+            peer,
+            _permit: permit,
+        })
+    }
+
+    pub fn peer(&self) -> Option<SocketAddr> {
+        self.peer
+    }
+
+    pub fn is_secure(&self) -> bool {
+        matches!(self.reader.get_ref().stream, Stream::Tls(_))
+    }
+
+    pub fn socket(&self) -> &TcpStream {
+        self.reader.get_ref().stream.socket()
+    }
+
+    /// Bytes received from the client so far, after TLS decryption.
+    pub fn received(&self) -> u64 {
+        self.reader.get_ref().received
+    }
+
+    /// Bytes sent to the client so far, before TLS encryption.
+    pub fn sent(&self) -> u64 {
+        self.reader.get_ref().sent
+    }
+
+    /// Read the next request head, which must arrive completely within
+    /// `timeout` however slowly its bytes trickle in; each read also times
+    /// out after `read_timeout`. This keeps a client from holding the
+    /// connection by sending a byte just often enough.
+    pub fn read_request(&mut self, timeout: Duration, read_timeout: Duration) -> Result<Request, Error> {
+        let mut reader = Deadline {
+            reader: &mut self.reader,
+            deadline: Instant::now() + timeout,
+            read_timeout,
+        };
+        let mut request = Request::read_from(&mut reader)?;
+        request.peer = self.peer;
+        request.secure = self.is_secure();
+        Ok(request)
+    }
+
+    /// Start reading a request body framed as `framing`. With
+    /// `expect_continue`, the client is told to go ahead only once the body
+    /// is first read, so a request answered without reading its body never
+    /// has it sent.
+    pub fn body(&mut self, framing: Framing, expect_continue: bool) -> BodyReader<BodySource<'_>> {
+        BodyReader::new(BodySource { reader: &mut self.reader, expect_continue }, framing)
+    }
+
+    pub fn set_timeouts(&self, read: Duration, write: Duration) -> io::Result<()> {
+        self.socket().set_read_timeout(Some(read))?;
+        self.socket().set_write_timeout(Some(write))
+    }
+
+    /// Whether request bytes have already been read from the socket, so the
+    /// next request can be served without waiting for it to become readable.
+    pub fn has_buffered(&mut self) -> bool {
+        if !self.reader.buffer().is_empty() {
+            return true;
+        }
+        match &mut self.reader.get_mut().stream {
+            Stream::Plain(_) => false,
+            // Records received with the last request may hold the next one.
+            Stream::Tls(stream) => stream
+                .conn
+                .process_new_packets()
+                .map_or(true, |state| state.plaintext_bytes_to_read() > 0 || state.peer_has_closed()),
+        }
+    }
+
+    /// Wait up to `timeout` for the client to start another request.
+    /// Return false if it closed the connection or stayed silent.
+    pub fn wait_for_request(&mut self, timeout: Duration) -> bool {
+        if self.socket().set_read_timeout(Some(timeout)).is_err() {
+            return false;
+        }
+        matches!(self.reader.fill_buf(), Ok(buf) if !buf.is_empty())
+    }
+
+    /// Whether the client picked HTTP/2 while negotiating TLS. This
+    /// finishes the handshake if no request has been read yet, failing if
+    /// it takes longer than `timeout` in all, like a request head would.
+    pub fn negotiated_http2(&mut self, timeout: Duration, read_timeout: Duration) -> io::Result<bool> {
+        match &mut self.reader.get_mut().stream {
+            Stream::Plain(_) => Ok(false),
+            Stream::Tls(stream) => {
+                let mut socket = DeadlineSocket {
+                    socket: &stream.sock,
+                    deadline: Instant::now() + timeout,
+                    read_timeout,
+                };
+                // A read that times out after others made progress returns
+                // early without an error, so keep going until the deadline.
+                while stream.conn.is_handshaking() {
+                    stream.conn.complete_io(&mut socket)?;
+                }
+                Ok(stream.conn.alpn_protocol() == Some(b"h2"))
+            }
+        }
+    }
+
+    /// Take the connection apart, for a protocol that reads and writes it
+    /// from different threads.
+    pub fn into_parts(self) -> Parts {
+        let buffered = self.reader.buffer().to_vec();
+        Parts {
+            stream: self.reader.into_inner().stream,
+            buffered,
+            peer: self.peer,
+            permit: self._permit,
+        }
+    }
+
+    /// End the connection, telling a TLS client the session is over so it
+    /// can tell the last response was not truncated.
+    pub fn close(mut self) {
+        if let Stream::Tls(stream) = &mut self.reader.get_mut().stream {
+            stream.conn.send_close_notify();
+            while stream.conn.wants_write() {
+                if stream.conn.write_tls(&mut stream.sock).is_err() {
+                    break;
+                }
+            }
+        }
+    }
+}
+
+/// Reading and writing a connection directly speaks whatever protocol it
+/// was upgraded to. Reads start with any bytes buffered after the request.
+impl Read for Connection {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.reader.read(buf)
+    }
+}
+
+impl Write for Connection {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.reader.get_mut().write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.reader.get_mut().flush()
+    }
+}
+
+/// The bytes of a request body as they arrive on the connection.
+pub struct BodySource<'a> {
+    reader: &'a mut BufReader<Counted>,
+    expect_continue: bool,
+}
+
+impl Read for BodySource<'_> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let available = self.fill_buf()?;
+        let read = available.len().min(buf.len());
+        buf[..read].copy_from_slice(&available[..read]);
+        self.consume(read);
+        Ok(read)
+    }
+}
+
+impl BufRead for BodySource<'_> {
+    fn fill_buf(&mut self) -> io::Result<&[u8]> {
+        if self.expect_continue {
+            self.expect_continue = false;
+            let stream = self.reader.get_mut();
+            stream.write_all(b"HTTP/1.1 100 Continue\r\n\r\n")?;
+            stream.flush()?;
+        }
+        self.reader.fill_buf()
+    }
+
+    fn consume(&mut self, amount: usize) {
+        self.reader.consume(amount);
+    }
+}
+
+/// Reads from a connection until a deadline, shortening the socket's read
+/// timeout as it approaches.
+struct Deadline<'a> {
+    reader: &'a mut BufReader<Counted>,
+    deadline: Instant,
+    read_timeout: Duration,
+}
+
+impl Read for Deadline<'_> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let available = self.fill_buf()?;
+        let read = available.len().min(buf.len());
+        buf[..read].copy_from_slice(&available[..read]);
+        self.consume(read);
+        Ok(read)
+    }
+}
+
+impl BufRead for Deadline<'_> {
+    fn fill_buf(&mut self) -> io::Result<&[u8]> {
+        if self.reader.buffer().is_empty() {
+            let remaining = self.deadline.saturating_duration_since(Instant::now());
+            if remaining.is_zero() {
+                return Err(io::Error::new(io::ErrorKind::TimedOut, "request head not received in time"));
+            }
+            self.reader
+                .get_ref()
+                .stream
+                .socket()
+                .set_read_timeout(Some(remaining.min(self.read_timeout)))?;
+        }
+        self.reader.fill_buf()
+    }
+
+    fn consume(&mut self, amount: usize) {
+        self.reader.consume(amount);
+    }
+}
+
+/// The socket under a TLS session, with every read timing out by
+/// `deadline`, so a handshake trickled in byte by byte still ends in time.
+struct DeadlineSocket<'a> {
+    socket: &'a TcpStream,
+    deadline: Instant,
+    read_timeout: Duration,
+}
+
+impl Read for DeadlineSocket<'_> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let remaining = self.deadline.saturating_duration_since(Instant::now());
+        if remaining.is_zero() {
+            return Err(io::Error::new(io::ErrorKind::TimedOut, "TLS handshake not finished in time"));
+        }
+        self.socket.set_read_timeout(Some(remaining.min(self.read_timeout)))?;
+        self.socket.read(buf)
+    }
+}
+
+impl Write for DeadlineSocket<'_> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.socket.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.socket.flush()
+    }
+}
@@ -0,0 +1,84 @@
+use std::fmt;
+use std::io;
+
+use crate::response::Status;
+
+/// Everything that can go wrong while reading a request from a client.
+#[derive(Debug)]
+pub enum Error {
+    /// The client closed the connection before sending a complete request.
+    ConnectionClosed,
+    /// The request line or a header field could not be parsed.
+    BadRequest(&'static str),
+    /// The request head exceeded the size limits.
+    HeadersTooLarge,
+    /// The request named an HTTP version other than 1.0 or 1.1.
+    UnsupportedVersion(String),
+    /// The request body uses a transfer coding other than `chunked`.
+    UnsupportedTransferCoding(String),
+    Io(io::Error),
+}
+
+impl Error {
+    /// The status to answer the client with, or `None` when the connection
+    /// is unusable and should simply be closed.
+    pub fn status(&self) -> Option<Status> {
+        match self {
+            Error::ConnectionClosed => None,
+            Error::BadRequest(_) => Some(Status::BadRequest),
+            Error::HeadersTooLarge => Some(Status::RequestHeaderFieldsTooLarge),
+            Error::UnsupportedVersion(_) => Some(Status::HttpVersionNotSupported),
+            Error::UnsupportedTransferCoding(_) => Some(Status::NotImplemented),
+            Error::Io(e) => match e.kind() {
+                io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut => Some(Status::RequestTimeout),
+                _ => None,
+            },
+        }
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::ConnectionClosed => write!(f, "connection closed by client"),
+            Error::BadRequest(reason) => write!(f, "bad request: {reason}"),
+            Error::HeadersTooLarge => write!(f, "request head too large"),
+            Error::UnsupportedVersion(version) => write!(f, "unsupported HTTP version {version:?}"),
+            Error::UnsupportedTransferCoding(coding) => write!(f, "unsupported transfer coding {coding:?}"),
+            Error::Io(e) => write!(f, "I/O error: {e}"),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::Io(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for Error {
+    fn from(e: io::Error) -> Self {
+        match e.kind() {
+            io::ErrorKind::UnexpectedEof
+            | io::ErrorKind::ConnectionReset
+            | io::ErrorKind::ConnectionAborted
+            | io::ErrorKind::BrokenPipe => Error::ConnectionClosed,
+            _ => Error::Io(e),
+        }
+    }
+}
+
+impl From<Error> for io::Error {
+    /// Turn a parse error back into an I/O error, for readers of message
+    /// bodies and upstream responses.
+    fn from(e: Error) -> Self {
+        match e {
+            Error::Io(e) => e,
+            Error::ConnectionClosed => io::ErrorKind::UnexpectedEof.into(),
+            e => io::Error::new(io::ErrorKind::InvalidData, e.to_string()),
+        }
+    }
+}
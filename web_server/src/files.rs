@@ -0,0 +1,208 @@
+use std::fs::{self, File, Metadata};
+use std::io::{self, Read, Seek, SeekFrom};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use log::{error, warn};
+
+use crate::cache::{self, FileCache, Validators};
+use crate::request::Request;
+use crate::response::{Body, Response, Status};
+use crate::config::Config;
+use crate::server::error_response;
+use crate::{compress, mime, range};
+
+/// Map a request path onto a file below `root`.
+///
+/// The path is percent-decoded and split into segments; `.` and empty
+/// segments are dropped, and any `..` segment is rejected so requests cannot
+/// escape the document root. A path naming a directory resolves to its
+/// `index` file.
+///
+/// Return `None` when the path is malformed or tries to leave the root.
+pub fn resolve(root: &Path, request_path: &str, index: &str) -> Option<PathBuf> {
+    let decoded = percent_decode(request_path.strip_prefix('/')?)?;
+
+    let mut path = root.to_path_buf();
+    for segment in decoded.split('/') {
+        match segment {
+            "" | "." => {}
+            ".." => return None,
+            s if s.contains('\\') || s.contains('\0') => return None,
+            s => path.push(s),
+        }
+    }
+
+    if path.is_dir() {
+        path.push(index);
+    }
+    Some(path)
+}
+
+/// Answer a GET or HEAD request for the file at `path`.
+///
+/// The response carries the file's validators and the `Cache-Control` value
+/// configured for the request path, and becomes a 304 when the request's
+/// preconditions show the client's copy is current. A `Range` header, unless
+/// an `If-Range` shows the file has changed, turns the response into a 206
+/// or 416. Files that cannot be opened are answered with a 404, 403 or 500
+/// error page.
+pub fn serve_file(path: &Path, request: &Request, config: &Config, file_cache: Option<&FileCache>) -> Response {
+    let content_type = mime::content_type(path);
+
+    let sibling = if config.precompressed {
+        compress::precompressed_sibling(path, request)
+    } else {
+        None
+    };
+    let (opened, encoding) = match sibling {
+        Some((sibling, encoding)) => match open(&sibling, file_cache) {
+            Ok(opened) => (Ok(opened), Some(encoding)),
+            Err(e) => {
+                warn!("failed to open {}: {e}", sibling.display());
+                (open(path, file_cache), None)
+            }
+        },
+        None => (open(path, file_cache), None),
+    };
+
+    let (content, metadata) = match opened {
+        Ok(opened) => opened,
+        Err(e) => {
+            let status = match e.kind() {
+                io::ErrorKind::NotFound => Status::NotFound,
+                io::ErrorKind::PermissionDenied => Status::Forbidden,
+                _ => Status::InternalServerError,
+            };
+            if status == Status::InternalServerError {
+                error!("failed to open {}: {e}", path.display());
+            }
+            return error_response(status, config);
+        }
+    };
+
+    let mut response = Response::new(Status::Ok)
+        .with_header("Content-Type", content_type)
+        .with_header("Accept-Ranges", "bytes");
+    let validators = Validators::from_metadata(&metadata);
+    validators.apply(&mut response.headers);
+    if let Some(value) = cache::cache_control(&config.cache_control, request.path()) {
+        response.headers.set("Cache-Control", value);
+    }
+    if let Some(encoding) = encoding {
+        response.headers.set("Content-Encoding", encoding.token());
+    }
+    // Set here rather than left to compression so that a 304 carries it too.
+    if compress::is_compressible(content_type) && (config.compression || config.precompressed) {
+        response.headers.append_token("Vary", "Accept-Encoding");
+    }
+
+    if validators.is_not_modified(&request.headers) {
+        return cache::not_modified(response);
+    }
+
+    let total = metadata.len();
+    let ranges = match request.headers.get("Range") {
+        Some(_) if request.headers.get("If-Range").is_some_and(|v| !range::if_range_matches(v, &validators)) => None,
+        Some(header) => match range::parse(header, total) {
+            Ok(ranges) => Some(ranges),
+            Err(range::RangeError::Ignored) => None,
+            Err(range::RangeError::Unsatisfiable) => return range::unsatisfiable(response, total),
+        },
+        None => None,
+    };
+    match ranges {
+        Some(ranges) => range::partial_response(response, content, total, &ranges).unwrap_or_else(|e| {
+            error!("failed to seek in {}: {e}", path.display());
+            error_response(Status::InternalServerError, config)
+        }),
+        None => response.with_body(Body::Stream(Box::new(content), total)),
+    }
+}
+
+/// An opened file, either read from disk or shared from the file cache.
+pub enum Content {
+    File(File),
+    Cached(io::Cursor<Arc<[u8]>>),
+}
+
+impl Read for Content {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self {
+            Content::File(file) => file.read(buf),
+            Content::Cached(cursor) => cursor.read(buf),
+        }
+    }
+}
+
+impl Seek for Content {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        match self {
+            Content::File(file) => file.seek(pos),
+            Content::Cached(cursor) => cursor.seek(pos),
+        }
+    }
+}
+
+/// Open the regular file at `path`, reading it through `cache` if given and
+/// the file is small enough. Directories count as not found.
+pub fn open(path: &Path, cache: Option<&FileCache>) -> io::Result<(Content, Metadata)> {
+    let metadata = fs::metadata(path)?;
+    if metadata.is_dir() {
+        return Err(io::Error::new(io::ErrorKind::NotFound, "is a directory"));
+    }
+
+    if let Some(cache) = cache.filter(|cache| cache.accepts(&metadata)) {
+        let data = cache.load(path, &metadata)?;
+        return Ok((Content::Cached(io::Cursor::new(data)), metadata));
+    }
+
+    let file = File::open(path)?;
+    let metadata = file.metadata()?;
+    Ok((Content::File(file), metadata))
+}
+
+/// Decode `%XX` escapes. Return `None` for malformed escapes or if the result
+/// is not UTF-8.
+pub fn percent_decode(s: &str) -> Option<String> {
+    let bytes = s.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        if bytes[i] == b'%' {
+            let hex = s.get(i + 1..i + 3)?;
+            decoded.push(u8::from_str_radix(hex, 16).ok()?);
+            i += 3;
+        } else {
+            decoded.push(bytes[i]);
+            i += 1;
+        }
+    }
+    String::from_utf8(decoded).ok()
+}
+
+#[test]
+fn test_percent_decode() {
+    assert_eq!(percent_decode("hello%20world").as_deref(), Some("hello world"));
+    assert_eq!(percent_decode("%E2%9C%93").as_deref(), Some("✓"));
+    assert_eq!(percent_decode("bad%2"), None);
+    assert_eq!(percent_decode("bad%zz"), None);
+}
+
+#[test]
+fn test_resolve() {
+    let root = Path::new(".");
+    assert_eq!(resolve(root, "/", "hello.html"), Some(PathBuf::from("./hello.html")));
+    assert_eq!(resolve(root, "/404.html", "hello.html"), Some(PathBuf::from("./404.html")));
+    assert_eq!(resolve(root, "/src/", "main.rs"), Some(PathBuf::from("./src/main.rs")));
+    assert_eq!(resolve(root, "/./src//lib.rs", "x"), Some(PathBuf::from("./src/lib.rs")));
+}
+
+#[test]
+fn test_resolve_rejects_traversal() {
+    let root = Path::new(".");
+    assert_eq!(resolve(root, "/../Cargo.toml", "x"), None);
+    assert_eq!(resolve(root, "/src/%2e%2e/%2e%2e/etc/passwd", "x"), None);
+    assert_eq!(resolve(root, "/a%5C..%5Cb", "x"), None);
+    assert_eq!(resolve(root, "no-leading-slash", "x"), None);
+}
@@ -0,0 +1,367 @@
+use std::io::{self, Read};
+
+use crate::files::percent_decode;
+use crate::headers::Headers;
+
+/// The most bytes of part headers read before a part's body starts.
+const MAX_PART_HEAD: usize = 8 * 1024;
+
+/// The most bytes asked of the underlying reader at a time.
+const READ_SIZE: usize = 16 * 1024;
+
+/// The longest boundary RFC 2046 allows.
+const MAX_BOUNDARY: usize = 70;
+
+/// Decode an `application/x-www-form-urlencoded` body or query string into
+/// its name and value pairs, in order. `+` stands for a space; pairs with
+/// malformed escapes are skipped.
+pub fn parse_urlencoded(input: &str) -> Vec<(String, String)> {
+    input
+        .split('&')
+        .filter(|pair| !pair.is_empty())
+        .filter_map(|pair| {
+            let (name, value) = pair.split_once('=').unwrap_or((pair, ""));
+            let decode = |s: &str| percent_decode(&s.replace('+', " "));
+            Some((decode(name)?, decode(value)?))
+        })
+        .collect()
+}
+
+/// The media type of a `Content-Type` value, lowercased, without its
+/// parameters.
+pub fn media_type(content_type: &str) -> String {
+    content_type.split(';').next().unwrap_or("").trim().to_ascii_lowercase()
+}
+
+/// The value of parameter `name` in a header value such as
+/// `form-data; name="file"`, unquoted.
+pub fn parameter(value: &str, name: &str) -> Option<String> {
+    let mut rest = value.split_once(';')?.1;
+    loop {
+        let (key, after) = rest.split_once('=')?;
+        let after = after.trim_start();
+        let (found, after) = match after.strip_prefix('"') {
+            Some(quoted) => {
+                let mut unquoted = String::new();
+                let mut chars = quoted.char_indices();
+                let end = loop {
+                    match chars.next()? {
+                        (i, '"') => break i + 1,
+                        (_, '\\') => unquoted.push(chars.next()?.1),
+                        (_, c) => unquoted.push(c),
+                    }
+                };
+                (unquoted, &quoted[end..])
+            }
+            None => {
+                let end = after.find(';').unwrap_or(after.len());
+                (after[..end].trim_end().to_string(), &after[end..])
+            }
+        };
+        if key.trim().eq_ignore_ascii_case(name) {
+            return Some(found);
+        }
+        rest = after.split_once(';')?.1;
+    }
+}
+
+/// The boundary of a `multipart/form-data` body with `content_type`,
+/// or `None` when the body is of another type or the boundary is
+/// missing or invalid.
+pub fn boundary(content_type: &str) -> Option<String> {
+    if media_type(content_type) != "multipart/form-data" {
+        return None;
+    }
+    parameter(content_type, "boundary").filter(|b| !b.is_empty() && b.len() <= MAX_BOUNDARY)
+}
+
+/// One part of a `multipart/form-data` body, as described by its headers.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Part {
+    /// The form field the part is for.
+    pub name: String,
+    /// The name of the uploaded file, for file fields.
+    pub filename: Option<String>,
+    pub content_type: Option<String>,
+}
+
+impl Part {
+    fn from_headers(headers: &Headers) -> io::Result<Part> {
+        let disposition = headers.get("Content-Disposition").unwrap_or("");
+        if media_type(disposition) != "form-data" {
+            return Err(malformed("part is not form-data"));
+        }
+        let Some(name) = parameter(disposition, "name") else {
+            return Err(malformed("part has no field name"));
+        };
+        // RFC 7578 says not to send filename*, but browsers once did.
+        let extended = parameter(disposition, "filename*").and_then(|value| {
+            let (charset, encoded) = value.split_once("''")?;
+            charset.eq_ignore_ascii_case("utf-8").then(|| percent_decode(encoded))?
+        });
+        Ok(Part {
+            name,
+            filename: extended.or_else(|| parameter(disposition, "filename")),
+            content_type: headers.get("Content-Type").map(String::from),
+        })
+    }
+}
+
+/// Where a [`Multipart`] reader is in the body.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum State {
+    /// Reading a part's body, or the preamble before the first part, up to
+    /// the next delimiter.
+    Body,
+    /// Just past a delimiter, before the part headers or the closing `--`.
+    Delimiter,
+    /// Past the closing delimiter.
+    Done,
+}
+
+/// Reads a `multipart/form-data` body part by part, without holding more
+/// than a little of it in memory.
+///
+/// [`Multipart::next_part`] moves to the next part, and reading from the
+/// `Multipart` itself yields that part's body.
+pub struct Multipart<R> {
+    reader: R,
+    /// `\r\n--` and the boundary, which ends every part.
+    delimiter: Vec<u8>,
+    buffer: Vec<u8>,
+    /// Where the unread bytes in `buffer` start.
+    start: usize,
+    eof: bool,
+    state: State,
+}
+
+impl<R: Read> Multipart<R> {
+    /// Read the body from `reader`, whose parts are separated by
+    /// `boundary`, as given in the `Content-Type`.
+    pub fn new(reader: R, boundary: &str) -> Multipart<R> {
+        Multipart {
+            reader,
+            delimiter: [b"\r\n--", boundary.as_bytes()].concat(),
+            // The first delimiter may start the body, without a line break
+            // before it.
+            buffer: b"\r\n".to_vec(),
+            start: 0,
+            eof: false,
+            state: State::Body,
+        }
+    }
+
+    /// Skip whatever is left of the current part and read the headers of
+    /// the next one. Return `None` after the last part.
+    pub fn next_part(&mut self) -> io::Result<Option<Part>> {
+        io::copy(self, &mut io::sink())?;
+        if self.state == State::Done {
+            return Ok(None);
+        }
+
+        self.fill_to(2)?;
+        if self.unread().starts_with(b"--") {
+            // Anything after the closing delimiter is an epilogue, to be
+            // ignored; it is read anyway so the body ends where it should.
+            self.state = State::Done;
+            self.start = self.buffer.len();
+            io::copy(&mut self.reader, &mut io::sink())?;
+            return Ok(None);
+        }
+        // The delimiter line may have trailing whitespace.
+        let head = loop {
+            if let Some(end) = find(self.unread(), b"\r\n\r\n") {
+                break self.unread()[..end + 2].to_vec();
+            }
+            if self.unread().len() > MAX_PART_HEAD {
+                return Err(malformed("part headers too long"));
+            }
+            self.fill()?;
+        };
+        self.start += head.len() + 2;
+
+        let text = String::from_utf8(head).map_err(|_| malformed("part headers are not valid UTF-8"))?;
+        let mut lines = text.split("\r\n");
+        if lines.next().is_some_and(|padding| !padding.trim_matches([' ', '\t']).is_empty()) {
+            return Err(malformed("text after boundary"));
+        }
+        let mut headers = Headers::new();
+        for line in lines.filter(|line| !line.is_empty()) {
+            match line.split_once(':') {
+                Some((name, value)) if !name.is_empty() => headers.append(name.trim(), value.trim()),
+                _ => return Err(malformed("malformed part header")),
+            }
+        }
+        self.state = State::Body;
+        Part::from_headers(&headers).map(Some)
+    }
+
+    fn unread(&self) -> &[u8] {
+        &self.buffer[self.start..]
+    }
+
+    /// Read more of the body into the buffer, failing at its end.
+    fn fill(&mut self) -> io::Result<()> {
+        if self.eof {
+            return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "multipart body ends early"));
+        }
+        self.buffer.drain(..self.start);
+        self.start = 0;
+        let len = self.buffer.len();
+        self.buffer.resize(len + READ_SIZE, 0);
+        let read = self.reader.read(&mut self.buffer[len..]);
+        self.buffer.truncate(len + *read.as_ref().unwrap_or(&0));
+        self.eof = read? == 0;
+        Ok(())
+    }
+
+    /// Fill the buffer until at least `len` bytes are unread.
+    fn fill_to(&mut self, len: usize) -> io::Result<()> {
+        while self.unread().len() < len {
+            self.fill()?;
+        }
+        Ok(())
+    }
+}
+
+impl<R: Read> Read for Multipart<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.state != State::Body || buf.is_empty() {
+            return Ok(0);
+        }
+        loop {
+            let unread = self.unread();
+            let (available, found) = match find(unread, &self.delimiter) {
+                Some(at) => (at, true),
+                // The end of the buffer may be the start of a delimiter.
+                None => (unread.len().saturating_sub(self.delimiter.len() - 1), false),
+            };
+            if available > 0 {
+                let len = available.min(buf.len());
+                buf[..len].copy_from_slice(&unread[..len]);
+                self.start += len;
+                return Ok(len);
+            }
+            if found {
+                self.start += self.delimiter.len();
+                self.state = State::Delimiter;
+                return Ok(0);
+            }
+            self.fill()?;
+        }
+    }
+}
+
+fn find(haystack: &[u8], needle: &[u8]) -> Option<usize> {
+    haystack.windows(needle.len()).position(|window| window == needle)
+}
+
+fn malformed(reason: &'static str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, reason)
+}
+
+#[cfg(test)]
+const BODY: &[u8] = b"preamble\r\n\
+    --XyZ\r\n\
+    Content-Disposition: form-data; name=\"title\"\r\n\
+    \r\n\
+    Holiday\r\n\
+    --XyZ \r\n\
+    Content-Disposition: form-data; name=\"photo\"; filename=\"beach \\\"1\\\".jpg\"\r\n\
+    Content-Type: image/jpeg\r\n\
+    \r\n\
+    \xff\xd8--XyZ\r\n--XyY\r\n\r\n-\r\n\
+    --XyZ--\r\n\
+    epilogue";
+
+#[cfg(test)]
+fn read_parts<R: Read>(multipart: &mut Multipart<R>) -> io::Result<Vec<(Part, Vec<u8>)>> {
+    let mut parts = Vec::new();
+    while let Some(part) = multipart.next_part()? {
+        let mut body = Vec::new();
+        multipart.read_to_end(&mut body)?;
+        parts.push((part, body));
+    }
+    Ok(parts)
+}
+
+/// Hands out a few bytes at a time, to split delimiters across reads.
+#[cfg(test)]
+struct Trickle<'a>(&'a [u8]);
+
+#[cfg(test)]
+impl Read for Trickle<'_> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let len = buf.len().min(self.0.len()).min(3);
+        buf[..len].copy_from_slice(&self.0[..len]);
+        self.0 = &self.0[len..];
+        Ok(len)
+    }
+}
+
+#[test]
+fn test_parse_urlencoded() {
+    assert_eq!(parse_urlencoded("a=1&b=two+words&c=%E2%9C%93&flag&&d="), vec![
+        ("a".to_string(), "1".to_string()),
+        ("b".to_string(), "two words".to_string()),
+        ("c".to_string(), "✓".to_string()),
+        ("flag".to_string(), String::new()),
+        ("d".to_string(), String::new()),
+    ]);
+    assert_eq!(parse_urlencoded("bad=%zz&ok=1"), vec![("ok".to_string(), "1".to_string())]);
+}
+
+#[test]
+fn test_parameter() {
+    let disposition = r#"form-data; name="a;b"; filename=plain.txt ; size=3"#;
+    assert_eq!(parameter(disposition, "name").as_deref(), Some("a;b"));
+    assert_eq!(parameter(disposition, "FILENAME").as_deref(), Some("plain.txt"));
+    assert_eq!(parameter(disposition, "size").as_deref(), Some("3"));
+    assert_eq!(parameter(disposition, "missing"), None);
+    assert_eq!(parameter("form-data", "name"), None);
+    assert_eq!(boundary("Multipart/Form-Data; boundary=\"a b\"").as_deref(), Some("a b"));
+    assert_eq!(boundary("multipart/mixed; boundary=x"), None);
+    assert_eq!(boundary("multipart/form-data"), None);
+}
+
+#[test]
+fn test_multipart() {
+    let expected = vec![
+        (Part { name: "title".to_string(), filename: None, content_type: None }, b"Holiday".to_vec()),
+        (
+            Part {
+                name: "photo".to_string(),
+                filename: Some("beach \"1\".jpg".to_string()),
+                content_type: Some("image/jpeg".to_string()),
+            },
+            b"\xff\xd8--XyZ\r\n--XyY\r\n\r\n-".to_vec(),
+        ),
+    ];
+    assert_eq!(read_parts(&mut Multipart::new(BODY, "XyZ")).unwrap(), expected);
+    assert_eq!(read_parts(&mut Multipart::new(Trickle(BODY), "XyZ")).unwrap(), expected);
+
+    // Parts left unread are skipped.
+    let mut multipart = Multipart::new(BODY, "XyZ");
+    let names: Vec<String> = std::iter::from_fn(|| multipart.next_part().unwrap()).map(|part| part.name).collect();
+    assert_eq!(names, vec!["title", "photo"]);
+}
+
+#[test]
+fn test_multipart_without_preamble() {
+    let body = b"--b\r\nContent-Disposition: form-data; name=\"x\"; filename*=UTF-8''%E2%9C%93.txt\r\n\r\n1\r\n--b--";
+    let parts = read_parts(&mut Multipart::new(&body[..], "b")).unwrap();
+    assert_eq!(parts[0].0.filename.as_deref(), Some("✓.txt"));
+    assert_eq!(parts[0].1, b"1");
+}
+
+#[test]
+fn test_malformed_multipart() {
+    let error = |body: &[u8]| read_parts(&mut Multipart::new(body, "b")).unwrap_err().kind();
+    assert_eq!(error(b"--b\r\nContent-Disposition: form-data; name=x\r\n\r\nno end"), io::ErrorKind::UnexpectedEof);
+    assert_eq!(error(b"no delimiter at all"), io::ErrorKind::UnexpectedEof);
+    assert_eq!(error(b"--b\r\nContent-Disposition: attachment\r\n\r\n\r\n--b--"), io::ErrorKind::InvalidData);
+    assert_eq!(error(b"--b\r\nContent-Disposition: form-data\r\n\r\n\r\n--b--"), io::ErrorKind::InvalidData);
+    assert_eq!(error(b"--bx\r\nContent-Disposition: form-data; name=x\r\n\r\n\r\n--b--"), io::ErrorKind::InvalidData);
+    let long_head = [&b"--b\r\nX: "[..], &[b'a'; MAX_PART_HEAD + READ_SIZE], b"\r\n\r\n"].concat();
+    assert_eq!(error(&long_head), io::ErrorKind::InvalidData);
+}
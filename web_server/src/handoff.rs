@@ -0,0 +1,142 @@
+use std::env;
+use std::io::{self, Read, Write};
+use std::net::{SocketAddr, TcpListener, ToSocketAddrs};
+use std::os::fd::{AsRawFd, FromRawFd, RawFd};
+use std::os::unix::net::UnixStream;
+use std::os::unix::process::CommandExt;
+use std::process::Command;
+use std::time::Duration;
+
+use log::{debug, info, warn};
+
+/// Descriptors of the listening sockets a restarting server hands to its
+/// successor, separated by commas.
+const LISTEN_FDS: &str = "WEB_SERVER_LISTEN_FDS";
+
+/// Descriptor the successor writes a byte to once it is listening.
+const READY_FD: &str = "WEB_SERVER_READY_FD";
+
+/// How long a successor gets to load its configuration and report ready.
+const START_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// The listening sockets handed over by the server that started this
+/// process, if it was started by a restart.
+pub(crate) fn inherited() -> Vec<TcpListener> {
+    let Ok(fds) = env::var(LISTEN_FDS) else {
+        return Vec::new();
+    };
+    fds.split(',')
+        .filter_map(|fd| fd.parse::<RawFd>().ok())
+        .filter_map(|fd| {
+            // SAFETY: the previous server made these descriptors inheritable
+            // for this process alone, and nothing else here owns them.
+            let socket = unsafe { TcpListener::from_raw_fd(fd) };
+            match socket.local_addr().and_then(|_| close_on_exec(fd)) {
+                Ok(()) => Some(socket),
+                Err(e) => {
+                    warn!("ignoring inherited socket {fd}: {e}");
+                    None
+                }
+            }
+        })
+        .collect()
+}
+
+/// Remove the socket bound to `address` from `inherited` and return it.
+pub(crate) fn take(inherited: &mut Vec<TcpListener>, address: &str) -> Option<TcpListener> {
+    let wanted: Vec<SocketAddr> = address.to_socket_addrs().ok()?.collect();
+    let i = inherited
+        .iter()
+        .position(|socket| socket.local_addr().is_ok_and(|bound| wanted.contains(&bound)))?;
+    let socket = inherited.swap_remove(i);
+    debug!("using inherited socket for {address}");
+    Some(socket)
+}
+
+/// Tell the server that started this process, if any, that this one is
+/// listening and it may stop accepting connections.
+pub fn notify_ready() {
+    let Some(fd) = env::var(READY_FD).ok().and_then(|fd| fd.parse::<RawFd>().ok()) else {
+        return;
+    };
+    // SAFETY: as for the listening sockets; this is the only place the
+    // descriptor is used.
+    let mut ready = unsafe { UnixStream::from_raw_fd(fd) };
+    if let Err(e) = ready.write_all(b"\n") {
+        warn!("failed to report ready to the previous server: {e}");
+    }
+}
+
+/// Start a new server with this process's command line, handing it
+/// `sockets`, and wait until it reports that it is listening.
+///
+/// The program is started by the name it was run as rather than from this
+/// process's executable, so a restart picks up a newly installed binary.
+pub(crate) fn spawn_successor(sockets: &[TcpListener]) -> io::Result<()> {
+    let mut args = env::args_os();
+    let program = args.next().ok_or_else(|| io::Error::other("no program name to start"))?;
+    let (mut ready, successor_end) = UnixStream::pair()?;
+
+    let fds: Vec<RawFd> = sockets.iter().map(AsRawFd::as_raw_fd).collect();
+    let listen_fds: Vec<String> = fds.iter().map(ToString::to_string).collect();
+    let inherit: Vec<RawFd> = fds.iter().copied().chain([successor_end.as_raw_fd()]).collect();
+    let mut command = Command::new(program);
+    command
+        .args(args)
+        .env(LISTEN_FDS, listen_fds.join(","))
+        .env(READY_FD, successor_end.as_raw_fd().to_string());
+    // SAFETY: the closure only calls fcntl, which is async-signal-safe, and
+    // allocates nothing.
+    unsafe {
+        command.pre_exec(move || {
+            for &fd in &inherit {
+                if libc::fcntl(fd, libc::F_SETFD, 0) == -1 {
+                    return Err(io::Error::last_os_error());
+                }
+            }
+            Ok(())
+        });
+    }
+    let mut successor = command.spawn()?;
+    drop(successor_end);
+
+    ready.set_read_timeout(Some(START_TIMEOUT))?;
+    let result = match ready.read(&mut [0]) {
+        Ok(1) => {
+            info!("new server process {} is listening", successor.id());
+            return Ok(());
+        }
+        Ok(_) => io::Error::other("new server process exited before listening"),
+        Err(e) if matches!(e.kind(), io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut) => {
+            io::Error::new(io::ErrorKind::TimedOut, "new server process took too long to start")
+        }
+        Err(e) => e,
+    };
+    let _ = successor.kill();
+    let _ = successor.wait();
+    Err(result)
+}
+
+/// Keep `fd` from being inherited by programs this process starts, such as
+/// CGI scripts.
+fn close_on_exec(fd: RawFd) -> io::Result<()> {
+    // SAFETY: fcntl only reads and sets the flags of a descriptor we own.
+    match unsafe { libc::fcntl(fd, libc::F_SETFD, libc::FD_CLOEXEC) } {
+        -1 => Err(io::Error::last_os_error()),
+        _ => Ok(()),
+    }
+}
+
+#[test]
+fn test_take() {
+    let first = TcpListener::bind("127.0.0.1:0").unwrap();
+    let second = TcpListener::bind("127.0.0.1:0").unwrap();
+    let address = second.local_addr().unwrap().to_string();
+    let mut inherited = vec![first, second];
+
+    let socket = take(&mut inherited, &address).unwrap();
+    assert_eq!(socket.local_addr().unwrap().to_string(), address);
+    assert_eq!(inherited.len(), 1);
+    assert!(take(&mut inherited, &address).is_none());
+    assert!(take(&mut inherited, "127.0.0.1:0").is_none());
+}
@@ -0,0 +1,124 @@
+use std::fmt;
+
+/// An ordered list of HTTP header fields.
+///
+/// Field names are compared case-insensitively, as required by RFC 9110,
+/// but the original spelling is kept so responses are written the way
+/// they were built.
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct Headers {
+    fields: Vec<(String, String)>,
+}
+
+impl Headers {
+    pub fn new() -> Self {
+        Headers { fields: Vec::new() }
+    }
+
+    /// Return the first value of the field `name`, if present.
+    pub fn get(&self, name: &str) -> Option<&str> {
+        self.fields
+            .iter()
+            .find(|(n, _)| n.eq_ignore_ascii_case(name))
+            .map(|(_, v)| v.as_str())
+    }
+
+    /// Return every value of the field `name`, in order.
+    pub fn get_all<'a>(&'a self, name: &'a str) -> impl Iterator<Item = &'a str> + 'a {
+        self.fields
+            .iter()
+            .filter(move |(n, _)| n.eq_ignore_ascii_case(name))
+            .map(|(_, v)| v.as_str())
+    }
+
+    pub fn contains(&self, name: &str) -> bool {
+        self.get(name).is_some()
+    }
+
+    /// Replace every value of the field `name` with `value`.
+    pub fn set(&mut self, name: &str, value: impl Into<String>) {
+        self.remove(name);
+        self.fields.push((name.to_string(), value.into()));
+    }
+
+    /// Add another value for the field `name`, keeping existing ones.
+    pub fn append(&mut self, name: &str, value: impl Into<String>) {
+        self.fields.push((name.to_string(), value.into()));
+    }
+
+    /// Add `token` to the comma-separated list in the field `name`, such as
+    /// `Vary`, unless it is already listed.
+    pub fn append_token(&mut self, name: &str, token: &str) {
+        let present = self
+            .get_all(name)
+            .flat_map(|v| v.split(','))
+            .any(|t| t.trim().eq_ignore_ascii_case(token));
+        if present {
+            return;
+        }
+        match self.fields.iter_mut().find(|(n, _)| n.eq_ignore_ascii_case(name)) {
+            Some((_, value)) if !value.trim().is_empty() => {
+                value.push_str(", ");
+                value.push_str(token);
+            }
+            Some((_, value)) => *value = token.to_string(),
+            None => self.append(name, token),
+        }
+    }
+
+    pub fn remove(&mut self, name: &str) {
+        self.fields.retain(|(n, _)| !n.eq_ignore_ascii_case(name));
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&str, &str)> {
+        self.fields.iter().map(|(n, v)| (n.as_str(), v.as_str()))
+    }
+
+    pub fn len(&self) -> usize {
+        self.fields.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.fields.is_empty()
+    }
+}
+
+impl fmt::Display for Headers {
+    /// Format the fields the way they appear on the wire, each line ending
+    /// with CRLF.
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (name, value) in &self.fields {
+            write!(f, "{name}: {value}\r\n")?;
+        }
+        Ok(())
+    }
+}
+
+#[test]
+fn test_headers_case_insensitive() {
+    let mut headers = Headers::new();
+    headers.set("Content-Type", "text/html");
+    assert_eq!(headers.get("content-type"), Some("text/html"));
+
+    headers.set("CONTENT-TYPE", "text/plain");
+    assert_eq!(headers.len(), 1);
+    assert_eq!(headers.get("Content-Type"), Some("text/plain"));
+}
+
+#[test]
+fn test_headers_append() {
+    let mut headers = Headers::new();
+    headers.append("Vary", "Accept");
+    headers.append("vary", "Origin");
+    assert_eq!(headers.get_all("Vary").collect::<Vec<_>>(), vec!["Accept", "Origin"]);
+    assert_eq!(headers.to_string(), "Vary: Accept\r\nvary: Origin\r\n");
+}
+
+#[test]
+fn test_headers_append_token() {
+    let mut headers = Headers::new();
+    headers.append_token("Vary", "Accept-Encoding");
+    headers.append_token("Vary", "Origin");
+    headers.append_token("vary", "accept-encoding");
+    assert_eq!(headers.get("Vary"), Some("Accept-Encoding, Origin"));
+}
@@ -0,0 +1,473 @@
+use std::collections::VecDeque;
+use std::fmt;
+use std::sync::OnceLock;
+
+/// The static table of RFC 7541 Appendix A; index 1 is the first entry.
+const STATIC_TABLE: [(&str, &str); 61] = [
+    (":authority", ""),
+    (":method", "GET"),
+    (":method", "POST"),
+    (":path", "/"),
+    (":path", "/index.html"),
+    (":scheme", "http"),
+    (":scheme", "https"),
+    (":status", "200"),
+    (":status", "204"),
+    (":status", "206"),
+    (":status", "304"),
+    (":status", "400"),
+    (":status", "404"),
+    (":status", "500"),
+    ("accept-charset", ""),
+    ("accept-encoding", "gzip, deflate"),
+    ("accept-language", ""),
+    ("accept-ranges", ""),
+    ("accept", ""),
+    ("access-control-allow-origin", ""),
+    ("age", ""),
+    ("allow", ""),
+    ("authorization", ""),
+    ("cache-control", ""),
+    ("content-disposition", ""),
+    ("content-encoding", ""),
+    ("content-language", ""),
+    ("content-length", ""),
+    ("content-location", ""),
+    ("content-range", ""),
+    ("content-type", ""),
+    ("cookie", ""),
+    ("date", ""),
+    ("etag", ""),
+    ("expect", ""),
+    ("expires", ""),
+    ("from", ""),
+    ("host", ""),
+    ("if-match", ""),
+    ("if-modified-since", ""),
+    ("if-none-match", ""),
+    ("if-range", ""),
+    ("if-unmodified-since", ""),
+    ("last-modified", ""),
+    ("link", ""),
+    ("location", ""),
+    ("max-forwards", ""),
+    ("proxy-authenticate", ""),
+    ("proxy-authorization", ""),
+    ("range", ""),
+    ("referer", ""),
+    ("refresh", ""),
+    ("retry-after", ""),
+    ("server", ""),
+    ("set-cookie", ""),
+    ("strict-transport-security", ""),
+    ("transfer-encoding", ""),
+    ("user-agent", ""),
+    ("vary", ""),
+    ("via", ""),
+    ("www-authenticate", ""),
+];
+
+/// Bit lengths of the Huffman codes of RFC 7541 Appendix B, by symbol; the
+/// last is end-of-string. The code is canonical, so the lengths determine
+/// the codes.
+const HUFFMAN_LENGTHS: [u8; 257] = [
+    13, 23, 28, 28, 28, 28, 28, 28, 28, 24, 30, 28, 28, 30, 28, 28,
+    28, 28, 28, 28, 28, 28, 30, 28, 28, 28, 28, 28, 28, 28, 28, 28,
+    6, 10, 10, 12, 13, 6, 8, 11, 10, 10, 8, 11, 8, 6, 6, 6,
+    5, 5, 5, 6, 6, 6, 6, 6, 6, 6, 7, 8, 15, 6, 12, 10,
+    13, 6, 7, 7, 7, 7, 7, 7, 7, 7, 7, 7, 7, 7, 7, 7,
+    7, 7, 7, 7, 7, 7, 7, 7, 8, 7, 8, 13, 19, 13, 14, 6,
+    15, 5, 6, 5, 6, 5, 6, 6, 6, 5, 7, 7, 6, 6, 6, 5,
+    6, 7, 6, 5, 5, 6, 7, 7, 7, 7, 7, 15, 11, 14, 13, 28,
+    20, 22, 20, 20, 22, 22, 22, 23, 22, 23, 23, 23, 23, 23, 24, 23,
+    24, 24, 22, 23, 24, 23, 23, 23, 23, 21, 22, 23, 22, 23, 23, 24,
+    22, 21, 20, 22, 22, 23, 23, 21, 23, 22, 22, 24, 21, 22, 23, 23,
+    21, 21, 22, 21, 23, 22, 23, 23, 20, 22, 22, 22, 23, 22, 22, 23,
+    26, 26, 20, 19, 22, 23, 22, 25, 26, 26, 26, 27, 27, 26, 24, 25,
+    19, 21, 26, 27, 27, 26, 27, 24, 21, 21, 26, 26, 28, 27, 27, 27,
+    20, 24, 20, 21, 22, 21, 21, 23, 22, 22, 25, 25, 24, 24, 26, 23,
+    26, 27, 26, 26, 27, 27, 27, 27, 27, 28, 27, 27, 27, 27, 27, 26,
+    30,
+];
+
+const END_OF_STRING: u16 = 256;
+
+/// Every table entry costs its name and value plus this much.
+const ENTRY_OVERHEAD: usize = 32;
+
+/// A header field as decoded: names and values need not be UTF-8.
+pub type Field = (Vec<u8>, Vec<u8>);
+
+/// Why a header block could not be decoded. The connection it came on can
+/// no longer be decoded either, as the tables are out of step.
+#[derive(Debug, PartialEq, Eq)]
+pub enum DecodeError {
+    /// The block ended in the middle of a representation.
+    Truncated,
+    /// An integer too large to be meaningful.
+    Overflow,
+    /// An index beyond the static and dynamic tables.
+    BadIndex(usize),
+    /// A Huffman-coded string with invalid code or padding.
+    BadHuffman,
+    /// A table size update above the allowed maximum, or after a field.
+    BadSizeUpdate(usize),
+    /// The fields add up to more than the decoder's list size limit.
+    TooLarge,
+}
+
+impl fmt::Display for DecodeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DecodeError::Truncated => write!(f, "header block ends early"),
+            DecodeError::Overflow => write!(f, "integer too large in header block"),
+            DecodeError::BadIndex(index) => write!(f, "no header table entry {index}"),
+            DecodeError::BadHuffman => write!(f, "invalid Huffman-coded string"),
+            DecodeError::BadSizeUpdate(size) => write!(f, "invalid header table size update to {size}"),
+            DecodeError::TooLarge => write!(f, "header list too large"),
+        }
+    }
+}
+
+impl std::error::Error for DecodeError {}
+
+/// Decodes the header blocks of one connection, keeping its dynamic table.
+pub struct Decoder {
+    table: VecDeque<Field>,
+    size: usize,
+    /// The size the peer chose, up to `limit`.
+    max_size: usize,
+    /// The size this end allows, as advertised in its settings.
+    limit: usize,
+    /// The largest header list a block may decode to, counted as table
+    /// entries are. A few bytes of indexed fields can stand for kilobytes.
+    max_list_size: usize,
+}
+
+impl Decoder {
+    /// A decoder whose dynamic table may grow to `limit` bytes.
+    pub fn new(limit: usize) -> Decoder {
+        Decoder {
+            table: VecDeque::new(),
+            size: 0,
+            max_size: limit,
+            limit,
+            max_list_size: usize::MAX,
+        }
+    }
+
+    /// Refuse header blocks that decode to more than `size` bytes.
+    pub fn max_list_size(mut self, size: usize) -> Decoder {
+        self.max_list_size = size;
+        self
+    }
+
+    /// Decode one complete header block into its fields, in order.
+    pub fn decode(&mut self, mut block: &[u8]) -> Result<Vec<Field>, DecodeError> {
+        let mut fields = Vec::new();
+        let mut list_size = 0;
+        while let Some(&first) = block.first() {
+            if first & 0x80 != 0 {
+                let index = integer(&mut block, 7)?;
+                fields.push(self.entry(index)?);
+            } else if first & 0x40 != 0 {
+                let field = self.literal(&mut block, 6)?;
+                self.insert(field.clone());
+                fields.push(field);
+            } else if first & 0x20 != 0 {
+                // Size updates may only start a block.
+                let size = integer(&mut block, 5)?;
+                if size > self.limit || !fields.is_empty() {
+                    return Err(DecodeError::BadSizeUpdate(size));
+                }
+                self.max_size = size;
+                self.evict(0);
+            } else {
+                // Without indexing, or never indexed; neither touches the
+                // table, and this end forwards nothing onward to compress.
+                fields.push(self.literal(&mut block, 4)?);
+            }
+            if let Some((name, value)) = fields.last().filter(|_| first & 0xe0 != 0x20) {
+                list_size += name.len() + value.len() + ENTRY_OVERHEAD;
+                if list_size > self.max_list_size {
+                    return Err(DecodeError::TooLarge);
+                }
+            }
+        }
+        Ok(fields)
+    }
+
+    fn entry(&self, index: usize) -> Result<Field, DecodeError> {
+        match index {
+            1..=61 => {
+                let (name, value) = STATIC_TABLE[index - 1];
+                Ok((name.as_bytes().to_vec(), value.as_bytes().to_vec()))
+            }
+            _ => self
+                .table
+                .get(index.wrapping_sub(62))
+                .cloned()
+                .ok_or(DecodeError::BadIndex(index)),
+        }
+    }
+
+    /// Read a literal field whose name index has a `prefix`-bit prefix.
+    fn literal(&self, block: &mut &[u8], prefix: u8) -> Result<Field, DecodeError> {
+        let name = match integer(block, prefix)? {
+            0 => string(block)?,
+            index => self.entry(index)?.0,
+        };
+        Ok((name, string(block)?))
+    }
+
+    fn insert(&mut self, field: Field) {
+        let size = field.0.len() + field.1.len() + ENTRY_OVERHEAD;
+        self.evict(size);
+        // An entry larger than the table empties it and is not added.
+        if size <= self.max_size {
+            self.size += size;
+            self.table.push_front(field);
+        }
+    }
+
+    /// Drop the oldest entries until `room` more bytes fit.
+    fn evict(&mut self, room: usize) {
+        while self.size + room > self.max_size {
+            let Some((name, value)) = self.table.pop_back() else {
+                break;
+            };
+            self.size -= name.len() + value.len() + ENTRY_OVERHEAD;
+        }
+    }
+}
+
+/// Encode `fields` as a header block, without touching the dynamic table:
+/// fields matching a static entry are indexed, the rest are literals not
+/// to be indexed. Names must be lowercase.
+pub fn encode<'a>(fields: impl IntoIterator<Item = (&'a str, &'a str)>, block: &mut Vec<u8>) {
+    for (name, value) in fields {
+        let exact = STATIC_TABLE.iter().position(|&entry| entry == (name, value));
+        if let Some(i) = exact {
+            encode_integer(i + 1, 7, 0x80, block);
+            continue;
+        }
+        match STATIC_TABLE.iter().position(|&(n, _)| n == name) {
+            Some(i) => encode_integer(i + 1, 4, 0, block),
+            None => {
+                block.push(0);
+                encode_string(name.as_bytes(), block);
+            }
+        }
+        encode_string(value.as_bytes(), block);
+    }
+}
+
+fn encode_integer(value: usize, prefix: u8, flags: u8, block: &mut Vec<u8>) {
+    let max = (1usize << prefix) - 1;
+    if value < max {
+        block.push(flags | value as u8);
+        return;
+    }
+    block.push(flags | max as u8);
+    let mut rest = value - max;
+    while rest >= 0x80 {
+        block.push(0x80 | (rest & 0x7f) as u8);
+        rest >>= 7;
+    }
+    block.push(rest as u8);
+}
+
+fn encode_string(s: &[u8], block: &mut Vec<u8>) {
+    encode_integer(s.len(), 7, 0, block);
+    block.extend_from_slice(s);
+}
+
+/// Read an integer with a `prefix`-bit prefix from the start of `block`.
+fn integer(block: &mut &[u8], prefix: u8) -> Result<usize, DecodeError> {
+    let (&first, rest) = block.split_first().ok_or(DecodeError::Truncated)?;
+    *block = rest;
+    let max = (1usize << prefix) - 1;
+    let mut value = first as usize & max;
+    if value < max {
+        return Ok(value);
+    }
+    let mut shift = 0;
+    loop {
+        let (&byte, rest) = block.split_first().ok_or(DecodeError::Truncated)?;
+        *block = rest;
+        if shift > 28 {
+            return Err(DecodeError::Overflow);
+        }
+        value += ((byte & 0x7f) as usize) << shift;
+        shift += 7;
+        if byte & 0x80 == 0 {
+            return Ok(value);
+        }
+    }
+}
+
+/// Read a string literal, Huffman-coded or not, from the start of `block`.
+fn string(block: &mut &[u8]) -> Result<Vec<u8>, DecodeError> {
+    let huffman = block.first().is_some_and(|&byte| byte & 0x80 != 0);
+    let len = integer(block, 7)?;
+    if len > block.len() {
+        return Err(DecodeError::Truncated);
+    }
+    let (data, rest) = block.split_at(len);
+    *block = rest;
+    if huffman {
+        decode_huffman(data)
+    } else {
+        Ok(data.to_vec())
+    }
+}
+
+/// The canonical Huffman code as decoding tables: for each length, the first
+/// code of that length and where its symbols start in `symbols`, which
+/// holds every symbol ordered by code.
+struct Huffman {
+    first_code: [u32; 31],
+    first_symbol: [usize; 31],
+    count: [u32; 31],
+    symbols: Vec<u16>,
+}
+
+fn huffman() -> &'static Huffman {
+    static HUFFMAN: OnceLock<Huffman> = OnceLock::new();
+    HUFFMAN.get_or_init(|| {
+        let mut symbols: Vec<u16> = (0..=END_OF_STRING).collect();
+        symbols.sort_by_key(|&symbol| HUFFMAN_LENGTHS[symbol as usize]);
+        let mut huffman = Huffman { first_code: [0; 31], first_symbol: [0; 31], count: [0; 31], symbols };
+        for &len in &HUFFMAN_LENGTHS {
+            huffman.count[len as usize] += 1;
+        }
+        let mut code = 0;
+        let mut start = 0;
+        for len in 1..31 {
+            huffman.first_code[len] = code;
+            huffman.first_symbol[len] = start;
+            code = (code + huffman.count[len]) << 1;
+            start += huffman.count[len] as usize;
+        }
+        huffman
+    })
+}
+
+fn decode_huffman(data: &[u8]) -> Result<Vec<u8>, DecodeError> {
+    let huffman = huffman();
+    let mut decoded = Vec::with_capacity(data.len() * 8 / 5);
+    let (mut code, mut len) = (0u32, 0usize);
+    for &byte in data {
+        for bit in (0..8).rev() {
+            code = code << 1 | u32::from(byte >> bit & 1);
+            len += 1;
+            let offset = code.wrapping_sub(huffman.first_code[len]);
+            if offset < huffman.count[len] {
+                match huffman.symbols[huffman.first_symbol[len] + offset as usize] {
+                    END_OF_STRING => return Err(DecodeError::BadHuffman),
+                    symbol => decoded.push(symbol as u8),
+                }
+                (code, len) = (0, 0);
+            } else if len == 30 {
+                return Err(DecodeError::BadHuffman);
+            }
+        }
+    }
+    // Padding is the start of end-of-string, all ones, and under a byte.
+    if len > 7 || code != (1 << len) - 1 {
+        return Err(DecodeError::BadHuffman);
+    }
+    Ok(decoded)
+}
+
+#[cfg(test)]
+fn fields(pairs: &[(&str, &str)]) -> Vec<Field> {
+    pairs.iter().map(|(n, v)| (n.as_bytes().to_vec(), v.as_bytes().to_vec())).collect()
+}
+
+#[cfg(test)]
+fn hex(s: &str) -> Vec<u8> {
+    let digits: Vec<u8> = s.bytes().filter(u8::is_ascii_hexdigit).collect();
+    digits
+        .chunks(2)
+        .map(|pair| u8::from_str_radix(std::str::from_utf8(pair).unwrap(), 16).unwrap())
+        .collect()
+}
+
+#[test]
+fn test_decode_requests() {
+    // RFC 7541 C.3, then C.4: the same requests without and with Huffman.
+    for blocks in [
+        [
+            "8286 8441 0f77 7777 2e65 7861 6d70 6c65 2e63 6f6d",
+            "8286 84be 5808 6e6f 2d63 6163 6865",
+            "8287 85bf 400a 6375 7374 6f6d 2d6b 6579 0c63 7573 746f 6d2d 7661 6c75 65",
+        ],
+        [
+            "8286 8441 8cf1 e3c2 e5f2 3a6b a0ab 90f4 ff",
+            "8286 84be 5886 a8eb 1064 9cbf",
+            "8287 85bf 4088 25a8 49e9 5ba9 7d7f 8925 a849 e95b b8e8 b4bf",
+        ],
+    ] {
+        let mut decoder = Decoder::new(4096);
+        let request = [(":method", "GET"), (":scheme", "http"), (":path", "/"), (":authority", "www.example.com")];
+        assert_eq!(decoder.decode(&hex(blocks[0])).unwrap(), fields(&request));
+        assert_eq!(decoder.decode(&hex(blocks[1])).unwrap(),
+                   fields(&[request[0], request[1], request[2], request[3], ("cache-control", "no-cache")]));
+        assert_eq!(decoder.decode(&hex(blocks[2])).unwrap(),
+                   fields(&[(":method", "GET"), (":scheme", "https"), (":path", "/index.html"),
+                            (":authority", "www.example.com"), ("custom-key", "custom-value")]));
+        assert_eq!(decoder.table.len(), 3);
+        assert_eq!(decoder.size, 164);
+    }
+}
+
+#[test]
+fn test_decode_evicts() {
+    // RFC 7541 C.5.1 and C.5.2: responses with a 256-byte table.
+    let mut decoder = Decoder::new(256);
+    decoder.decode(&hex("4803 3330 3258 0770 7269 7661 7465 611d 4d6f 6e2c 2032 3120 4f63 7420 3230 3133 \
+                         2032 303a 3133 3a32 3120 474d 546e 1768 7474 7073 3a2f 2f77 7777 2e65 7861 6d70 \
+                         6c65 2e63 6f6d")).unwrap();
+    assert_eq!(decoder.size, 222);
+    let response = decoder.decode(&hex("4803 3330 37c1 c0bf")).unwrap();
+    assert_eq!(response, fields(&[(":status", "307"), ("cache-control", "private"),
+                                  ("date", "Mon, 21 Oct 2013 20:13:21 GMT"), ("location", "https://www.example.com")]));
+    assert_eq!(decoder.size, 222);
+    assert_eq!(decoder.table[0], (b":status".to_vec(), b"307".to_vec()));
+}
+
+#[test]
+fn test_decode_errors() {
+    let mut decoder = Decoder::new(4096);
+    assert_eq!(decoder.decode(&[0xbe]), Err(DecodeError::BadIndex(62)));
+    assert_eq!(decoder.decode(&[0x80]), Err(DecodeError::BadIndex(0)));
+    assert_eq!(decoder.decode(&[0x41, 0x05, b'a']), Err(DecodeError::Truncated));
+    assert_eq!(decoder.decode(&[0xff, 0xff, 0xff, 0xff, 0xff, 0xff]), Err(DecodeError::Truncated));
+    assert_eq!(decoder.decode(&[0x3f, 0xe2, 0x1f]), Err(DecodeError::BadSizeUpdate(4097)));
+    assert_eq!(decoder.decode(&[0x82, 0x20]), Err(DecodeError::BadSizeUpdate(0)));
+    // Padding longer than seven bits, and padding that is not all ones.
+    assert_eq!(decoder.decode(&[0x04, 0x82, 0xff, 0xff]), Err(DecodeError::BadHuffman));
+    assert_eq!(decoder.decode(&[0x04, 0x81, 0x00]), Err(DecodeError::BadHuffman));
+
+    // Each :method GET counts 42 bytes; two are over the limit.
+    let mut decoder = Decoder::new(4096).max_list_size(80);
+    assert_eq!(decoder.decode(&[0x82]).unwrap().len(), 1);
+    assert_eq!(decoder.decode(&[0x82, 0x82]), Err(DecodeError::TooLarge));
+}
+
+#[test]
+fn test_encode() {
+    let mut block = Vec::new();
+    encode([(":status", "200"), ("content-type", "text/plain"), ("x-custom", "yes"), ("content-length", "123456")], &mut block);
+    let mut decoder = Decoder::new(4096);
+    assert_eq!(decoder.decode(&block).unwrap(),
+               fields(&[(":status", "200"), ("content-type", "text/plain"), ("x-custom", "yes"), ("content-length", "123456")]));
+    assert_eq!(block[0], 0x88);
+    assert!(decoder.table.is_empty());
+
+    let long = "x".repeat(300);
+    let mut block = Vec::new();
+    encode([("x-long", long.as_str())], &mut block);
+    assert_eq!(decoder.decode(&block).unwrap(), fields(&[("x-long", &long)]));
+}
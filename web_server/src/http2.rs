@@ -0,0 +1,1158 @@
+use std::collections::HashMap;
+use std::io::{self, Read, Write};
+use std::iter;
+use std::net::{Shutdown, SocketAddr, TcpStream};
+use std::sync::atomic::{AtomicI64, Ordering};
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError, Sender};
+use std::sync::{Arc, Condvar, Mutex, MutexGuard};
+use std::thread::{self, Scope};
+use std::time::{Duration, Instant, SystemTime};
+
+use log::{debug, warn};
+use rustls::{ServerConnection, StreamOwned};
+
+use crate::access_log::AccessEntry;
+use crate::connection::{Connection, Stream};
+use crate::headers::Headers;
+use crate::hpack::{self, Decoder, Field};
+use crate::pool::ThreadPermit;
+use crate::request::{Method, Request};
+use crate::response::{Body, Response};
+use crate::server::{self, Context};
+
+/// The connection preface every HTTP/2 client starts with.
+pub const PREFACE: &[u8] = b"PRI * HTTP/2.0\r\n\r\nSM\r\n\r\n";
+
+/// How much of the preface an HTTP/1 parser takes for a request head: the
+/// `PRI * HTTP/2.0` request line and an empty header section.
+pub(crate) const PREFACE_HEAD: usize = 18;
+
+/// Frame types from RFC 9113 section 6.
+const DATA: u8 = 0x0;
+const HEADERS: u8 = 0x1;
+const PRIORITY: u8 = 0x2;
+const RST_STREAM: u8 = 0x3;
+const SETTINGS: u8 = 0x4;
+const PUSH_PROMISE: u8 = 0x5;
+const PING: u8 = 0x6;
+const GOAWAY: u8 = 0x7;
+const WINDOW_UPDATE: u8 = 0x8;
+const CONTINUATION: u8 = 0x9;
+
+/// Frame flags. `ACK` shares its bit with `END_STREAM`, on other frames.
+const END_STREAM: u8 = 0x1;
+const ACK: u8 = 0x1;
+const END_HEADERS: u8 = 0x4;
+const PADDED: u8 = 0x8;
+const PRIORITY_FLAG: u8 = 0x20;
+
+/// Settings from RFC 9113 section 6.5.2 that the server checks or sends.
+const ENABLE_PUSH: u16 = 0x2;
+const MAX_CONCURRENT_STREAMS: u16 = 0x3;
+const INITIAL_WINDOW_SIZE: u16 = 0x4;
+const MAX_FRAME_SIZE: u16 = 0x5;
+const MAX_HEADER_LIST_SIZE: u16 = 0x6;
+
+/// Error codes from RFC 9113 section 7.
+const NO_ERROR: u32 = 0x0;
+const PROTOCOL_ERROR: u32 = 0x1;
+const INTERNAL_ERROR: u32 = 0x2;
+const FLOW_CONTROL_ERROR: u32 = 0x3;
+const STREAM_CLOSED: u32 = 0x5;
+const FRAME_SIZE_ERROR: u32 = 0x6;
+const REFUSED_STREAM: u32 = 0x7;
+const COMPRESSION_ERROR: u32 = 0x9;
+const ENHANCE_YOUR_CALM: u32 = 0xb;
+
+/// The largest frame payload either side sends. This is the protocol's
+/// default, which the server never raises and clients cannot lower.
+const FRAME_SIZE: usize = 16_384;
+
+/// The flow-control window streams and the connection start with.
+const DEFAULT_WINDOW: i64 = 65_535;
+const MAX_WINDOW: i64 = (1 << 31) - 1;
+
+/// Streams a client may have open at once.
+const MAX_STREAMS: usize = 100;
+
+/// Size of the table the client may use to compress header fields.
+const TABLE_SIZE: usize = 4096;
+
+/// The most header fields accepted in one request, as for HTTP/1.
+const MAX_HEADERS: usize = 100;
+
+/// The largest header list accepted, decoded, and header block, still
+/// compressed.
+const MAX_HEADER_LIST: usize = 64 * 1024;
+const MAX_HEADER_BLOCK: usize = 64 * 1024;
+
+/// How often a connection waiting for frames checks whether it has been
+/// idle too long or the server is stopping.
+const TICK: Duration = Duration::from_millis(250);
+
+/// Header fields that only manage an HTTP/1 connection, which HTTP/2
+/// forbids (RFC 9113 section 8.2.2).
+const CONNECTION_SPECIFIC: [&str; 5] = ["connection", "keep-alive", "proxy-connection", "transfer-encoding", "upgrade"];
+
+/// One frame, as read from the client.
+#[derive(Debug)]
+struct Frame {
+    kind: u8,
+    flags: u8,
+    stream: u32,
+    payload: Vec<u8>,
+}
+
+/// Why a connection could not go on.
+#[derive(Debug)]
+enum ConnectionError {
+    /// No frame arrived within a tick.
+    Idle,
+    Io(io::Error),
+    /// The client broke the protocol and is sent a GOAWAY with the given
+    /// error code.
+    Protocol(u32, &'static str),
+}
+
+impl From<io::Error> for ConnectionError {
+    fn from(e: io::Error) -> Self {
+        ConnectionError::Io(e)
+    }
+}
+
+/// Serve HTTP/2 on `connection`, whose client has sent the connection
+/// preface, until either side ends it.
+///
+/// The calling thread reads frames. Each request is answered on a thread
+/// of its own, so a slow response holds up neither the connection nor the
+/// other streams on it. Those threads count against
+/// `config.stream_threads` along with every other connection's, and a
+/// stream that finds none to spare is refused.
+pub(crate) fn serve(connection: Connection, context: &Context) {
+    let parts = connection.into_parts();
+    let peer_name = parts.peer.map_or_else(|| "unknown peer".to_string(), |addr| addr.to_string());
+    let secure = matches!(parts.stream, Stream::Tls(_));
+    let (input, output, socket) = match split(parts.stream, parts.buffered) {
+        Ok(halves) => halves,
+        Err(e) => {
+            warn!("{peer_name}: failed to set up HTTP/2 connection: {e}");
+            return;
+        }
+    };
+
+    let shared = Shared {
+        context,
+        output: Mutex::new(output),
+        socket,
+        flow: Mutex::new(Flow {
+            window: DEFAULT_WINDOW,
+            streams: HashMap::new(),
+            initial_window: DEFAULT_WINDOW,
+            closed: false,
+        }),
+        window_opened: Condvar::new(),
+    };
+    let mut session = Session {
+        shared: &shared,
+        input,
+        decoder: Decoder::new(TABLE_SIZE).max_list_size(MAX_HEADER_LIST),
+        peer: parts.peer,
+        secure,
+        receiving: HashMap::new(),
+        last_stream: 0,
+        header_block: None,
+        settled: false,
+        going_away: false,
+    };
+    let result = thread::scope(|scope| {
+        let result = session.run(scope);
+        if let Err(ConnectionError::Protocol(code, _)) = result {
+            let _ = session.go_away(code);
+        }
+        // Streams still being answered fail at their next read or write,
+        // rather than keeping the connection open.
+        session.receiving.clear();
+        shared.close();
+        result
+    });
+    match result {
+        Ok(()) => debug!("{peer_name}: HTTP/2 connection closed"),
+        Err(ConnectionError::Protocol(_, reason)) => warn!("{peer_name}: HTTP/2 protocol error: {reason}"),
+        Err(ConnectionError::Io(e)) => debug!("{peer_name}: HTTP/2 connection failed: {e}"),
+        Err(ConnectionError::Idle) => {}
+    }
+}
+
+/// Split `stream` into the halves the reading and writing threads use,
+/// the reading half starting with the plaintext in `buffered`. The socket
+/// returned alongside is for shutting the connection down.
+fn split(stream: Stream, buffered: Vec<u8>) -> io::Result<(Input, Output, TcpStream)> {
+    let (socket, tls) = match stream {
+        Stream::Plain(socket) => (socket, None),
+        Stream::Tls(stream) => {
+            let StreamOwned { conn, sock } = *stream;
+            (sock, Some(Arc::new(Mutex::new(conn))))
+        }
+    };
+    socket.set_read_timeout(Some(TICK))?;
+    let input = Input {
+        socket: socket.try_clone()?,
+        tls: tls.clone(),
+        buffer: buffered,
+    };
+    let output = Output { socket: socket.try_clone()?, tls };
+    Ok((input, output, socket))
+}
+
+fn lock<T>(mutex: &Mutex<T>) -> MutexGuard<'_, T> {
+    mutex.lock().unwrap_or_else(|e| e.into_inner())
+}
+
+/// Append a frame to `out`.
+fn put_frame(out: &mut Vec<u8>, kind: u8, flags: u8, stream: u32, payload: &[u8]) {
+    out.extend_from_slice(&(payload.len() as u32).to_be_bytes()[1..]);
+    out.push(kind);
+    out.push(flags);
+    out.extend_from_slice(&stream.to_be_bytes());
+    out.extend_from_slice(payload);
+}
+
+/// The receiving half of a connection.
+struct Input {
+    socket: TcpStream,
+    /// The TLS session, shared with the sending half.
+    tls: Option<Arc<Mutex<ServerConnection>>>,
+    /// Plaintext received but not yet taken as frames.
+    buffer: Vec<u8>,
+}
+
+impl Input {
+    /// Read the next frame, or `None` if the client closed the connection.
+    ///
+    /// A frame that has not arrived in full stays buffered, so a read that
+    /// times out midway loses nothing.
+    fn read_frame(&mut self) -> Result<Option<Frame>, ConnectionError> {
+        loop {
+            if let [a, b, c, kind, flags, s0, s1, s2, s3, ..] = self.buffer[..] {
+                let length = u32::from_be_bytes([0, a, b, c]) as usize;
+                if length > FRAME_SIZE {
+                    return Err(ConnectionError::Protocol(FRAME_SIZE_ERROR, "frame larger than allowed"));
+                }
+                if self.buffer.len() >= 9 + length {
+                    let payload = self.buffer[9..9 + length].to_vec();
+                    self.buffer.drain(..9 + length);
+                    // The reserved bit is ignored.
+                    let stream = u32::from_be_bytes([s0, s1, s2, s3]) & 0x7fff_ffff;
+                    return Ok(Some(Frame { kind, flags, stream, payload }));
+                }
+            }
+            match self.fill() {
+                Ok(0) if self.buffer.is_empty() => return Ok(None),
+                Ok(0) => {
+                    return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "connection closed in the middle of a frame").into())
+                }
+                Ok(_) => {}
+                Err(e) if matches!(e.kind(), io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut) => {
+                    return Err(ConnectionError::Idle)
+                }
+                Err(e) => return Err(e.into()),
+            }
+        }
+    }
+
+    /// Receive more plaintext into the buffer. Return how much, which is 0
+    /// once the client has closed the connection.
+    fn fill(&mut self) -> io::Result<usize> {
+        let before = self.buffer.len();
+        let mut received = [0; 16 * 1024];
+        loop {
+            if let Some(tls) = &self.tls {
+                let ended = take_plaintext(&mut lock(tls), &mut self.buffer)?;
+                if ended || self.buffer.len() > before {
+                    return Ok(self.buffer.len() - before);
+                }
+            }
+            // The session is not locked while waiting, so responses can be
+            // sent meanwhile.
+            let read = self.socket.read(&mut received)?;
+            let Some(tls) = &self.tls else {
+                self.buffer.extend_from_slice(&received[..read]);
+                return Ok(read);
+            };
+            if read == 0 {
+                return Ok(0);
+            }
+            let mut session = lock(tls);
+            let mut records = &received[..read];
+            while !records.is_empty() {
+                session.read_tls(&mut records)?;
+                session
+                    .process_new_packets()
+                    .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+                take_plaintext(&mut session, &mut self.buffer)?;
+            }
+            // Key updates and alerts the records called for.
+            while session.wants_write() {
+                session.write_tls(&mut self.socket)?;
+            }
+        }
+    }
+}
+
+/// Move the plaintext `session` has decrypted to `buffer`. Return whether
+/// the client has ended the session.
+fn take_plaintext(session: &mut ServerConnection, buffer: &mut Vec<u8>) -> io::Result<bool> {
+    let mut chunk = [0; 4096];
+    loop {
+        match session.reader().read(&mut chunk) {
+            Ok(0) => return Ok(true),
+            Ok(read) => buffer.extend_from_slice(&chunk[..read]),
+            Err(e) if e.kind() == io::ErrorKind::WouldBlock => return Ok(false),
+            Err(e) => return Err(e),
+        }
+    }
+}
+
+/// The sending half of a connection.
+struct Output {
+    socket: TcpStream,
+    tls: Option<Arc<Mutex<ServerConnection>>>,
+}
+
+impl Output {
+    fn write_all(&mut self, mut data: &[u8]) -> io::Result<()> {
+        let Some(tls) = &self.tls else {
+            return self.socket.write_all(data);
+        };
+        let mut session = lock(tls);
+        loop {
+            let written = session.writer().write(data)?;
+            data = &data[written..];
+            while session.wants_write() {
+                session.write_tls(&mut self.socket)?;
+            }
+            if data.is_empty() {
+                return Ok(());
+            }
+        }
+    }
+
+    /// Tell a TLS client the session is over.
+    fn close_notify(&mut self) {
+        if let Some(tls) = &self.tls {
+            let mut session = lock(tls);
+            session.send_close_notify();
+            while session.wants_write() {
+                if session.write_tls(&mut self.socket).is_err() {
+                    break;
+                }
+            }
+        }
+    }
+}
+
+/// What the thread reading a connection shares with the threads answering
+/// its streams.
+struct Shared<'a> {
+    context: &'a Context,
+    output: Mutex<Output>,
+    socket: TcpStream,
+    flow: Mutex<Flow>,
+    /// Signalled when the client lets more data through, resets a stream,
+    /// or the connection goes down.
+    window_opened: Condvar,
+}
+
+/// How much the server may still send.
+struct Flow {
+    /// On the connection as a whole.
+    window: i64,
+    /// On each stream being answered.
+    streams: HashMap<u32, Sending>,
+    /// The window new streams start with, from the client's settings.
+    initial_window: i64,
+    closed: bool,
+}
+
+/// A stream being answered.
+struct Sending {
+    window: i64,
+    /// Set once either side has reset the stream.
+    reset: bool,
+}
+
+impl Shared<'_> {
+    fn flow(&self) -> MutexGuard<'_, Flow> {
+        lock(&self.flow)
+    }
+
+    /// Streams being answered.
+    fn active(&self) -> usize {
+        self.flow().streams.len()
+    }
+
+    fn send(&self, kind: u8, flags: u8, stream: u32, payload: &[u8]) -> io::Result<()> {
+        let mut frame = Vec::with_capacity(9 + payload.len());
+        put_frame(&mut frame, kind, flags, stream, payload);
+        self.write(&frame)
+    }
+
+    fn write(&self, frames: &[u8]) -> io::Result<()> {
+        lock(&self.output).write_all(frames)?;
+        if let Some(metrics) = &self.context.metrics {
+            metrics.transferred(0, frames.len() as u64);
+        }
+        Ok(())
+    }
+
+    /// Send a header block on `stream`, split into a HEADERS frame and as
+    /// many CONTINUATION frames as it takes. They go out in one write, as
+    /// no other frame may come between them.
+    fn send_headers(&self, stream: u32, block: &[u8], end_stream: bool) -> io::Result<()> {
+        if self.flow().streams.get(&stream).is_some_and(|sending| sending.reset) {
+            return Err(io::Error::new(io::ErrorKind::ConnectionReset, "stream reset"));
+        }
+        let mut frames = Vec::with_capacity(block.len() + 9);
+        let mut chunks = block.chunks(FRAME_SIZE).peekable();
+        let (mut kind, mut flags) = (HEADERS, if end_stream { END_STREAM } else { 0 });
+        while let Some(chunk) = chunks.next() {
+            if chunks.peek().is_none() {
+                flags |= END_HEADERS;
+            }
+            put_frame(&mut frames, kind, flags, stream, chunk);
+            (kind, flags) = (CONTINUATION, 0);
+        }
+        self.write(&frames)
+    }
+
+    /// Send `data` on `stream` in DATA frames as the flow-control windows
+    /// allow, the last one ending the stream if `end_stream` is set.
+    fn send_data(&self, stream: u32, mut data: &[u8], end_stream: bool) -> io::Result<()> {
+        loop {
+            let granted = match data.len() {
+                0 => 0,
+                len => self.reserve(stream, len.min(FRAME_SIZE))?,
+            };
+            let (chunk, rest) = data.split_at(granted);
+            let last = rest.is_empty();
+            if last && chunk.is_empty() && !end_stream {
+                return Ok(());
+            }
+            self.send(DATA, if last && end_stream { END_STREAM } else { 0 }, stream, chunk)?;
+            if last {
+                return Ok(());
+            }
+            data = rest;
+        }
+    }
+
+    /// Wait until some of `wanted` bytes may be sent on `stream`, up to
+    /// `config.write_timeout`, and take them from the windows. Return how
+    /// many were granted.
+    fn reserve(&self, stream: u32, wanted: usize) -> io::Result<usize> {
+        let deadline = Instant::now() + self.context.config.write_timeout;
+        let mut flow = self.flow();
+        loop {
+            let Flow { window, streams, closed, .. } = &mut *flow;
+            if *closed {
+                return Err(io::Error::new(io::ErrorKind::ConnectionAborted, "connection closed"));
+            }
+            let sending = match streams.get_mut(&stream) {
+                Some(sending) if !sending.reset => sending,
+                _ => return Err(io::Error::new(io::ErrorKind::ConnectionReset, "stream reset")),
+            };
+            let available = sending.window.min(*window);
+            if available > 0 {
+                let granted = available.min(wanted as i64);
+                sending.window -= granted;
+                *window -= granted;
+                return Ok(granted as usize);
+            }
+            let remaining = deadline.saturating_duration_since(Instant::now());
+            if remaining.is_zero() {
+                return Err(io::Error::new(io::ErrorKind::TimedOut, "client did not accept more data in time"));
+            }
+            flow = self
+                .window_opened
+                .wait_timeout(flow, remaining)
+                .unwrap_or_else(|e| e.into_inner())
+                .0;
+        }
+    }
+
+    /// Reset `stream` with the error `code`, unless it already was.
+    fn reset(&self, stream: u32, code: u32) {
+        if let Some(sending) = self.flow().streams.get_mut(&stream) {
+            if sending.reset {
+                return;
+            }
+            sending.reset = true;
+        }
+        self.window_opened.notify_all();
+        if let Err(e) = self.send(RST_STREAM, 0, stream, &code.to_be_bytes()) {
+            debug!("failed to reset stream {stream}: {e}");
+        }
+    }
+
+    /// Forget `stream` once its response is sent.
+    fn finish(&self, stream: u32) {
+        self.flow().streams.remove(&stream);
+    }
+
+    /// Fail every stream still being answered and hang up.
+    fn close(&self) {
+        self.flow().closed = true;
+        self.window_opened.notify_all();
+        // A stream blocked sending holds the output; it fails once the
+        // socket is shut down.
+        if let Ok(mut output) = self.output.try_lock() {
+            output.close_notify();
+        }
+        let _ = self.socket.shutdown(Shutdown::Both);
+    }
+}
+
+/// A stream whose request body is still arriving.
+struct Receiving {
+    /// Where its DATA goes, with `None` once the body is complete.
+    body: Sender<Option<Vec<u8>>>,
+    /// How much the client may still send on the stream.
+    window: Arc<AtomicI64>,
+}
+
+/// The header block of a HEADERS frame and the CONTINUATION frames after it.
+struct HeaderBlock {
+    stream: u32,
+    /// Whether the HEADERS frame opened the stream, rather than carrying
+    /// trailers.
+    opens: bool,
+    end_stream: bool,
+    block: Vec<u8>,
+}
+
+/// The state of a connection, kept by the thread reading it.
+struct Session<'a> {
+    shared: &'a Shared<'a>,
+    input: Input,
+    decoder: Decoder,
+    peer: Option<SocketAddr>,
+    secure: bool,
+    receiving: HashMap<u32, Receiving>,
+    /// The highest stream the client has opened.
+    last_stream: u32,
+    header_block: Option<HeaderBlock>,
+    /// Whether the client has sent its settings, which must come first.
+    settled: bool,
+    /// Set once either side has sent GOAWAY; the connection ends when its
+    /// last stream is answered.
+    going_away: bool,
+}
+
+impl<'a> Session<'a> {
+    fn run<'s>(&mut self, scope: &'s Scope<'s, 'a>) -> Result<(), ConnectionError> {
+        let context = self.shared.context;
+        let mut settings = Vec::new();
+        for (id, value) in [
+            (MAX_CONCURRENT_STREAMS, MAX_STREAMS),
+            (MAX_HEADER_LIST_SIZE, MAX_HEADER_LIST),
+        ] {
+            settings.extend_from_slice(&id.to_be_bytes());
+            settings.extend_from_slice(&(value as u32).to_be_bytes());
+        }
+        self.shared.send(SETTINGS, 0, 0, &settings)?;
+
+        let mut idle_since = Instant::now();
+        loop {
+            if context.stopping.load(Ordering::Relaxed) && !self.going_away {
+                self.go_away(NO_ERROR)?;
+            }
+            if self.shared.active() > 0 {
+                idle_since = Instant::now();
+            } else if self.going_away {
+                return Ok(());
+            }
+            match self.input.read_frame() {
+                Ok(Some(frame)) => {
+                    if let Some(metrics) = &context.metrics {
+                        metrics.transferred(9 + frame.payload.len() as u64, 0);
+                    }
+                    idle_since = Instant::now();
+                    self.handle(frame, scope)?;
+                }
+                Ok(None) => return Ok(()),
+                Err(ConnectionError::Idle) if idle_since.elapsed() >= context.config.keep_alive_timeout => {
+                    self.go_away(NO_ERROR)?;
+                    return Ok(());
+                }
+                Err(ConnectionError::Idle) => {}
+                Err(e) => return Err(e),
+            }
+        }
+    }
+
+    /// Tell the client no more streams will be answered after the last one
+    /// it opened, with the error `code`.
+    fn go_away(&mut self, code: u32) -> io::Result<()> {
+        self.going_away = true;
+        let mut payload = self.last_stream.to_be_bytes().to_vec();
+        payload.extend_from_slice(&code.to_be_bytes());
+        self.shared.send(GOAWAY, 0, 0, &payload)
+    }
+
+    fn handle<'s>(&mut self, frame: Frame, scope: &'s Scope<'s, 'a>) -> Result<(), ConnectionError> {
+        use ConnectionError::Protocol;
+
+        if !self.settled && frame.kind != SETTINGS {
+            return Err(Protocol(PROTOCOL_ERROR, "connection did not start with SETTINGS"));
+        }
+        if let Some(block) = &self.header_block {
+            if frame.kind != CONTINUATION || frame.stream != block.stream {
+                return Err(Protocol(PROTOCOL_ERROR, "header block interrupted"));
+            }
+        }
+        let on_connection = frame.stream == 0;
+        match frame.kind {
+            DATA | HEADERS | PRIORITY | RST_STREAM | CONTINUATION if on_connection => {
+                Err(Protocol(PROTOCOL_ERROR, "stream frame on stream 0"))
+            }
+            SETTINGS | PING | GOAWAY if !on_connection => Err(Protocol(PROTOCOL_ERROR, "connection frame on a stream")),
+            DATA => self.data(frame),
+            HEADERS => self.headers(frame, scope),
+            CONTINUATION => self.continuation(frame, scope),
+            PRIORITY if frame.payload.len() != 5 => self.reset(frame.stream, FRAME_SIZE_ERROR),
+            RST_STREAM => {
+                if frame.payload.len() != 4 {
+                    return Err(Protocol(FRAME_SIZE_ERROR, "RST_STREAM of the wrong size"));
+                }
+                if frame.stream > self.last_stream {
+                    return Err(Protocol(PROTOCOL_ERROR, "RST_STREAM on an idle stream"));
+                }
+                // Dropping the body's sender fails the handler's next read.
+                self.receiving.remove(&frame.stream);
+                if let Some(sending) = self.shared.flow().streams.get_mut(&frame.stream) {
+                    sending.reset = true;
+                }
+                self.shared.window_opened.notify_all();
+                Ok(())
+            }
+            SETTINGS => self.settings(frame),
+            PUSH_PROMISE => Err(Protocol(PROTOCOL_ERROR, "PUSH_PROMISE from a client")),
+            PING => {
+                if frame.payload.len() != 8 {
+                    return Err(Protocol(FRAME_SIZE_ERROR, "PING of the wrong size"));
+                }
+                if frame.flags & ACK == 0 {
+                    self.shared.send(PING, ACK, 0, &frame.payload)?;
+                }
+                Ok(())
+            }
+            GOAWAY => {
+                self.going_away = true;
+                Ok(())
+            }
+            WINDOW_UPDATE => self.window_update(frame),
+            // Including PRIORITY, which is deprecated; unknown types must
+            // be ignored.
+            _ => Ok(()),
+        }
+    }
+
+    fn data(&mut self, frame: Frame) -> Result<(), ConnectionError> {
+        let length = frame.payload.len();
+        // The connection's window is given back at once: how much of a
+        // request body may wait to be read is up to its stream's window.
+        if length > 0 {
+            self.shared.send(WINDOW_UPDATE, 0, 0, &(length as u32).to_be_bytes())?;
+        }
+        let data = unpad(&frame)?;
+        let Some(receiving) = self.receiving.get(&frame.stream) else {
+            if frame.stream > self.last_stream {
+                return Err(ConnectionError::Protocol(PROTOCOL_ERROR, "DATA on an idle stream"));
+            }
+            return self.reset(frame.stream, STREAM_CLOSED);
+        };
+        if receiving.window.fetch_sub(length as i64, Ordering::Relaxed) < length as i64 {
+            return self.reset(frame.stream, FLOW_CONTROL_ERROR);
+        }
+        // Padding never reaches the handler to be read, so its share of
+        // the window is given back too.
+        let padding = length - data.len();
+        if padding > 0 {
+            receiving.window.fetch_add(padding as i64, Ordering::Relaxed);
+            self.shared
+                .send(WINDOW_UPDATE, 0, frame.stream, &(padding as u32).to_be_bytes())?;
+        }
+        // A handler that has returned no longer reads its body.
+        let mut delivered = data.is_empty() || receiving.body.send(Some(data.to_vec())).is_ok();
+        if frame.flags & END_STREAM != 0 {
+            delivered &= receiving.body.send(None).is_ok();
+        }
+        if !delivered || frame.flags & END_STREAM != 0 {
+            self.receiving.remove(&frame.stream);
+        }
+        Ok(())
+    }
+
+    fn headers<'s>(&mut self, frame: Frame, scope: &'s Scope<'s, 'a>) -> Result<(), ConnectionError> {
+        let mut fragment = unpad(&frame)?;
+        if frame.flags & PRIORITY_FLAG != 0 {
+            fragment = fragment
+                .get(5..)
+                .ok_or(ConnectionError::Protocol(FRAME_SIZE_ERROR, "HEADERS too short for its priority"))?;
+        }
+        let opens = frame.stream > self.last_stream;
+        if opens {
+            if frame.stream.is_multiple_of(2) {
+                return Err(ConnectionError::Protocol(PROTOCOL_ERROR, "client opened an even-numbered stream"));
+            }
+            self.last_stream = frame.stream;
+        }
+        self.header_block = Some(HeaderBlock {
+            stream: frame.stream,
+            opens,
+            end_stream: frame.flags & END_STREAM != 0,
+            block: fragment.to_vec(),
+        });
+        if frame.flags & END_HEADERS != 0 {
+            self.end_headers(scope)?;
+        }
+        Ok(())
+    }
+
+    fn continuation<'s>(&mut self, frame: Frame, scope: &'s Scope<'s, 'a>) -> Result<(), ConnectionError> {
+        let Some(header_block) = &mut self.header_block else {
+            return Err(ConnectionError::Protocol(PROTOCOL_ERROR, "CONTINUATION without HEADERS"));
+        };
+        header_block.block.extend_from_slice(&frame.payload);
+        if header_block.block.len() > MAX_HEADER_BLOCK {
+            return Err(ConnectionError::Protocol(ENHANCE_YOUR_CALM, "header block too large"));
+        }
+        if frame.flags & END_HEADERS != 0 {
+            self.end_headers(scope)?;
+        }
+        Ok(())
+    }
+
+    /// Act on a complete header block: open a stream, or end one's body
+    /// with trailers.
+    fn end_headers<'s>(&mut self, scope: &'s Scope<'s, 'a>) -> Result<(), ConnectionError> {
+        let Some(HeaderBlock { stream, opens, end_stream, block }) = self.header_block.take() else {
+            return Ok(());
+        };
+        // Every block must be decoded, to keep the table in step with the
+        // client's, even when the stream is then refused.
+        let fields = self.decoder.decode(&block).map_err(|e| {
+            debug!("stream {stream}: {e}");
+            ConnectionError::Protocol(COMPRESSION_ERROR, "header block could not be decoded")
+        })?;
+
+        if !opens {
+            // Trailers, which handlers have no use for, end the body.
+            return match self.receiving.remove(&stream) {
+                Some(receiving) if end_stream => {
+                    let _ = receiving.body.send(None);
+                    Ok(())
+                }
+                Some(_) => self.reset(stream, PROTOCOL_ERROR),
+                None => self.reset(stream, STREAM_CLOSED),
+            };
+        }
+        if self.going_away || self.shared.active() >= MAX_STREAMS {
+            return self.reset(stream, REFUSED_STREAM);
+        }
+        let request = match request(fields, self.peer, self.secure) {
+            Ok(request) => request,
+            Err(reason) => {
+                debug!("stream {stream}: malformed request: {reason}");
+                return self.reset(stream, PROTOCOL_ERROR);
+            }
+        };
+        let Some(permit) = self.shared.context.stream_threads.acquire() else {
+            return self.reset(stream, REFUSED_STREAM);
+        };
+        self.open(stream, request, end_stream, permit, scope);
+        Ok(())
+    }
+
+    /// Start answering `request` on `stream` on a thread of its own, counted
+    /// until it ends by `permit`.
+    fn open<'s>(&mut self, stream: u32, request: Request, end_stream: bool, permit: ThreadPermit,
+                scope: &'s Scope<'s, 'a>) {
+        let (sender, chunks) = mpsc::channel();
+        let window = Arc::new(AtomicI64::new(DEFAULT_WINDOW));
+        if !end_stream {
+            let receiving = Receiving { body: sender, window: Arc::clone(&window) };
+            self.receiving.insert(stream, receiving);
+        }
+        {
+            let mut flow = self.shared.flow();
+            let window = flow.initial_window;
+            flow.streams.insert(stream, Sending { window, reset: false });
+        }
+
+        let body = RequestBody {
+            shared: self.shared,
+            stream,
+            chunks,
+            chunk: Vec::new(),
+            position: 0,
+            window,
+            finished: end_stream,
+        };
+        let shared = self.shared;
+        scope.spawn(move || {
+            let _permit = permit;
+            serve_stream(shared, stream, request, body);
+        });
+    }
+
+    fn settings(&mut self, frame: Frame) -> Result<(), ConnectionError> {
+        use ConnectionError::Protocol;
+
+        if frame.flags & ACK != 0 {
+            return match frame.payload.len() {
+                0 => Ok(()),
+                _ => Err(Protocol(FRAME_SIZE_ERROR, "SETTINGS acknowledgement with a payload")),
+            };
+        }
+        if !frame.payload.len().is_multiple_of(6) {
+            return Err(Protocol(FRAME_SIZE_ERROR, "SETTINGS of the wrong size"));
+        }
+        for setting in frame.payload.chunks(6) {
+            let id = u16::from_be_bytes([setting[0], setting[1]]);
+            let value = u32::from_be_bytes([setting[2], setting[3], setting[4], setting[5]]);
+            match id {
+                ENABLE_PUSH if value > 1 => return Err(Protocol(PROTOCOL_ERROR, "invalid SETTINGS_ENABLE_PUSH")),
+                INITIAL_WINDOW_SIZE => {
+                    if i64::from(value) > MAX_WINDOW {
+                        return Err(Protocol(FLOW_CONTROL_ERROR, "SETTINGS_INITIAL_WINDOW_SIZE too large"));
+                    }
+                    let mut flow = self.shared.flow();
+                    let change = i64::from(value) - flow.initial_window;
+                    flow.initial_window = i64::from(value);
+                    for sending in flow.streams.values_mut() {
+                        sending.window += change;
+                    }
+                    drop(flow);
+                    self.shared.window_opened.notify_all();
+                }
+                MAX_FRAME_SIZE if !(16_384..=16_777_215).contains(&value) => {
+                    return Err(Protocol(PROTOCOL_ERROR, "invalid SETTINGS_MAX_FRAME_SIZE"));
+                }
+                // The rest only limit what the server would do anyway: its
+                // encoder keeps no table, it never pushes, it sends frames
+                // of the default size, and it opens no streams.
+                _ => {}
+            }
+        }
+        self.settled = true;
+        self.shared.send(SETTINGS, ACK, 0, &[])?;
+        Ok(())
+    }
+
+    fn window_update(&mut self, frame: Frame) -> Result<(), ConnectionError> {
+        use ConnectionError::Protocol;
+
+        let Ok(bytes) = <[u8; 4]>::try_from(&frame.payload[..]) else {
+            return Err(Protocol(FRAME_SIZE_ERROR, "WINDOW_UPDATE of the wrong size"));
+        };
+        let increment = i64::from(u32::from_be_bytes(bytes) & 0x7fff_ffff);
+        let mut flow = self.shared.flow();
+        if frame.stream == 0 {
+            if increment == 0 {
+                return Err(Protocol(PROTOCOL_ERROR, "WINDOW_UPDATE of 0"));
+            }
+            flow.window += increment;
+            if flow.window > MAX_WINDOW {
+                return Err(Protocol(FLOW_CONTROL_ERROR, "connection window too large"));
+            }
+        } else if let Some(sending) = flow.streams.get_mut(&frame.stream) {
+            sending.window += increment;
+            if increment == 0 || sending.window > MAX_WINDOW {
+                drop(flow);
+                let code = if increment == 0 { PROTOCOL_ERROR } else { FLOW_CONTROL_ERROR };
+                return self.reset(frame.stream, code);
+            }
+        } else if frame.stream > self.last_stream {
+            return Err(Protocol(PROTOCOL_ERROR, "WINDOW_UPDATE on an idle stream"));
+        }
+        drop(flow);
+        self.shared.window_opened.notify_all();
+        Ok(())
+    }
+
+    /// Reset `stream` because of an error confined to it.
+    fn reset(&mut self, stream: u32, code: u32) -> Result<(), ConnectionError> {
+        self.receiving.remove(&stream);
+        self.shared.reset(stream, code);
+        Ok(())
+    }
+}
+
+/// The payload of a DATA or HEADERS frame without its padding.
+fn unpad(frame: &Frame) -> Result<&[u8], ConnectionError> {
+    if frame.flags & PADDED == 0 {
+        return Ok(&frame.payload);
+    }
+    match frame.payload.split_first() {
+        Some((&padding, rest)) if usize::from(padding) <= rest.len() => Ok(&rest[..rest.len() - usize::from(padding)]),
+        _ => Err(ConnectionError::Protocol(PROTOCOL_ERROR, "padding longer than the frame")),
+    }
+}
+
+/// Build the request a decoded header block describes, with the header
+/// fields HTTP/1 handlers expect: `Host` from `:authority`, and cookies in
+/// one field.
+fn request(fields: Vec<Field>, peer: Option<SocketAddr>, secure: bool) -> Result<Request, &'static str> {
+    if fields.len() > MAX_HEADERS {
+        return Err("too many header fields");
+    }
+    let (mut method, mut scheme, mut authority, mut path) = (None, None, None, None);
+    let mut headers = Headers::new();
+    let mut cookies = Vec::new();
+    for (name, value) in fields {
+        let (Ok(name), Ok(value)) = (String::from_utf8(name), String::from_utf8(value)) else {
+            return Err("header field is not valid UTF-8");
+        };
+        if let Some(pseudo) = name.strip_prefix(':') {
+            if !headers.is_empty() || !cookies.is_empty() {
+                return Err("pseudo-header field after a regular one");
+            }
+            let slot = match pseudo {
+                "method" => &mut method,
+                "scheme" => &mut scheme,
+                "authority" => &mut authority,
+                "path" => &mut path,
+                _ => return Err("unknown pseudo-header field"),
+            };
+            if slot.replace(value).is_some() {
+                return Err("repeated pseudo-header field");
+            }
+        } else if name.is_empty() || name.bytes().any(|b| b.is_ascii_uppercase()) {
+            return Err("header field name empty or not lowercase");
+        } else if CONNECTION_SPECIFIC.contains(&name.as_str()) || (name == "te" && value != "trailers") {
+            return Err("connection-specific header field");
+        } else if name == "cookie" {
+            // Clients may split cookies across fields to compress better.
+            cookies.push(value);
+        } else {
+            headers.append(&name, value);
+        }
+    }
+
+    let (Some(method), Some(_), Some(path)) = (method, scheme, path) else {
+        return Err("missing :method, :scheme or :path");
+    };
+    if !path.starts_with('/') && path != "*" {
+        return Err("invalid :path");
+    }
+    if !cookies.is_empty() {
+        headers.append("cookie", cookies.join("; "));
+    }
+    if let Some(authority) = authority.filter(|_| !headers.contains("host")) {
+        headers.append("host", authority);
+    }
+    Ok(Request {
+        method: Method::parse(&method),
+        target: path,
+        version: "HTTP/2.0".to_string(),
+        headers,
+        peer,
+        secure,
+        user: None,
+    })
+}
+
+/// The body of a request on a stream, as its DATA frames arrive.
+struct RequestBody<'a> {
+    shared: &'a Shared<'a>,
+    stream: u32,
+    chunks: Receiver<Option<Vec<u8>>>,
+    /// The DATA being read, up to `position`.
+    chunk: Vec<u8>,
+    position: usize,
+    window: Arc<AtomicI64>,
+    finished: bool,
+}
+
+impl RequestBody<'_> {
+    /// Whether the client has sent the whole body, read or not.
+    fn is_finished(&mut self) -> bool {
+        while !self.finished {
+            match self.chunks.try_recv() {
+                Ok(Some(_)) => {}
+                Ok(None) => self.finished = true,
+                Err(_) => return false,
+            }
+        }
+        true
+    }
+}
+
+impl Read for RequestBody<'_> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        while self.position == self.chunk.len() && !self.finished {
+            match self.chunks.recv_timeout(self.shared.context.config.read_timeout) {
+                Ok(Some(chunk)) => (self.chunk, self.position) = (chunk, 0),
+                Ok(None) => self.finished = true,
+                Err(RecvTimeoutError::Timeout) => {
+                    return Err(io::Error::new(io::ErrorKind::TimedOut, "request body not received in time"))
+                }
+                Err(RecvTimeoutError::Disconnected) => {
+                    return Err(io::Error::new(io::ErrorKind::ConnectionReset, "stream reset"))
+                }
+            }
+        }
+        let read = buf.len().min(self.chunk.len() - self.position);
+        buf[..read].copy_from_slice(&self.chunk[self.position..self.position + read]);
+        self.position += read;
+
+        // Once a chunk is read, the client may send as much again.
+        if self.position == self.chunk.len() && !self.chunk.is_empty() && !self.finished {
+            let length = self.chunk.len();
+            self.window.fetch_add(length as i64, Ordering::Relaxed);
+            // Should the connection be gone, the next read says so.
+            let _ = self
+                .shared
+                .send(WINDOW_UPDATE, 0, self.stream, &(length as u32).to_be_bytes());
+            (self.chunk, self.position) = (Vec::new(), 0);
+        }
+        Ok(read)
+    }
+}
+
+/// Answer `request` on `stream` and record it in the access log, as
+/// `server::serve_request` does for HTTP/1.
+fn serve_stream(shared: &Shared, stream: u32, mut request: Request, mut body: RequestBody) {
+    let context = shared.context;
+    let started = Instant::now();
+    let mut entry = AccessEntry::new(request.peer.map(|addr| addr.ip()));
+    entry.describe(&request);
+    let route = server::route_name(&request, context);
+
+    let response = server::answer(&mut request, &mut body, context);
+    entry.user = request.user.clone();
+    entry.status = response.status.code();
+    match send_response(shared, stream, response, request.method == Method::Head) {
+        Ok(bytes) => {
+            entry.bytes = bytes;
+            // Tell the client to stop sending a body nobody will read.
+            if !body.is_finished() {
+                shared.reset(stream, NO_ERROR);
+            }
+        }
+        Err(e) => {
+            debug!("stream {stream}: {e}");
+            shared.reset(stream, INTERNAL_ERROR);
+        }
+    }
+    shared.finish(stream);
+
+    entry.duration = started.elapsed();
+    context.access_log.record(&entry);
+    if let Some(metrics) = &context.metrics {
+        metrics.record(route, entry.status, entry.duration);
+    }
+}
+
+/// Send `response` on `stream`, without its body if `head_only`. Return the
+/// number of body bytes sent.
+fn send_response(shared: &Shared, stream: u32, response: Response, head_only: bool) -> io::Result<u64> {
+    let Response { status, mut headers, body, .. } = response;
+    if !headers.contains("Date") {
+        headers.set("Date", httpdate::fmt_http_date(SystemTime::now()));
+    }
+    let body = if status.allows_body() {
+        if let Some(len) = body.len() {
+            headers.set("Content-Length", len.to_string());
+        }
+        body
+    } else {
+        Body::Empty
+    };
+
+    let code = status.code().to_string();
+    let fields: Vec<(String, &str)> = headers
+        .iter()
+        .map(|(name, value)| (name.to_ascii_lowercase(), value))
+        .filter(|(name, _)| !CONNECTION_SPECIFIC.contains(&name.as_str()))
+        .collect();
+    let mut block = Vec::new();
+    hpack::encode(
+        iter::once((":status", code.as_str())).chain(fields.iter().map(|(name, value)| (name.as_str(), *value))),
+        &mut block,
+    );
+    let end_stream = head_only || body.is_empty();
+    shared.send_headers(stream, &block, end_stream)?;
+    if end_stream {
+        return Ok(0);
+    }
+
+    let mut writer = DataWriter { shared, stream, buffer: Vec::with_capacity(FRAME_SIZE) };
+    let written = body.copy_to(&mut writer)?;
+    shared.send_data(stream, &writer.buffer, true)?;
+    Ok(written)
+}
+
+/// Gathers a response body into DATA frames of the largest size.
+struct DataWriter<'a> {
+    shared: &'a Shared<'a>,
+    stream: u32,
+    buffer: Vec<u8>,
+}
+
+impl Write for DataWriter<'_> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.buffer.extend_from_slice(buf);
+        if self.buffer.len() >= FRAME_SIZE {
+            self.flush()?;
+        }
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.shared.send_data(self.stream, &self.buffer, false)?;
+        self.buffer.clear();
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+fn fields(pairs: &[(&str, &str)]) -> Vec<Field> {
+    pairs.iter().map(|(name, value)| (name.as_bytes().to_vec(), value.as_bytes().to_vec())).collect()
+}
+
+#[test]
+fn test_request() {
+    let parsed = request(fields(&[(":method", "POST"), (":scheme", "https"), (":authority", "example.com"),
+                                  (":path", "/form?x=1"), ("cookie", "a=1"), ("user-agent", "test"), ("cookie", "b=2")]),
+                         None, true).unwrap();
+    assert_eq!(parsed.method, Method::Post);
+    assert_eq!(parsed.target, "/form?x=1");
+    assert_eq!(parsed.version, "HTTP/2.0");
+    assert_eq!(parsed.headers.get("Host"), Some("example.com"));
+    assert_eq!(parsed.headers.get("Cookie"), Some("a=1; b=2"));
+    assert_eq!(parsed.headers.get("User-Agent"), Some("test"));
+    assert!(parsed.secure);
+
+    let base = [(":method", "GET"), (":scheme", "http"), (":path", "/")];
+    let malformed = |extra: &[(&str, &str)]| request(fields(&[&base[..], extra].concat()), None, false).unwrap_err();
+    assert_eq!(malformed(&[("Accept", "*/*")]), "header field name empty or not lowercase");
+    assert_eq!(malformed(&[("connection", "keep-alive")]), "connection-specific header field");
+    assert_eq!(malformed(&[("te", "gzip")]), "connection-specific header field");
+    assert_eq!(malformed(&[(":path", "/again")]), "repeated pseudo-header field");
+    assert_eq!(malformed(&[("accept", "*/*"), (":authority", "late")]), "pseudo-header field after a regular one");
+    assert_eq!(malformed(&[(":protocol", "websocket")]), "unknown pseudo-header field");
+    assert!(request(fields(&base[..2]), None, false).is_err());
+    assert!(request(fields(&[("te", "trailers")]).into_iter().chain(fields(&base)).collect(), None, false).is_err());
+}
+
+#[test]
+fn test_unpad() {
+    let frame = |flags, payload: &[u8]| Frame { kind: DATA, flags, stream: 1, payload: payload.to_vec() };
+    assert_eq!(unpad(&frame(0, b"\x02abcd")).unwrap(), b"\x02abcd");
+    assert_eq!(unpad(&frame(PADDED, b"\x02abcd")).unwrap(), b"ab");
+    assert_eq!(unpad(&frame(PADDED, b"\x04abcd")).unwrap(), b"");
+    assert!(unpad(&frame(PADDED, b"\x05abcd")).is_err());
+    assert!(unpad(&frame(PADDED, b"")).is_err());
+}
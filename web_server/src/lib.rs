@@ -0,0 +1,36 @@
+pub mod access_log;
+pub mod auth;
+pub mod body;
+pub mod cache;
+pub mod cgi;
+pub mod compress;
+pub mod config;
+pub mod connection;
+pub mod error;
+pub mod files;
+pub mod form;
+#[cfg(unix)]
+pub mod handoff;
+pub mod headers;
+pub mod hpack;
+pub mod http2;
+pub mod limit;
+pub mod logger;
+pub mod metrics;
+pub mod middleware;
+pub mod mime;
+pub mod pool;
+pub mod proxy;
+pub mod range;
+#[cfg(unix)]
+mod reactor;
+pub mod request;
+pub mod response;
+pub mod server;
+#[cfg(unix)]
+pub mod signals;
+pub mod sse;
+pub mod tls;
+pub mod upload;
+pub mod vhost;
+pub mod websocket;
@@ -0,0 +1,257 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::net::IpAddr;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use log::warn;
+
+use crate::middleware::{Middleware, Next};
+use crate::request::Request;
+use crate::response::{Body, Response, Status};
+
+/// Buckets are pruned once this many clients are tracked, so a scan of many
+/// addresses cannot grow the table without bound.
+const PRUNE_THRESHOLD: usize = 16 * 1024;
+
+/// A token bucket: holds up to `burst` tokens and refills at `rate` tokens
+/// per second.
+#[derive(Debug, Clone, Copy)]
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+}
+
+/// Limits each client address to `rate` requests per second, with bursts of
+/// up to `burst` requests.
+pub struct RateLimiter {
+    rate: f64,
+    burst: f64,
+    buckets: Mutex<HashMap<IpAddr, Bucket>>,
+}
+
+impl RateLimiter {
+    /// # Panics
+    ///
+    /// Panics if `rate` is not positive or `burst` is zero.
+    pub fn new(rate: f64, burst: u32) -> RateLimiter {
+        assert!(rate > 0.0 && burst > 0);
+        RateLimiter {
+            rate,
+            burst: f64::from(burst),
+            buckets: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Take a token for a request from `client` at `now`. If none is left,
+    /// return how long until one will be.
+    pub fn check(&self, client: IpAddr, now: Instant) -> Result<(), Duration> {
+        let mut buckets = self.buckets.lock().unwrap_or_else(|e| e.into_inner());
+        if buckets.len() >= PRUNE_THRESHOLD {
+            // A bucket that has had time to refill is the same as no bucket.
+            let refill = Duration::from_secs_f64(self.burst / self.rate);
+            buckets.retain(|_, bucket| now.saturating_duration_since(bucket.updated) < refill);
+        }
+
+        let bucket = buckets.entry(client).or_insert(Bucket { tokens: self.burst, updated: now });
+        let elapsed = now.saturating_duration_since(bucket.updated).as_secs_f64();
+        bucket.tokens = (bucket.tokens + elapsed * self.rate).min(self.burst);
+        bucket.updated = now;
+
+        if bucket.tokens >= 1.0 {
+            bucket.tokens -= 1.0;
+            Ok(())
+        } else {
+            Err(Duration::from_secs_f64((1.0 - bucket.tokens) / self.rate))
+        }
+    }
+}
+
+impl Middleware for RateLimiter {
+    fn handle(&self, request: &mut Request, next: Next<'_>) -> Response {
+        let Some(peer) = request.peer else {
+            return next.run(request);
+        };
+        match self.check(peer.ip(), Instant::now()) {
+            Ok(()) => next.run(request),
+            Err(wait) => {
+                warn!("{}: rate limit exceeded", peer.ip());
+                too_many(Status::TooManyRequests, wait)
+            }
+        }
+    }
+}
+
+/// A 429 or 503 telling the client to come back after `wait`, rounded up to
+/// whole seconds as `Retry-After` requires.
+pub fn too_many(status: Status, wait: Duration) -> Response {
+    let seconds = wait.as_secs() + u64::from(wait.subsec_nanos() > 0);
+    Response::new(status)
+        .with_header("Retry-After", seconds.max(1).to_string())
+        .with_header("Content-Type", "text/plain; charset=utf-8")
+        .with_header("Connection", "close")
+        .with_body(Body::Text(format!("{} {}\n", status.code(), status.reason())))
+}
+
+/// Counts open connections, globally and per client address.
+pub struct ConnectionLimits {
+    max_total: AtomicUsize,
+    max_per_client: AtomicUsize,
+    counts: Mutex<Counts>,
+}
+
+#[derive(Default)]
+struct Counts {
+    total: usize,
+    per_client: HashMap<IpAddr, usize>,
+}
+
+/// Why a connection was refused.
+#[derive(Debug, PartialEq, Eq)]
+pub enum Refused {
+    /// The server as a whole has too many connections.
+    Total,
+    /// This client has too many connections.
+    Client,
+}
+
+impl fmt::Display for Refused {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Refused::Total => write!(f, "too many connections"),
+            Refused::Client => write!(f, "too many connections from this client"),
+        }
+    }
+}
+
+impl Refused {
+    /// The status to answer a refused connection with.
+    pub fn status(&self) -> Status {
+        match self {
+            Refused::Total => Status::ServiceUnavailable,
+            Refused::Client => Status::TooManyRequests,
+        }
+    }
+}
+
+impl ConnectionLimits {
+    /// Allow `max_total` connections in all and `max_per_client` from any
+    /// one address; zero means no limit.
+    pub fn new(max_total: usize, max_per_client: usize) -> Arc<ConnectionLimits> {
+        Arc::new(ConnectionLimits {
+            max_total: AtomicUsize::new(max_total),
+            max_per_client: AtomicUsize::new(max_per_client),
+            counts: Mutex::new(Counts::default()),
+        })
+    }
+
+    /// Count a new connection from `client`, unless that would exceed a
+    /// limit. The connection is counted until the permit is dropped.
+    pub fn acquire(self: &Arc<Self>, client: IpAddr) -> Result<ConnectionPermit, Refused> {
+        let max_total = self.max_total.load(Ordering::Relaxed);
+        let max_per_client = self.max_per_client.load(Ordering::Relaxed);
+        let mut counts = self.counts.lock().unwrap_or_else(|e| e.into_inner());
+        if max_total != 0 && counts.total >= max_total {
+            return Err(Refused::Total);
+        }
+        let count = counts.per_client.entry(client).or_insert(0);
+        if max_per_client != 0 && *count >= max_per_client {
+            return Err(Refused::Client);
+        }
+        *count += 1;
+        counts.total += 1;
+        Ok(ConnectionPermit { limits: Arc::clone(self), client })
+    }
+
+    /// Change the limits. Connections already open are kept even if they
+    /// exceed the new ones.
+    pub fn resize(&self, max_total: usize, max_per_client: usize) {
+        self.max_total.store(max_total, Ordering::Relaxed);
+        self.max_per_client.store(max_per_client, Ordering::Relaxed);
+    }
+
+    /// The number of connections currently counted.
+    pub fn total(&self) -> usize {
+        self.counts.lock().unwrap_or_else(|e| e.into_inner()).total
+    }
+
+    fn release(&self, client: IpAddr) {
+        let mut counts = self.counts.lock().unwrap_or_else(|e| e.into_inner());
+        counts.total -= 1;
+        if let Some(count) = counts.per_client.get_mut(&client) {
+            *count -= 1;
+            if *count == 0 {
+                counts.per_client.remove(&client);
+            }
+        }
+    }
+}
+
+/// One connection counted against the limits; dropping it releases it.
+pub struct ConnectionPermit {
+    limits: Arc<ConnectionLimits>,
+    client: IpAddr,
+}
+
+impl Drop for ConnectionPermit {
+    fn drop(&mut self) {
+        self.limits.release(self.client);
+    }
+}
+
+#[test]
+fn test_rate_limiter() {
+    let limiter = RateLimiter::new(2.0, 3);
+    let client: IpAddr = "192.0.2.1".parse().unwrap();
+    let other: IpAddr = "192.0.2.2".parse().unwrap();
+    let start = Instant::now();
+
+    for _ in 0..3 {
+        assert_eq!(limiter.check(client, start), Ok(()));
+    }
+    assert_eq!(limiter.check(client, start), Err(Duration::from_millis(500)));
+    assert_eq!(limiter.check(other, start), Ok(()));
+
+    // Half a second buys one more request, and no more.
+    let later = start + Duration::from_millis(500);
+    assert_eq!(limiter.check(client, later), Ok(()));
+    assert!(limiter.check(client, later).is_err());
+
+    // A long pause refills only up to the burst.
+    let much_later = later + Duration::from_secs(60);
+    for _ in 0..3 {
+        assert_eq!(limiter.check(client, much_later), Ok(()));
+    }
+    assert!(limiter.check(client, much_later).is_err());
+}
+
+#[test]
+fn test_connection_limits() {
+    let limits = ConnectionLimits::new(3, 2);
+    let a: IpAddr = "192.0.2.1".parse().unwrap();
+    let b: IpAddr = "192.0.2.2".parse().unwrap();
+
+    let first = limits.acquire(a).unwrap();
+    let _second = limits.acquire(a).unwrap();
+    assert_eq!(limits.acquire(a).err(), Some(Refused::Client));
+    let _third = limits.acquire(b).unwrap();
+    assert_eq!(limits.acquire(b).err(), Some(Refused::Total));
+    assert_eq!(limits.total(), 3);
+
+    drop(first);
+    assert_eq!(limits.total(), 2);
+    assert!(limits.acquire(a).is_ok());
+
+    limits.resize(0, 1);
+    assert_eq!(limits.acquire(a).err(), Some(Refused::Client));
+    assert!(limits.acquire("192.0.2.3".parse().unwrap()).is_ok());
+}
+
+#[test]
+fn test_too_many() {
+    let response = too_many(Status::TooManyRequests, Duration::from_millis(1500));
+    assert_eq!(response.status, Status::TooManyRequests);
+    assert_eq!(response.headers.get("Retry-After"), Some("2"));
+    assert_eq!(too_many(Status::ServiceUnavailable, Duration::ZERO).headers.get("Retry-After"), Some("1"));
+}
@@ -0,0 +1,260 @@
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use log::{Level, Log, Metadata, Record, SetLoggerError};
+
+/// Where a log is written.
+#[derive(Debug, Clone, PartialEq)]
+pub enum LogTarget {
+    Off,
+    /// Standard output for the access log, standard error for the error log.
+    Console,
+    File(PathBuf),
+}
+
+impl LogTarget {
+    /// Parse a target as written in the configuration: `off`, `-` for the
+    /// console, or a file path.
+    pub fn parse(s: &str) -> LogTarget {
+        match s {
+            "off" => LogTarget::Off,
+            "-" => LogTarget::Console,
+            path => LogTarget::File(PathBuf::from(path)),
+        }
+    }
+}
+
+/// When and how log files are rotated.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Rotation {
+    /// Rotate once the file would grow beyond this many bytes.
+    pub max_size: u64,
+    /// How many rotated files (`name.1`, `name.2`, ...) to keep.
+    pub keep: usize,
+}
+
+/// A log file that is renamed to `name.1` once it reaches its maximum size,
+/// shifting older files along and deleting the oldest.
+pub struct RotatingFile {
+    path: PathBuf,
+    rotation: Rotation,
+    file: File,
+    size: u64,
+}
+
+impl RotatingFile {
+    pub fn open(path: &Path, rotation: Rotation) -> io::Result<RotatingFile> {
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        let size = file.metadata()?.len();
+        Ok(RotatingFile {
+            path: path.to_path_buf(),
+            rotation,
+            file,
+            size,
+        })
+    }
+
+    pub fn write_line(&mut self, line: &str) -> io::Result<()> {
+        let len = line.len() as u64 + 1;
+        if self.size > 0 && self.size + len > self.rotation.max_size {
+            self.rotate()?;
+        }
+        writeln!(self.file, "{line}")?;
+        self.size += len;
+        Ok(())
+    }
+
+    fn rotate(&mut self) -> io::Result<()> {
+        let numbered = |n: usize| {
+            let mut name = self.path.clone().into_os_string();
+            name.push(format!(".{n}"));
+            PathBuf::from(name)
+        };
+
+        if self.rotation.keep == 0 {
+            fs::remove_file(&self.path)?;
+        } else {
+            for n in (1..self.rotation.keep).rev() {
+                let from = numbered(n);
+                if from.exists() {
+                    fs::rename(from, numbered(n + 1))?;
+                }
+            }
+            fs::rename(&self.path, numbered(1))?;
+        }
+
+        *self = RotatingFile::open(&self.path, self.rotation)?;
+        Ok(())
+    }
+}
+
+/// An open log destination, safe to share between threads.
+pub enum LogOutput {
+    Off,
+    Stdout,
+    Stderr,
+    File(Mutex<RotatingFile>),
+}
+
+impl LogOutput {
+    /// Open `target`, using `console` when it names the console.
+    pub fn open(target: &LogTarget, console: LogOutput, rotation: Rotation) -> io::Result<LogOutput> {
+        match target {
+            LogTarget::Off => Ok(LogOutput::Off),
+            LogTarget::Console => Ok(console),
+            LogTarget::File(path) => match RotatingFile::open(path, rotation) {
+                Ok(file) => Ok(LogOutput::File(Mutex::new(file))),
+                Err(e) => Err(io::Error::new(e.kind(), format!("failed to open {}: {e}", path.display()))),
+            },
+        }
+    }
+
+    pub fn write_line(&self, line: &str) {
+        // There is nowhere left to report a failure to write a log line.
+        let _ = match self {
+            LogOutput::Off => Ok(()),
+            LogOutput::Stdout => writeln!(io::stdout().lock(), "{line}"),
+            LogOutput::Stderr => writeln!(io::stderr().lock(), "{line}"),
+            LogOutput::File(file) => match file.lock() {
+                Ok(mut file) => file.write_line(line),
+                Err(_) => Ok(()),
+            },
+        };
+    }
+}
+
+/// A point in time broken down into UTC calendar fields.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct UtcTime {
+    pub year: i64,
+    pub month: u32,
+    pub day: u32,
+    pub hour: u32,
+    pub minute: u32,
+    pub second: u32,
+}
+
+impl UtcTime {
+    pub fn from(time: SystemTime) -> UtcTime {
+        let secs = time.duration_since(UNIX_EPOCH).map_or(0, |d| d.as_secs()) as i64;
+        let (days, rem) = (secs.div_euclid(86_400), secs.rem_euclid(86_400));
+
+        // Convert days since the epoch to a civil date, following Howard
+        // Hinnant's `civil_from_days`.
+        let z = days + 719_468;
+        let era = z.div_euclid(146_097);
+        let doe = z.rem_euclid(146_097);
+        let yoe = (doe - doe / 1460 + doe / 36_524 - doe / 146_096) / 365;
+        let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+        let mp = (5 * doy + 2) / 153;
+        let day = (doy - (153 * mp + 2) / 5 + 1) as u32;
+        let month = if mp < 10 { mp + 3 } else { mp - 9 } as u32;
+        let year = yoe + era * 400 + i64::from(month <= 2);
+
+        UtcTime {
+            year,
+            month,
+            day,
+            hour: (rem / 3600) as u32,
+            minute: (rem % 3600 / 60) as u32,
+            second: (rem % 60) as u32,
+        }
+    }
+
+    /// Format as RFC 3339, like `2000-10-10T13:55:36Z`.
+    pub fn rfc3339(&self) -> String {
+        format!("{:04}-{:02}-{:02}T{:02}:{:02}:{:02}Z",
+                self.year, self.month, self.day, self.hour, self.minute, self.second)
+    }
+
+    /// Format as in the Common Log Format, like `10/Oct/2000:13:55:36 +0000`.
+    pub fn clf(&self) -> String {
+        const MONTHS: [&str; 12] = ["Jan", "Feb", "Mar", "Apr", "May", "Jun",
+            "Jul", "Aug", "Sep", "Oct", "Nov", "Dec"];
+        format!("{:02}/{}/{:04}:{:02}:{:02}:{:02} +0000",
+                self.day, MONTHS[self.month as usize - 1], self.year, self.hour, self.minute, self.second)
+    }
+}
+
+/// The error log: every record from the `log` macros, as
+/// `[time] LEVEL target: message`.
+struct ErrorLogger {
+    output: LogOutput,
+}
+
+impl Log for ErrorLogger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        metadata.level() <= log::max_level()
+    }
+
+    fn log(&self, record: &Record) {
+        if self.enabled(record.metadata()) {
+            self.output.write_line(&format!("[{}] {:<5} {}: {}",
+                                            UtcTime::from(SystemTime::now()).rfc3339(),
+                                            record.level(), record.target(), record.args()));
+        }
+    }
+
+    fn flush(&self) {}
+}
+
+#[derive(Debug)]
+pub enum InitError {
+    Open(io::Error),
+    SetLogger(SetLoggerError),
+}
+
+impl std::fmt::Display for InitError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            InitError::Open(e) => write!(f, "{e}"),
+            InitError::SetLogger(e) => write!(f, "failed to install logger: {e}"),
+        }
+    }
+}
+
+impl std::error::Error for InitError {}
+
+/// Install the error log, showing records up to `level`.
+pub fn init(level: Level, target: &LogTarget, rotation: Rotation) -> Result<(), InitError> {
+    let output = LogOutput::open(target, LogOutput::Stderr, rotation).map_err(InitError::Open)?;
+
+    log::set_boxed_logger(Box::new(ErrorLogger { output })).map_err(InitError::SetLogger)?;
+    log::set_max_level(level.to_level_filter());
+    Ok(())
+}
+
+#[test]
+fn test_utc_time() {
+    let time = UNIX_EPOCH + std::time::Duration::from_secs(971_186_136);
+    let utc = UtcTime::from(time);
+    assert_eq!(utc.rfc3339(), "2000-10-10T13:55:36Z");
+    assert_eq!(utc.clf(), "10/Oct/2000:13:55:36 +0000");
+
+    assert_eq!(UtcTime::from(UNIX_EPOCH).rfc3339(), "1970-01-01T00:00:00Z");
+    let leap_day = UNIX_EPOCH + std::time::Duration::from_secs(1_709_164_800);
+    assert_eq!(UtcTime::from(leap_day).rfc3339(), "2024-02-29T00:00:00Z");
+}
+
+#[test]
+fn test_rotating_file() {
+    let dir = std::env::temp_dir().join(format!("web_server_rotate_{}", std::process::id()));
+    fs::create_dir_all(&dir).unwrap();
+    let path = dir.join("access.log");
+
+    let rotation = Rotation { max_size: 10, keep: 2 };
+    let mut file = RotatingFile::open(&path, rotation).unwrap();
+    for line in ["one", "two", "three", "four", "five"] {
+        file.write_line(line).unwrap();
+    }
+
+    assert_eq!(fs::read_to_string(&path).unwrap(), "four\nfive\n");
+    assert_eq!(fs::read_to_string(dir.join("access.log.1")).unwrap(), "three\n");
+    assert_eq!(fs::read_to_string(dir.join("access.log.2")).unwrap(), "one\ntwo\n");
+    assert!(!dir.join("access.log.3").exists());
+
+    fs::remove_dir_all(&dir).unwrap();
+}
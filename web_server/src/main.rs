@@ -1,31 +1,51 @@
-use std::fs;
-use std::io::{BufRead, BufReader, Write};
-use std::net::{TcpListener, TcpStream};
+use std::env;
 
-fn main() {
-    let listener = TcpListener::bind("127.0.0.1:7878").unwrap();
+use log::error;
 
-    for stream in listener.incoming() {
-        let stream = stream.unwrap();
-        println!("Connection established!");
+use web_server::config::{self, Config};
+use web_server::logger;
+use web_server::server::Server;
+#[cfg(unix)]
+use web_server::{handoff, signals};
 
-        handle_connection(stream)
+fn main() {
+    let args: Vec<String> = env::args().skip(1).collect();
+    if args.iter().any(|arg| arg == "-h" || arg == "--help") {
+        config::print_usage();
+        return;
     }
-}
-
-fn handle_connection(mut stream: TcpStream) {
-    let buf_reader = BufReader::new(&mut stream);
-    let request_line = buf_reader.lines().next().unwrap().unwrap();
 
-    let (status_line, filename) = match &request_line[..] {
-        "GET / HTTP/1.1" => ("HTTP/1.1 200 OK", "hello.html"),
-        _ => ("HTTP/1.1 404 NOT FOUND", "404.html"),
+    let config = match Config::from_args(args.clone()) {
+        Ok(config) => config,
+        Err(e) => {
+            eprintln!("web_server: {e}");
+            eprintln!("Try 'web_server --help' for more information.");
+            std::process::exit(2);
+        }
     };
 
-    let contents = fs::read_to_string(filename).unwrap();
-    let length = contents.len();
+    if let Err(e) = logger::init(config.log_level, &config.error_log, config.log_rotation) {
+        eprintln!("web_server: {e}");
+        std::process::exit(1);
+    }
+
+    let server = match Server::bind(config) {
+        Ok(server) => server,
+        Err(e) => {
+            error!("{e}");
+            std::process::exit(1);
+        }
+    };
 
-    let response = format!("{status_line}\r\nContent-Length: {length}\r\n\r\n{contents}");
+    // A reload reads the configuration file again, with the same flags on
+    // top.
+    #[cfg(unix)]
+    if let Err(e) = signals::install(server.handle(), move || Config::from_args(args.clone())) {
+        error!("failed to install signal handlers: {e}");
+        std::process::exit(1);
+    }
+    #[cfg(unix)]
+    handoff::notify_ready();
 
-    stream.write_all(response.as_bytes()).unwrap();
+    server.run();
 }
@@ -0,0 +1,172 @@
+use std::collections::BTreeMap;
+use std::fmt::Write as _;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::time::Duration;
+
+/// Upper bounds of the request duration buckets, in seconds.
+const BUCKETS: [f64; 11] = [0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0];
+
+/// Request durations counted into `BUCKETS`.
+#[derive(Debug, Default, Clone)]
+struct Histogram {
+    /// Requests per bucket, not cumulative; the last counts those slower
+    /// than every bound.
+    buckets: [u64; BUCKETS.len() + 1],
+    sum: f64,
+}
+
+impl Histogram {
+    fn observe(&mut self, seconds: f64) {
+        let bucket = BUCKETS.iter().position(|&bound| seconds <= bound).unwrap_or(BUCKETS.len());
+        self.buckets[bucket] += 1;
+        self.sum += seconds;
+    }
+}
+
+#[derive(Default, Clone)]
+struct Requests {
+    /// Requests answered, by route and status code.
+    counts: BTreeMap<(String, u16), u64>,
+    durations: BTreeMap<String, Histogram>,
+}
+
+/// Counters describing the server since it started, rendered in the
+/// Prometheus text format.
+pub struct Metrics {
+    requests: Mutex<Requests>,
+    received: AtomicU64,
+    sent: AtomicU64,
+    workers: usize,
+    /// Workers serving a connection, kept up to date by the thread pool.
+    pub busy_workers: Arc<AtomicUsize>,
+}
+
+impl Metrics {
+    pub fn new(workers: usize) -> Metrics {
+        Metrics {
+            requests: Mutex::default(),
+            received: AtomicU64::new(0),
+            sent: AtomicU64::new(0),
+            workers,
+            busy_workers: Arc::default(),
+        }
+    }
+
+    fn requests(&self) -> MutexGuard<'_, Requests> {
+        self.requests.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    /// Count a request answered by `route` with `status` in `duration`.
+    pub fn record(&self, route: &str, status: u16, duration: Duration) {
+        let mut requests = self.requests();
+        *requests.counts.entry((route.to_string(), status)).or_default() += 1;
+        requests
+            .durations
+            .entry(route.to_string())
+            .or_default()
+            .observe(duration.as_secs_f64());
+    }
+
+    /// Count bytes read from and written to clients.
+    pub fn transferred(&self, received: u64, sent: u64) {
+        self.received.fetch_add(received, Ordering::Relaxed);
+        self.sent.fetch_add(sent, Ordering::Relaxed);
+    }
+
+    /// Every metric, in the Prometheus text exposition format, with
+    /// `connections` reported as the number of open connections.
+    pub fn render(&self, connections: usize) -> String {
+        let mut text = String::new();
+        // Render from a copy, so requests are not held up meanwhile.
+        let requests = self.requests().clone();
+
+        header(&mut text, "web_server_requests_total", "counter", "Requests answered, by route and status code.");
+        for ((route, status), count) in &requests.counts {
+            let _ = writeln!(text, "web_server_requests_total{{route=\"{}\",status=\"{status}\"}} {count}", escape(route));
+        }
+
+        header(&mut text, "web_server_request_duration_seconds", "histogram",
+               "Time from receiving a request to sending its response, by route.");
+        for (route, histogram) in &requests.durations {
+            let route = escape(route);
+            let mut cumulative = 0;
+            for (i, count) in histogram.buckets.iter().enumerate() {
+                cumulative += count;
+                let bound = BUCKETS.get(i).map_or_else(|| "+Inf".to_string(), |bound| bound.to_string());
+                let _ = writeln!(text, "web_server_request_duration_seconds_bucket{{route=\"{route}\",le=\"{bound}\"}} {cumulative}");
+            }
+            let _ = writeln!(text, "web_server_request_duration_seconds_sum{{route=\"{route}\"}} {}", histogram.sum);
+            let _ = writeln!(text, "web_server_request_duration_seconds_count{{route=\"{route}\"}} {cumulative}");
+        }
+
+        let simple = [
+            ("web_server_connections", "gauge", "Client connections open.", connections as u64),
+            ("web_server_received_bytes_total", "counter", "Bytes received from clients.", self.received.load(Ordering::Relaxed)),
+            ("web_server_sent_bytes_total", "counter", "Bytes sent to clients.", self.sent.load(Ordering::Relaxed)),
+            ("web_server_workers", "gauge", "Threads in the worker pool.", self.workers as u64),
+            ("web_server_busy_workers", "gauge", "Worker threads serving a connection.",
+             self.busy_workers.load(Ordering::Relaxed) as u64),
+        ];
+        for (name, kind, help, value) in simple {
+            header(&mut text, name, kind, help);
+            let _ = writeln!(text, "{name} {value}");
+        }
+        text
+    }
+}
+
+fn header(text: &mut String, name: &str, kind: &str, help: &str) {
+    let _ = writeln!(text, "# HELP {name} {help}");
+    let _ = writeln!(text, "# TYPE {name} {kind}");
+}
+
+/// Escape a label value.
+fn escape(value: &str) -> String {
+    value.replace('\\', "\\\\").replace('"', "\\\"").replace('\n', "\\n")
+}
+
+#[test]
+fn test_histogram() {
+    let mut histogram = Histogram::default();
+    for seconds in [0.001, 0.005, 0.3, 60.0] {
+        histogram.observe(seconds);
+    }
+    assert_eq!(histogram.buckets, [2, 0, 0, 0, 0, 0, 1, 0, 0, 0, 0, 1]);
+    assert!((histogram.sum - 60.306).abs() < 1e-9);
+}
+
+#[test]
+fn test_render() {
+    let metrics = Metrics::new(4);
+    metrics.record("static", 200, Duration::from_millis(3));
+    metrics.record("static", 200, Duration::from_millis(30));
+    metrics.record("/api/", 502, Duration::from_secs(20));
+    metrics.record("say \"hi\"", 404, Duration::ZERO);
+    metrics.transferred(100, 2000);
+    metrics.busy_workers.store(1, Ordering::Relaxed);
+    let text = metrics.render(3);
+
+    for line in [
+        "# TYPE web_server_requests_total counter",
+        "web_server_requests_total{route=\"static\",status=\"200\"} 2",
+        "web_server_requests_total{route=\"/api/\",status=\"502\"} 1",
+        "web_server_requests_total{route=\"say \\\"hi\\\"\",status=\"404\"} 1",
+        "# TYPE web_server_request_duration_seconds histogram",
+        "web_server_request_duration_seconds_bucket{route=\"static\",le=\"0.005\"} 1",
+        "web_server_request_duration_seconds_bucket{route=\"static\",le=\"0.025\"} 1",
+        "web_server_request_duration_seconds_bucket{route=\"static\",le=\"0.05\"} 2",
+        "web_server_request_duration_seconds_bucket{route=\"static\",le=\"+Inf\"} 2",
+        "web_server_request_duration_seconds_count{route=\"static\"} 2",
+        "web_server_request_duration_seconds_bucket{route=\"/api/\",le=\"10\"} 0",
+        "web_server_request_duration_seconds_bucket{route=\"/api/\",le=\"+Inf\"} 1",
+        "web_server_request_duration_seconds_sum{route=\"/api/\"} 20",
+        "web_server_connections 3",
+        "web_server_received_bytes_total 100",
+        "web_server_sent_bytes_total 2000",
+        "web_server_workers 4",
+        "web_server_busy_workers 1",
+    ] {
+        assert!(text.lines().any(|l| l == line), "missing {line:?} in\n{text}");
+    }
+}
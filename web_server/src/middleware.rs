@@ -0,0 +1,342 @@
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, OnceLock};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use log::debug;
+
+use crate::request::{Method, Request};
+use crate::response::{Response, Status};
+
+/// The header carrying the request ID, on both the request and the response.
+pub const REQUEST_ID_HEADER: &str = "X-Request-Id";
+
+/// A layer around request handling.
+///
+/// A middleware may change the request before passing it on with
+/// `next.run(request)`, answer it itself without calling `next`, or change
+/// the response on its way back.
+pub trait Middleware: Send + Sync {
+    fn handle(&self, request: &mut Request, next: Next<'_>) -> Response;
+}
+
+impl<F> Middleware for F
+where
+    F: Fn(&mut Request, Next<'_>) -> Response + Send + Sync,
+{
+    fn handle(&self, request: &mut Request, next: Next<'_>) -> Response {
+        self(request, next)
+    }
+}
+
+/// The layers after the current one, ending with the handler that produces
+/// the response.
+pub struct Next<'a> {
+    layers: &'a [Arc<dyn Middleware>],
+    handler: &'a dyn Fn(&Request) -> Response,
+}
+
+impl Next<'_> {
+    /// Pass `request` on to the rest of the pipeline.
+    pub fn run(self, request: &mut Request) -> Response {
+        match self.layers.split_first() {
+            Some((layer, layers)) => layer.handle(request, Next { layers, handler: self.handler }),
+            None => (self.handler)(request),
+        }
+    }
+}
+
+/// Middleware applied in order around every request: the first layer added
+/// sees the request first and the response last.
+///
+/// Clones share their layers.
+#[derive(Default, Clone)]
+pub struct Pipeline {
+    layers: Vec<Arc<dyn Middleware>>,
+}
+
+impl Pipeline {
+    pub fn new() -> Pipeline {
+        Pipeline { layers: Vec::new() }
+    }
+
+    /// Add `middleware` inside the layers already added.
+    pub fn push<M: Middleware + 'static>(&mut self, middleware: M) {
+        self.layers.push(Arc::new(middleware));
+    }
+
+    /// Add the layers of `other` inside those already added.
+    pub fn append(&mut self, other: &Pipeline) {
+        self.layers.extend(other.layers.iter().cloned());
+    }
+
+    pub fn len(&self) -> usize {
+        self.layers.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.layers.is_empty()
+    }
+
+    /// Answer `request` by running it through every layer and then
+    /// `handler`.
+    pub fn run(&self, request: &mut Request, handler: &dyn Fn(&Request) -> Response) -> Response {
+        Next { layers: &self.layers, handler }.run(request)
+    }
+}
+
+/// Cross-origin resource sharing: lets scripts on the listed origins read
+/// responses, and answers their preflight requests.
+pub struct Cors {
+    origins: Vec<String>,
+    headers: Vec<String>,
+    max_age: Duration,
+}
+
+impl Cors {
+    /// Allow requests from `origins`, such as `https://example.com`, or
+    /// from anywhere if the list holds `*`.
+    pub fn new(origins: Vec<String>) -> Cors {
+        Cors {
+            origins,
+            headers: Vec::new(),
+            max_age: Duration::from_secs(600),
+        }
+    }
+
+    /// Request headers scripts may send besides the CORS-safelisted ones.
+    pub fn allow_headers(mut self, headers: Vec<String>) -> Cors {
+        self.headers = headers;
+        self
+    }
+
+    /// How long browsers may cache a preflight answer.
+    pub fn max_age(mut self, max_age: Duration) -> Cors {
+        self.max_age = max_age;
+        self
+    }
+
+    fn allows(&self, origin: &str) -> bool {
+        self.origins.iter().any(|allowed| allowed == "*" || allowed.eq_ignore_ascii_case(origin))
+    }
+
+    fn any_origin(&self) -> bool {
+        self.origins.iter().any(|allowed| allowed == "*")
+    }
+
+    /// The methods to allow in answer to a preflight asking for `requested`.
+    /// Routes such as uploads, CGI scripts and proxies take other methods
+    /// than GET and HEAD, and each handler still turns away those it does
+    /// not serve, so the method asked for is allowed if it is well formed.
+    fn allow_methods(requested: &str) -> String {
+        let requested = requested.trim();
+        let token = !requested.is_empty()
+            && requested.bytes().all(|b| b.is_ascii_alphanumeric() || b"!#$%&'*+-.^_`|~".contains(&b));
+        if token && !matches!(requested, "GET" | "HEAD") {
+            format!("GET, HEAD, {requested}")
+        } else {
+            "GET, HEAD".to_string()
+        }
+    }
+}
+
+impl Middleware for Cors {
+    fn handle(&self, request: &mut Request, next: Next<'_>) -> Response {
+        let origin = match request.headers.get("Origin") {
+            Some(origin) if self.allows(origin) => origin.to_string(),
+            _ => return next.run(request),
+        };
+        let allow_origin = if self.any_origin() { "*".to_string() } else { origin };
+
+        let requested = match request.headers.get("Access-Control-Request-Method") {
+            Some(method) if request.method == Method::Options => Some(method),
+            _ => None,
+        };
+        let mut response = if let Some(requested) = requested {
+            let mut response = Response::new(Status::NoContent)
+                .with_header("Access-Control-Allow-Methods", Cors::allow_methods(requested))
+                .with_header("Access-Control-Max-Age", self.max_age.as_secs().to_string());
+            if !self.headers.is_empty() {
+                response.headers.set("Access-Control-Allow-Headers", self.headers.join(", "));
+            }
+            response
+        } else {
+            next.run(request)
+        };
+
+        response.headers.set("Access-Control-Allow-Origin", allow_origin);
+        if !self.any_origin() {
+            response.headers.append_token("Vary", "Origin");
+        }
+        response
+    }
+}
+
+/// Gives every request an ID in `X-Request-Id`, keeping one sent by the
+/// client or a proxy in front if it looks sane, and echoes it in the
+/// response so both sides can refer to the request in logs.
+#[derive(Default)]
+pub struct RequestId;
+
+impl RequestId {
+    fn is_valid(id: &str) -> bool {
+        (1..=128).contains(&id.len())
+            && id.bytes().all(|b| b.is_ascii_alphanumeric() || b"-_.".contains(&b))
+    }
+
+    /// An ID unique within this process and very unlikely to repeat across
+    /// restarts.
+    fn generate() -> String {
+        static START: OnceLock<u64> = OnceLock::new();
+        static COUNTER: AtomicU64 = AtomicU64::new(0);
+        let start = START.get_or_init(|| {
+            let nanos = SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |d| d.as_nanos() as u64);
+            nanos ^ (u64::from(std::process::id()) << 32)
+        });
+        format!("{start:016x}-{:08x}", COUNTER.fetch_add(1, Ordering::Relaxed))
+    }
+}
+
+impl Middleware for RequestId {
+    fn handle(&self, request: &mut Request, next: Next<'_>) -> Response {
+        let id = match request.headers.get(REQUEST_ID_HEADER) {
+            Some(id) if RequestId::is_valid(id) => id.to_string(),
+            _ => {
+                let id = RequestId::generate();
+                request.headers.set(REQUEST_ID_HEADER, id.as_str());
+                id
+            }
+        };
+        let mut response = next.run(request);
+        response.headers.set(REQUEST_ID_HEADER, id);
+        response
+    }
+}
+
+/// Measures how long the rest of the pipeline takes to produce a response
+/// and reports it in a `Server-Timing` header and the debug log. Time spent
+/// sending the body is not included.
+#[derive(Default)]
+pub struct Timing;
+
+impl Middleware for Timing {
+    fn handle(&self, request: &mut Request, next: Next<'_>) -> Response {
+        let started = Instant::now();
+        let mut response = next.run(request);
+        let millis = started.elapsed().as_secs_f64() * 1000.0;
+
+        debug!("{} {} handled in {millis:.3}ms", request.method, request.target);
+        response.headers.append("Server-Timing", format!("app;dur={millis:.3}"));
+        response
+    }
+}
+
+#[cfg(test)]
+fn request(raw: &str) -> Request {
+    Request::read_from(&mut raw.as_bytes()).unwrap()
+}
+
+#[cfg(test)]
+fn ok(_: &Request) -> Response {
+    Response::new(Status::Ok)
+}
+
+#[test]
+fn test_pipeline_order() {
+    let mut pipeline = Pipeline::new();
+    pipeline.push(|request: &mut Request, next: Next<'_>| {
+        request.headers.append("X-Trace", "outer");
+        let mut response = next.run(request);
+        response.headers.append("X-Trace", "outer");
+        response
+    });
+    pipeline.push(|request: &mut Request, next: Next<'_>| {
+        request.headers.append("X-Trace", "inner");
+        let mut response = next.run(request);
+        response.headers.append("X-Trace", "inner");
+        response
+    });
+
+    let handler = |request: &Request| {
+        let seen: Vec<&str> = request.headers.get_all("X-Trace").collect();
+        Response::new(Status::Ok).with_header("X-Seen", seen.join(","))
+    };
+    let response = pipeline.run(&mut request("GET / HTTP/1.1\r\n\r\n"), &handler);
+    assert_eq!(response.headers.get("X-Seen"), Some("outer,inner"));
+    assert_eq!(response.headers.get_all("X-Trace").collect::<Vec<_>>(), vec!["inner", "outer"]);
+}
+
+#[test]
+fn test_short_circuit() {
+    let mut pipeline = Pipeline::new();
+    pipeline.push(|_: &mut Request, _: Next<'_>| Response::new(Status::Forbidden));
+    pipeline.push(|_: &mut Request, _: Next<'_>| -> Response { panic!("inner layer reached") });
+
+    let handler = |_: &Request| -> Response { panic!("handler reached") };
+    let response = pipeline.run(&mut request("GET / HTTP/1.1\r\n\r\n"), &handler);
+    assert_eq!(response.status, Status::Forbidden);
+}
+
+#[test]
+fn test_cors() {
+    let mut pipeline = Pipeline::new();
+    pipeline.push(Cors::new(vec!["https://example.com".to_string()]).allow_headers(vec!["X-Token".to_string()]));
+
+    let response = pipeline.run(&mut request("GET / HTTP/1.1\r\nOrigin: https://example.com\r\n\r\n"), &ok);
+    assert_eq!(response.status, Status::Ok);
+    assert_eq!(response.headers.get("Access-Control-Allow-Origin"), Some("https://example.com"));
+    assert_eq!(response.headers.get("Vary"), Some("Origin"));
+
+    let preflight = "OPTIONS /data HTTP/1.1\r\nOrigin: https://example.com\r\n\
+                     Access-Control-Request-Method: GET\r\n\r\n";
+    let response = pipeline.run(&mut request(preflight), &ok);
+    assert_eq!(response.status, Status::NoContent);
+    assert_eq!(response.headers.get("Access-Control-Allow-Methods"), Some("GET, HEAD"));
+    assert_eq!(response.headers.get("Access-Control-Allow-Headers"), Some("X-Token"));
+    assert_eq!(response.headers.get("Access-Control-Max-Age"), Some("600"));
+
+    let preflight = "OPTIONS /upload HTTP/1.1\r\nOrigin: https://example.com\r\n\
+                     Access-Control-Request-Method: POST\r\n\r\n";
+    let response = pipeline.run(&mut request(preflight), &ok);
+    assert_eq!(response.status, Status::NoContent);
+    assert_eq!(response.headers.get("Access-Control-Allow-Methods"), Some("GET, HEAD, POST"));
+    let preflight = "OPTIONS /upload HTTP/1.1\r\nOrigin: https://example.com\r\n\
+                     Access-Control-Request-Method: POST, PUT\r\n\r\n";
+    let response = pipeline.run(&mut request(preflight), &ok);
+    assert_eq!(response.headers.get("Access-Control-Allow-Methods"), Some("GET, HEAD"));
+
+    let response = pipeline.run(&mut request("GET / HTTP/1.1\r\nOrigin: https://evil.example\r\n\r\n"), &ok);
+    assert!(!response.headers.contains("Access-Control-Allow-Origin"));
+
+    let mut pipeline = Pipeline::new();
+    pipeline.push(Cors::new(vec!["*".to_string()]));
+    let response = pipeline.run(&mut request("GET / HTTP/1.1\r\nOrigin: https://any.example\r\n\r\n"), &ok);
+    assert_eq!(response.headers.get("Access-Control-Allow-Origin"), Some("*"));
+    assert!(!response.headers.contains("Vary"));
+}
+
+#[test]
+fn test_request_id() {
+    let mut pipeline = Pipeline::new();
+    pipeline.push(RequestId);
+    let handler = |request: &Request| {
+        Response::new(Status::Ok).with_header("X-Seen", request.headers.get(REQUEST_ID_HEADER).unwrap_or(""))
+    };
+
+    let response = pipeline.run(&mut request("GET / HTTP/1.1\r\nX-Request-Id: abc-123\r\n\r\n"), &handler);
+    assert_eq!(response.headers.get(REQUEST_ID_HEADER), Some("abc-123"));
+    assert_eq!(response.headers.get("X-Seen"), Some("abc-123"));
+
+    let first = pipeline.run(&mut request("GET / HTTP/1.1\r\nX-Request-Id: <script>\r\n\r\n"), &handler);
+    let second = pipeline.run(&mut request("GET / HTTP/1.1\r\n\r\n"), &handler);
+    let first = first.headers.get(REQUEST_ID_HEADER).unwrap();
+    assert!(RequestId::is_valid(first));
+    assert_ne!(Some(first), second.headers.get(REQUEST_ID_HEADER));
+}
+
+#[test]
+fn test_timing() {
+    let mut pipeline = Pipeline::new();
+    pipeline.push(Timing);
+    let response = pipeline.run(&mut request("GET / HTTP/1.1\r\n\r\n"), &ok);
+    assert!(response.headers.get("Server-Timing").unwrap().starts_with("app;dur="));
+}
@@ -0,0 +1,43 @@
+use std::path::Path;
+
+/// Guess the `Content-Type` of a file from its extension.
+///
+/// Unknown extensions are served as `application/octet-stream`, which makes
+/// browsers download the file instead of trying to render it.
+pub fn content_type(path: &Path) -> &'static str {
+    let extension = path
+        .extension()
+        .and_then(|e| e.to_str())
+        .map(|e| e.to_ascii_lowercase());
+
+    match extension.as_deref() {
+        Some("html") | Some("htm") => "text/html; charset=utf-8",
+        Some("css") => "text/css; charset=utf-8",
+        Some("js") | Some("mjs") => "text/javascript; charset=utf-8",
+        Some("json") => "application/json",
+        Some("txt") => "text/plain; charset=utf-8",
+        Some("xml") => "application/xml",
+        Some("svg") => "image/svg+xml",
+        Some("png") => "image/png",
+        Some("jpg") | Some("jpeg") => "image/jpeg",
+        Some("gif") => "image/gif",
+        Some("webp") => "image/webp",
+        Some("ico") => "image/x-icon",
+        Some("wasm") => "application/wasm",
+        Some("pdf") => "application/pdf",
+        Some("zip") => "application/zip",
+        Some("gz") => "application/gzip",
+        Some("woff") => "font/woff",
+        Some("woff2") => "font/woff2",
+        Some("mp4") => "video/mp4",
+        Some("mp3") => "audio/mpeg",
+        _ => "application/octet-stream",
+    }
+}
+
+#[test]
+fn test_content_type() {
+    assert_eq!(content_type(Path::new("hello.html")), "text/html; charset=utf-8");
+    assert_eq!(content_type(Path::new("logo.PNG")), "image/png");
+    assert_eq!(content_type(Path::new("archive")), "application/octet-stream");
+}
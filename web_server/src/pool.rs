@@ -0,0 +1,248 @@
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{mpsc, Arc, Mutex};
+use std::thread;
+
+use log::error;
+
+type Job = Box<dyn FnOnce() + Send + 'static>;
+
+/// A fixed set of threads that run jobs sent to them over a channel.
+pub struct ThreadPool {
+    workers: Vec<Worker>,
+    sender: Option<mpsc::Sender<Job>>,
+    /// Workers running a job right now.
+    busy: Arc<AtomicUsize>,
+}
+
+impl ThreadPool {
+    /// Create a new ThreadPool.
+    ///
+    /// The size is the number of threads in the pool.
+    ///
+    /// # Panics
+    ///
+    /// The `new` function will panic if the size is zero.
+    pub fn new(size: usize) -> ThreadPool {
+        ThreadPool::counting(size, Arc::default())
+    }
+
+    /// Create a new ThreadPool that keeps `busy` set to the number of
+    /// workers running a job.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the size is zero.
+    pub fn counting(size: usize, busy: Arc<AtomicUsize>) -> ThreadPool {
+        assert!(size > 0);
+
+        let (sender, receiver) = mpsc::channel();
+        let receiver = Arc::new(Mutex::new(receiver));
+
+        let workers = (0..size)
+            .map(|id| Worker::new(id, Arc::clone(&receiver), Arc::clone(&busy)))
+            .collect();
+
+        ThreadPool {
+            workers,
+            sender: Some(sender),
+            busy,
+        }
+    }
+
+    /// The number of workers running a job right now.
+    pub fn busy(&self) -> usize {
+        self.busy.load(Ordering::Relaxed)
+    }
+
+    pub fn execute<F>(&self, f: F)
+    where
+        F: FnOnce() + Send + 'static,
+    {
+        if let Some(sender) = &self.sender {
+            // Sending only fails once every worker has exited, which
+            // cannot happen while the pool is alive.
+            let _ = sender.send(Box::new(f));
+        }
+    }
+}
+
+impl Drop for ThreadPool {
+    fn drop(&mut self) {
+        // Closing the channel makes every worker leave its loop.
+        drop(self.sender.take());
+
+        for worker in &mut self.workers {
+            if let Some(thread) = worker.thread.take() {
+                let _ = thread.join();
+            }
+        }
+    }
+}
+
+/// Counts threads that last as long as a connection rather than a request,
+/// such as upgraded protocols, so there are never more than a set number.
+pub struct ThreadLimit {
+    max: AtomicUsize,
+    running: AtomicUsize,
+}
+
+impl ThreadLimit {
+    /// Allow `max` threads at once; zero means no limit.
+    pub fn new(max: usize) -> Arc<ThreadLimit> {
+        Arc::new(ThreadLimit {
+            max: AtomicUsize::new(max),
+            running: AtomicUsize::new(0),
+        })
+    }
+
+    /// Count one more thread, unless the limit is reached. The thread is
+    /// counted until the permit is dropped.
+    pub fn acquire(self: &Arc<Self>) -> Option<ThreadPermit> {
+        let max = self.max.load(Ordering::Relaxed);
+        self.running
+            .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |running| {
+                (max == 0 || running < max).then_some(running + 1)
+            })
+            .ok()?;
+        Some(ThreadPermit { limit: Arc::clone(self) })
+    }
+
+    /// Run `f` on a new thread, unless the limit is reached, in which case
+    /// `f` is dropped and `false` returned.
+    pub fn spawn<F>(self: &Arc<Self>, f: F) -> bool
+    where
+        F: FnOnce() + Send + 'static,
+    {
+        let Some(permit) = self.acquire() else {
+            return false;
+        };
+        thread::spawn(move || {
+            let _permit = permit;
+            f();
+        });
+        true
+    }
+
+    /// Change the limit. Threads already running are kept even if they
+    /// exceed the new one.
+    pub fn resize(&self, max: usize) {
+        self.max.store(max, Ordering::Relaxed);
+    }
+
+    /// The number of threads currently counted.
+    pub fn running(&self) -> usize {
+        self.running.load(Ordering::Relaxed)
+    }
+}
+
+/// One thread counted against a `ThreadLimit`; dropping it releases it.
+pub struct ThreadPermit {
+    limit: Arc<ThreadLimit>,
+}
+
+impl Drop for ThreadPermit {
+    fn drop(&mut self) {
+        self.limit.running.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+struct Worker {
+    thread: Option<thread::JoinHandle<()>>,
+}
+
+impl Worker {
+    fn new(id: usize, receiver: Arc<Mutex<mpsc::Receiver<Job>>>, busy: Arc<AtomicUsize>) -> Worker {
+        let thread = thread::Builder::new()
+            .name(format!("worker-{id}"))
+            .spawn(move || loop {
+                let message = match receiver.lock() {
+                    Ok(receiver) => receiver.recv(),
+                    Err(_) => break,
+                };
+
+                match message {
+                    Ok(job) => {
+                        busy.fetch_add(1, Ordering::Relaxed);
+                        // A panicking job must not take the worker down with it.
+                        if panic::catch_unwind(AssertUnwindSafe(job)).is_err() {
+                            error!("worker {id}: job panicked");
+                        }
+                        busy.fetch_sub(1, Ordering::Relaxed);
+                    }
+                    Err(_) => break,
+                }
+            })
+            .expect("failed to spawn worker thread");
+
+        Worker {
+            thread: Some(thread),
+        }
+    }
+}
+
+#[test]
+fn test_pool_runs_jobs() {
+    let (sender, receiver) = mpsc::channel();
+    let pool = ThreadPool::new(2);
+    for i in 0..8 {
+        let sender = sender.clone();
+        pool.execute(move || sender.send(i).unwrap());
+    }
+    drop(pool);
+
+    let mut results: Vec<i32> = receiver.try_iter().collect();
+    results.sort();
+    assert_eq!(results, (0..8).collect::<Vec<_>>());
+}
+
+#[test]
+fn test_pool_survives_panics() {
+    let (sender, receiver) = mpsc::channel();
+    let pool = ThreadPool::new(1);
+    pool.execute(|| panic!("boom"));
+    pool.execute(move || sender.send("still alive").unwrap());
+    drop(pool);
+
+    assert_eq!(receiver.recv().unwrap(), "still alive");
+}
+
+#[test]
+fn test_pool_counts_busy_workers() {
+    let (started, wait) = mpsc::channel();
+    let (finish, finished) = mpsc::channel::<()>();
+    let finished = Arc::new(Mutex::new(finished));
+    let pool = ThreadPool::new(3);
+    for _ in 0..2 {
+        let started = started.clone();
+        let finished = Arc::clone(&finished);
+        pool.execute(move || {
+            started.send(()).unwrap();
+            let _ = finished.lock().unwrap().recv();
+        });
+    }
+    wait.recv().unwrap();
+    wait.recv().unwrap();
+    assert_eq!(pool.busy(), 2);
+
+    drop(finish);
+    drop(pool);
+}
+
+#[test]
+fn test_thread_limit() {
+    let limit = ThreadLimit::new(2);
+    let first = limit.acquire().unwrap();
+    let _second = limit.acquire().unwrap();
+    assert!(limit.acquire().is_none());
+    assert!(!limit.spawn(|| panic!("over the limit")));
+    assert_eq!(limit.running(), 2);
+
+    drop(first);
+    let (sender, receiver) = mpsc::channel();
+    assert!(limit.spawn(move || sender.send(()).unwrap()));
+    receiver.recv().unwrap();
+
+    limit.resize(0);
+    let _many: Vec<ThreadPermit> = (0..8).map(|_| limit.acquire().unwrap()).collect();
+}
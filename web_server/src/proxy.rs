@@ -0,0 +1,557 @@
+use std::io::{self, BufRead, BufReader, BufWriter, Read, Write};
+use std::net::{TcpStream, ToSocketAddrs};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use log::{debug, warn};
+use serde::Deserialize;
+
+use crate::body::{self, ChunkedReader};
+use crate::config::Config;
+use crate::headers::Headers;
+use crate::request::{self, Framing, Method, Request};
+use crate::response::{Body, Response, Status};
+use crate::server::error_response;
+
+/// Header fields that describe one connection rather than the message, and
+/// so are never passed on (RFC 9110 section 7.6.1).
+const HOP_BY_HOP: [&str; 9] = [
+    "Connection",
+    "Keep-Alive",
+    "Proxy-Connection",
+    "TE",
+    "Trailer",
+    "Transfer-Encoding",
+    "Upgrade",
+    "Expect",
+    "Content-Length",
+];
+
+/// A path prefix forwarded to upstream servers, as configured.
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ProxyRule {
+    /// A path prefix such as `/api/`; it covers the path itself and
+    /// everything below it.
+    pub path: String,
+    /// Upstream servers as `host:port`, used in turn.
+    pub upstreams: Vec<String>,
+    /// Whether the prefix is removed from the path before forwarding.
+    #[serde(default)]
+    pub strip_prefix: bool,
+    /// Whether the client's `Host` is forwarded instead of the upstream's
+    /// address.
+    #[serde(default)]
+    pub preserve_host: bool,
+    /// Seconds allowed to connect to an upstream.
+    #[serde(default = "default_connect_timeout")]
+    pub connect_timeout: u64,
+    /// Seconds allowed for each read from and write to an upstream.
+    #[serde(default = "default_timeout")]
+    pub timeout: u64,
+    /// Failures in a row after which an upstream is taken out of rotation.
+    #[serde(default = "default_max_fails")]
+    pub max_fails: u32,
+    /// Seconds an upstream stays out of rotation.
+    #[serde(default = "default_fail_timeout")]
+    pub fail_timeout: u64,
+}
+
+fn default_connect_timeout() -> u64 {
+    5
+}
+
+fn default_timeout() -> u64 {
+    60
+}
+
+fn default_max_fails() -> u32 {
+    1
+}
+
+fn default_fail_timeout() -> u64 {
+    10
+}
+
+impl ProxyRule {
+    /// Forward `path` to `upstreams` with the default settings.
+    pub fn new(path: &str, upstreams: Vec<String>) -> ProxyRule {
+        ProxyRule {
+            path: path.to_string(),
+            upstreams,
+            strip_prefix: false,
+            preserve_host: false,
+            connect_timeout: default_connect_timeout(),
+            timeout: default_timeout(),
+            max_fails: default_max_fails(),
+            fail_timeout: default_fail_timeout(),
+        }
+    }
+}
+
+/// Passive health of an upstream: it is taken out of rotation after
+/// `max_fails` failed exchanges in a row, and tried again once
+/// `fail_timeout` has passed.
+#[derive(Debug, Default)]
+struct Health {
+    fails: u32,
+    down_until: Option<Instant>,
+}
+
+#[derive(Debug)]
+struct Upstream {
+    address: String,
+    health: Mutex<Health>,
+}
+
+impl Upstream {
+    fn is_available(&self, now: Instant) -> bool {
+        self.health().down_until.is_none_or(|until| now >= until)
+    }
+
+    fn succeeded(&self) {
+        *self.health() = Health::default();
+    }
+
+    fn failed(&self, rule: &ProxyRule) {
+        let mut health = self.health();
+        health.fails += 1;
+        if health.fails >= rule.max_fails {
+            warn!("upstream {} is down, retrying in {}s", self.address, rule.fail_timeout);
+            health.fails = 0;
+            health.down_until = Some(Instant::now() + Duration::from_secs(rule.fail_timeout));
+        }
+    }
+
+    fn health(&self) -> std::sync::MutexGuard<'_, Health> {
+        self.health.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    fn connect(&self, timeout: Duration) -> io::Result<TcpStream> {
+        connect(&self.address, timeout)
+    }
+}
+
+/// Connect to `address`, a `host:port`, trying each address it resolves to.
+pub(crate) fn connect(address: &str, timeout: Duration) -> io::Result<TcpStream> {
+    let mut last_error = io::Error::new(io::ErrorKind::NotFound, "address resolved to nothing");
+    for addr in address.to_socket_addrs()? {
+        match TcpStream::connect_timeout(&addr, timeout) {
+            Ok(stream) => return Ok(stream),
+            Err(e) => last_error = e,
+        }
+    }
+    Err(last_error)
+}
+
+/// Why an exchange with an upstream failed.
+enum Failure {
+    /// Reading the request body from the client failed.
+    Client(io::Error),
+    Upstream(io::Error),
+}
+
+#[derive(Debug)]
+struct Route {
+    rule: ProxyRule,
+    upstreams: Vec<Upstream>,
+    next: AtomicUsize,
+}
+
+impl Route {
+    /// The upstreams in rotation, starting with the next one in turn.
+    fn candidates(&self) -> impl Iterator<Item = &Upstream> {
+        let start = self.next.fetch_add(1, Ordering::Relaxed);
+        let now = Instant::now();
+        let count = self.upstreams.len();
+        (0..count)
+            .map(move |i| &self.upstreams[(start + i) % count])
+            .filter(move |upstream| upstream.is_available(now))
+    }
+
+    fn forward(&self, request: &Request, body: &mut dyn Read, config: &Config) -> Response {
+        let rule = &self.rule;
+        for upstream in self.candidates() {
+            // Nothing has been sent yet, so the next upstream can be tried.
+            let stream = match upstream.connect(Duration::from_secs(rule.connect_timeout)) {
+                Ok(stream) => stream,
+                Err(e) => {
+                    warn!("failed to connect to upstream {}: {e}", upstream.address);
+                    upstream.failed(rule);
+                    continue;
+                }
+            };
+            debug!("forwarding {} {} to {}", request.method, request.target, upstream.address);
+
+            return match self.exchange(stream, &upstream.address, request, body) {
+                Ok(response) => {
+                    upstream.succeeded();
+                    response
+                }
+                Err(Failure::Client(e)) => {
+                    debug!("failed to read request body: {e}");
+                    error_response(body::error_status(&e), config)
+                }
+                Err(Failure::Upstream(e)) => {
+                    warn!("upstream {} failed: {e}", upstream.address);
+                    upstream.failed(rule);
+                    match e.kind() {
+                        io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut => {
+                            error_response(Status::GatewayTimeout, config)
+                        }
+                        _ => error_response(Status::BadGateway, config),
+                    }
+                }
+            };
+        }
+
+        warn!("no upstream available for {}", request.target);
+        error_response(Status::BadGateway, config)
+    }
+
+    /// Send `request` and its body to the upstream at `address` over
+    /// `stream`, and read the head of its response. The response body is
+    /// streamed to the client as it is sent.
+    fn exchange(&self, stream: TcpStream, address: &str, request: &Request, body: &mut dyn Read) -> Result<Response, Failure> {
+        let timeout = Some(Duration::from_secs(self.rule.timeout));
+        stream.set_read_timeout(timeout).map_err(Failure::Upstream)?;
+        stream.set_write_timeout(timeout).map_err(Failure::Upstream)?;
+        let reader = stream.try_clone().map_err(Failure::Upstream)?;
+
+        // Validated when the request was read.
+        let framing = request.framing().unwrap_or(Framing::None);
+        let mut writer = BufWriter::new(stream);
+        writer
+            .write_all(self.request_head(request, address, framing).as_bytes())
+            .map_err(Failure::Upstream)?;
+        copy_body(body, &mut writer, framing)?;
+        writer.flush().map_err(Failure::Upstream)?;
+
+        read_response(BufReader::new(reader), request.method == Method::Head).map_err(Failure::Upstream)
+    }
+
+    fn request_head(&self, request: &Request, address: &str, framing: Framing) -> String {
+        let mut target = request.path();
+        if self.rule.strip_prefix {
+            target = target.strip_prefix(self.rule.path.trim_end_matches('/')).unwrap_or(target);
+        }
+        let target = match (target.starts_with('/'), request.query()) {
+            (true, Some(query)) => format!("{target}?{query}"),
+            (false, Some(query)) => format!("/{target}?{query}"),
+            (true, None) => target.to_string(),
+            (false, None) => format!("/{target}"),
+        };
+
+        let mut headers = end_to_end(&request.headers);
+        let client_host = request.headers.get("Host");
+        match client_host {
+            Some(host) if self.rule.preserve_host => headers.set("Host", host),
+            _ => headers.set("Host", address),
+        }
+        if let Some(peer) = request.peer {
+            let forwarded_for = match request.headers.get("X-Forwarded-For") {
+                Some(chain) => format!("{chain}, {}", peer.ip()),
+                None => peer.ip().to_string(),
+            };
+            headers.set("X-Forwarded-For", forwarded_for);
+        }
+        headers.set("X-Forwarded-Proto", if request.secure { "https" } else { "http" });
+        if let Some(host) = client_host {
+            headers.set("X-Forwarded-Host", host);
+        }
+        // One exchange per connection keeps the response framing simple: a
+        // body without a length ends when the upstream closes.
+        headers.set("Connection", "close");
+        match framing {
+            Framing::None => {}
+            Framing::Length(len) => headers.set("Content-Length", len.to_string()),
+            Framing::Chunked => headers.set("Transfer-Encoding", "chunked"),
+        }
+
+        format!("{} {target} HTTP/1.1\r\n{headers}\r\n", request.method)
+    }
+}
+
+/// Forwards requests under the configured path prefixes to upstream HTTP
+/// servers.
+///
+/// Upstreams of a prefix take requests in turn. One that cannot be reached
+/// or fails mid-exchange is passed over for `fail_timeout` seconds once it
+/// has failed `max_fails` times in a row; if it fails before anything was
+/// sent, the request goes to the next one.
+#[derive(Debug, Default)]
+pub struct Proxy {
+    routes: Vec<Route>,
+}
+
+impl Proxy {
+    pub fn new(rules: &[ProxyRule]) -> Proxy {
+        let routes = rules
+            .iter()
+            .map(|rule| Route {
+                rule: rule.clone(),
+                upstreams: rule
+                    .upstreams
+                    .iter()
+                    .map(|address| Upstream { address: address.clone(), health: Mutex::default() })
+                    .collect(),
+                next: AtomicUsize::new(0),
+            })
+            .collect();
+        Proxy { routes }
+    }
+
+    /// Forward `request`, reading its body from `body`, if a rule covers its
+    /// path. Return `None` when no rule does.
+    pub fn forward(&self, request: &Request, body: &mut dyn Read, config: &Config) -> Option<Response> {
+        Some(self.route(request.path())?.forward(request, body, config))
+    }
+
+    /// The rule covering `path`, if any.
+    pub fn rule(&self, path: &str) -> Option<&ProxyRule> {
+        self.route(path).map(|route| &route.rule)
+    }
+
+    fn route(&self, path: &str) -> Option<&Route> {
+        self.routes.iter().find(|route| request::is_under(path, &route.rule.path))
+    }
+}
+
+/// The fields of `headers` that are passed on, without the hop-by-hop ones
+/// and those the `Connection` field names.
+pub(crate) fn end_to_end(headers: &Headers) -> Headers {
+    let named: Vec<&str> = headers
+        .get_all("Connection")
+        .flat_map(|value| value.split(','))
+        .map(str::trim)
+        .collect();
+    let mut forwarded = Headers::new();
+    for (name, value) in headers.iter() {
+        let hop_by_hop = |n: &&str| n.eq_ignore_ascii_case(name);
+        if !HOP_BY_HOP.iter().any(hop_by_hop) && !named.iter().any(hop_by_hop) {
+            forwarded.append(name, value);
+        }
+    }
+    forwarded
+}
+
+/// Copy the request body to the upstream, chunked again if it came chunked.
+fn copy_body<W: Write>(body: &mut dyn Read, upstream: &mut W, framing: Framing) -> Result<(), Failure> {
+    let mut buf = vec![0; 16 * 1024];
+    loop {
+        let read = match body.read(&mut buf) {
+            Ok(0) => break,
+            Ok(read) => read,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+            Err(e) => return Err(Failure::Client(e)),
+        };
+        let sent = match framing {
+            Framing::Chunked => write!(upstream, "{read:X}\r\n")
+                .and_then(|()| upstream.write_all(&buf[..read]))
+                .and_then(|()| upstream.write_all(b"\r\n")),
+            _ => upstream.write_all(&buf[..read]),
+        };
+        sent.map_err(Failure::Upstream)?;
+    }
+    if framing == Framing::Chunked {
+        upstream.write_all(b"0\r\n\r\n").map_err(Failure::Upstream)?;
+    }
+    Ok(())
+}
+
+/// Read the upstream's response head, skipping interim 1xx responses, and
+/// wrap the rest of the stream as the body.
+fn read_response<R: BufRead + Send + 'static>(mut reader: R, head_request: bool) -> io::Result<Response> {
+    let invalid = |message: &str| io::Error::new(io::ErrorKind::InvalidData, message.to_string());
+    let (status, headers) = loop {
+        let line = request::read_line(&mut reader)?;
+        let code = match line.split(' ').collect::<Vec<_>>()[..] {
+            [version, code, ..] if version.starts_with("HTTP/1.") && code.len() == 3 => {
+                code.parse::<u16>().map_err(|_| invalid("malformed status line"))?
+            }
+            _ => return Err(invalid("malformed status line")),
+        };
+        let headers = request::read_headers(&mut reader)?;
+        match code {
+            101 => return Err(invalid("upstream switched protocols")),
+            100..=199 => continue,
+            _ => break (Status::from_code(code), headers),
+        }
+    };
+
+    let length = match headers.get("Content-Length") {
+        Some(length) => Some(request::parse_length(length).ok_or_else(|| invalid("invalid Content-Length"))?),
+        None => None,
+    };
+    let chunked = match headers.get("Transfer-Encoding") {
+        Some(coding) if coding.trim().eq_ignore_ascii_case("chunked") => true,
+        Some(_) => return Err(invalid("unsupported transfer coding")),
+        None => false,
+    };
+
+    let body = if head_request || !status.allows_body() {
+        // Keep the length the upstream reported for a GET, or none when it
+        // reported none; only the head of a HEAD response is written.
+        match length {
+            Some(length) => Body::Stream(Box::new(io::empty()), length),
+            None => Body::Chunked(Box::new(std::iter::empty())),
+        }
+    } else if chunked {
+        Body::from_reader(ChunkedReader::new(reader))
+    } else if let Some(length) = length {
+        Body::Stream(Box::new(reader), length)
+    } else {
+        Body::from_reader(reader)
+    };
+    Ok(Response { status, headers: end_to_end(&headers), body, upgrade: None })
+}
+
+/// Serve one exchange per accepted connection on an ephemeral port,
+/// answering with `handler`, which sees the request head and its body.
+#[cfg(test)]
+pub(crate) fn spawn_upstream<F>(handler: F) -> String
+where
+    F: Fn(&Request, Vec<u8>) -> Response + Send + 'static,
+{
+    use crate::body::BodyReader;
+
+    let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+    let address = listener.local_addr().unwrap().to_string();
+    std::thread::spawn(move || {
+        for stream in listener.incoming() {
+            let Ok(mut stream) = stream else { continue };
+            let mut reader = BufReader::new(stream.try_clone().unwrap());
+            let Ok(request) = Request::read_from(&mut reader) else { continue };
+            let mut body = Vec::new();
+            let framing = request.framing().unwrap();
+            BodyReader::new(&mut reader, framing).read_to_end(&mut body).unwrap();
+            let response = handler(&request, body);
+            let _ = response.write_to(&mut stream, request.method == Method::Head, true);
+        }
+    });
+    address
+}
+
+#[cfg(test)]
+fn request(raw: &str) -> Request {
+    let mut request = Request::read_from(&mut raw.as_bytes()).unwrap();
+    request.peer = Some("192.0.2.7:5000".parse().unwrap());
+    request
+}
+
+#[cfg(test)]
+fn echo(request: &Request, body: Vec<u8>) -> Response {
+    let mut seen = format!("{} {}\n", request.method, request.target);
+    for name in ["Host", "X-Forwarded-For", "X-Forwarded-Proto", "X-Forwarded-Host", "X-Secret", "Connection"] {
+        seen.push_str(&format!("{name}: {}\n", request.headers.get(name).unwrap_or("-")));
+    }
+    seen.push_str(&String::from_utf8(body).unwrap());
+    Response::new(Status::Other(418))
+        .with_header("X-Upstream", "yes")
+        .with_header("Keep-Alive", "timeout=5")
+        .with_body(Body::Text(seen))
+}
+
+#[cfg(test)]
+fn body_text(response: Response) -> String {
+    let mut out = Vec::new();
+    response.write_to(&mut out, false, true).unwrap();
+    let text = String::from_utf8(out).unwrap();
+    let (head, body) = text.split_once("\r\n\r\n").unwrap();
+    if head.contains("Transfer-Encoding: chunked") {
+        let mut decoded = String::new();
+        ChunkedReader::new(body.as_bytes()).read_to_string(&mut decoded).unwrap();
+        decoded
+    } else {
+        body.to_string()
+    }
+}
+
+#[test]
+fn test_forward() {
+    let upstream = spawn_upstream(echo);
+    let mut rule = ProxyRule::new("/api/", vec![upstream.clone()]);
+    rule.strip_prefix = true;
+    let proxy = Proxy::new(&[rule]);
+    let config = Config::default();
+
+    assert!(proxy.forward(&request("GET /apiary HTTP/1.1\r\n\r\n"), &mut io::empty(), &config).is_none());
+
+    let raw = "POST /api/items?page=2 HTTP/1.1\r\nHost: example.com\r\nX-Forwarded-For: 10.0.0.1\r\n\
+               Connection: X-Secret\r\nX-Secret: hop\r\nContent-Length: 5\r\n\r\n";
+    let response = proxy.forward(&request(raw), &mut &b"hello"[..], &config).unwrap();
+    assert_eq!(response.status, Status::Other(418));
+    assert_eq!(response.headers.get("X-Upstream"), Some("yes"));
+    assert!(!response.headers.contains("Keep-Alive"));
+    assert_eq!(body_text(response),
+               format!("POST /items?page=2\nHost: {upstream}\nX-Forwarded-For: 10.0.0.1, 192.0.2.7\n\
+                        X-Forwarded-Proto: http\nX-Forwarded-Host: example.com\nX-Secret: -\n\
+                        Connection: close\nhello"));
+
+    // A chunked request body is passed on chunked.
+    let raw = "PUT /api HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\n";
+    let response = proxy.forward(&request(raw), &mut &b"a whole body"[..], &config).unwrap();
+    assert!(body_text(response).starts_with("PUT /\n"));
+}
+
+#[test]
+fn test_round_robin_and_health() {
+    let first = spawn_upstream(|_, _| Response::new(Status::Ok).with_body(Body::Text("first".to_string())));
+    let second = spawn_upstream(|_, _| Response::new(Status::Ok).with_body(Body::Text("second".to_string())));
+    // Nothing listens on a port just released.
+    let dead = std::net::TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap().to_string();
+
+    let proxy = Proxy::new(&[ProxyRule::new("/", vec![first, dead, second])]);
+    let config = Config::default();
+    let get = || body_text(proxy.forward(&request("GET / HTTP/1.1\r\n\r\n"), &mut io::empty(), &config).unwrap());
+
+    // The dead upstream's turn goes to the next one, and then it is skipped.
+    assert_eq!(get(), "first");
+    assert_eq!(get(), "second");
+    assert_eq!(get(), "second");
+    assert_eq!(get(), "first");
+    assert_eq!(get(), "second");
+    assert_eq!(get(), "second");
+}
+
+#[test]
+fn test_upstream_failures() {
+    let config = Config::default();
+    let dead = std::net::TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap().to_string();
+    let proxy = Proxy::new(&[ProxyRule::new("/", vec![dead])]);
+    let response = proxy.forward(&request("GET / HTTP/1.1\r\n\r\n"), &mut io::empty(), &config).unwrap();
+    assert_eq!(response.status, Status::BadGateway);
+
+    // An upstream that accepts but never answers.
+    let silent = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+    let mut rule = ProxyRule::new("/", vec![silent.local_addr().unwrap().to_string()]);
+    rule.timeout = 1;
+    let proxy = Proxy::new(&[rule]);
+    let response = proxy.forward(&request("GET / HTTP/1.1\r\n\r\n"), &mut io::empty(), &config).unwrap();
+    assert_eq!(response.status, Status::GatewayTimeout);
+}
+
+#[test]
+fn test_read_response() {
+    let raw = "HTTP/1.1 100 Continue\r\n\r\nHTTP/1.1 200 OK\r\nTransfer-Encoding: chunked\r\n\
+               Connection: close\r\n\r\n5\r\nhello\r\n0\r\n\r\n";
+    let response = read_response(io::Cursor::new(raw.as_bytes().to_vec()), false).unwrap();
+    assert_eq!(response.status, Status::Ok);
+    assert!(!response.headers.contains("Connection"));
+    assert_eq!(body_text(response), "hello");
+
+    let raw = "HTTP/1.1 200 OK\r\nContent-Length: 1234\r\n\r\n";
+    let response = read_response(io::Cursor::new(raw.as_bytes().to_vec()), true).unwrap();
+    assert_eq!(response.body.len(), Some(1234));
+
+    let raw = "HTTP/1.1 200 OK\r\nTransfer-Encoding: chunked\r\n\r\n";
+    let response = read_response(io::Cursor::new(raw.as_bytes().to_vec()), true).unwrap();
+    assert_eq!(response.body.len(), None);
+
+    let raw = "HTTP/1.1 200 OK\r\n\r\nuntil the end";
+    let response = read_response(io::Cursor::new(raw.as_bytes().to_vec()), false).unwrap();
+    assert_eq!(body_text(response), "until the end");
+
+    assert!(read_response(io::Cursor::new(b"garbage\r\n\r\n".to_vec()), false).is_err());
+}
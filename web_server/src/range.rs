@@ -0,0 +1,303 @@
+use std::collections::VecDeque;
+use std::io::{self, Read, Seek, SeekFrom};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::cache::Validators;
+use crate::headers::Headers;
+use crate::response::{Body, Response, Status};
+
+/// Requests asking for more ranges than this are answered with the whole
+/// representation, so a client cannot make the server do an unbounded
+/// amount of seeking for one request.
+const MAX_RANGES: usize = 64;
+
+/// An inclusive range of byte offsets, already checked against the length of
+/// the representation.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ByteRange {
+    pub start: u64,
+    pub end: u64,
+}
+
+impl ByteRange {
+    /// The number of bytes in the range, which is never empty.
+    pub fn size(&self) -> u64 {
+        self.end - self.start + 1
+    }
+
+    /// The `Content-Range` value for this range of a `total` byte body.
+    pub fn content_range(&self, total: u64) -> String {
+        format!("bytes {}-{}/{total}", self.start, self.end)
+    }
+}
+
+/// Why a `Range` header cannot be honored.
+#[derive(Debug, PartialEq, Eq)]
+pub enum RangeError {
+    /// The header is malformed, uses another unit or asks for too many
+    /// ranges; it should be ignored and the full body sent.
+    Ignored,
+    /// None of the ranges overlap the body; the answer is a 416.
+    Unsatisfiable,
+}
+
+/// Parse a `Range` header for a body of `len` bytes, following RFC 9110
+/// section 14.1.2. Ranges reaching past the end are shortened, and ranges
+/// lying wholly past it are dropped.
+pub fn parse(header: &str, len: u64) -> Result<Vec<ByteRange>, RangeError> {
+    let (unit, specs) = header.split_once('=').ok_or(RangeError::Ignored)?;
+    if !unit.trim().eq_ignore_ascii_case("bytes") {
+        return Err(RangeError::Ignored);
+    }
+
+    let mut ranges = Vec::new();
+    let mut requested = 0;
+    for spec in specs.split(',').map(str::trim).filter(|s| !s.is_empty()) {
+        requested += 1;
+        if requested > MAX_RANGES {
+            return Err(RangeError::Ignored);
+        }
+
+        let (first, last) = spec.split_once('-').ok_or(RangeError::Ignored)?;
+        let number = |s: &str| s.trim().parse::<u64>().map_err(|_| RangeError::Ignored);
+        let range = match (first.trim(), last.trim()) {
+            ("", suffix) => {
+                let suffix = number(suffix)?;
+                (suffix > 0 && len > 0).then(|| ByteRange {
+                    start: len.saturating_sub(suffix),
+                    end: len - 1,
+                })
+            }
+            (first, "") => {
+                let start = number(first)?;
+                (start < len).then(|| ByteRange { start, end: len - 1 })
+            }
+            (first, last) => {
+                let (start, end) = (number(first)?, number(last)?);
+                if end < start {
+                    return Err(RangeError::Ignored);
+                }
+                (start < len).then(|| ByteRange { start, end: end.min(len - 1) })
+            }
+        };
+        ranges.extend(range);
+    }
+
+    if requested == 0 {
+        Err(RangeError::Ignored)
+    } else if ranges.is_empty() {
+        Err(RangeError::Unsatisfiable)
+    } else {
+        Ok(ranges)
+    }
+}
+
+/// Whether an `If-Range` precondition holds, so the `Range` header may be
+/// honored. An entity tag must match strongly, and a date must equal the
+/// last modification time exactly.
+pub fn if_range_matches(if_range: &str, validators: &Validators) -> bool {
+    let if_range = if_range.trim();
+    if if_range.starts_with('"') || if_range.starts_with("W/") {
+        return !if_range.starts_with("W/")
+            && !validators.etag.starts_with("W/")
+            && if_range == validators.etag;
+    }
+
+    match (httpdate::parse_http_date(if_range), validators.last_modified) {
+        (Ok(date), Some(modified)) => {
+            let seconds = |t: SystemTime| t.duration_since(UNIX_EPOCH).map_or(0, |d| d.as_secs());
+            seconds(date) == seconds(modified)
+        }
+        _ => false,
+    }
+}
+
+/// Turn `response`, a 200 whose body is `content` of `total` bytes, into
+/// the answer to a request for `ranges`.
+///
+/// One range becomes a plain 206 with a `Content-Range`; several become a
+/// `multipart/byteranges` body with one part per range.
+pub fn partial_response<R>(mut response: Response, mut content: R, total: u64, ranges: &[ByteRange]) -> io::Result<Response>
+where
+    R: Read + Seek + Send + 'static,
+{
+    response.status = Status::PartialContent;
+
+    if let [range] = ranges {
+        content.seek(SeekFrom::Start(range.start))?;
+        response.headers.set("Content-Range", range.content_range(total));
+        response.body = Body::Stream(Box::new(content), range.size());
+        return Ok(response);
+    }
+
+    let boundary = boundary();
+    let content_type = response
+        .headers
+        .get("Content-Type")
+        .unwrap_or("application/octet-stream")
+        .to_string();
+
+    let mut parts = VecDeque::new();
+    let mut len = 0;
+    for (i, range) in ranges.iter().enumerate() {
+        let mut part_head = Headers::new();
+        part_head.set("Content-Type", content_type.as_str());
+        part_head.set("Content-Range", range.content_range(total));
+        let delimiter = if i == 0 { "" } else { "\r\n" };
+        let head = format!("{delimiter}--{boundary}\r\n{part_head}\r\n").into_bytes();
+
+        len += head.len() as u64 + range.size();
+        parts.push_back(Part::Bytes(io::Cursor::new(head)));
+        parts.push_back(Part::Range { range: *range, positioned: false, remaining: range.size() });
+    }
+    let close = format!("\r\n--{boundary}--\r\n").into_bytes();
+    len += close.len() as u64;
+    parts.push_back(Part::Bytes(io::Cursor::new(close)));
+
+    response.headers.remove("Content-Range");
+    response.headers.set("Content-Type", format!("multipart/byteranges; boundary={boundary}"));
+    response.body = Body::Stream(Box::new(Multipart { content, parts }), len);
+    Ok(response)
+}
+
+/// The 416 answer to a request none of whose ranges overlap a body of
+/// `total` bytes.
+pub fn unsatisfiable(mut response: Response, total: u64) -> Response {
+    response.status = Status::RangeNotSatisfiable;
+    response.headers.set("Content-Range", format!("bytes */{total}"));
+    response
+}
+
+/// A boundary string that is unique within this process and very unlikely
+/// to occur in any served file.
+fn boundary() -> String {
+    static COUNTER: AtomicU64 = AtomicU64::new(0);
+    let nanos = SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |d| d.subsec_nanos());
+    format!("web_server_{:08x}{:08x}", nanos, COUNTER.fetch_add(1, Ordering::Relaxed))
+}
+
+enum Part {
+    Bytes(io::Cursor<Vec<u8>>),
+    Range { range: ByteRange, positioned: bool, remaining: u64 },
+}
+
+/// Yields the parts of a `multipart/byteranges` body, seeking in `content`
+/// to the start of each range as it is reached.
+struct Multipart<R> {
+    content: R,
+    parts: VecDeque<Part>,
+}
+
+impl<R: Read + Seek> Read for Multipart<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        while let Some(part) = self.parts.front_mut() {
+            let read = match part {
+                Part::Bytes(bytes) => bytes.read(buf)?,
+                Part::Range { range, positioned, remaining } => {
+                    if !*positioned {
+                        self.content.seek(SeekFrom::Start(range.start))?;
+                        *positioned = true;
+                    }
+                    let limit = buf.len().min(usize::try_from(*remaining).unwrap_or(usize::MAX));
+                    let read = self.content.read(&mut buf[..limit])?;
+                    if read == 0 && *remaining > 0 {
+                        return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "file shrank while being sent"));
+                    }
+                    *remaining -= read as u64;
+                    read
+                }
+            };
+            if read > 0 || buf.is_empty() {
+                return Ok(read);
+            }
+            self.parts.pop_front();
+        }
+        Ok(0)
+    }
+}
+
+#[test]
+fn test_parse_ranges() {
+    let range = |start, end| ByteRange { start, end };
+
+    assert_eq!(parse("bytes=0-499", 10_000), Ok(vec![range(0, 499)]));
+    assert_eq!(parse("bytes=9500-", 10_000), Ok(vec![range(9500, 9999)]));
+    assert_eq!(parse("bytes=-500", 10_000), Ok(vec![range(9500, 9999)]));
+    assert_eq!(parse("bytes=-20000", 10_000), Ok(vec![range(0, 9999)]));
+    assert_eq!(parse("bytes=9000-20000", 10_000), Ok(vec![range(9000, 9999)]));
+    assert_eq!(parse("bytes=0-0, -1", 10_000), Ok(vec![range(0, 0), range(9999, 9999)]));
+    assert_eq!(parse("Bytes = 1-2 ,, 20000-", 10_000), Ok(vec![range(1, 2)]));
+}
+
+#[test]
+fn test_parse_bad_ranges() {
+    assert_eq!(parse("bytes=20000-", 10_000), Err(RangeError::Unsatisfiable));
+    assert_eq!(parse("bytes=-0", 10_000), Err(RangeError::Unsatisfiable));
+    assert_eq!(parse("bytes=0-", 0), Err(RangeError::Unsatisfiable));
+    assert_eq!(parse("bytes=5-1", 10_000), Err(RangeError::Ignored));
+    assert_eq!(parse("bytes=a-b", 10_000), Err(RangeError::Ignored));
+    assert_eq!(parse("bytes=", 10_000), Err(RangeError::Ignored));
+    assert_eq!(parse("items=0-1", 10_000), Err(RangeError::Ignored));
+    assert_eq!(parse(&format!("bytes={}", "0-0,".repeat(MAX_RANGES + 1)), 10_000), Err(RangeError::Ignored));
+}
+
+#[test]
+fn test_if_range() {
+    let validators = Validators {
+        etag: "\"a-1\"".to_string(),
+        last_modified: Some(UNIX_EPOCH + std::time::Duration::from_millis(784_111_777_250)),
+    };
+
+    assert!(if_range_matches("\"a-1\"", &validators));
+    assert!(!if_range_matches("W/\"a-1\"", &validators));
+    assert!(!if_range_matches("\"a-2\"", &validators));
+    assert!(if_range_matches("Sun, 06 Nov 1994 08:49:37 GMT", &validators));
+    assert!(!if_range_matches("Sun, 06 Nov 1994 08:49:38 GMT", &validators));
+    assert!(!if_range_matches("not a date", &validators));
+}
+
+#[cfg(test)]
+fn read_body(response: Response) -> Vec<u8> {
+    let mut out = Vec::new();
+    if let Body::Stream(mut reader, len) = response.body {
+        reader.by_ref().take(len).read_to_end(&mut out).unwrap();
+        assert_eq!(out.len() as u64, len);
+    }
+    out
+}
+
+#[test]
+fn test_single_range_response() {
+    let content = io::Cursor::new(b"0123456789".to_vec());
+    let response = Response::new(Status::Ok).with_header("Content-Type", "text/plain");
+
+    let response = partial_response(response, content, 10, &[ByteRange { start: 2, end: 5 }]).unwrap();
+    assert_eq!(response.status, Status::PartialContent);
+    assert_eq!(response.headers.get("Content-Range"), Some("bytes 2-5/10"));
+    assert_eq!(read_body(response), b"2345");
+}
+
+#[test]
+fn test_multipart_range_response() {
+    let content = io::Cursor::new(b"0123456789".to_vec());
+    let response = Response::new(Status::Ok).with_header("Content-Type", "text/plain");
+    let ranges = [ByteRange { start: 0, end: 1 }, ByteRange { start: 8, end: 9 }];
+
+    let response = partial_response(response, content, 10, &ranges).unwrap();
+    let content_type = response.headers.get("Content-Type").unwrap().to_string();
+    let boundary = content_type.strip_prefix("multipart/byteranges; boundary=").unwrap();
+
+    let expected = format!("--{boundary}\r\n\
+                            Content-Type: text/plain\r\n\
+                            Content-Range: bytes 0-1/10\r\n\
+                            \r\n\
+                            01\r\n\
+                            --{boundary}\r\n\
+                            Content-Type: text/plain\r\n\
+                            Content-Range: bytes 8-9/10\r\n\
+                            \r\n\
+                            89\r\n\
+                            --{boundary}--\r\n");
+    assert_eq!(String::from_utf8(read_body(response)).unwrap(), expected);
+}
@@ -0,0 +1,265 @@
+use std::collections::HashMap;
+use std::io;
+use std::mem;
+use std::os::fd::AsRawFd;
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use log::{debug, warn};
+use mio::unix::SourceFd;
+use mio::{Events, Interest, Poll, Token, Waker};
+
+use crate::connection::Connection;
+use crate::http2;
+use crate::pool::ThreadPool;
+use crate::server::{admit, open_connection, serve_request, Control, Listener, Outcome};
+
+const WAKER: Token = Token(usize::MAX);
+
+/// How often idle connections are checked against their deadlines, failed
+/// accepts retried, and the server checked for stopping.
+const SWEEP_INTERVAL: Duration = Duration::from_millis(500);
+
+/// Accept connections on `listeners` and watch every open connection that
+/// is between requests, handing it to `pool` once the client sends
+/// something.
+///
+/// A worker serves requests on a connection only while they are already
+/// waiting; then it parks the connection back here. Holding an idle
+/// connection costs a map entry rather than a thread.
+///
+/// Once the server stops, close the listeners and the idle connections and
+/// return; connections being served close after their current request.
+pub(crate) fn run(listeners: Vec<Listener>, pool: Arc<ThreadPool>, control: Arc<Control>) -> io::Result<()> {
+    let poll = Poll::new()?;
+    let waker = Arc::new(Waker::new(poll.registry(), WAKER)?);
+    let (sender, parked) = mpsc::channel();
+
+    for (i, listener) in listeners.iter().enumerate() {
+        listener.socket.set_nonblocking(true)?;
+        poll.registry()
+            .register(&mut SourceFd(&listener.socket.as_raw_fd()), Token(i), Interest::READABLE)?;
+        listener.log_address();
+    }
+
+    let mut reactor = Reactor {
+        poll,
+        next_token: listeners.len(),
+        listeners,
+        idle: HashMap::new(),
+        parked,
+        parker: Parker { sender, waker },
+        pool,
+        control,
+    };
+    reactor.run()
+}
+
+/// A connection waiting for its next request.
+struct Idle {
+    connection: Connection,
+    deadline: Instant,
+}
+
+/// Hands connections that are between requests back to the event loop.
+#[derive(Clone)]
+struct Parker {
+    sender: Sender<Connection>,
+    waker: Arc<Waker>,
+}
+
+impl Parker {
+    fn park(&self, connection: Connection) {
+        if self.sender.send(connection).is_ok() {
+            if let Err(e) = self.waker.wake() {
+                warn!("failed to wake the event loop: {e}");
+            }
+        }
+    }
+}
+
+struct Reactor {
+    poll: Poll,
+    listeners: Vec<Listener>,
+    idle: HashMap<Token, Idle>,
+    next_token: usize,
+    parked: Receiver<Connection>,
+    parker: Parker,
+    pool: Arc<ThreadPool>,
+    control: Arc<Control>,
+}
+
+impl Reactor {
+    fn run(&mut self) -> io::Result<()> {
+        let mut events = Events::with_capacity(1024);
+        let mut last_sweep = Instant::now();
+        let mut retry_accept = false;
+
+        loop {
+            if let Err(e) = self.poll.poll(&mut events, Some(SWEEP_INTERVAL)) {
+                if e.kind() == io::ErrorKind::Interrupted {
+                    continue;
+                }
+                return Err(e);
+            }
+
+            for event in &events {
+                match event.token() {
+                    WAKER => {
+                        let keep_alive = self.control.context().config.keep_alive_timeout;
+                        while let Ok(connection) = self.parked.try_recv() {
+                            self.watch(connection, keep_alive);
+                        }
+                    }
+                    Token(i) if i < self.listeners.len() => retry_accept |= !self.accept(i),
+                    token => {
+                        if let Some(idle) = self.idle.remove(&token) {
+                            self.unwatch(&idle.connection);
+                            self.dispatch(idle.connection);
+                        }
+                    }
+                }
+            }
+
+            if last_sweep.elapsed() >= SWEEP_INTERVAL {
+                last_sweep = Instant::now();
+                if self.control.is_stopping() {
+                    self.stop();
+                    return Ok(());
+                }
+                self.close_expired(last_sweep);
+                if retry_accept {
+                    retry_accept = false;
+                    for i in 0..self.listeners.len() {
+                        retry_accept |= !self.accept(i);
+                    }
+                }
+            }
+        }
+    }
+
+    /// Accept every pending connection on listener `i`. Return false if
+    /// accepting failed, in which case connections may still be pending.
+    fn accept(&mut self, i: usize) -> bool {
+        loop {
+            let listener = &self.listeners[i];
+            match listener.socket.accept() {
+                Ok((stream, _)) => {
+                    // The event loop only waits for readiness; workers use
+                    // blocking reads and writes with timeouts.
+                    if let Err(e) = stream.set_nonblocking(false) {
+                        warn!("failed to set up connection: {e}");
+                        continue;
+                    }
+                    let context = self.control.context();
+                    let Ok(permit) = admit(&stream, listener.tls.is_some(), &context) else {
+                        continue;
+                    };
+                    if let Some(connection) = open_connection(stream, listener.tls.clone(), permit) {
+                        self.watch(connection, context.config.read_timeout);
+                    }
+                }
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => return true,
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+                Err(e) => {
+                    // Errors such as running out of file descriptors persist
+                    // for a while; retry at the next sweep.
+                    warn!("failed to accept connection: {e}");
+                    return false;
+                }
+            }
+        }
+    }
+
+    /// Wait for `connection` to become readable, closing it if that takes
+    /// longer than `timeout`.
+    fn watch(&mut self, connection: Connection, timeout: Duration) {
+        let token = Token(self.next_token);
+        self.next_token += 1;
+
+        let fd = connection.socket().as_raw_fd();
+        if let Err(e) = self.poll.registry().register(&mut SourceFd(&fd), token, Interest::READABLE) {
+            warn!("failed to watch connection: {e}");
+            connection.close();
+            return;
+        }
+        let deadline = Instant::now() + timeout;
+        self.idle.insert(token, Idle { connection, deadline });
+    }
+
+    fn unwatch(&self, connection: &Connection) {
+        let fd = connection.socket().as_raw_fd();
+        if let Err(e) = self.poll.registry().deregister(&mut SourceFd(&fd)) {
+            debug!("failed to stop watching connection: {e}");
+        }
+    }
+
+    /// Serve the requests waiting on `connection` on the pool, then park it
+    /// back here if the client may send more.
+    fn dispatch(&self, mut connection: Connection) {
+        let context = self.control.context();
+        let parker = self.parker.clone();
+        self.pool.execute(move || {
+            loop {
+                match serve_request(&mut connection, &context) {
+                    Outcome::KeepAlive if connection.has_buffered() => {}
+                    Outcome::KeepAlive => return parker.park(connection),
+                    // Upgraded protocols and event streams are not
+                    // request-response, so they get a thread of their own
+                    // rather than a worker, while there are threads to
+                    // spare. Otherwise the connection is dropped, and so
+                    // closed.
+                    Outcome::Upgrade(upgrade) => {
+                        if !context.stream_threads.spawn(move || upgrade(connection)) {
+                            warn!("too many stream threads, closing connection");
+                        }
+                        return;
+                    }
+                    Outcome::Http2 => {
+                        let threads = Arc::clone(&context.stream_threads);
+                        if !threads.spawn(move || http2::serve(connection, &context)) {
+                            warn!("too many stream threads, closing HTTP/2 connection");
+                        }
+                        return;
+                    }
+                    Outcome::Close => break,
+                }
+            }
+            connection.close();
+        });
+    }
+
+    /// Close the listeners and every idle connection. Connections parked
+    /// after this are dropped, and so closed, with the channel they are
+    /// sent on.
+    fn stop(&mut self) {
+        for listener in self.listeners.drain(..) {
+            if let Err(e) = self.poll.registry().deregister(&mut SourceFd(&listener.socket.as_raw_fd())) {
+                debug!("failed to stop watching listener: {e}");
+            }
+        }
+        for idle in mem::take(&mut self.idle).into_values() {
+            self.unwatch(&idle.connection);
+            idle.connection.close();
+        }
+    }
+
+    fn close_expired(&mut self, now: Instant) {
+        let expired: Vec<Token> = self
+            .idle
+            .iter()
+            .filter(|(_, idle)| idle.deadline <= now)
+            .map(|(token, _)| *token)
+            .collect();
+        for token in expired {
+            if let Some(idle) = self.idle.remove(&token) {
+                self.unwatch(&idle.connection);
+                if let Some(peer) = idle.connection.peer() {
+                    debug!("{peer}: closing idle connection");
+                }
+                idle.connection.close();
+            }
+        }
+    }
+}
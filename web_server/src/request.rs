@@ -0,0 +1,307 @@
+use std::fmt;
+use std::io::{BufRead, Read};
+use std::net::SocketAddr;
+
+use crate::error::Error;
+use crate::headers::Headers;
+
+/// The longest request line or header field accepted, in bytes.
+const MAX_LINE: usize = 8 * 1024;
+
+/// The most header fields accepted in one request.
+const MAX_HEADERS: usize = 100;
+
+/// The request method, as sent on the request line.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Method {
+    Get,
+    Head,
+    Post,
+    Put,
+    Delete,
+    Options,
+    Patch,
+    Other(String),
+}
+
+impl Method {
+    pub fn parse(s: &str) -> Method {
+        match s {
+            "GET" => Method::Get,
+            "HEAD" => Method::Head,
+            "POST" => Method::Post,
+            "PUT" => Method::Put,
+            "DELETE" => Method::Delete,
+            "OPTIONS" => Method::Options,
+            "PATCH" => Method::Patch,
+            other => Method::Other(other.to_string()),
+        }
+    }
+
+    pub fn as_str(&self) -> &str {
+        match self {
+            Method::Get => "GET",
+            Method::Head => "HEAD",
+            Method::Post => "POST",
+            Method::Put => "PUT",
+            Method::Delete => "DELETE",
+            Method::Options => "OPTIONS",
+            Method::Patch => "PATCH",
+            Method::Other(s) => s,
+        }
+    }
+}
+
+impl fmt::Display for Method {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+/// An HTTP/1.x request head: the request line and the header fields.
+#[derive(Debug, Clone)]
+pub struct Request {
+    pub method: Method,
+    pub target: String,
+    pub version: String,
+    pub headers: Headers,
+    /// The client's address, when the request came over a connection.
+    pub peer: Option<SocketAddr>,
+    /// Whether the request came over TLS.
+    pub secure: bool,
+    /// The authenticated user, once authentication middleware has checked
+    /// the request's credentials.
+    pub user: Option<String>,
+}
+
+impl Request {
+    /// Read a request head from `reader`, stopping after the empty line that
+    /// ends the header section.
+    pub fn read_from<R: BufRead>(reader: &mut R) -> Result<Request, Error> {
+        let request_line = read_line(reader)?;
+        let mut parts = request_line.split(' ');
+        let (method, target, version) = match (parts.next(), parts.next(), parts.next(), parts.next()) {
+            (Some(m), Some(t), Some(v), None) if !m.is_empty() && !t.is_empty() => (m, t, v),
+            _ => return Err(Error::BadRequest("malformed request line")),
+        };
+        if !version.starts_with("HTTP/") {
+            return Err(Error::BadRequest("malformed request line"));
+        }
+        // The start of the HTTP/2 connection preface reads as a request;
+        // the server decides whether to go on with it.
+        let preface = method == "PRI" && target == "*" && version == "HTTP/2.0";
+        if version != "HTTP/1.1" && version != "HTTP/1.0" && !preface {
+            return Err(Error::UnsupportedVersion(version.to_string()));
+        }
+
+        Ok(Request {
+            method: Method::parse(method),
+            target: target.to_string(),
+            version: version.to_string(),
+            headers: read_headers(reader)?,
+            peer: None,
+            secure: false,
+            user: None,
+        })
+    }
+
+    /// How the request body is delimited, following RFC 9112 section 6.3.
+    ///
+    /// A request with both `Transfer-Encoding` and `Content-Length` is
+    /// rejected rather than guessed at, since a proxy in front may have
+    /// picked the other one.
+    pub fn framing(&self) -> Result<Framing, Error> {
+        let lengths: Vec<&str> = self.headers.get_all("Content-Length").collect();
+        if self.headers.contains("Transfer-Encoding") {
+            if !lengths.is_empty() {
+                return Err(Error::BadRequest("both Transfer-Encoding and Content-Length"));
+            }
+            let codings: Vec<&str> = self.headers.get_all("Transfer-Encoding").flat_map(|v| v.split(',')).collect();
+            return match codings[..] {
+                [coding] if coding.trim().eq_ignore_ascii_case("chunked") => Ok(Framing::Chunked),
+                _ => Err(Error::UnsupportedTransferCoding(codings.join(",").trim().to_string())),
+            };
+        }
+        match lengths[..] {
+            [] => Ok(Framing::None),
+            [length, ref rest @ ..] if rest.iter().all(|other| other == &length) => {
+                match parse_length(length) {
+                    Some(0) => Ok(Framing::None),
+                    Some(length) => Ok(Framing::Length(length)),
+                    None => Err(Error::BadRequest("invalid Content-Length")),
+                }
+            }
+            _ => Err(Error::BadRequest("conflicting Content-Length values")),
+        }
+    }
+
+    /// The path component of the request target, without the query string.
+    pub fn path(&self) -> &str {
+        match self.target.split_once('?') {
+            Some((path, _)) => path,
+            None => &self.target,
+        }
+    }
+
+    /// The query string of the request target, if there is one.
+    pub fn query(&self) -> Option<&str> {
+        self.target.split_once('?').map(|(_, query)| query)
+    }
+
+    /// The host name from the `Host` header, without its port or a
+    /// trailing dot. IPv6 literals keep their brackets.
+    pub fn host(&self) -> Option<&str> {
+        let host = self.headers.get("Host")?;
+        let name = match host.rsplit_once(':') {
+            Some((name, port)) if !port.contains(']') => name,
+            _ => host,
+        };
+        Some(name.strip_suffix('.').unwrap_or(name))
+    }
+}
+
+/// Whether `path` is `prefix` or lies below it, comparing whole segments:
+/// `/admin` and `/admin/` both cover `/admin` and `/admin/x`, but not
+/// `/administrator`.
+pub fn is_under(path: &str, prefix: &str) -> bool {
+    let prefix = prefix.trim_end_matches('/');
+    match path.strip_prefix(prefix) {
+        Some(rest) => prefix.is_empty() || rest.is_empty() || rest.starts_with('/'),
+        None => false,
+    }
+}
+
+/// How a message body is delimited.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Framing {
+    /// There is no body.
+    None,
+    /// The body is exactly this many bytes.
+    Length(u64),
+    /// The body is sent in chunks, ending with an empty one.
+    Chunked,
+}
+
+/// Parse a `Content-Length` value, which is only ever digits. `u64`'s own
+/// parsing would also take a leading `+`, which a proxy in front might
+/// reject or read differently.
+pub(crate) fn parse_length(value: &str) -> Option<u64> {
+    let value = value.trim();
+    if value.is_empty() || !value.bytes().all(|b| b.is_ascii_digit()) {
+        return None;
+    }
+    value.parse().ok()
+}
+
+/// Read header fields up to and including the empty line that ends them.
+pub(crate) fn read_headers<R: BufRead>(reader: &mut R) -> Result<Headers, Error> {
+    let mut headers = Headers::new();
+    loop {
+        let line = read_line(reader)?;
+        if line.is_empty() {
+            return Ok(headers);
+        }
+        if headers.len() == MAX_HEADERS {
+            return Err(Error::HeadersTooLarge);
+        }
+        match line.split_once(':') {
+            Some((name, value)) if !name.is_empty() && !name.ends_with(' ') => {
+                headers.append(name, value.trim());
+            }
+            _ => return Err(Error::BadRequest("malformed header field")),
+        }
+    }
+}
+
+/// Read one CRLF (or bare LF) terminated line, without the terminator.
+///
+/// Lines longer than `MAX_LINE` bytes are rejected rather than buffered, so
+/// a client cannot make the server allocate without bound.
+pub(crate) fn read_line<R: BufRead>(reader: &mut R) -> Result<String, Error> {
+    let mut line = Vec::new();
+    let read = reader.take(MAX_LINE as u64 + 1).read_until(b'\n', &mut line)?;
+    if read == 0 {
+        return Err(Error::ConnectionClosed);
+    }
+    if line.last() != Some(&b'\n') {
+        return if read > MAX_LINE {
+            Err(Error::HeadersTooLarge)
+        } else {
+            Err(Error::ConnectionClosed)
+        };
+    }
+    line.pop();
+    if line.last() == Some(&b'\r') {
+        line.pop();
+    }
+    String::from_utf8(line).map_err(|_| Error::BadRequest("request head is not valid UTF-8"))
+}
+
+#[test]
+fn test_read_request() {
+    let raw = b"GET /index.html?lang=id HTTP/1.1\r\nHost: localhost\r\nAccept:  */*\r\n\r\n";
+    let request = Request::read_from(&mut &raw[..]).unwrap();
+    assert_eq!(request.method, Method::Get);
+    assert_eq!(request.path(), "/index.html");
+    assert_eq!(request.query(), Some("lang=id"));
+    assert_eq!(request.version, "HTTP/1.1");
+    assert_eq!(request.headers.get("host"), Some("localhost"));
+    assert_eq!(request.headers.get("accept"), Some("*/*"));
+}
+
+#[test]
+fn test_read_http2_preface() {
+    let mut raw = &b"PRI * HTTP/2.0\r\n\r\nSM\r\n\r\n"[..];
+    let request = Request::read_from(&mut raw).unwrap();
+    assert_eq!(request.version, "HTTP/2.0");
+    assert_eq!(raw, b"SM\r\n\r\n");
+}
+
+#[test]
+fn test_read_malformed_request() {
+    let parse = |raw: &[u8]| Request::read_from(&mut &raw[..]).unwrap_err();
+
+    assert!(matches!(parse(b"GET /\r\n\r\n"), Error::BadRequest(_)));
+    assert!(matches!(parse(b"GET / HTTP/1.1\r\nno colon\r\n\r\n"), Error::BadRequest(_)));
+    assert!(matches!(parse(b"GET / HTTP/2.0\r\n\r\n"), Error::UnsupportedVersion(_)));
+    assert!(matches!(parse(b"PRI / HTTP/2.0\r\n\r\n"), Error::UnsupportedVersion(_)));
+    assert!(matches!(parse(b""), Error::ConnectionClosed));
+    assert!(matches!(parse(b"GET / HTTP/1.1\r\nHost: loc"), Error::ConnectionClosed));
+}
+
+#[test]
+fn test_read_oversized_request() {
+    let long_line = format!("GET /{} HTTP/1.1\r\n\r\n", "a".repeat(MAX_LINE));
+    assert!(matches!(Request::read_from(&mut long_line.as_bytes()), Err(Error::HeadersTooLarge)));
+
+    let many_headers = format!("GET / HTTP/1.1\r\n{}\r\n", "X-A: b\r\n".repeat(MAX_HEADERS + 1));
+    assert!(matches!(Request::read_from(&mut many_headers.as_bytes()), Err(Error::HeadersTooLarge)));
+}
+
+#[test]
+fn test_is_under() {
+    assert!(is_under("/admin", "/admin/"));
+    assert!(is_under("/admin/users", "/admin"));
+    assert!(!is_under("/administrator", "/admin"));
+    assert!(is_under("/anything", "/"));
+}
+
+#[test]
+fn test_framing() {
+    let framing = |headers: &str| {
+        let raw = format!("POST / HTTP/1.1\r\n{headers}\r\n");
+        Request::read_from(&mut raw.as_bytes()).unwrap().framing()
+    };
+
+    assert_eq!(framing("").unwrap(), Framing::None);
+    assert_eq!(framing("Content-Length: 0\r\n").unwrap(), Framing::None);
+    assert_eq!(framing("Content-Length: 42\r\n").unwrap(), Framing::Length(42));
+    assert_eq!(framing("Content-Length: 42\r\nContent-Length: 42\r\n").unwrap(), Framing::Length(42));
+    assert_eq!(framing("Transfer-Encoding: Chunked\r\n").unwrap(), Framing::Chunked);
+
+    assert!(matches!(framing("Content-Length: -1\r\n"), Err(Error::BadRequest(_))));
+    assert!(matches!(framing("Content-Length: +5\r\n"), Err(Error::BadRequest(_))));
+    assert!(matches!(framing("Content-Length: 1\r\nContent-Length: 2\r\n"), Err(Error::BadRequest(_))));
+    assert!(matches!(framing("Transfer-Encoding: chunked\r\nContent-Length: 5\r\n"), Err(Error::BadRequest(_))));
+    assert!(matches!(framing("Transfer-Encoding: gzip, chunked\r\n"), Err(Error::UnsupportedTransferCoding(_))));
+}
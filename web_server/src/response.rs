@@ -0,0 +1,444 @@
+use std::fs::File;
+use std::io::{self, Read, Write};
+use std::time::SystemTime;
+
+use crate::connection::Connection;
+use crate::headers::Headers;
+
+/// The most bytes read from a reader for one chunk of a chunked body.
+const CHUNK_SIZE: usize = 16 * 1024;
+
+/// The status codes this server knows how to send.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Status {
+    SwitchingProtocols,
+    Ok,
+    Created,
+    NoContent,
+    PartialContent,
+    MovedPermanently,
+    Found,
+    NotModified,
+    BadRequest,
+    Unauthorized,
+    Forbidden,
+    NotFound,
+    MethodNotAllowed,
+    RequestTimeout,
+    PayloadTooLarge,
+    UnsupportedMediaType,
+    RangeNotSatisfiable,
+    UpgradeRequired,
+    TooManyRequests,
+    RequestHeaderFieldsTooLarge,
+    InternalServerError,
+    NotImplemented,
+    BadGateway,
+    ServiceUnavailable,
+    GatewayTimeout,
+    HttpVersionNotSupported,
+    /// Any other code, such as one relayed from an upstream server.
+    Other(u16),
+}
+
+impl Status {
+    pub fn code(&self) -> u16 {
+        match self {
+            Status::SwitchingProtocols => 101,
+            Status::Ok => 200,
+            Status::Created => 201,
+            Status::NoContent => 204,
+            Status::PartialContent => 206,
+            Status::MovedPermanently => 301,
+            Status::Found => 302,
+            Status::NotModified => 304,
+            Status::BadRequest => 400,
+            Status::Unauthorized => 401,
+            Status::Forbidden => 403,
+            Status::NotFound => 404,
+            Status::MethodNotAllowed => 405,
+            Status::RequestTimeout => 408,
+            Status::PayloadTooLarge => 413,
+            Status::UnsupportedMediaType => 415,
+            Status::RangeNotSatisfiable => 416,
+            Status::UpgradeRequired => 426,
+            Status::TooManyRequests => 429,
+            Status::RequestHeaderFieldsTooLarge => 431,
+            Status::InternalServerError => 500,
+            Status::NotImplemented => 501,
+            Status::BadGateway => 502,
+            Status::ServiceUnavailable => 503,
+            Status::GatewayTimeout => 504,
+            Status::HttpVersionNotSupported => 505,
+            Status::Other(code) => *code,
+        }
+    }
+
+    pub fn reason(&self) -> &'static str {
+        match self {
+            Status::SwitchingProtocols => "Switching Protocols",
+            Status::Ok => "OK",
+            Status::Created => "Created",
+            Status::NoContent => "No Content",
+            Status::PartialContent => "Partial Content",
+            Status::MovedPermanently => "Moved Permanently",
+            Status::Found => "Found",
+            Status::NotModified => "Not Modified",
+            Status::BadRequest => "Bad Request",
+            Status::Unauthorized => "Unauthorized",
+            Status::Forbidden => "Forbidden",
+            Status::NotFound => "Not Found",
+            Status::MethodNotAllowed => "Method Not Allowed",
+            Status::RequestTimeout => "Request Timeout",
+            Status::PayloadTooLarge => "Content Too Large",
+            Status::UnsupportedMediaType => "Unsupported Media Type",
+            Status::RangeNotSatisfiable => "Range Not Satisfiable",
+            Status::UpgradeRequired => "Upgrade Required",
+            Status::TooManyRequests => "Too Many Requests",
+            Status::RequestHeaderFieldsTooLarge => "Request Header Fields Too Large",
+            Status::InternalServerError => "Internal Server Error",
+            Status::NotImplemented => "Not Implemented",
+            Status::BadGateway => "Bad Gateway",
+            Status::ServiceUnavailable => "Service Unavailable",
+            Status::GatewayTimeout => "Gateway Timeout",
+            Status::HttpVersionNotSupported => "HTTP Version Not Supported",
+            Status::Other(_) => "",
+        }
+    }
+
+    /// The status for `code`, using the named variant when there is one.
+    pub fn from_code(code: u16) -> Status {
+        const KNOWN: [Status; 26] = [
+            Status::SwitchingProtocols, Status::Ok, Status::Created, Status::NoContent,
+            Status::PartialContent, Status::MovedPermanently, Status::Found, Status::NotModified,
+            Status::BadRequest, Status::Unauthorized, Status::Forbidden, Status::NotFound,
+            Status::MethodNotAllowed, Status::RequestTimeout, Status::PayloadTooLarge,
+            Status::UnsupportedMediaType, Status::RangeNotSatisfiable, Status::UpgradeRequired,
+            Status::TooManyRequests, Status::RequestHeaderFieldsTooLarge, Status::InternalServerError,
+            Status::NotImplemented, Status::BadGateway, Status::ServiceUnavailable, Status::GatewayTimeout,
+            Status::HttpVersionNotSupported,
+        ];
+        KNOWN.into_iter().find(|status| status.code() == code).unwrap_or(Status::Other(code))
+    }
+
+    /// 1xx, 204 and 304 responses never carry a body, even for GET.
+    pub fn allows_body(&self) -> bool {
+        !matches!(self.code(), 100..=199 | 204 | 304)
+    }
+}
+
+/// The payload of a response.
+pub enum Body {
+    Empty,
+    Bytes(Vec<u8>),
+    Text(String),
+    /// A reader, usually an open file, that yields exactly `len` bytes.
+    Stream(Box<dyn Read + Send>, u64),
+    /// A body of unknown length, sent with `Transfer-Encoding: chunked`, or
+    /// to clients without chunked coding as is, ended by closing the
+    /// connection.
+    ///
+    /// An error ends the response without the final empty chunk, so the
+    /// client can tell the body is incomplete.
+    Chunked(Box<dyn Iterator<Item = io::Result<Vec<u8>>> + Send>),
+}
+
+impl Body {
+    /// Stream the whole of `file`, using its current size as the length.
+    pub fn from_file(file: File) -> io::Result<Body> {
+        let len = file.metadata()?.len();
+        Ok(Body::Stream(Box::new(file), len))
+    }
+
+    /// Stream everything `reader` yields, in chunks, when its length is not
+    /// known in advance.
+    pub fn from_reader<R: Read + Send + 'static>(mut reader: R) -> Body {
+        let mut done = false;
+        Body::Chunked(Box::new(std::iter::from_fn(move || {
+            if done {
+                return None;
+            }
+            let mut chunk = vec![0; CHUNK_SIZE];
+            match reader.read(&mut chunk) {
+                Ok(0) => None,
+                Ok(n) => {
+                    chunk.truncate(n);
+                    Some(Ok(chunk))
+                }
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => Some(Ok(Vec::new())),
+                Err(e) => {
+                    done = true;
+                    Some(Err(e))
+                }
+            }
+        })))
+    }
+
+    /// The body length, or `None` when it is only known once fully sent.
+    pub fn len(&self) -> Option<u64> {
+        match self {
+            Body::Empty => Some(0),
+            Body::Bytes(bytes) => Some(bytes.len() as u64),
+            Body::Text(text) => Some(text.len() as u64),
+            Body::Stream(_, len) => Some(*len),
+            Body::Chunked(_) => None,
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == Some(0)
+    }
+
+    /// Write the content of the body to `writer` without the chunk framing
+    /// of HTTP/1.1, flushing after each chunk of a `Chunked` body. Return
+    /// the number of bytes written.
+    pub fn copy_to<W: Write>(self, writer: &mut W) -> io::Result<u64> {
+        match self {
+            Body::Chunked(chunks) => {
+                let mut written = 0;
+                for chunk in chunks {
+                    let chunk = chunk?;
+                    writer.write_all(&chunk)?;
+                    writer.flush()?;
+                    written += chunk.len() as u64;
+                }
+                Ok(written)
+            }
+            body => write_body(writer, body),
+        }
+    }
+}
+
+/// Takes over a connection once a `101 Switching Protocols` response has
+/// been sent on it.
+pub type Upgrade = Box<dyn FnOnce(Connection) + Send>;
+
+/// An HTTP response, built up with the `with_*` methods and written to a
+/// connection with [`Response::write_to`].
+pub struct Response {
+    pub status: Status,
+    pub headers: Headers,
+    pub body: Body,
+    /// What to run on the connection after the response, instead of
+    /// reading another request.
+    pub upgrade: Option<Upgrade>,
+}
+
+impl Response {
+    pub fn new(status: Status) -> Self {
+        Response {
+            status,
+            headers: Headers::new(),
+            body: Body::Empty,
+            upgrade: None,
+        }
+    }
+
+    pub fn with_header(mut self, name: &str, value: impl Into<String>) -> Self {
+        self.headers.set(name, value);
+        self
+    }
+
+    pub fn with_body(mut self, body: Body) -> Self {
+        self.body = body;
+        self
+    }
+
+    pub fn with_upgrade(mut self, upgrade: Upgrade) -> Self {
+        self.upgrade = Some(upgrade);
+        self
+    }
+
+    /// Serialize the response as HTTP/1.1.
+    ///
+    /// `Date`, and either `Content-Length` or `Transfer-Encoding`, are filled
+    /// in from the body unless already set. When `head_only` is true the
+    /// headers describe the body as a GET would, but the body itself is not
+    /// sent, as required for HEAD requests. `chunked` says whether the client
+    /// understands chunked coding, which HTTP/1.0 clients do not; without it
+    /// a body of unknown length is sent as is, and the caller must close the
+    /// connection after it.
+    ///
+    /// Return the number of body bytes written.
+    pub fn write_to<W: Write>(self, writer: &mut W, head_only: bool, chunked: bool) -> io::Result<u64> {
+        let Response { status, mut headers, body, .. } = self;
+
+        if !headers.contains("Date") {
+            headers.set("Date", httpdate::fmt_http_date(SystemTime::now()));
+        }
+        let body = if status.allows_body() {
+            match body.len() {
+                Some(len) => headers.set("Content-Length", len.to_string()),
+                None if chunked => headers.set("Transfer-Encoding", "chunked"),
+                None => {}
+            }
+            body
+        } else {
+            Body::Empty
+        };
+
+        write!(writer, "HTTP/1.1 {} {}\r\n{headers}\r\n", status.code(), status.reason())?;
+        // A streamed body may be slow to start; let the client see the
+        // response has begun.
+        if matches!(body, Body::Chunked(_)) && !head_only {
+            writer.flush()?;
+        }
+
+        let written = match body {
+            _ if head_only => 0,
+            Body::Chunked(_) if !chunked => body.copy_to(writer)?,
+            body => write_body(writer, body)?,
+        };
+        writer.flush()?;
+        Ok(written)
+    }
+}
+
+fn write_body<W: Write>(writer: &mut W, body: Body) -> io::Result<u64> {
+    match body {
+        Body::Empty => Ok(0),
+        Body::Bytes(bytes) => {
+            writer.write_all(&bytes)?;
+            Ok(bytes.len() as u64)
+        }
+        Body::Text(text) => {
+            writer.write_all(text.as_bytes())?;
+            Ok(text.len() as u64)
+        }
+        Body::Stream(reader, len) => {
+            let copied = io::copy(&mut reader.take(len), writer)?;
+            if copied < len {
+                return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "body shorter than Content-Length"));
+            }
+            Ok(copied)
+        }
+        Body::Chunked(chunks) => {
+            let mut written = 0;
+            for chunk in chunks {
+                let chunk = chunk?;
+                if chunk.is_empty() {
+                    continue;
+                }
+                write!(writer, "{:X}\r\n", chunk.len())?;
+                writer.write_all(&chunk)?;
+                writer.write_all(b"\r\n")?;
+                // Flush each chunk so streamed bodies reach the client as
+                // they are produced.
+                writer.flush()?;
+                written += chunk.len() as u64;
+            }
+            writer.write_all(b"0\r\n\r\n")?;
+            Ok(written)
+        }
+    }
+}
+
+#[cfg(test)]
+fn serialize(response: Response, head_only: bool) -> String {
+    let mut out = Vec::new();
+    response.write_to(&mut out, head_only, true).unwrap();
+    String::from_utf8(out).unwrap()
+}
+
+#[test]
+fn test_write_text_response() {
+    let response = Response::new(Status::Ok)
+        .with_header("Content-Type", "text/plain")
+        .with_header("Date", "Sun, 06 Nov 1994 08:49:37 GMT")
+        .with_body(Body::Text("Hi from Rust".to_string()));
+
+    assert_eq!(serialize(response, false),
+               "HTTP/1.1 200 OK\r\n\
+                Content-Type: text/plain\r\n\
+                Date: Sun, 06 Nov 1994 08:49:37 GMT\r\n\
+                Content-Length: 12\r\n\
+                \r\n\
+                Hi from Rust");
+}
+
+#[test]
+fn test_write_head_response() {
+    let response = Response::new(Status::Ok)
+        .with_body(Body::Bytes(vec![0; 42]));
+
+    let text = serialize(response, true);
+    assert!(text.contains("Content-Length: 42\r\n"));
+    assert!(text.contains("Date: "));
+    assert!(text.ends_with("\r\n\r\n"));
+}
+
+#[test]
+fn test_write_chunked_response() {
+    let chunks = vec![Ok(b"Hello".to_vec()), Ok(Vec::new()), Ok(b", world!".to_vec())];
+    let response = Response::new(Status::Ok)
+        .with_body(Body::Chunked(Box::new(chunks.into_iter())));
+
+    let text = serialize(response, false);
+    assert!(text.contains("Transfer-Encoding: chunked\r\n"));
+    assert!(!text.contains("Content-Length"));
+    assert!(text.ends_with("\r\n\r\n5\r\nHello\r\n8\r\n, world!\r\n0\r\n\r\n"));
+}
+
+#[test]
+fn test_write_unchunked_response() {
+    let chunks = vec![Ok(b"Hello".to_vec()), Ok(b", world!".to_vec())];
+    let response = Response::new(Status::Ok)
+        .with_body(Body::Chunked(Box::new(chunks.into_iter())));
+
+    let mut out = Vec::new();
+    assert_eq!(response.write_to(&mut out, false, false).unwrap(), 13);
+    let text = String::from_utf8(out).unwrap();
+    assert!(!text.contains("Transfer-Encoding"));
+    assert!(!text.contains("Content-Length"));
+    assert!(text.ends_with("\r\n\r\nHello, world!"));
+}
+
+#[test]
+fn test_write_failed_chunked_response() {
+    let chunks = vec![Ok(b"Hello".to_vec()), Err(io::Error::other("disk on fire"))];
+    let response = Response::new(Status::Ok)
+        .with_body(Body::Chunked(Box::new(chunks.into_iter())));
+
+    let mut out = Vec::new();
+    assert!(response.write_to(&mut out, false, true).is_err());
+    assert!(out.ends_with(b"5\r\nHello\r\n"));
+}
+
+#[test]
+fn test_body_from_reader() {
+    let Body::Chunked(chunks) = Body::from_reader(io::Cursor::new(vec![7; CHUNK_SIZE + 1])) else {
+        panic!("expected a chunked body");
+    };
+    let sizes: Vec<usize> = chunks.map(|c| c.unwrap().len()).collect();
+    assert_eq!(sizes, vec![CHUNK_SIZE, 1]);
+}
+
+#[test]
+fn test_write_stream_response() {
+    let response = Response::new(Status::Ok)
+        .with_body(Body::Stream(Box::new(io::Cursor::new(b"abcdef".to_vec())), 3));
+
+    let text = serialize(response, false);
+    assert!(text.contains("Content-Length: 3\r\n"));
+    assert!(text.ends_with("\r\n\r\nabc"));
+}
+
+#[test]
+fn test_write_not_modified_has_no_body() {
+    let response = Response::new(Status::NotModified)
+        .with_body(Body::Text("ignored".to_string()));
+
+    let text = serialize(response, false);
+    assert!(text.starts_with("HTTP/1.1 304 Not Modified\r\n"));
+    assert!(!text.contains("Content-Length"));
+    assert!(text.ends_with("\r\n\r\n"));
+}
+
+#[test]
+fn test_status_from_code() {
+    assert_eq!(Status::from_code(404), Status::NotFound);
+    assert_eq!(Status::from_code(418), Status::Other(418));
+    assert_eq!(Status::Other(418).code(), 418);
+    assert!(!Status::from_code(304).allows_body());
+    assert!(Status::Other(418).allows_body());
+}
@@ -0,0 +1,55 @@
+use std::io;
+use std::process;
+use std::thread;
+
+use log::{error, info, warn};
+use signal_hook::consts::{SIGHUP, SIGINT, SIGTERM, SIGUSR2};
+use signal_hook::iterator::Signals;
+
+use crate::config::{Config, ConfigError};
+use crate::server::Handle;
+
+/// Control the server behind `handle` with signals:
+///
+/// - `SIGHUP` reloads the configuration returned by `load`.
+/// - `SIGUSR2` restarts the server in a new process, see `Handle::restart`.
+/// - `SIGTERM` and `SIGINT` stop it once open connections finish; a second
+///   one exits at once.
+pub fn install<F>(handle: Handle, load: F) -> io::Result<()>
+where
+    F: Fn() -> Result<Config, ConfigError> + Send + 'static,
+{
+    let mut signals = Signals::new([SIGHUP, SIGUSR2, SIGTERM, SIGINT])?;
+    thread::spawn(move || {
+        for signal in signals.forever() {
+            match signal {
+                SIGHUP => {
+                    info!("reloading configuration");
+                    match load() {
+                        Ok(config) => {
+                            if let Err(e) = handle.reload(config) {
+                                error!("failed to reload configuration: {e}");
+                            }
+                        }
+                        Err(e) => error!("failed to reload configuration: {e}"),
+                    }
+                }
+                SIGUSR2 => {
+                    info!("restarting");
+                    if let Err(e) = handle.restart() {
+                        error!("failed to restart: {e}");
+                    }
+                }
+                _ if handle.is_stopping() => {
+                    warn!("exiting without waiting for open connections");
+                    process::exit(1);
+                }
+                _ => {
+                    info!("stopping; send the signal again to exit at once");
+                    handle.stop();
+                }
+            }
+        }
+    });
+    Ok(())
+}
@@ -0,0 +1,250 @@
+use std::collections::{HashMap, VecDeque};
+use std::io;
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError, SyncSender, TrySendError};
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::time::Duration;
+
+use log::debug;
+
+use crate::request::{Method, Request};
+use crate::response::{Body, Response, Status};
+
+/// Events queued for one subscriber before it is considered too slow and
+/// disconnected. It can catch up from the replay buffer on reconnecting.
+const SUBSCRIBER_QUEUE: usize = 256;
+
+/// One published event.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Event {
+    /// Increases by one with each event on a channel, starting at 1.
+    pub id: u64,
+    /// The event type; clients see `message` when absent.
+    pub name: Option<String>,
+    pub data: String,
+}
+
+impl Event {
+    /// The event in `text/event-stream` format, ending with a blank line.
+    /// Line breaks in `data` become one `data:` field per line.
+    pub fn format(&self) -> String {
+        let mut text = format!("id: {}\n", self.id);
+        if let Some(name) = &self.name {
+            // A line break would end the field early.
+            text.push_str(&format!("event: {}\n", name.replace(['\r', '\n'], "")));
+        }
+        for line in self.data.replace("\r\n", "\n").split(['\r', '\n']) {
+            text.push_str("data: ");
+            text.push_str(line);
+            text.push('\n');
+        }
+        text.push('\n');
+        text
+    }
+}
+
+#[derive(Default)]
+struct Channel {
+    next_id: u64,
+    /// The latest events, oldest first, for clients resuming with
+    /// `Last-Event-ID`.
+    history: VecDeque<Arc<Event>>,
+    subscribers: HashMap<u64, SyncSender<Arc<Event>>>,
+}
+
+struct Shared {
+    channels: HashMap<String, Channel>,
+    next_subscriber: u64,
+}
+
+/// Publishes events to the clients subscribed to each channel.
+///
+/// Clones share the same channels, so one can be given to the server while
+/// others publish from elsewhere. Channels are created by their first
+/// event or subscriber.
+#[derive(Clone)]
+pub struct Broker {
+    shared: Arc<Mutex<Shared>>,
+    /// Events kept per channel for replay.
+    replay: usize,
+    /// How long a stream may stay silent before a comment is sent to keep
+    /// it open and find out whether the client is still there.
+    heartbeat: Duration,
+}
+
+impl Broker {
+    pub fn new(replay: usize, heartbeat: Duration) -> Broker {
+        Broker {
+            shared: Arc::new(Mutex::new(Shared { channels: HashMap::new(), next_subscriber: 0 })),
+            replay,
+            heartbeat,
+        }
+    }
+
+    fn lock(&self) -> MutexGuard<'_, Shared> {
+        self.shared.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    /// Send an event to every subscriber of `channel`, returning its id.
+    /// Subscribers that have gone away or fallen too far behind are dropped.
+    pub fn publish(&self, channel: &str, name: Option<&str>, data: &str) -> u64 {
+        let mut shared = self.lock();
+        let channel = shared.channels.entry(channel.to_string()).or_default();
+        channel.next_id += 1;
+        let event = Arc::new(Event {
+            id: channel.next_id,
+            name: name.map(String::from),
+            data: data.to_string(),
+        });
+
+        if self.replay > 0 {
+            if channel.history.len() == self.replay {
+                channel.history.pop_front();
+            }
+            channel.history.push_back(Arc::clone(&event));
+        }
+        channel.subscribers.retain(|id, sender| match sender.try_send(Arc::clone(&event)) {
+            Ok(()) => true,
+            Err(TrySendError::Full(_)) => {
+                debug!("dropping slow event stream subscriber {id}");
+                false
+            }
+            Err(TrySendError::Disconnected(_)) => false,
+        });
+        event.id
+    }
+
+    /// Subscribe to `channel`. Buffered events after `last_event_id` are
+    /// replayed before live ones.
+    pub fn subscribe(&self, channel: &str, last_event_id: Option<u64>) -> Subscription {
+        let (sender, receiver) = mpsc::sync_channel(SUBSCRIBER_QUEUE);
+        let mut shared = self.lock();
+        shared.next_subscriber += 1;
+        let id = shared.next_subscriber;
+        let state = shared.channels.entry(channel.to_string()).or_default();
+        state.subscribers.insert(id, sender);
+        let replay = match last_event_id {
+            Some(last) => state.history.iter().filter(|event| event.id > last).cloned().collect(),
+            None => VecDeque::new(),
+        };
+        Subscription {
+            broker: self.clone(),
+            channel: channel.to_string(),
+            id,
+            replay,
+            receiver,
+        }
+    }
+
+    /// The number of clients subscribed to `channel`.
+    pub fn subscribers(&self, channel: &str) -> usize {
+        self.lock().channels.get(channel).map_or(0, |channel| channel.subscribers.len())
+    }
+}
+
+/// A client's view of a channel: an endless iterator over the stream's
+/// bytes, for use as a `Body::Chunked`. Dropping it, as happens once writing
+/// to a disconnected client fails, unsubscribes.
+pub struct Subscription {
+    broker: Broker,
+    channel: String,
+    id: u64,
+    replay: VecDeque<Arc<Event>>,
+    receiver: Receiver<Arc<Event>>,
+}
+
+impl Iterator for Subscription {
+    type Item = io::Result<Vec<u8>>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if let Some(event) = self.replay.pop_front() {
+            return Some(Ok(event.format().into_bytes()));
+        }
+        match self.receiver.recv_timeout(self.broker.heartbeat) {
+            Ok(event) => Some(Ok(event.format().into_bytes())),
+            Err(RecvTimeoutError::Timeout) => Some(Ok(b": heartbeat\n\n".to_vec())),
+            // Dropped by the broker for falling behind.
+            Err(RecvTimeoutError::Disconnected) => None,
+        }
+    }
+}
+
+impl Drop for Subscription {
+    fn drop(&mut self) {
+        let mut shared = self.broker.lock();
+        if let Some(channel) = shared.channels.get_mut(&self.channel) {
+            channel.subscribers.remove(&self.id);
+            // Forget channels that were only ever subscribed to.
+            if channel.subscribers.is_empty() && channel.history.is_empty() {
+                shared.channels.remove(&self.channel);
+            }
+        }
+    }
+}
+
+/// Answer a request for the event stream mounted at `prefix`, subscribing
+/// the client to the channel named by the rest of the path.
+pub fn stream(request: &Request, prefix: &str, broker: &Broker) -> Response {
+    if !matches!(request.method, Method::Get | Method::Head) {
+        return Response::new(Status::MethodNotAllowed).with_header("Allow", "GET, HEAD");
+    }
+    let channel = request.path()[prefix.trim_end_matches('/').len()..].trim_start_matches('/');
+    let last_event_id = request.headers.get("Last-Event-ID").and_then(|id| id.trim().parse().ok());
+
+    Response::new(Status::Ok)
+        .with_header("Content-Type", "text/event-stream")
+        .with_header("Cache-Control", "no-cache")
+        // Asks buffering proxies such as nginx to pass events on at once.
+        .with_header("X-Accel-Buffering", "no")
+        .with_body(Body::Chunked(Box::new(broker.subscribe(channel, last_event_id))))
+}
+
+#[cfg(test)]
+fn text(chunk: Option<io::Result<Vec<u8>>>) -> String {
+    String::from_utf8(chunk.unwrap().unwrap()).unwrap()
+}
+
+#[test]
+fn test_event_format() {
+    let event = Event { id: 7, name: Some("up\ndate".to_string()), data: "one\r\ntwo\nthree".to_string() };
+    assert_eq!(event.format(), "id: 7\nevent: update\ndata: one\ndata: two\ndata: three\n\n");
+    let event = Event { id: 1, name: None, data: String::new() };
+    assert_eq!(event.format(), "id: 1\ndata: \n\n");
+}
+
+#[test]
+fn test_publish_and_replay() {
+    let broker = Broker::new(2, Duration::from_secs(60));
+    let mut live = broker.subscribe("news", None);
+    assert_eq!(broker.publish("news", None, "first"), 1);
+    assert_eq!(broker.publish("news", Some("alert"), "second"), 2);
+    assert_eq!(broker.publish("news", None, "third"), 3);
+    assert_eq!(broker.publish("other", None, "elsewhere"), 1);
+    assert_eq!(text(live.next()), "id: 1\ndata: first\n\n");
+    assert_eq!(text(live.next()), "id: 2\nevent: alert\ndata: second\n\n");
+
+    // Only the last two events are buffered.
+    let mut resumed = broker.subscribe("news", Some(0));
+    assert_eq!(text(resumed.next()), "id: 2\nevent: alert\ndata: second\n\n");
+    assert_eq!(text(resumed.next()), "id: 3\ndata: third\n\n");
+    let mut resumed = broker.subscribe("news", Some(2));
+    assert_eq!(text(resumed.next()), "id: 3\ndata: third\n\n");
+}
+
+#[test]
+fn test_heartbeat_and_cleanup() {
+    let broker = Broker::new(0, Duration::from_millis(10));
+    let mut subscription = broker.subscribe("quiet", None);
+    assert_eq!(text(subscription.next()), ": heartbeat\n\n");
+    assert_eq!(broker.subscribers("quiet"), 1);
+    drop(subscription);
+    assert_eq!(broker.subscribers("quiet"), 0);
+    assert!(broker.lock().channels.is_empty());
+
+    // A subscriber that stops reading is dropped once its queue is full.
+    let mut slow = broker.subscribe("busy", None);
+    for i in 0..=SUBSCRIBER_QUEUE {
+        broker.publish("busy", None, &i.to_string());
+    }
+    assert_eq!(broker.subscribers("busy"), 0);
+    assert_eq!(slow.by_ref().count(), SUBSCRIBER_QUEUE);
+}
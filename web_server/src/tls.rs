@@ -0,0 +1,127 @@
+use std::fs::{self, File};
+use std::io::{self, BufReader};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, RwLock};
+use std::time::SystemTime;
+
+use log::{info, warn};
+use rustls::crypto::{ring, CryptoProvider};
+use rustls::server::{ClientHello, ResolvesServerCert};
+use rustls::sign::CertifiedKey;
+use rustls::ServerConfig;
+
+/// Build the TLS configuration for the HTTPS listeners from a PEM
+/// certificate chain and private key, offering HTTP/2 to clients that
+/// support it when `http2` is set.
+///
+/// The files are checked for changes at every handshake, so a renewed
+/// certificate is picked up without restarting the server.
+pub fn server_config(cert: &Path, key: &Path, http2: bool) -> io::Result<Arc<ServerConfig>> {
+    let provider = Arc::new(ring::default_provider());
+    let resolver = ReloadingResolver::new(cert, key, Arc::clone(&provider))?;
+
+    let mut config = ServerConfig::builder_with_provider(provider)
+        .with_safe_default_protocol_versions()
+        .map_err(io::Error::other)?
+        .with_no_client_auth()
+        .with_cert_resolver(Arc::new(resolver));
+    config.alpn_protocols = if http2 {
+        vec![b"h2".to_vec(), b"http/1.1".to_vec()]
+    } else {
+        vec![b"http/1.1".to_vec()]
+    };
+    Ok(Arc::new(config))
+}
+
+/// Read a certificate chain and its private key from PEM files.
+pub fn load_certified_key(cert: &Path, key: &Path, provider: &CryptoProvider) -> io::Result<CertifiedKey> {
+    let with_path = |path: &Path, e: io::Error| io::Error::new(e.kind(), format!("{}: {e}", path.display()));
+
+    let certs = rustls_pemfile::certs(&mut BufReader::new(File::open(cert).map_err(|e| with_path(cert, e))?))
+        .collect::<io::Result<Vec<_>>>()
+        .map_err(|e| with_path(cert, e))?;
+    if certs.is_empty() {
+        return Err(with_path(cert, io::Error::new(io::ErrorKind::InvalidData, "no certificates found")));
+    }
+
+    let private_key = rustls_pemfile::private_key(&mut BufReader::new(File::open(key).map_err(|e| with_path(key, e))?))
+        .map_err(|e| with_path(key, e))?
+        .ok_or_else(|| with_path(key, io::Error::new(io::ErrorKind::InvalidData, "no private key found")))?;
+    let signing_key = provider
+        .key_provider
+        .load_private_key(private_key)
+        .map_err(|e| with_path(key, io::Error::new(io::ErrorKind::InvalidData, e)))?;
+
+    Ok(CertifiedKey::new(certs, signing_key))
+}
+
+/// Modification times of the certificate and key files.
+type Stamp = (Option<SystemTime>, Option<SystemTime>);
+
+fn stamp(cert: &Path, key: &Path) -> Stamp {
+    let modified = |path: &Path| fs::metadata(path).and_then(|m| m.modified()).ok();
+    (modified(cert), modified(key))
+}
+
+/// Serves the certificate last loaded from disk, reloading it when either
+/// file's modification time changes.
+#[derive(Debug)]
+struct ReloadingResolver {
+    cert: PathBuf,
+    key: PathBuf,
+    provider: Arc<CryptoProvider>,
+    current: RwLock<(Arc<CertifiedKey>, Stamp)>,
+}
+
+impl ReloadingResolver {
+    fn new(cert: &Path, key: &Path, provider: Arc<CryptoProvider>) -> io::Result<ReloadingResolver> {
+        let stamp = stamp(cert, key);
+        let certified_key = load_certified_key(cert, key, &provider)?;
+        Ok(ReloadingResolver {
+            cert: cert.to_path_buf(),
+            key: key.to_path_buf(),
+            provider,
+            current: RwLock::new((Arc::new(certified_key), stamp)),
+        })
+    }
+
+    fn reload_if_changed(&self) {
+        let stamp = stamp(&self.cert, &self.key);
+        if self.current.read().unwrap_or_else(|e| e.into_inner()).1 == stamp {
+            return;
+        }
+
+        let mut current = self.current.write().unwrap_or_else(|e| e.into_inner());
+        if current.1 == stamp {
+            return;
+        }
+        // Remember the stamp even when loading fails, so a half-written file
+        // is reported once rather than at every handshake; the old
+        // certificate stays in use until the files change again.
+        current.1 = stamp;
+        match load_certified_key(&self.cert, &self.key, &self.provider) {
+            Ok(certified_key) => {
+                info!("reloaded TLS certificate {}", self.cert.display());
+                current.0 = Arc::new(certified_key);
+            }
+            Err(e) => warn!("failed to reload TLS certificate, keeping the old one: {e}"),
+        }
+    }
+}
+
+impl ResolvesServerCert for ReloadingResolver {
+    fn resolve(&self, _client_hello: ClientHello<'_>) -> Option<Arc<CertifiedKey>> {
+        self.reload_if_changed();
+        Some(Arc::clone(&self.current.read().unwrap_or_else(|e| e.into_inner()).0))
+    }
+}
+
+#[test]
+fn test_load_errors() {
+    let provider = ring::default_provider();
+    let error = load_certified_key(Path::new("missing.pem"), Path::new("missing.key"), &provider).unwrap_err();
+    assert!(error.to_string().starts_with("missing.pem: "));
+
+    let error = load_certified_key(Path::new("public/hello.html"), Path::new("public/hello.html"), &provider).unwrap_err();
+    assert_eq!(error.to_string(), "public/hello.html: no certificates found");
+}
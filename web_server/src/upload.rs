@@ -0,0 +1,327 @@
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Read, Write};
+use std::path::{Path, PathBuf};
+
+use log::{debug, error, info};
+use serde::Deserialize;
+use serde_json::json;
+
+use crate::body::{self, TooLarge};
+use crate::config::Config;
+use crate::form::{self, Multipart};
+use crate::request::{Method, Request};
+use crate::response::{Body, Response, Status};
+use crate::server::error_response;
+
+/// The largest form field kept in memory, rather than stored as a file.
+const MAX_FIELD: u64 = 64 * 1024;
+
+/// The largest `application/x-www-form-urlencoded` body, which is read
+/// whole.
+const MAX_URLENCODED: u64 = 1024 * 1024;
+
+/// The longest name an uploaded file is stored under, in bytes.
+const MAX_NAME: usize = 100;
+
+/// Tries at finding a free name for an uploaded file before giving up.
+const MAX_TRIES: usize = 1000;
+
+/// A path prefix that accepts form posts, storing uploaded files in a
+/// directory, as configured.
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct UploadRule {
+    /// A path prefix such as `/upload/`; it covers the path itself and
+    /// everything below it.
+    pub path: String,
+    /// Directory uploaded files are stored in.
+    pub dir: PathBuf,
+    /// Largest request body accepted under `path`, in bytes, in place of
+    /// the server's `max_body_size`. 0 means no limit.
+    #[serde(default)]
+    pub max_body_size: Option<u64>,
+}
+
+impl UploadRule {
+    /// Store files posted to `path` in `dir`.
+    pub fn new(path: &str, dir: PathBuf) -> UploadRule {
+        UploadRule {
+            path: path.to_string(),
+            dir,
+            max_body_size: None,
+        }
+    }
+}
+
+/// A form as received: its plain fields, and the files stored for its file
+/// fields.
+#[derive(Debug, Default, PartialEq)]
+pub struct Form {
+    pub fields: Vec<(String, String)>,
+    pub files: Vec<StoredFile>,
+}
+
+/// A file uploaded in a form field and stored on disk.
+#[derive(Debug, PartialEq)]
+pub struct StoredFile {
+    /// The form field the file came in.
+    pub field: String,
+    /// The file name the client gave.
+    pub filename: String,
+    pub content_type: Option<String>,
+    /// Where the file was stored.
+    pub path: PathBuf,
+    pub size: u64,
+}
+
+/// Why receiving a form failed.
+#[derive(Debug)]
+enum Failure {
+    /// The request body could not be read or is malformed.
+    Client(io::Error),
+    /// An uploaded file could not be stored.
+    Store(io::Error),
+}
+
+/// Answer `request`, whose path lies under `rule.path`, by receiving the
+/// form in `body` and storing its files in `rule.dir`.
+///
+/// A `multipart/form-data` body is streamed: file parts go straight to
+/// disk, so they may be as large as the body size limit allows. A form
+/// without files is answered with 200, one with files with 201; either
+/// way the body describes what was received as JSON.
+pub fn handle(rule: &UploadRule, request: &Request, body: &mut dyn Read, config: &Config) -> Response {
+    if request.method != Method::Post {
+        return error_response(Status::MethodNotAllowed, config).with_header("Allow", "POST");
+    }
+    let content_type = request.headers.get("Content-Type").unwrap_or("");
+    let received = if let Some(boundary) = form::boundary(content_type) {
+        receive_multipart(&mut Multipart::new(body, &boundary), &rule.dir)
+    } else if form::media_type(content_type) == "application/x-www-form-urlencoded" {
+        receive_urlencoded(body)
+    } else {
+        return error_response(Status::UnsupportedMediaType, config);
+    };
+
+    match received {
+        Ok(form) => {
+            for file in &form.files {
+                info!("stored upload {:?} as {} ({} bytes)", file.filename, file.path.display(), file.size);
+            }
+            let status = if form.files.is_empty() { Status::Ok } else { Status::Created };
+            Response::new(status)
+                .with_header("Content-Type", "application/json")
+                .with_header("Cache-Control", "no-store")
+                .with_body(Body::Text(describe(&form)))
+        }
+        Err(Failure::Client(e)) => {
+            debug!("failed to receive form for {}: {e}", request.target);
+            error_response(body::error_status(&e), config)
+        }
+        Err(Failure::Store(e)) => {
+            error!("failed to store upload in {}: {e}", rule.dir.display());
+            error_response(Status::InternalServerError, config)
+        }
+    }
+}
+
+/// Read a `multipart/form-data` body, storing file parts in `dir`. Files
+/// already stored are removed again if the body turns out to be bad.
+fn receive_multipart<R: Read>(multipart: &mut Multipart<R>, dir: &Path) -> Result<Form, Failure> {
+    let mut form = Form::default();
+    let result = receive_parts(multipart, dir, &mut form);
+    if result.is_err() {
+        for file in &form.files {
+            if let Err(e) = fs::remove_file(&file.path) {
+                error!("failed to remove partial upload {}: {e}", file.path.display());
+            }
+        }
+    }
+    result.map(|()| form)
+}
+
+fn receive_parts<R: Read>(multipart: &mut Multipart<R>, dir: &Path, form: &mut Form) -> Result<(), Failure> {
+    while let Some(part) = multipart.next_part().map_err(Failure::Client)? {
+        let Some(filename) = part.filename else {
+            let mut value = Vec::new();
+            multipart.by_ref().take(MAX_FIELD + 1).read_to_end(&mut value).map_err(Failure::Client)?;
+            if value.len() as u64 > MAX_FIELD {
+                return Err(Failure::Client(io::Error::new(io::ErrorKind::InvalidData, TooLarge)));
+            }
+            let value = String::from_utf8(value)
+                .map_err(|_| Failure::Client(io::Error::new(io::ErrorKind::InvalidData, "field is not UTF-8")))?;
+            form.fields.push((part.name, value));
+            continue;
+        };
+        // A file input left empty is sent with an empty name and no data.
+        if filename.is_empty() {
+            continue;
+        }
+
+        let (mut file, path) = create_unique(dir, &sanitize(&filename)).map_err(Failure::Store)?;
+        form.files.push(StoredFile {
+            field: part.name,
+            filename,
+            content_type: part.content_type,
+            path,
+            size: 0,
+        });
+        let stored = form.files.last_mut().expect("just pushed");
+        stored.size = copy(multipart, &mut file)?;
+        file.sync_all().map_err(Failure::Store)?;
+    }
+    Ok(())
+}
+
+/// Read an `application/x-www-form-urlencoded` body.
+fn receive_urlencoded(body: &mut dyn Read) -> Result<Form, Failure> {
+    let mut text = String::new();
+    body.take(MAX_URLENCODED + 1).read_to_string(&mut text).map_err(Failure::Client)?;
+    if text.len() as u64 > MAX_URLENCODED {
+        return Err(Failure::Client(io::Error::new(io::ErrorKind::InvalidData, TooLarge)));
+    }
+    Ok(Form { fields: form::parse_urlencoded(&text), files: Vec::new() })
+}
+
+/// Copy a part's body to `file`, telling failures to read from failures to
+/// write.
+fn copy(from: &mut impl Read, file: &mut File) -> Result<u64, Failure> {
+    let mut buf = [0; 16 * 1024];
+    let mut copied = 0;
+    loop {
+        let read = from.read(&mut buf).map_err(Failure::Client)?;
+        if read == 0 {
+            return Ok(copied);
+        }
+        file.write_all(&buf[..read]).map_err(Failure::Store)?;
+        copied += read as u64;
+    }
+}
+
+/// A safe file name for a file the client called `filename`: its last path
+/// component, with anything but ASCII letters, digits, `.`, `-` and `_`
+/// replaced, and no leading dots.
+fn sanitize(filename: &str) -> String {
+    let base = filename.rsplit(['/', '\\']).next().unwrap_or("");
+    let mut name: String = base
+        .chars()
+        .map(|c| if c.is_ascii_alphanumeric() || matches!(c, '.' | '-' | '_') { c } else { '_' })
+        .collect();
+    name = name.trim_start_matches('.').to_string();
+    if name.len() > MAX_NAME {
+        // Keep the extension, which says what the file is.
+        let extension = name.rfind('.').map_or("", |dot| &name[dot..]);
+        let extension = if extension.len() < MAX_NAME / 2 { extension } else { "" };
+        name = format!("{}{extension}", &name[..MAX_NAME - extension.len()]);
+    }
+    if name.is_empty() { "upload".to_string() } else { name }
+}
+
+/// Create a new file in `dir` named `name`, or `name` with a number added
+/// before its extension if that is taken, so no upload replaces another.
+fn create_unique(dir: &Path, name: &str) -> io::Result<(File, PathBuf)> {
+    let (stem, extension) = match name.rfind('.') {
+        Some(dot) if dot > 0 => name.split_at(dot),
+        _ => (name, ""),
+    };
+    for n in 0..MAX_TRIES {
+        let path = match n {
+            0 => dir.join(name),
+            n => dir.join(format!("{stem}-{n}{extension}")),
+        };
+        match OpenOptions::new().write(true).create_new(true).open(&path) {
+            Ok(file) => return Ok((file, path)),
+            Err(e) if e.kind() == io::ErrorKind::AlreadyExists => continue,
+            Err(e) => return Err(e),
+        }
+    }
+    Err(io::Error::new(io::ErrorKind::AlreadyExists, format!("no free name for {name}")))
+}
+
+/// The JSON answer to a received form.
+fn describe(form: &Form) -> String {
+    let fields: Vec<_> = form.fields.iter().map(|(name, value)| json!({"name": name, "value": value})).collect();
+    let files: Vec<_> = form
+        .files
+        .iter()
+        .map(|file| {
+            json!({
+                "field": file.field,
+                "filename": file.filename,
+                "content_type": file.content_type,
+                "stored": file.path.file_name().map(|name| name.to_string_lossy()),
+                "size": file.size,
+            })
+        })
+        .collect();
+    json!({"fields": fields, "files": files}).to_string() + "\n"
+}
+
+#[cfg(test)]
+fn upload_dir(name: &str) -> PathBuf {
+    let dir = std::env::temp_dir().join(format!("web_server_upload_{name}_{}", std::process::id()));
+    let _ = fs::remove_dir_all(&dir);
+    fs::create_dir_all(&dir).unwrap();
+    dir
+}
+
+#[test]
+fn test_sanitize() {
+    assert_eq!(sanitize("photo.jpg"), "photo.jpg");
+    assert_eq!(sanitize("../../etc/passwd"), "passwd");
+    assert_eq!(sanitize("C:\\Users\\me\\My Report (final).pdf"), "My_Report__final_.pdf");
+    assert_eq!(sanitize(".htaccess"), "htaccess");
+    assert_eq!(sanitize(".."), "upload");
+    assert_eq!(sanitize("résumé.txt"), "r_sum_.txt");
+    let long = sanitize(&format!("{}.tar.gz", "a".repeat(200)));
+    assert_eq!(long.len(), MAX_NAME);
+    assert!(long.ends_with("a.gz"));
+}
+
+#[test]
+fn test_create_unique() {
+    let dir = upload_dir("unique");
+    let names: Vec<PathBuf> = ["a.txt", "a.txt", "a.txt", "b"]
+        .iter()
+        .map(|name| create_unique(&dir, name).unwrap().1)
+        .collect();
+    assert_eq!(names, ["a.txt", "a-1.txt", "a-2.txt", "b"].map(|name| dir.join(name)));
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn test_receive_multipart() {
+    let dir = upload_dir("multipart");
+    let body = b"--b\r\n\
+        Content-Disposition: form-data; name=\"note\"\r\n\r\nhello\r\n\
+        --b\r\n\
+        Content-Disposition: form-data; name=\"doc\"; filename=\"../a.txt\"\r\n\
+        Content-Type: text/plain\r\n\r\nfile contents\r\n\
+        --b\r\n\
+        Content-Disposition: form-data; name=\"empty\"; filename=\"\"\r\n\r\n\r\n\
+        --b--\r\n";
+    let form = receive_multipart(&mut Multipart::new(&body[..], "b"), &dir).unwrap();
+    assert_eq!(form.fields, vec![("note".to_string(), "hello".to_string())]);
+    assert_eq!(form.files, vec![StoredFile {
+        field: "doc".to_string(),
+        filename: "../a.txt".to_string(),
+        content_type: Some("text/plain".to_string()),
+        path: dir.join("a.txt"),
+        size: 13,
+    }]);
+    assert_eq!(fs::read_to_string(dir.join("a.txt")).unwrap(), "file contents");
+
+    // A body cut short leaves nothing behind.
+    let truncated = &body[..body.len() - 40];
+    let result = receive_multipart(&mut Multipart::new(truncated, "b"), &dir);
+    assert!(matches!(result, Err(Failure::Client(_))));
+    assert!(!dir.join("a-1.txt").exists());
+
+    let field = [&b"--b\r\nContent-Disposition: form-data; name=x\r\n\r\n"[..], &[b'x'; MAX_FIELD as usize + 1], b"\r\n--b--"]
+        .concat();
+    match receive_multipart(&mut Multipart::new(&field[..], "b"), &dir) {
+        Err(Failure::Client(e)) => assert_eq!(body::error_status(&e), Status::PayloadTooLarge),
+        other => panic!("unexpected result: {other:?}"),
+    }
+    fs::remove_dir_all(&dir).unwrap();
+}
@@ -0,0 +1,115 @@
+use std::collections::HashMap;
+use std::path::PathBuf;
+
+use crate::cache::CacheRule;
+use crate::config::Config;
+use crate::proxy::{Proxy, ProxyRule};
+use crate::request::Request;
+
+/// A site served for requests whose `Host` is one of `names`, with its own
+/// document root, error pages and routes. Every other setting is shared
+/// with the server.
+#[derive(Debug, Clone)]
+pub struct VirtualHost {
+    /// Host names such as `example.com`, or `*.example.com` for any
+    /// subdomain of it.
+    pub names: Vec<String>,
+    pub root: PathBuf,
+    pub index: String,
+    /// Custom error pages, by status code; not inherited from the server.
+    pub error_pages: HashMap<u16, PathBuf>,
+    pub cache_control: Vec<CacheRule>,
+    pub proxy: Vec<ProxyRule>,
+}
+
+/// Whether `pattern`, a host name or `*.` wildcard, matches `host`.
+pub fn name_matches(pattern: &str, host: &str) -> bool {
+    match pattern.strip_prefix("*.") {
+        Some(domain) => host
+            .len()
+            .checked_sub(domain.len() + 1)
+            .filter(|&dot| dot > 0 && host.as_bytes()[dot] == b'.')
+            .is_some_and(|dot| host[dot + 1..].eq_ignore_ascii_case(domain)),
+        None => pattern.eq_ignore_ascii_case(host),
+    }
+}
+
+/// A virtual host ready to serve requests.
+pub struct Site {
+    pub names: Vec<String>,
+    /// The server's configuration with the host's own settings in place.
+    pub config: Config,
+    pub proxy: Proxy,
+}
+
+impl Site {
+    pub fn new(server: &Config, host: &VirtualHost) -> Site {
+        let config = Config {
+            root: host.root.clone(),
+            index: host.index.clone(),
+            error_pages: host.error_pages.clone(),
+            cache_control: host.cache_control.clone(),
+            proxy: host.proxy.clone(),
+            vhosts: Vec::new(),
+            ..server.clone()
+        };
+        Site {
+            names: host.names.clone(),
+            proxy: Proxy::new(&config.proxy),
+            config,
+        }
+    }
+}
+
+/// The site `request` is addressed to. Exact names take precedence over
+/// wildcards; `None` means the default host, the server's own settings,
+/// should answer.
+pub fn find<'a>(sites: &'a [Site], request: &Request) -> Option<&'a Site> {
+    let host = request.host()?;
+    let listed = |exact: bool| {
+        sites.iter().find(|site| {
+            site.names
+                .iter()
+                .any(|name| name.starts_with("*.") != exact && name_matches(name, host))
+        })
+    };
+    listed(true).or_else(|| listed(false))
+}
+
+#[test]
+fn test_name_matches() {
+    assert!(name_matches("example.com", "Example.COM"));
+    assert!(!name_matches("example.com", "www.example.com"));
+    assert!(name_matches("*.example.com", "www.example.com"));
+    assert!(name_matches("*.example.com", "a.b.example.com"));
+    assert!(!name_matches("*.example.com", "example.com"));
+    assert!(!name_matches("*.example.com", ".example.com"));
+    assert!(!name_matches("*.example.com", "badexample.com"));
+}
+
+#[test]
+fn test_find() {
+    let host = |names: &[&str], root: &str| VirtualHost {
+        names: names.iter().map(|name| name.to_string()).collect(),
+        root: PathBuf::from(root),
+        index: "index.html".to_string(),
+        error_pages: HashMap::new(),
+        cache_control: Vec::new(),
+        proxy: Vec::new(),
+    };
+    let server = Config::default();
+    let sites = [
+        Site::new(&server, &host(&["*.example.com"], "any")),
+        Site::new(&server, &host(&["example.com", "www.example.com"], "www")),
+    ];
+    let root = |raw: &str| {
+        let request = Request::read_from(&mut raw.as_bytes()).unwrap();
+        find(&sites, &request).map(|site| site.config.root.clone())
+    };
+
+    assert_eq!(root("GET / HTTP/1.1\r\nHost: www.example.com:8080\r\n\r\n"), Some(PathBuf::from("www")));
+    assert_eq!(root("GET / HTTP/1.1\r\nHost: example.com.\r\n\r\n"), Some(PathBuf::from("www")));
+    assert_eq!(root("GET / HTTP/1.1\r\nHost: blog.example.com\r\n\r\n"), Some(PathBuf::from("any")));
+    assert_eq!(root("GET / HTTP/1.1\r\nHost: example.org\r\n\r\n"), None);
+    assert_eq!(root("GET / HTTP/1.0\r\n\r\n"), None);
+}
@@ -0,0 +1,574 @@
+use std::io::{self, Read, Write};
+use std::sync::Arc;
+use std::time::Duration;
+
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine as _;
+use log::debug;
+use sha1::{Digest, Sha1};
+
+use crate::connection::Connection;
+use crate::request::{Method, Request};
+use crate::response::{Response, Status};
+
+/// Appended to the client's key to compute `Sec-WebSocket-Accept`.
+const GUID: &str = "258EAFA5-E914-47DA-95CA-C5AB0DC85B11";
+
+/// The largest message accepted, in bytes, after reassembling fragments.
+pub const MAX_MESSAGE: usize = 16 * 1024 * 1024;
+
+/// How long a connection may stay silent before it is pinged, and then
+/// before it is given up on.
+pub const PING_INTERVAL: Duration = Duration::from_secs(30);
+
+/// Close status codes from RFC 6455 section 7.4.1.
+pub const NORMAL_CLOSURE: u16 = 1000;
+pub const GOING_AWAY: u16 = 1001;
+pub const PROTOCOL_ERROR: u16 = 1002;
+pub const INVALID_DATA: u16 = 1007;
+pub const MESSAGE_TOO_BIG: u16 = 1009;
+
+/// A message-based WebSocket endpoint. It runs on its own thread for as
+/// long as the connection is open, and the connection is closed when it
+/// returns.
+pub type Endpoint = dyn Fn(&Request, &mut WebSocket) + Send + Sync;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Opcode {
+    Continuation,
+    Text,
+    Binary,
+    Close,
+    Ping,
+    Pong,
+}
+
+impl Opcode {
+    fn from_bits(bits: u8) -> Option<Opcode> {
+        match bits {
+            0x0 => Some(Opcode::Continuation),
+            0x1 => Some(Opcode::Text),
+            0x2 => Some(Opcode::Binary),
+            0x8 => Some(Opcode::Close),
+            0x9 => Some(Opcode::Ping),
+            0xA => Some(Opcode::Pong),
+            _ => None,
+        }
+    }
+
+    fn bits(self) -> u8 {
+        match self {
+            Opcode::Continuation => 0x0,
+            Opcode::Text => 0x1,
+            Opcode::Binary => 0x2,
+            Opcode::Close => 0x8,
+            Opcode::Ping => 0x9,
+            Opcode::Pong => 0xA,
+        }
+    }
+
+    fn is_control(self) -> bool {
+        matches!(self, Opcode::Close | Opcode::Ping | Opcode::Pong)
+    }
+}
+
+/// One WebSocket frame, with its payload unmasked.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Frame {
+    /// Whether this is the last frame of its message.
+    pub fin: bool,
+    pub opcode: Opcode,
+    /// Whether the payload arrived masked, as it must from a client.
+    pub masked: bool,
+    pub payload: Vec<u8>,
+}
+
+/// Why a frame could not be read.
+#[derive(Debug)]
+pub enum FrameError {
+    /// The read timed out before the frame began.
+    Idle,
+    Io(io::Error),
+    /// The peer broke the protocol and should be sent a close frame with
+    /// the given status code.
+    Protocol(u16, &'static str),
+}
+
+impl From<io::Error> for FrameError {
+    fn from(e: io::Error) -> Self {
+        FrameError::Io(e)
+    }
+}
+
+/// Read one frame, refusing payloads over `max_payload` bytes.
+///
+/// A timeout once the frame has begun is an error like any other, since
+/// the stream can no longer be resynchronized.
+pub fn read_frame<R: Read>(reader: &mut R, max_payload: usize) -> Result<Frame, FrameError> {
+    let mut head = [0; 2];
+    match reader.read_exact(&mut head[..1]) {
+        Err(e) if matches!(e.kind(), io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut) => return Err(FrameError::Idle),
+        result => result?,
+    }
+    reader.read_exact(&mut head[1..])?;
+    if head[0] & 0x70 != 0 {
+        return Err(FrameError::Protocol(PROTOCOL_ERROR, "reserved bits set"));
+    }
+    let fin = head[0] & 0x80 != 0;
+    let opcode = Opcode::from_bits(head[0] & 0x0F).ok_or(FrameError::Protocol(PROTOCOL_ERROR, "unknown opcode"))?;
+    let masked = head[1] & 0x80 != 0;
+
+    let len = match head[1] & 0x7F {
+        126 => {
+            let mut len = [0; 2];
+            reader.read_exact(&mut len)?;
+            u64::from(u16::from_be_bytes(len))
+        }
+        127 => {
+            let mut len = [0; 8];
+            reader.read_exact(&mut len)?;
+            u64::from_be_bytes(len)
+        }
+        len => u64::from(len),
+    };
+    if opcode.is_control() && (!fin || len > 125) {
+        return Err(FrameError::Protocol(PROTOCOL_ERROR, "fragmented or oversized control frame"));
+    }
+    if len > max_payload as u64 {
+        return Err(FrameError::Protocol(MESSAGE_TOO_BIG, "message too big"));
+    }
+
+    let mut mask = [0; 4];
+    if masked {
+        reader.read_exact(&mut mask)?;
+    }
+    let mut payload = vec![0; len as usize];
+    reader.read_exact(&mut payload)?;
+    if masked {
+        apply_mask(&mut payload, mask);
+    }
+    Ok(Frame { fin, opcode, masked, payload })
+}
+
+/// Write one frame, masked with `mask` if given. Servers never mask;
+/// clients always do.
+pub fn write_frame<W: Write>(writer: &mut W, fin: bool, opcode: Opcode, payload: &[u8], mask: Option<[u8; 4]>) -> io::Result<()> {
+    let mut head = Vec::with_capacity(14);
+    head.push(if fin { 0x80 } else { 0 } | opcode.bits());
+    let mask_bit = if mask.is_some() { 0x80 } else { 0 };
+    match payload.len() {
+        len @ 0..=125 => head.push(mask_bit | len as u8),
+        len @ 126..=0xFFFF => {
+            head.push(mask_bit | 126);
+            head.extend_from_slice(&(len as u16).to_be_bytes());
+        }
+        len => {
+            head.push(mask_bit | 127);
+            head.extend_from_slice(&(len as u64).to_be_bytes());
+        }
+    }
+
+    match mask {
+        Some(mask) => {
+            head.extend_from_slice(&mask);
+            let mut masked = payload.to_vec();
+            apply_mask(&mut masked, mask);
+            head.extend_from_slice(&masked);
+        }
+        None => head.extend_from_slice(payload),
+    }
+    writer.write_all(&head)?;
+    writer.flush()
+}
+
+fn apply_mask(payload: &mut [u8], mask: [u8; 4]) {
+    for (i, byte) in payload.iter_mut().enumerate() {
+        *byte ^= mask[i % 4];
+    }
+}
+
+/// A complete message, reassembled from its fragments.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Message {
+    Text(String),
+    Binary(Vec<u8>),
+}
+
+/// The server side of an open WebSocket connection.
+///
+/// `recv` answers pings, reassembles fragmented messages and completes the
+/// closing handshake, so endpoints only see whole messages. A connection
+/// silent for `PING_INTERVAL` is pinged, and dropped if it stays silent
+/// for another.
+pub struct WebSocket<S = Connection> {
+    stream: S,
+    /// Whether a close frame has been sent; nothing may follow it.
+    close_sent: bool,
+    awaiting_pong: bool,
+}
+
+impl<S: Read + Write> WebSocket<S> {
+    /// Speak the protocol on `stream`, once the handshake is done.
+    pub fn new(stream: S) -> WebSocket<S> {
+        WebSocket { stream, close_sent: false, awaiting_pong: false }
+    }
+
+    /// Wait for the next message. Return `None` once the connection is
+    /// closed, by either side.
+    pub fn recv(&mut self) -> io::Result<Option<Message>> {
+        if self.close_sent {
+            return Ok(None);
+        }
+        let mut partial: Option<(Opcode, Vec<u8>)> = None;
+        loop {
+            let frame = match read_frame(&mut self.stream, MAX_MESSAGE) {
+                Ok(frame) => frame,
+                Err(FrameError::Idle) => {
+                    if self.awaiting_pong {
+                        let _ = self.close(GOING_AWAY, "ping timeout");
+                        return Err(io::Error::new(io::ErrorKind::TimedOut, "no answer to ping"));
+                    }
+                    self.awaiting_pong = true;
+                    write_frame(&mut self.stream, true, Opcode::Ping, b"", None)?;
+                    continue;
+                }
+                Err(FrameError::Io(e)) => return Err(e),
+                Err(FrameError::Protocol(code, reason)) => return Err(self.fail(code, reason)),
+            };
+            self.awaiting_pong = false;
+            if !frame.masked {
+                return Err(self.fail(PROTOCOL_ERROR, "unmasked client frame"));
+            }
+
+            match frame.opcode {
+                Opcode::Ping => write_frame(&mut self.stream, true, Opcode::Pong, &frame.payload, None)?,
+                Opcode::Pong => {}
+                Opcode::Close => {
+                    // Echo the peer's status code, as the closing handshake
+                    // asks. A close without one is answered with 1000,
+                    // since 1005 only stands for its absence.
+                    let code = match frame.payload[..] {
+                        [] => NORMAL_CLOSURE,
+                        [high, low, ..] if is_valid_close_code(u16::from_be_bytes([high, low])) => {
+                            u16::from_be_bytes([high, low])
+                        }
+                        _ => return Err(self.fail(PROTOCOL_ERROR, "invalid close status")),
+                    };
+                    let _ = self.close(code, "");
+                    return Ok(None);
+                }
+                Opcode::Text | Opcode::Binary if partial.is_some() => {
+                    return Err(self.fail(PROTOCOL_ERROR, "new message before the last one ended"));
+                }
+                Opcode::Text | Opcode::Binary => partial = Some((frame.opcode, frame.payload)),
+                Opcode::Continuation => match &mut partial {
+                    Some((_, payload)) if payload.len() + frame.payload.len() <= MAX_MESSAGE => {
+                        payload.extend_from_slice(&frame.payload);
+                    }
+                    Some(_) => return Err(self.fail(MESSAGE_TOO_BIG, "message too big")),
+                    None => return Err(self.fail(PROTOCOL_ERROR, "continuation without a message")),
+                },
+            }
+
+            if frame.fin && !frame.opcode.is_control() {
+                let Some((opcode, payload)) = partial.take() else { continue };
+                return match opcode {
+                    Opcode::Text => match String::from_utf8(payload) {
+                        Ok(text) => Ok(Some(Message::Text(text))),
+                        Err(_) => Err(self.fail(INVALID_DATA, "text message is not valid UTF-8")),
+                    },
+                    _ => Ok(Some(Message::Binary(payload))),
+                };
+            }
+        }
+    }
+
+    /// Send `message` in a single frame.
+    pub fn send(&mut self, message: &Message) -> io::Result<()> {
+        if self.close_sent {
+            return Err(io::Error::new(io::ErrorKind::NotConnected, "WebSocket is closed"));
+        }
+        match message {
+            Message::Text(text) => write_frame(&mut self.stream, true, Opcode::Text, text.as_bytes(), None),
+            Message::Binary(bytes) => write_frame(&mut self.stream, true, Opcode::Binary, bytes, None),
+        }
+    }
+
+    /// Start the closing handshake with status `code`. Further messages
+    /// from the peer are discarded.
+    ///
+    /// A code no close frame may carry, such as 1005 or 1006, is left out
+    /// along with `reason`, closing without a status.
+    pub fn close(&mut self, code: u16, reason: &str) -> io::Result<()> {
+        if self.close_sent {
+            return Ok(());
+        }
+        self.close_sent = true;
+        let mut payload = Vec::new();
+        if is_valid_close_code(code) {
+            payload.extend(code.to_be_bytes());
+            // Control frames carry at most 125 bytes.
+            payload.extend(reason.bytes().take(123));
+        }
+        write_frame(&mut self.stream, true, Opcode::Close, &payload, None)
+    }
+
+    /// Close the connection after a protocol error, returning the error to
+    /// report.
+    fn fail(&mut self, code: u16, reason: &'static str) -> io::Error {
+        debug!("closing WebSocket: {reason}");
+        let _ = self.close(code, reason);
+        io::Error::new(io::ErrorKind::InvalidData, reason)
+    }
+
+    pub fn into_inner(self) -> S {
+        self.stream
+    }
+}
+
+/// Whether a close frame may carry status `code`: one RFC 6455 or the IANA
+/// registry defines for that, or one from the ranges left to libraries and
+/// applications. 1005, 1006 and 1015 only stand for closes without a frame
+/// saying why.
+fn is_valid_close_code(code: u16) -> bool {
+    matches!(code, 1000..=1003 | 1007..=1014 | 3000..=4999)
+}
+
+/// Whether the comma-separated field `name` lists `token`.
+fn has_token(request: &Request, name: &str, token: &str) -> bool {
+    request
+        .headers
+        .get_all(name)
+        .flat_map(|value| value.split(','))
+        .any(|t| t.trim().eq_ignore_ascii_case(token))
+}
+
+/// The `Sec-WebSocket-Accept` value proving the server read `key`.
+pub fn accept_key(key: &str) -> String {
+    let mut sha1 = Sha1::new();
+    sha1.update(key.as_bytes());
+    sha1.update(GUID.as_bytes());
+    STANDARD.encode(sha1.finalize())
+}
+
+/// Answer a request for a WebSocket endpoint: a 101 that hands the
+/// connection to `endpoint` for a valid opening handshake, a 426 for a
+/// plain HTTP request and a 400 for a malformed handshake.
+pub fn handshake(request: &Request, endpoint: Arc<Endpoint>) -> Response {
+    if !has_token(request, "Upgrade", "websocket") || !has_token(request, "Connection", "upgrade") {
+        return Response::new(Status::UpgradeRequired)
+            .with_header("Upgrade", "websocket")
+            .with_header("Connection", "Upgrade");
+    }
+    if request.headers.get("Sec-WebSocket-Version") != Some("13") {
+        return Response::new(Status::UpgradeRequired).with_header("Sec-WebSocket-Version", "13");
+    }
+    let key = match request.headers.get("Sec-WebSocket-Key") {
+        Some(key) if STANDARD.decode(key).is_ok_and(|nonce| nonce.len() == 16) => key,
+        _ => return Response::new(Status::BadRequest),
+    };
+    if request.method != Method::Get || request.version != "HTTP/1.1" {
+        return Response::new(Status::BadRequest);
+    }
+
+    let accept = accept_key(key);
+    let request = request.clone();
+    Response::new(Status::SwitchingProtocols)
+        .with_header("Upgrade", "websocket")
+        .with_header("Connection", "Upgrade")
+        .with_header("Sec-WebSocket-Accept", accept)
+        .with_upgrade(Box::new(move |connection: Connection| {
+            if let Err(e) = connection.set_timeouts(PING_INTERVAL, PING_INTERVAL) {
+                debug!("failed to set WebSocket timeouts: {e}");
+            }
+            let mut socket = WebSocket::new(connection);
+            endpoint(&request, &mut socket);
+            let _ = socket.close(NORMAL_CLOSURE, "");
+            socket.into_inner().close();
+        }))
+}
+
+/// An endpoint that sends every message back, for testing clients.
+pub fn echo(_: &Request, socket: &mut WebSocket) {
+    while let Ok(Some(message)) = socket.recv() {
+        if socket.send(&message).is_err() {
+            break;
+        }
+    }
+}
+
+/// A byte pipe standing in for a connection: reads come from `input`,
+/// writes go to `output`.
+#[cfg(test)]
+struct Duplex {
+    input: io::Cursor<Vec<u8>>,
+    output: Vec<u8>,
+}
+
+#[cfg(test)]
+impl Read for Duplex {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.input.read(buf)
+    }
+}
+
+#[cfg(test)]
+impl Write for Duplex {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.output.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// A frame from the client: whether it is final, its opcode and payload.
+#[cfg(test)]
+type ClientFrame<'a> = (bool, Opcode, &'a [u8]);
+
+/// A server-side socket that will read the client frames `frames`.
+#[cfg(test)]
+fn socket_reading(frames: &[ClientFrame]) -> WebSocket<Duplex> {
+    let mut input = Vec::new();
+    for &(fin, opcode, payload) in frames {
+        write_frame(&mut input, fin, opcode, payload, Some([1, 2, 3, 4])).unwrap();
+    }
+    WebSocket::new(Duplex { input: io::Cursor::new(input), output: Vec::new() })
+}
+
+/// Every frame the server side wrote.
+#[cfg(test)]
+fn written(socket: WebSocket<Duplex>) -> Vec<Frame> {
+    let output = socket.into_inner().output;
+    let mut reader = &output[..];
+    let mut frames = Vec::new();
+    while !reader.is_empty() {
+        frames.push(read_frame(&mut reader, MAX_MESSAGE).unwrap());
+    }
+    frames
+}
+
+#[test]
+fn test_accept_key() {
+    // The example from RFC 6455 section 1.3.
+    assert_eq!(accept_key("dGhlIHNhbXBsZSBub25jZQ=="), "s3pPLMBiTxaQ9kYGzzhZRbK+xOo=");
+}
+
+#[test]
+fn test_frame_round_trip() {
+    for len in [0, 125, 126, 65535, 65536] {
+        let payload = vec![7; len];
+        for mask in [None, Some([9, 8, 7, 6])] {
+            let mut wire = Vec::new();
+            write_frame(&mut wire, false, Opcode::Binary, &payload, mask).unwrap();
+            let frame = read_frame(&mut &wire[..], MAX_MESSAGE).unwrap();
+            assert_eq!(frame, Frame { fin: false, opcode: Opcode::Binary, masked: mask.is_some(), payload: payload.clone() });
+        }
+    }
+
+    let mut wire = Vec::new();
+    write_frame(&mut wire, true, Opcode::Text, b"Hello", Some([0x37, 0xfa, 0x21, 0x3d])).unwrap();
+    // The masked "Hello" example from RFC 6455 section 5.7.
+    assert_eq!(wire, [0x81, 0x85, 0x37, 0xfa, 0x21, 0x3d, 0x7f, 0x9f, 0x4d, 0x51, 0x58]);
+}
+
+#[test]
+fn test_invalid_frames() {
+    let protocol_error = |wire: &[u8], max| matches!(read_frame(&mut &wire[..], max), Err(FrameError::Protocol(..)));
+    assert!(protocol_error(&[0xC1, 0x00], MAX_MESSAGE), "reserved bit");
+    assert!(protocol_error(&[0x83, 0x00], MAX_MESSAGE), "unknown opcode");
+    assert!(protocol_error(&[0x09, 0x00], MAX_MESSAGE), "fragmented ping");
+    assert!(protocol_error(&[0x89, 0x7E, 0x00, 0x7E], MAX_MESSAGE), "long ping");
+    assert!(protocol_error(&[0x82, 0x05, 1, 2, 3, 4, 5], 4), "too big");
+}
+
+#[test]
+fn test_recv_reassembles_fragments() {
+    let mut socket = socket_reading(&[
+        (false, Opcode::Text, b"Hel"),
+        (true, Opcode::Ping, b"are you there"),
+        (false, Opcode::Continuation, b"lo, "),
+        (true, Opcode::Continuation, b"world"),
+        (true, Opcode::Binary, &[1, 2, 3]),
+        (true, Opcode::Close, &[0x03, 0xE8]),
+    ]);
+    assert_eq!(socket.recv().unwrap(), Some(Message::Text("Hello, world".to_string())));
+    assert_eq!(socket.recv().unwrap(), Some(Message::Binary(vec![1, 2, 3])));
+    assert_eq!(socket.recv().unwrap(), None);
+    assert!(socket.send(&Message::Text("too late".to_string())).is_err());
+
+    let frames = written(socket);
+    assert_eq!(frames.len(), 2);
+    assert_eq!((frames[0].opcode, &frames[0].payload[..]), (Opcode::Pong, &b"are you there"[..]));
+    assert_eq!((frames[1].opcode, &frames[1].payload[..]), (Opcode::Close, &[0x03, 0xE8][..]));
+    assert!(!frames[0].masked);
+}
+
+#[test]
+fn test_recv_rejects_protocol_errors() {
+    let cases: [(&[ClientFrame], u16); 9] = [
+        (&[(true, Opcode::Continuation, b"orphan")], PROTOCOL_ERROR),
+        (&[(false, Opcode::Text, b"a"), (true, Opcode::Text, b"b")], PROTOCOL_ERROR),
+        (&[(true, Opcode::Text, &[0xFF, 0xFE])], INVALID_DATA),
+        // Close frames with half a status code, or one no frame may carry.
+        (&[(true, Opcode::Close, &[0x03])], PROTOCOL_ERROR),
+        (&[(true, Opcode::Close, &1005u16.to_be_bytes())], PROTOCOL_ERROR),
+        (&[(true, Opcode::Close, &1006u16.to_be_bytes())], PROTOCOL_ERROR),
+        (&[(true, Opcode::Close, &1015u16.to_be_bytes())], PROTOCOL_ERROR),
+        (&[(true, Opcode::Close, &999u16.to_be_bytes())], PROTOCOL_ERROR),
+        (&[(true, Opcode::Close, &5000u16.to_be_bytes())], PROTOCOL_ERROR),
+    ];
+    for (frames, code) in cases {
+        let mut socket = socket_reading(frames);
+        assert_eq!(socket.recv().unwrap_err().kind(), io::ErrorKind::InvalidData);
+        let frames = written(socket);
+        assert_eq!(frames[0].opcode, Opcode::Close);
+        assert_eq!(frames[0].payload[..2], code.to_be_bytes());
+    }
+
+    // Clients must mask every frame.
+    let mut wire = Vec::new();
+    write_frame(&mut wire, true, Opcode::Text, b"plain", None).unwrap();
+    let mut socket = WebSocket::new(Duplex { input: io::Cursor::new(wire), output: Vec::new() });
+    assert!(socket.recv().is_err());
+}
+
+#[test]
+fn test_close_status() {
+    // Valid codes are echoed, and a close without one gets 1000.
+    for (payload, echoed) in [(&3000u16.to_be_bytes()[..], &3000u16.to_be_bytes()[..]), (&[], &[0x03, 0xE8])] {
+        let mut socket = socket_reading(&[(true, Opcode::Close, payload)]);
+        assert_eq!(socket.recv().unwrap(), None);
+        assert_eq!(written(socket)[0].payload, echoed);
+    }
+
+    // Codes reserved for closes without a frame are never sent.
+    let mut socket = socket_reading(&[]);
+    socket.close(1006, "abnormal").unwrap();
+    let frames = written(socket);
+    assert_eq!((frames[0].opcode, &frames[0].payload[..]), (Opcode::Close, &[][..]));
+}
+
+#[test]
+fn test_handshake() {
+    let request = |headers: &str| Request::read_from(&mut format!("GET /ws HTTP/1.1\r\n{headers}\r\n").as_bytes()).unwrap();
+    let endpoint: Arc<Endpoint> = Arc::new(echo);
+
+    let response = handshake(&request("Upgrade: websocket\r\nConnection: keep-alive, Upgrade\r\n\
+                                       Sec-WebSocket-Version: 13\r\nSec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\r\n"),
+                             Arc::clone(&endpoint));
+    assert_eq!(response.status, Status::SwitchingProtocols);
+    assert_eq!(response.headers.get("Sec-WebSocket-Accept"), Some("s3pPLMBiTxaQ9kYGzzhZRbK+xOo="));
+    assert!(response.upgrade.is_some());
+
+    let response = handshake(&request(""), Arc::clone(&endpoint));
+    assert_eq!(response.status, Status::UpgradeRequired);
+    let response = handshake(&request("Upgrade: websocket\r\nConnection: Upgrade\r\nSec-WebSocket-Version: 8\r\n"),
+                             Arc::clone(&endpoint));
+    assert_eq!(response.headers.get("Sec-WebSocket-Version"), Some("13"));
+    let response = handshake(&request("Upgrade: websocket\r\nConnection: Upgrade\r\n\
+                                       Sec-WebSocket-Version: 13\r\nSec-WebSocket-Key: short\r\n"),
+                             endpoint);
+    assert_eq!(response.status, Status::BadRequest);
+}
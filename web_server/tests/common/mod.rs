@@ -0,0 +1,204 @@
+//! Helpers shared by the integration tests: a server running on an
+//! ephemeral port and a small blocking HTTP/1.1 client.
+
+// Each test crate compiles this module and uses only some of it.
+#![allow(dead_code)]
+
+use std::collections::HashMap;
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::net::{SocketAddr, TcpStream};
+use std::path::PathBuf;
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+
+use web_server::config::Config;
+use web_server::logger::LogTarget;
+use web_server::server::{Handle, Server};
+
+/// How long the client waits for any one read before failing the test.
+const READ_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// The path of `name` in the directory the test servers serve files from.
+pub fn fixture(name: &str) -> PathBuf {
+    PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("tests/fixtures").join(name)
+}
+
+/// The configuration the tests start from: an ephemeral port on loopback,
+/// no access log, and files served from the fixture directory.
+pub fn config(event_loop: bool) -> Config {
+    Config {
+        listen: vec!["127.0.0.1:0".to_string()],
+        access_log: LogTarget::Off,
+        root: fixture(""),
+        error_pages: HashMap::from([(404, fixture("404.html"))]),
+        event_loop,
+        ..Config::default()
+    }
+}
+
+/// A server running on its own thread, stopped when dropped.
+pub struct TestServer {
+    pub addr: SocketAddr,
+    handle: Handle,
+    running: Option<JoinHandle<()>>,
+}
+
+impl TestServer {
+    pub fn start(config: Config) -> TestServer {
+        let server = Server::bind(config).expect("failed to start server");
+        let addr = server.local_addrs()[0];
+        let handle = server.handle();
+        let running = thread::spawn(move || server.run());
+        TestServer { addr, handle, running: Some(running) }
+    }
+
+    pub fn client(&self) -> Client {
+        Client::connect(self.addr)
+    }
+
+    /// Send a `GET` for `path` on a connection of its own.
+    pub fn get(&self, path: &str) -> Response {
+        self.client().request("GET", path, &[]).expect("request failed")
+    }
+
+    /// Stop the server and wait for `Server::run` to return.
+    pub fn stop(mut self) {
+        self.handle.stop();
+        if let Some(running) = self.running.take() {
+            running.join().expect("server thread panicked");
+        }
+    }
+
+    pub fn handle(&self) -> &Handle {
+        &self.handle
+    }
+}
+
+impl Drop for TestServer {
+    fn drop(&mut self) {
+        self.handle.stop();
+    }
+}
+
+/// A response as read off the wire.
+#[derive(Debug)]
+pub struct Response {
+    pub status: u16,
+    pub headers: Vec<(String, String)>,
+    pub body: Vec<u8>,
+}
+
+impl Response {
+    /// The first value of header `name`, matched case-insensitively.
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(n, _)| n.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value.as_str())
+    }
+
+    pub fn text(&self) -> String {
+        String::from_utf8_lossy(&self.body).into_owned()
+    }
+}
+
+/// One client connection, which may carry several requests.
+pub struct Client {
+    reader: BufReader<TcpStream>,
+}
+
+impl Client {
+    pub fn connect(addr: SocketAddr) -> Client {
+        let stream = TcpStream::connect(addr).expect("failed to connect");
+        stream.set_read_timeout(Some(READ_TIMEOUT)).unwrap();
+        Client { reader: BufReader::new(stream) }
+    }
+
+    /// Send `raw` as it is, for requests no well-behaved client would send.
+    pub fn send_raw(&mut self, raw: &[u8]) -> io::Result<()> {
+        self.reader.get_mut().write_all(raw)
+    }
+
+    /// Send a request with `headers` besides `Host`, and read the response.
+    pub fn request(&mut self, method: &str, path: &str, headers: &[(&str, &str)]) -> io::Result<Response> {
+        let mut head = format!("{method} {path} HTTP/1.1\r\nHost: localhost\r\n");
+        for (name, value) in headers {
+            head.push_str(&format!("{name}: {value}\r\n"));
+        }
+        head.push_str("\r\n");
+        self.send_raw(head.as_bytes())?;
+        self.read_response(method == "HEAD")
+    }
+
+    /// Read one response. Its body is delimited by `Content-Length`, chunked
+    /// coding or the end of the connection, and absent if `head_only`.
+    pub fn read_response(&mut self, head_only: bool) -> io::Result<Response> {
+        let status_line = self.read_line()?;
+        let status = status_line
+            .split(' ')
+            .nth(1)
+            .and_then(|code| code.parse().ok())
+            .ok_or_else(|| invalid(format!("bad status line: {status_line:?}")))?;
+
+        let mut headers = Vec::new();
+        loop {
+            let line = self.read_line()?;
+            if line.is_empty() {
+                break;
+            }
+            let (name, value) = line.split_once(':').ok_or_else(|| invalid(format!("bad header: {line:?}")))?;
+            headers.push((name.to_string(), value.trim().to_string()));
+        }
+        let mut response = Response { status, headers, body: Vec::new() };
+
+        if head_only || status == 204 || status == 304 {
+            return Ok(response);
+        }
+        if response.header("Transfer-Encoding").is_some_and(|coding| coding.eq_ignore_ascii_case("chunked")) {
+            response.body = self.read_chunked()?;
+        } else if let Some(length) = response.header("Content-Length") {
+            let length = length.parse().map_err(|_| invalid(format!("bad Content-Length: {length:?}")))?;
+            response.body = vec![0; length];
+            self.reader.read_exact(&mut response.body)?;
+        } else {
+            self.reader.read_to_end(&mut response.body)?;
+        }
+        Ok(response)
+    }
+
+    /// Whether the server has closed the connection, waiting up to the read
+    /// timeout for it to do so.
+    pub fn is_closed(&mut self) -> bool {
+        matches!(self.reader.read(&mut [0]), Ok(0) | Err(_))
+    }
+
+    fn read_chunked(&mut self) -> io::Result<Vec<u8>> {
+        let mut body = Vec::new();
+        loop {
+            let line = self.read_line()?;
+            let size = line.split(';').next().unwrap_or_default();
+            let size = usize::from_str_radix(size.trim(), 16).map_err(|_| invalid(format!("bad chunk size: {line:?}")))?;
+            if size == 0 {
+                // Trailers, up to the empty line.
+                while !self.read_line()?.is_empty() {}
+                return Ok(body);
+            }
+            let start = body.len();
+            body.resize(start + size, 0);
+            self.reader.read_exact(&mut body[start..])?;
+            self.read_line()?;
+        }
+    }
+
+    fn read_line(&mut self) -> io::Result<String> {
+        let mut line = String::new();
+        if self.reader.read_line(&mut line)? == 0 {
+            return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "connection closed"));
+        }
+        Ok(line.trim_end_matches(['\r', '\n']).to_string())
+    }
+}
+
+fn invalid(message: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
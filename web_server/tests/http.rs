@@ -0,0 +1,200 @@
+mod common;
+
+use std::fs;
+use std::net::{TcpListener, TcpStream};
+use std::sync::{mpsc, Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+
+use web_server::config::Config;
+use web_server::middleware::Next;
+use web_server::request::Request;
+use web_server::response::Response;
+use web_server::server::{self, Context, Server};
+
+use common::{config, fixture, Client, TestServer};
+
+/// Run `test` against a server in each connection mode the platform has.
+fn each_mode(test: impl Fn(bool)) {
+    test(false);
+    if cfg!(unix) {
+        test(true);
+    }
+}
+
+#[test]
+fn test_routing() {
+    each_mode(|event_loop| {
+        let server = TestServer::start(config(event_loop));
+
+        let response = server.get("/");
+        assert_eq!(response.status, 200);
+        assert_eq!(response.header("Content-Type"), Some("text/html; charset=utf-8"));
+        assert_eq!(response.body, fs::read(fixture("hello.html")).unwrap());
+
+        let response = server.get("/docs/notes.txt");
+        assert_eq!(response.status, 200);
+        assert_eq!(response.header("Content-Type"), Some("text/plain; charset=utf-8"));
+        assert_eq!(response.body, fs::read(fixture("docs/notes.txt")).unwrap());
+
+        let response = server.client().request("HEAD", "/hello.html", &[]).unwrap();
+        assert_eq!(response.status, 200);
+        assert_eq!(response.header("Content-Length"), Some(fs::metadata(fixture("hello.html")).unwrap().len().to_string().as_str()));
+        assert!(response.body.is_empty());
+
+        let response = server.client().request("POST", "/hello.html", &[("Content-Length", "0")]).unwrap();
+        assert_eq!(response.status, 405);
+        assert_eq!(response.header("Allow"), Some("GET, HEAD"));
+    });
+}
+
+#[test]
+fn test_not_found() {
+    each_mode(|event_loop| {
+        let server = TestServer::start(config(event_loop));
+
+        let response = server.get("/missing.html");
+        assert_eq!(response.status, 404);
+        assert_eq!(response.body, fs::read(fixture("404.html")).unwrap());
+
+        // Directories without an index, and paths outside the root.
+        assert_eq!(server.get("/docs").status, 404);
+        assert_eq!(server.get("/../fixtures/hello.html").status, 404);
+        assert_eq!(server.get("/docs/%2e%2e/%2e%2e/Cargo.toml").status, 404);
+    });
+}
+
+#[test]
+fn test_malformed_requests() {
+    each_mode(|event_loop| {
+        let server = TestServer::start(config(event_loop));
+        let cases: [(&[u8], u16); 5] = [
+            (b"garbage\r\n\r\n", 400),
+            (b"GET / HTTP/3\r\nHost: localhost\r\n\r\n", 505),
+            (b"GET / HTTP/1.1\r\nHost: localhost\r\nno colon here\r\n\r\n", 400),
+            (b"POST / HTTP/1.1\r\nHost: localhost\r\nContent-Length: many\r\n\r\n", 400),
+            (b"POST / HTTP/1.1\r\nHost: localhost\r\nTransfer-Encoding: gzip\r\n\r\n", 501),
+        ];
+        for (raw, status) in cases {
+            let mut client = server.client();
+            client.send_raw(raw).unwrap();
+            let response = client.read_response(false).unwrap();
+            assert_eq!(response.status, status, "{}", String::from_utf8_lossy(raw));
+            assert!(client.is_closed(), "{}", String::from_utf8_lossy(raw));
+        }
+
+        // A request without a Host is refused, but its connection can carry
+        // the next one.
+        let mut client = server.client();
+        client.send_raw(b"GET / HTTP/1.1\r\n\r\n").unwrap();
+        assert_eq!(client.read_response(false).unwrap().status, 400);
+        assert_eq!(client.request("GET", "/", &[]).unwrap().status, 200);
+
+        let mut client = server.client();
+        let padding = "x".repeat(64 * 1024);
+        // The server may answer and hang up before the whole head is sent.
+        let _ = client.send_raw(format!("GET / HTTP/1.1\r\nHost: localhost\r\nX-Padding: {padding}\r\n\r\n").as_bytes());
+        assert_eq!(client.read_response(false).unwrap().status, 431);
+
+        // Broken clients leave the server serving others.
+        assert_eq!(server.get("/").status, 200);
+    });
+}
+
+#[test]
+fn test_concurrent_clients() {
+    each_mode(|event_loop| {
+        let server = TestServer::start(config(event_loop));
+        let expected = Arc::new(fs::read(fixture("hello.html")).unwrap());
+
+        let clients: Vec<_> = (0..32)
+            .map(|_| {
+                let addr = server.addr;
+                let expected = Arc::clone(&expected);
+                thread::spawn(move || {
+                    let mut client = Client::connect(addr);
+                    for _ in 0..20 {
+                        let response = client.request("GET", "/", &[]).unwrap();
+                        assert_eq!(response.status, 200);
+                        assert_eq!(response.body, *expected);
+                    }
+                })
+            })
+            .collect();
+        for client in clients {
+            client.join().unwrap();
+        }
+    });
+}
+
+#[test]
+fn test_shutdown() {
+    each_mode(|event_loop| {
+        // Holds the slow request until the server has been told to stop.
+        let (arrived, arrival) = mpsc::channel();
+        let (release, released) = mpsc::channel::<()>();
+        let released = Mutex::new(released);
+        let slow = move |request: &mut Request, next: Next<'_>| -> Response {
+            if request.target.ends_with("?slow") {
+                arrived.send(()).unwrap();
+                let _ = released.lock().unwrap().recv();
+            }
+            next.run(request)
+        };
+        let config = Config {
+            // The blocking loop closes an idle connection only once its
+            // keep-alive ends.
+            keep_alive_timeout: Duration::from_secs(1),
+            ..config(event_loop)
+        };
+        let server = Server::bind(config).unwrap().layer(slow);
+        let addr = server.local_addrs()[0];
+        let handle = server.handle();
+        let running = thread::spawn(move || server.run());
+
+        // A kept-alive connection between requests, and one in the middle
+        // of a request.
+        let mut idle = Client::connect(addr);
+        assert_eq!(idle.request("GET", "/", &[]).unwrap().status, 200);
+        let mut busy = Client::connect(addr);
+        busy.send_raw(b"GET /?slow HTTP/1.1\r\nHost: localhost\r\n\r\n").unwrap();
+        arrival.recv().unwrap();
+
+        handle.stop();
+        release.send(()).unwrap();
+        let response = busy.read_response(false).unwrap();
+        assert_eq!(response.status, 200);
+        assert_eq!(response.text(), fs::read_to_string(fixture("hello.html")).unwrap());
+        assert_eq!(response.header("Connection"), Some("close"));
+        assert!(busy.is_closed());
+        assert!(idle.is_closed());
+
+        running.join().unwrap();
+        assert!(TcpStream::connect(addr).is_err());
+    });
+
+    let server = TestServer::start(config(false));
+    assert_eq!(server.get("/").status, 200);
+    assert!(!server.handle().is_stopping());
+    server.stop();
+}
+
+#[test]
+fn test_handle_connection() {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+    let context = Context::new(config(false)).unwrap();
+    let serving = thread::spawn(move || {
+        let (stream, _) = listener.accept().unwrap();
+        server::handle_connection(stream, None, &context);
+    });
+
+    let mut client = Client::connect(addr);
+    assert_eq!(client.request("GET", "/", &[]).unwrap().status, 200);
+    assert_eq!(client.request("GET", "/missing", &[]).unwrap().status, 404);
+    let response = client.request("GET", "/", &[("Connection", "close")]).unwrap();
+    assert_eq!(response.status, 200);
+    assert_eq!(response.header("Connection"), Some("close"));
+    assert!(client.is_closed());
+    serving.join().unwrap();
+}
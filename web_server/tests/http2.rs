@@ -0,0 +1,393 @@
+mod common;
+
+use std::collections::HashMap;
+use std::fs;
+use std::io::{self, Read, Write};
+use std::net::{SocketAddr, TcpStream};
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use web_server::config::Config;
+use web_server::hpack::{self, Decoder};
+use web_server::http2::PREFACE;
+
+use common::{config, fixture, Client, Response, TestServer};
+
+const DATA: u8 = 0x0;
+const HEADERS: u8 = 0x1;
+const RST_STREAM: u8 = 0x3;
+const SETTINGS: u8 = 0x4;
+const PING: u8 = 0x6;
+const GOAWAY: u8 = 0x7;
+const WINDOW_UPDATE: u8 = 0x8;
+
+const END_STREAM: u8 = 0x1;
+const ACK: u8 = 0x1;
+const END_HEADERS: u8 = 0x4;
+
+const INITIAL_WINDOW_SIZE: u16 = 0x4;
+
+#[derive(Debug)]
+struct Frame {
+    kind: u8,
+    flags: u8,
+    stream: u32,
+    payload: Vec<u8>,
+}
+
+/// A minimal HTTP/2 client: it sends requests with uncompressed header
+/// blocks and reads whatever the server sends back.
+struct H2<S> {
+    stream: S,
+    decoder: Decoder,
+    next_stream: u32,
+    /// Whether received DATA is acknowledged with WINDOW_UPDATE frames.
+    auto_window: bool,
+}
+
+impl<S: Read + Write> H2<S> {
+    /// Send the connection preface with `settings`, and take the server's
+    /// settings in return.
+    fn start(stream: S, settings: &[(u16, u32)]) -> H2<S> {
+        let mut client = H2 { stream, decoder: Decoder::new(4096), next_stream: 1, auto_window: true };
+        client.stream.write_all(PREFACE).unwrap();
+        let payload: Vec<u8> = settings
+            .iter()
+            .flat_map(|(id, value)| id.to_be_bytes().into_iter().chain(value.to_be_bytes()))
+            .collect();
+        client.send(SETTINGS, 0, 0, &payload);
+        let frame = client.read_frame().unwrap();
+        assert_eq!((frame.kind, frame.flags), (SETTINGS, 0));
+        client
+    }
+
+    fn send(&mut self, kind: u8, flags: u8, stream: u32, payload: &[u8]) {
+        let mut frame = (payload.len() as u32).to_be_bytes()[1..].to_vec();
+        frame.extend_from_slice(&[kind, flags]);
+        frame.extend_from_slice(&stream.to_be_bytes());
+        frame.extend_from_slice(payload);
+        self.stream.write_all(&frame).unwrap();
+    }
+
+    fn read_frame(&mut self) -> io::Result<Frame> {
+        let mut head = [0; 9];
+        self.stream.read_exact(&mut head)?;
+        let length = u32::from_be_bytes([0, head[0], head[1], head[2]]) as usize;
+        let mut payload = vec![0; length];
+        self.stream.read_exact(&mut payload)?;
+        let stream = u32::from_be_bytes([head[5], head[6], head[7], head[8]]);
+        Ok(Frame { kind: head[3], flags: head[4], stream, payload })
+    }
+
+    /// Open a stream for a request, ending it at once unless a body is to
+    /// follow. Return the stream's id.
+    fn request(&mut self, method: &str, path: &str, headers: &[(&str, &str)], end_stream: bool) -> u32 {
+        let id = self.next_stream;
+        self.next_stream += 2;
+        let mut block = Vec::new();
+        let pseudo = [(":method", method), (":scheme", "http"), (":authority", "localhost"), (":path", path)];
+        hpack::encode(pseudo.into_iter().chain(headers.iter().copied()), &mut block);
+        self.send(HEADERS, if end_stream { END_HEADERS | END_STREAM } else { END_HEADERS }, id, &block);
+        id
+    }
+
+    fn get(&mut self, path: &str) -> Response {
+        let id = self.request("GET", path, &[], true);
+        self.read_responses(&[id]).remove(&id).unwrap()
+    }
+
+    /// Read frames until every stream in `ids` has ended, answering pings
+    /// and settings and panicking at resets.
+    fn read_responses(&mut self, ids: &[u32]) -> HashMap<u32, Response> {
+        let mut responses = HashMap::new();
+        let mut ended = 0;
+        while ended < ids.len() {
+            let frame = self.read_frame().expect("connection failed");
+            match frame.kind {
+                HEADERS => {
+                    assert_ne!(frame.flags & END_HEADERS, 0, "CONTINUATION is not expected here");
+                    let fields = self.decoder.decode(&frame.payload).unwrap();
+                    let mut response = Response { status: 0, headers: Vec::new(), body: Vec::new() };
+                    for (name, value) in fields {
+                        let (name, value) = (String::from_utf8(name).unwrap(), String::from_utf8(value).unwrap());
+                        match name.as_str() {
+                            ":status" => response.status = value.parse().unwrap(),
+                            _ => response.headers.push((name, value)),
+                        }
+                    }
+                    responses.insert(frame.stream, response);
+                }
+                DATA => {
+                    responses.get_mut(&frame.stream).expect("DATA before HEADERS").body.extend(&frame.payload);
+                    if self.auto_window && !frame.payload.is_empty() {
+                        let increment = (frame.payload.len() as u32).to_be_bytes();
+                        self.send(WINDOW_UPDATE, 0, 0, &increment);
+                        self.send(WINDOW_UPDATE, 0, frame.stream, &increment);
+                    }
+                }
+                SETTINGS if frame.flags & ACK == 0 => self.send(SETTINGS, ACK, 0, &[]),
+                PING if frame.flags & ACK == 0 => self.send(PING, ACK, 0, &frame.payload),
+                RST_STREAM | GOAWAY => panic!("unexpected {frame:?}"),
+                _ => {}
+            }
+            if frame.flags & END_STREAM != 0 && matches!(frame.kind, HEADERS | DATA) && ids.contains(&frame.stream) {
+                ended += 1;
+            }
+        }
+        responses
+    }
+
+    /// Read frames until one of `kind` arrives, and return it.
+    fn expect(&mut self, kind: u8) -> Frame {
+        loop {
+            let frame = self.read_frame().expect("connection failed");
+            if frame.kind == kind {
+                return frame;
+            }
+        }
+    }
+}
+
+fn connect(addr: SocketAddr) -> TcpStream {
+    let stream = TcpStream::connect(addr).unwrap();
+    stream.set_read_timeout(Some(Duration::from_secs(10))).unwrap();
+    stream
+}
+
+fn error_code(frame: &Frame) -> u32 {
+    let code = &frame.payload[frame.payload.len() - 4..];
+    u32::from_be_bytes(code.try_into().unwrap())
+}
+
+#[test]
+fn test_prior_knowledge() {
+    for event_loop in [false, cfg!(unix)] {
+        let server = TestServer::start(config(event_loop));
+        let mut client = H2::start(connect(server.addr), &[]);
+
+        let response = client.get("/hello.html");
+        assert_eq!(response.status, 200);
+        assert_eq!(response.header("content-type"), Some("text/html; charset=utf-8"));
+        assert_eq!(response.header("content-length"), Some("164"));
+        assert!(response.headers.iter().all(|(name, _)| name == &name.to_lowercase()));
+        assert_eq!(response.body, fs::read(fixture("hello.html")).unwrap());
+
+        // Several requests at once on the one connection, including bodies
+        // larger than the initial flow-control window.
+        let ids: Vec<u32> = ["/large.txt", "/hello.html", "/missing", "/large.txt?again"]
+            .iter()
+            .map(|path| client.request("GET", path, &[], true))
+            .collect();
+        let responses = client.read_responses(&ids);
+        assert_eq!(responses[&ids[0]].body, fs::read(fixture("large.txt")).unwrap());
+        assert_eq!(responses[&ids[1]].body, fs::read(fixture("hello.html")).unwrap());
+        assert_eq!(responses[&ids[2]].status, 404);
+        assert_eq!(responses[&ids[3]].body, fs::read(fixture("large.txt")).unwrap());
+
+        let id = client.request("HEAD", "/hello.html", &[], true);
+        let response = client.read_responses(&[id]).remove(&id).unwrap();
+        assert_eq!(response.status, 200);
+        assert_eq!(response.header("content-length"), Some("164"));
+        assert!(response.body.is_empty());
+
+        // HTTP/1.1 still works alongside.
+        assert_eq!(server.get("/hello.html").status, 200);
+    }
+}
+
+#[test]
+fn test_flow_control() {
+    let server = TestServer::start(config(false));
+    let mut client = H2::start(connect(server.addr), &[(INITIAL_WINDOW_SIZE, 1000)]);
+    client.auto_window = false;
+
+    let id = client.request("GET", "/large.txt", &[], true);
+    assert_eq!(client.expect(HEADERS).stream, id);
+    let mut body = Vec::new();
+    while body.len() < 1000 {
+        let frame = client.expect(DATA);
+        body.extend(frame.payload);
+    }
+    assert_eq!(body.len(), 1000);
+
+    // Nothing more comes until the client makes room for it.
+    client.send(PING, 0, 0, b"waiting!");
+    let frame = client.read_frame().unwrap();
+    assert_eq!((frame.kind, frame.flags, &frame.payload[..]), (PING, ACK, &b"waiting!"[..]));
+
+    for stream in [0, id] {
+        client.send(WINDOW_UPDATE, 0, stream, &(1_u32 << 20).to_be_bytes());
+    }
+    loop {
+        let frame = client.expect(DATA);
+        body.extend(frame.payload);
+        if frame.flags & END_STREAM != 0 {
+            break;
+        }
+    }
+    assert_eq!(body, fs::read(fixture("large.txt")).unwrap());
+}
+
+#[test]
+fn test_stream_threads() {
+    let server = TestServer::start(Config { stream_threads: 1, ..config(false) });
+    // A stream waiting for the client to make room holds the only thread.
+    let mut client = H2::start(connect(server.addr), &[(INITIAL_WINDOW_SIZE, 0)]);
+    client.auto_window = false;
+    let id = client.request("GET", "/large.txt", &[], true);
+    assert_eq!(client.expect(HEADERS).stream, id);
+
+    // Streams past the limit are refused, for the client to retry.
+    let refused = client.request("GET", "/hello.html", &[], true);
+    let frame = client.expect(RST_STREAM);
+    assert_eq!((frame.stream, error_code(&frame)), (refused, 0x7));
+
+    for stream in [0, id] {
+        client.send(WINDOW_UPDATE, 0, stream, &(1_u32 << 20).to_be_bytes());
+    }
+    let mut body = Vec::new();
+    loop {
+        let frame = client.expect(DATA);
+        body.extend(frame.payload);
+        if frame.flags & END_STREAM != 0 {
+            break;
+        }
+    }
+    assert_eq!(body, fs::read(fixture("large.txt")).unwrap());
+}
+
+#[test]
+fn test_protocol_errors() {
+    let server = TestServer::start(config(false));
+
+    // A malformed request fails its stream alone.
+    let mut client = H2::start(connect(server.addr), &[]);
+    let id = client.request("GET", "/", &[("X-Upper", "case")], true);
+    let frame = client.expect(RST_STREAM);
+    assert_eq!((frame.stream, error_code(&frame)), (id, 0x1));
+    assert_eq!(client.get("/hello.html").status, 200);
+
+    // Broken framing fails the connection.
+    client.send(DATA, 0, 0, b"nowhere");
+    let frame = client.expect(GOAWAY);
+    assert_eq!(error_code(&frame), 0x1);
+    assert_eq!(u32::from_be_bytes(frame.payload[..4].try_into().unwrap()), id + 2);
+    assert!(client.read_frame().is_err());
+
+    // A client must start with its settings.
+    let mut stream = connect(server.addr);
+    stream.write_all(PREFACE).unwrap();
+    let mut client = H2 { stream, decoder: Decoder::new(4096), next_stream: 1, auto_window: true };
+    client.send(PING, 0, 0, &[0; 8]);
+    assert_eq!(error_code(&client.expect(GOAWAY)), 0x1);
+}
+
+#[cfg(unix)]
+#[test]
+fn test_request_body() {
+    use std::os::unix::fs::PermissionsExt;
+
+    use web_server::cgi::CgiRule;
+
+    let dir = std::env::temp_dir().join(format!("web_server_http2_cgi_{}", std::process::id()));
+    fs::create_dir_all(&dir).unwrap();
+    fs::write(dir.join("echo.sh"), "#!/bin/sh\nprintf 'Content-Type: text/plain\\n\\n'\ncat\n").unwrap();
+    fs::set_permissions(dir.join("echo.sh"), fs::Permissions::from_mode(0o755)).unwrap();
+    let server = TestServer::start(Config {
+        cgi: vec![CgiRule::scripts("/cgi-bin", dir.clone())],
+        ..config(false)
+    });
+
+    // Three times the initial window, so the server has to make room as
+    // the body is read.
+    let body: Vec<u8> = (0..200_000).map(|i| (i % 251) as u8).collect();
+    let mut client = H2::start(connect(server.addr), &[]);
+    let id = client.request("POST", "/cgi-bin/echo.sh", &[("content-length", "200000")], false);
+    let (mut window, mut sent) = (65_535, 0);
+    while sent < body.len() {
+        if window == 0 {
+            let frame = client.expect(WINDOW_UPDATE);
+            if frame.stream == id {
+                window += u32::from_be_bytes(frame.payload[..].try_into().unwrap()) as usize;
+            }
+            continue;
+        }
+        let chunk = (body.len() - sent).min(window).min(16_384);
+        let last = sent + chunk == body.len();
+        client.send(DATA, if last { END_STREAM } else { 0 }, id, &body[sent..sent + chunk]);
+        (window, sent) = (window - chunk, sent + chunk);
+    }
+    let response = client.read_responses(&[id]).remove(&id).unwrap();
+    assert_eq!(response.status, 200);
+    assert_eq!(response.body, body);
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn test_tls_alpn() {
+    let dir = std::env::temp_dir().join(format!("web_server_http2_tls_{}", std::process::id()));
+    fs::create_dir_all(&dir).unwrap();
+    let rcgen::CertifiedKey { cert, key_pair } = rcgen::generate_simple_self_signed(vec!["localhost".to_string()]).unwrap();
+    fs::write(dir.join("cert.pem"), cert.pem()).unwrap();
+    fs::write(dir.join("key.pem"), key_pair.serialize_pem()).unwrap();
+
+    let server = TestServer::start(Config {
+        listen: Vec::new(),
+        tls_listen: vec!["127.0.0.1:0".to_string()],
+        tls_cert: Some(dir.join("cert.pem")),
+        tls_key: Some(dir.join("key.pem")),
+        header_timeout: Duration::from_secs(1),
+        ..config(false)
+    });
+    let mut roots = rustls::RootCertStore::empty();
+    roots.add(cert.der().clone()).unwrap();
+    let client_tls = |protocols: &[&[u8]]| {
+        let mut config = rustls::ClientConfig::builder_with_provider(Arc::new(rustls::crypto::ring::default_provider()))
+            .with_safe_default_protocol_versions()
+            .unwrap()
+            .with_root_certificates(roots.clone())
+            .with_no_client_auth();
+        config.alpn_protocols = protocols.iter().map(|protocol| protocol.to_vec()).collect();
+        rustls::ClientConnection::new(Arc::new(config), "localhost".try_into().unwrap()).unwrap()
+    };
+    let connect_tls = |protocols: &[&[u8]]| rustls::StreamOwned::new(client_tls(protocols), connect(server.addr));
+
+    let mut client = H2::start(connect_tls(&[b"h2", b"http/1.1"]), &[]);
+    assert_eq!(client.stream.conn.alpn_protocol(), Some(&b"h2"[..]));
+    let ids: Vec<u32> = (0..3).map(|_| client.request("GET", "/large.txt", &[], true)).collect();
+    let expected = fs::read(fixture("large.txt")).unwrap();
+    for response in client.read_responses(&ids).values() {
+        assert_eq!(response.body, expected);
+    }
+
+    // Clients that only speak HTTP/1.1 get it.
+    let mut stream = connect_tls(&[b"http/1.1"]);
+    stream.write_all(b"GET /hello.html HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n").unwrap();
+    let mut response = String::new();
+    let _ = stream.read_to_string(&mut response);
+    assert!(response.starts_with("HTTP/1.1 200 OK\r\n"));
+
+    // A handshake trickled in byte by byte is cut off after header_timeout,
+    // though each byte arrives well within the read timeout.
+    let mut hello = Vec::new();
+    client_tls(&[b"h2"]).write_tls(&mut hello).unwrap();
+    let mut stream = connect(server.addr);
+    let started = Instant::now();
+    for byte in hello {
+        if stream.write_all(&[byte]).is_err() {
+            break;
+        }
+        thread::sleep(Duration::from_millis(100));
+    }
+    assert!(started.elapsed() < Duration::from_secs(5), "{:?}", started.elapsed());
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn test_disabled() {
+    let server = TestServer::start(Config { http2: false, ..config(false) });
+    let mut client = Client::connect(server.addr);
+    client.send_raw(PREFACE).unwrap();
+    assert_eq!(client.read_response(false).unwrap().status, 505);
+}